@@ -0,0 +1,425 @@
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::{NetError, NetResult};
+use crate::framing::Endian;
+use crate::session::{self, Updated};
+
+/// Rocksoft-model CRC parameters, as used by the reveng catalogue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrcParams {
+    /// Bits, 1 to 64.
+    pub width: u32,
+    pub poly: u64,
+    pub init: u64,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: u64,
+}
+
+/// A catalogue entry; `check` is the CRC of the ASCII string `123456789`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CrcPreset {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    #[serde(flatten)]
+    pub params: CrcParams,
+    pub check: u64,
+}
+
+const fn crc(width: u32, poly: u64, init: u64, refl: bool, xorout: u64) -> CrcParams {
+    CrcParams {
+        width,
+        poly,
+        init,
+        refin: refl,
+        refout: refl,
+        xorout,
+    }
+}
+
+/// The CRC closing every Modbus RTU frame, sent low byte first.
+pub const CRC16_MODBUS: CrcParams = crc(16, 0x8005, 0xFFFF, true, 0x0000);
+
+#[rustfmt::skip]
+pub const PRESETS: &[CrcPreset] = &[
+    CrcPreset { name: "CRC-8/SMBUS", aliases: &["crc8"], params: crc(8, 0x07, 0x00, false, 0x00), check: 0xF4 },
+    CrcPreset { name: "CRC-8/MAXIM-DOW", aliases: &["crc8_maxim", "dallas"], params: crc(8, 0x31, 0x00, true, 0x00), check: 0xA1 },
+    CrcPreset { name: "CRC-8/AUTOSAR", aliases: &[], params: crc(8, 0x2F, 0xFF, false, 0xFF), check: 0xDF },
+    CrcPreset { name: "CRC-8/ROHC", aliases: &[], params: crc(8, 0x07, 0xFF, true, 0x00), check: 0xD0 },
+    CrcPreset { name: "CRC-8/I-432-1", aliases: &["crc8_itu"], params: crc(8, 0x07, 0x00, false, 0x55), check: 0xA1 },
+    CrcPreset { name: "CRC-16/MODBUS", aliases: &["crc16_modbus", "modbus"], params: CRC16_MODBUS, check: 0x4B37 },
+    CrcPreset { name: "CRC-16/ARC", aliases: &["crc16", "crc16_ibm"], params: crc(16, 0x8005, 0x0000, true, 0x0000), check: 0xBB3D },
+    CrcPreset { name: "CRC-16/IBM-3740", aliases: &["crc16_ccitt_false"], params: crc(16, 0x1021, 0xFFFF, false, 0x0000), check: 0x29B1 },
+    CrcPreset { name: "CRC-16/XMODEM", aliases: &["crc16_xmodem"], params: crc(16, 0x1021, 0x0000, false, 0x0000), check: 0x31C3 },
+    CrcPreset { name: "CRC-16/KERMIT", aliases: &["crc16_ccitt"], params: crc(16, 0x1021, 0x0000, true, 0x0000), check: 0x2189 },
+    CrcPreset { name: "CRC-16/IBM-SDLC", aliases: &["crc16_x25"], params: crc(16, 0x1021, 0xFFFF, true, 0xFFFF), check: 0x906E },
+    CrcPreset { name: "CRC-16/USB", aliases: &[], params: crc(16, 0x8005, 0xFFFF, true, 0xFFFF), check: 0xB4C8 },
+    CrcPreset { name: "CRC-16/DNP", aliases: &[], params: crc(16, 0x3D65, 0x0000, true, 0xFFFF), check: 0xEA82 },
+    CrcPreset { name: "CRC-16/MAXIM-DOW", aliases: &[], params: crc(16, 0x8005, 0x0000, true, 0xFFFF), check: 0x44C2 },
+    CrcPreset { name: "CRC-32/ISO-HDLC", aliases: &["crc32"], params: crc(32, 0x04C1_1DB7, 0xFFFF_FFFF, true, 0xFFFF_FFFF), check: 0xCBF4_3926 },
+    CrcPreset { name: "CRC-32/BZIP2", aliases: &[], params: crc(32, 0x04C1_1DB7, 0xFFFF_FFFF, false, 0xFFFF_FFFF), check: 0xFC89_1918 },
+    CrcPreset { name: "CRC-32/ISCSI", aliases: &["crc32c"], params: crc(32, 0x1EDC_6F41, 0xFFFF_FFFF, true, 0xFFFF_FFFF), check: 0xE306_9283 },
+    CrcPreset { name: "CRC-32/MPEG-2", aliases: &[], params: crc(32, 0x04C1_1DB7, 0xFFFF_FFFF, false, 0x0000_0000), check: 0x0376_E6E7 },
+    CrcPreset { name: "CRC-32/CKSUM", aliases: &["crc32_posix"], params: crc(32, 0x04C1_1DB7, 0x0000_0000, false, 0xFFFF_FFFF), check: 0x765E_7680 },
+    CrcPreset { name: "CRC-32/JAMCRC", aliases: &[], params: crc(32, 0x04C1_1DB7, 0xFFFF_FFFF, true, 0x0000_0000), check: 0x340B_C6D9 },
+];
+
+/// How callers name a checksum: a catalogue CRC or simple checksum by name
+/// (`"CRC-16/MODBUS"`, `"crc32"`, `"adler32"`, `"lrc"`, ...), or explicit CRC parameters.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Algorithm {
+    Named(String),
+    Crc(CrcParams),
+}
+
+/// A resolved [`Algorithm`].
+#[derive(Clone, Copy, Debug)]
+pub enum Checksum {
+    Crc(CrcParams),
+    /// Two mod-255 sums over bytes.
+    Fletcher16,
+    /// Two mod-65535 sums over big-endian 16-bit words; an odd last byte is zero-padded.
+    Fletcher32,
+    Adler32,
+    /// Byte sum, mod 256.
+    Sum8,
+    /// Two's complement of the byte sum, as in Modbus ASCII.
+    Lrc,
+    Xor,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChecksumResult {
+    pub algorithm: String,
+    pub value: u64,
+    /// Zero-padded to the checksum width.
+    pub hex: String,
+    /// The checksum as it would be appended to a message.
+    pub bytes_b64: String,
+}
+
+/// Appended to every message a session sends, see `set_send_checksum`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AppendChecksum {
+    pub algorithm: Algorithm,
+    /// Defaults to low byte first for reflected CRCs (Modbus, X.25, ...), high byte first otherwise.
+    pub endian: Option<Endian>,
+    /// Leading bytes left out of the checksum, e.g. a start-of-frame marker.
+    #[serde(default)]
+    pub skip: usize,
+}
+
+impl Algorithm {
+    pub fn resolve(&self) -> NetResult<Checksum> {
+        match self {
+            Algorithm::Crc(p) => {
+                if !(1..=64).contains(&p.width) {
+                    return Err(NetError::unsupported(format!(
+                        "CRC width must be 1 to 64 bits, got {}",
+                        p.width
+                    )));
+                }
+                Ok(Checksum::Crc(*p))
+            }
+            Algorithm::Named(name) => {
+                let simple = match name.to_ascii_lowercase().as_str() {
+                    "fletcher16" => Some(Checksum::Fletcher16),
+                    "fletcher32" => Some(Checksum::Fletcher32),
+                    "adler32" => Some(Checksum::Adler32),
+                    "sum8" => Some(Checksum::Sum8),
+                    "lrc" => Some(Checksum::Lrc),
+                    "xor" => Some(Checksum::Xor),
+                    _ => None,
+                };
+                simple
+                    .or_else(|| preset(name).map(|p| Checksum::Crc(p.params)))
+                    .ok_or_else(|| NetError::unsupported(format!("unknown checksum {}", name)))
+            }
+        }
+    }
+}
+
+/// Looks a CRC up by catalogue name or alias, ignoring case.
+pub fn preset(name: &str) -> Option<&'static CrcPreset> {
+    PRESETS.iter().find(|p| {
+        p.name.eq_ignore_ascii_case(name) || p.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    })
+}
+
+impl Checksum {
+    /// Bytes the checksum takes up in a message.
+    pub fn width(self) -> usize {
+        match self {
+            Checksum::Crc(p) => p.width.div_ceil(8) as usize,
+            Checksum::Fletcher16 => 2,
+            Checksum::Fletcher32 | Checksum::Adler32 => 4,
+            Checksum::Sum8 | Checksum::Lrc | Checksum::Xor => 1,
+        }
+    }
+
+    pub fn default_endian(self) -> Endian {
+        match self {
+            Checksum::Crc(p) if p.refout => Endian::Little,
+            _ => Endian::Big,
+        }
+    }
+
+    pub fn compute(self, data: &[u8]) -> u64 {
+        match self {
+            Checksum::Crc(p) => crc_compute(&p, data),
+            Checksum::Fletcher16 => {
+                let (a, b) = data.iter().fold((0u32, 0u32), |(a, b), x| {
+                    let a = (a + *x as u32) % 255;
+                    (a, (b + a) % 255)
+                });
+                (b << 8 | a) as u64
+            }
+            Checksum::Fletcher32 => {
+                let (a, b) = data.chunks(2).fold((0u64, 0u64), |(a, b), w| {
+                    let word = (w[0] as u64) << 8 | w.get(1).copied().unwrap_or(0) as u64;
+                    let a = (a + word) % 65_535;
+                    (a, (b + a) % 65_535)
+                });
+                b << 16 | a
+            }
+            Checksum::Adler32 => {
+                let (a, b) = data.iter().fold((1u64, 0u64), |(a, b), x| {
+                    let a = (a + *x as u64) % 65_521;
+                    (a, (b + a) % 65_521)
+                });
+                b << 16 | a
+            }
+            Checksum::Sum8 => data.iter().fold(0u8, |s, x| s.wrapping_add(*x)) as u64,
+            Checksum::Lrc => data
+                .iter()
+                .fold(0u8, |s, x| s.wrapping_add(*x))
+                .wrapping_neg() as u64,
+            Checksum::Xor => data.iter().fold(0u8, |s, x| s ^ x) as u64,
+        }
+    }
+
+    /// The checksum of `data` in `endian` byte order, `width()` bytes long.
+    pub fn bytes(self, data: &[u8], endian: Endian) -> Vec<u8> {
+        let be = self.compute(data).to_be_bytes();
+        let mut out = be[8 - self.width()..].to_vec();
+        if let Endian::Little = endian {
+            out.reverse();
+        }
+        out
+    }
+
+    fn name(self) -> String {
+        match self {
+            Checksum::Crc(p) => PRESETS
+                .iter()
+                .find(|c| c.params == p)
+                .map(|c| c.name.to_string())
+                .unwrap_or_else(|| format!("CRC-{}/custom", p.width)),
+            other => format!("{:?}", other).to_ascii_lowercase(),
+        }
+    }
+}
+
+/// Bit-at-a-time CRC for any width; fast enough for the message sizes we send.
+fn crc_compute(p: &CrcParams, data: &[u8]) -> u64 {
+    let mask = u64::MAX >> (64 - p.width);
+    let top = 1u64 << (p.width - 1);
+    let mut crc = p.init & mask;
+    for &byte in data {
+        let byte = if p.refin { byte.reverse_bits() } else { byte };
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1 != 0;
+            let msb = crc & top != 0;
+            crc = (crc << 1) & mask;
+            if bit != msb {
+                crc ^= p.poly & mask;
+            }
+        }
+    }
+    if p.refout {
+        crc = crc.reverse_bits() >> (64 - p.width);
+    }
+    (crc ^ p.xorout) & mask
+}
+
+pub fn compute(
+    data_b64: &str,
+    algorithm: &Algorithm,
+    endian: Option<Endian>,
+) -> NetResult<ChecksumResult> {
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data_b64)
+        .map_err(NetError::decode)?;
+    let c = algorithm.resolve()?;
+    let value = c.compute(&data);
+    Ok(ChecksumResult {
+        algorithm: c.name(),
+        value,
+        hex: format!("{:0w$X}", value, w = c.width() * 2),
+        bytes_b64: base64::engine::general_purpose::STANDARD
+            .encode(c.bytes(&data, endian.unwrap_or(c.default_endian()))),
+    })
+}
+
+struct Appender {
+    checksum: Checksum,
+    endian: Endian,
+    skip: usize,
+}
+
+static APPEND: OnceCell<Mutex<HashMap<String, Appender>>> = OnceCell::new();
+
+fn appenders() -> NetResult<MutexGuard<'static, HashMap<String, Appender>>> {
+    APPEND
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+/// Sets (or with `None` clears) the checksum appended to everything the session sends.
+pub fn set_append(session_id: String, append: Option<AppendChecksum>) -> NetResult<Updated> {
+    session::get(&session_id)?;
+    let message = match append {
+        Some(a) => {
+            let checksum = a.algorithm.resolve()?;
+            let message = format!("appending {} to sent messages", checksum.name());
+            appenders()?.insert(
+                session_id.clone(),
+                Appender {
+                    checksum,
+                    endian: a.endian.unwrap_or(checksum.default_endian()),
+                    skip: a.skip,
+                },
+            );
+            message
+        }
+        None => {
+            appenders()?.remove(&session_id);
+            "no checksum appended to sent messages".to_string()
+        }
+    };
+    Ok(Updated {
+        session_id,
+        message,
+    })
+}
+
+/// `data` with the session's checksum appended, if it has one.
+pub fn append<'a>(session_id: &str, data: &'a [u8]) -> Cow<'a, [u8]> {
+    let Ok(apps) = appenders() else {
+        return Cow::Borrowed(data);
+    };
+    match apps.get(session_id) {
+        Some(a) => {
+            let covered = data.get(a.skip..).unwrap_or_default();
+            let mut out = data.to_vec();
+            out.extend(a.checksum.bytes(covered, a.endian));
+            Cow::Owned(out)
+        }
+        None => Cow::Borrowed(data),
+    }
+}
+
+/// Drops the append setting of a session that went away.
+pub fn forget(session_id: &str) {
+    if let Ok(mut apps) = appenders() {
+        apps.remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn presets_match_their_check_values() {
+        for p in PRESETS {
+            let value = Checksum::Crc(p.params).compute(CHECK);
+            assert_eq!(value, p.check, "{}: got 0x{:X}", p.name, value);
+            for name in std::iter::once(&p.name).chain(p.aliases) {
+                let Ok(Checksum::Crc(params)) = Algorithm::Named(name.to_uppercase()).resolve()
+                else {
+                    panic!("{} does not resolve", name);
+                };
+                assert_eq!(params, p.params, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn simple_checksums() {
+        let cases: &[(Checksum, &[u8], u64)] = &[
+            (Checksum::Fletcher16, b"abcde", 0xC8F0),
+            (Checksum::Fletcher16, b"abcdef", 0x2057),
+            (Checksum::Fletcher16, b"abcdefgh", 0x0627),
+            // the usual test vectors, taken over big-endian rather than little-endian words
+            (Checksum::Fletcher32, b"abcde", 0x4FF0_29C7),
+            (Checksum::Fletcher32, b"abcdef", 0x5056_2A2D),
+            (Checksum::Fletcher32, b"abcdefgh", 0xE1EB_9195),
+            (Checksum::Adler32, b"Wikipedia", 0x11E6_0398),
+            (Checksum::Adler32, CHECK, 0x091E_01DE),
+            (Checksum::Adler32, b"", 1),
+            // Modbus ASCII read of one holding register from slave 1
+            (Checksum::Lrc, &[0x01, 0x03, 0x00, 0x00, 0x00, 0x01], 0xFB),
+            (Checksum::Lrc, &[0x80, 0x80], 0x00),
+            (Checksum::Sum8, CHECK, 0xDD),
+            (Checksum::Sum8, &[0xFF, 0x02], 0x01),
+            (Checksum::Xor, CHECK, 0x31),
+            (Checksum::Xor, &[0xA5, 0xA5], 0x00),
+        ];
+        for (c, data, expected) in cases {
+            assert_eq!(c.compute(data), *expected, "{:?} over {:?}", c, data);
+        }
+    }
+
+    #[test]
+    fn bytes_and_widths() {
+        let modbus = Checksum::Crc(CRC16_MODBUS);
+        assert!(matches!(modbus.default_endian(), Endian::Little));
+        assert_eq!(modbus.bytes(CHECK, Endian::Little), [0x37, 0x4B]);
+        assert_eq!(modbus.bytes(CHECK, Endian::Big), [0x4B, 0x37]);
+        let crc12 = Checksum::Crc(crc(12, 0x80F, 0, false, 0));
+        assert_eq!(crc12.width(), 2);
+        assert_eq!(Checksum::Adler32.bytes(b"", Endian::Big), [0, 0, 0, 1]);
+
+        let r = compute("MTIzNDU2Nzg5", &Algorithm::Named("crc32".into()), None).unwrap();
+        assert_eq!(r.algorithm, "CRC-32/ISO-HDLC");
+        assert_eq!(r.hex, "CBF43926");
+        assert_eq!(r.bytes_b64, "Jjn0yw==");
+        let r = compute("", &Algorithm::Named("lrc".into()), None).unwrap();
+        assert_eq!((r.algorithm.as_str(), r.hex.as_str()), ("lrc", "00"));
+
+        assert!(Algorithm::Named("crc99".into()).resolve().is_err());
+        let too_wide = Algorithm::Crc(crc(65, 1, 0, false, 0));
+        assert!(too_wide.resolve().is_err());
+    }
+
+    #[test]
+    fn append_and_forget() {
+        let sid = "checksum-test";
+        appenders().unwrap().insert(
+            sid.into(),
+            Appender {
+                checksum: Checksum::Crc(CRC16_MODBUS),
+                endian: Endian::Little,
+                skip: 1,
+            },
+        );
+        let framed = append(sid, b":123456789");
+        assert_eq!(&framed[..], b":123456789\x37\x4B");
+        // a message shorter than `skip` gets the checksum of nothing
+        assert_eq!(&append(sid, b"")[..], [0xFF, 0xFF]);
+        assert!(matches!(append("other", b"x"), Cow::Borrowed(b"x")));
+
+        forget(sid);
+        assert!(matches!(append(sid, b"abc"), Cow::Borrowed(b"abc")));
+    }
+}
@@ -0,0 +1,233 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::session::SessionKind;
+
+/// Stable, machine-readable part of a [`NetError`]; serialized as `"code"` plus any variant fields.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "code")]
+pub enum ErrorCode {
+    AddrInUse,
+    AlreadyRunning,
+    NotRunning,
+    PeerNotFound {
+        peer: String,
+    },
+    SessionNotFound {
+        session_id: String,
+    },
+    JobNotFound {
+        job_id: String,
+    },
+    TemplateNotFound {
+        name: String,
+    },
+    DecodeError,
+    /// Bad TLS configuration (unreadable PEM, invalid server name, ...); handshake failures are `Io`.
+    Tls,
+    /// Invalid framer config, a payload the framer cannot wrap, or a malformed incoming frame.
+    Framing,
+    /// Invalid payload template, or one that cannot be rendered (e.g. a checksum range past the end).
+    Template,
+    /// Invalid WebSocket URL, a refused or malformed handshake, or a protocol violation.
+    WebSocket,
+    /// Invalid HTTP URL, request or mock route, a malformed response, or too many redirects.
+    Http,
+    /// Invalid Modbus request or slave config, a malformed response, or a table edit out of range.
+    Modbus,
+    /// Invalid MQTT option, topic or filter, a refused connection, or a malformed packet.
+    Mqtt,
+    /// The session exists but this operation does not apply to its kind or socket family.
+    Unsupported,
+    /// The operation was aborted by a stop command before it completed.
+    Cancelled,
+    /// Any other OS error; `kind` is the `std::io::ErrorKind` name, e.g. `ConnectionRefused`.
+    Io {
+        kind: String,
+    },
+    Internal,
+}
+
+/// Error returned by every Tauri command.
+///
+/// Serializes flat, e.g.
+/// `{"code":"Io","kind":"ConnectionRefused","errno":111,"addr":"10.0.0.5:502","message":"connect error: ..."}`.
+#[derive(Clone, Debug, Serialize)]
+pub struct NetError {
+    #[serde(flatten)]
+    pub code: ErrorCode,
+    pub errno: Option<i32>,
+    pub addr: Option<String>,
+    pub message: String,
+}
+
+pub type NetResult<T> = Result<T, NetError>;
+
+impl NetError {
+    fn new(code: ErrorCode, addr: Option<&str>, message: String) -> Self {
+        NetError {
+            code,
+            errno: None,
+            addr: addr.map(str::to_string),
+            message,
+        }
+    }
+
+    /// Wraps an OS error; `context` is the operation that failed ("bind", "connect", "send", ...).
+    pub fn io(context: &str, addr: &str, e: std::io::Error) -> Self {
+        let code = match e.kind() {
+            std::io::ErrorKind::AddrInUse => ErrorCode::AddrInUse,
+            std::io::ErrorKind::Unsupported => ErrorCode::Unsupported,
+            kind => ErrorCode::Io {
+                kind: format!("{:?}", kind),
+            },
+        };
+        NetError {
+            code,
+            errno: e.raw_os_error(),
+            addr: Some(addr.to_string()),
+            message: format!("{} error: {}", context, e),
+        }
+    }
+
+    /// An argument that can't be acted on, reported like the OS would (`InvalidInput`).
+    pub fn invalid_input(context: &str, addr: &str, message: String) -> Self {
+        Self::io(
+            context,
+            addr,
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, message),
+        )
+    }
+
+    pub fn already_running(kind: SessionKind, addr: &str) -> Self {
+        let message = match kind {
+            SessionKind::TcpClient => "TCP client already connected to this address".to_string(),
+            _ => format!("{} already running for this address", kind.label()),
+        };
+        Self::new(ErrorCode::AlreadyRunning, Some(addr), message)
+    }
+
+    pub fn not_running(kind: SessionKind, addr: &str) -> Self {
+        let message = match kind {
+            SessionKind::TcpClient => "TCP client not connected to that address".to_string(),
+            _ => format!("{} not running for that address", kind.label()),
+        };
+        Self::new(ErrorCode::NotRunning, Some(addr), message)
+    }
+
+    pub fn already_recording(id: &str) -> Self {
+        Self::new(
+            ErrorCode::AlreadyRunning,
+            None,
+            format!("session {} is already being recorded", id),
+        )
+    }
+
+    pub fn not_recording(id: &str) -> Self {
+        Self::new(
+            ErrorCode::NotRunning,
+            None,
+            format!("session {} is not being recorded", id),
+        )
+    }
+
+    pub fn peer_not_found(addr: &str, peer: &str) -> Self {
+        Self::new(
+            ErrorCode::PeerNotFound {
+                peer: peer.to_string(),
+            },
+            Some(addr),
+            "peer not connected".into(),
+        )
+    }
+
+    pub fn session_not_found(id: &str) -> Self {
+        Self::new(
+            ErrorCode::SessionNotFound {
+                session_id: id.to_string(),
+            },
+            None,
+            format!("no session with id {}", id),
+        )
+    }
+
+    pub fn job_not_found(id: &str) -> Self {
+        Self::new(
+            ErrorCode::JobNotFound {
+                job_id: id.to_string(),
+            },
+            None,
+            format!("no job with id {}", id),
+        )
+    }
+
+    pub fn template_not_found(name: &str) -> Self {
+        Self::new(
+            ErrorCode::TemplateNotFound {
+                name: name.to_string(),
+            },
+            None,
+            format!("no template named {}", name),
+        )
+    }
+
+    pub fn cancelled(what: &str, addr: &str) -> Self {
+        Self::new(
+            ErrorCode::Cancelled,
+            Some(addr),
+            format!("{} cancelled", what),
+        )
+    }
+
+    pub fn unsupported(message: String) -> Self {
+        Self::new(ErrorCode::Unsupported, None, message)
+    }
+
+    pub fn tls(message: String) -> Self {
+        Self::new(ErrorCode::Tls, None, message)
+    }
+
+    pub fn framing(message: String) -> Self {
+        Self::new(ErrorCode::Framing, None, message)
+    }
+
+    pub fn template(message: String) -> Self {
+        Self::new(ErrorCode::Template, None, message)
+    }
+
+    pub fn websocket(message: String) -> Self {
+        Self::new(ErrorCode::WebSocket, None, message)
+    }
+
+    pub fn http(message: String) -> Self {
+        Self::new(ErrorCode::Http, None, message)
+    }
+
+    pub fn modbus(message: String) -> Self {
+        Self::new(ErrorCode::Modbus, None, message)
+    }
+
+    pub fn mqtt(message: String) -> Self {
+        Self::new(ErrorCode::Mqtt, None, message)
+    }
+
+    pub fn decode(e: base64::DecodeError) -> Self {
+        Self::new(
+            ErrorCode::DecodeError,
+            None,
+            format!("base64 decode error: {}", e),
+        )
+    }
+
+    pub fn internal(message: String) -> Self {
+        Self::new(ErrorCode::Internal, None, message)
+    }
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for NetError {}
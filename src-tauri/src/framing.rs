@@ -0,0 +1,603 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::checksum::{self, Checksum};
+use crate::error::{NetError, NetResult};
+
+/// Frames larger than this are dropped unless the config sets its own `max_frame_len`.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1 << 20;
+/// Gateways blur the serial 3.5-character gap, so wait well past it before closing a frame.
+pub const DEFAULT_RTU_SILENCE_MS: u64 = 20;
+/// Unit id, function code, up to 252 data bytes and the CRC.
+pub const MAX_RTU_FRAME_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+}
+
+/// How a TCP byte stream is cut into messages before it is emitted.
+///
+/// Tagged by `"type"`, e.g. `{"type":"delimiter","delimiter_b64":"DQo="}` or
+/// `{"type":"length_prefixed","width":2,"header_offset":1}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FramerConfig {
+    /// Splits on a byte sequence such as `\r\n` or `0x7E`.
+    Delimiter {
+        delimiter_b64: String,
+        /// Keep the delimiter at the end of each emitted frame.
+        #[serde(default)]
+        include_delimiter: bool,
+        max_frame_len: Option<usize>,
+    },
+    Fixed {
+        length: usize,
+    },
+    /// A `width`-byte length field that starts `header_offset` bytes into the frame.
+    /// The frame is `header_offset + width + value + length_adjustment` bytes long and is
+    /// emitted whole, header included.
+    LengthPrefixed {
+        width: u8,
+        #[serde(default)]
+        endian: Endian,
+        #[serde(default)]
+        header_offset: usize,
+        #[serde(default)]
+        length_adjustment: i64,
+        max_frame_len: Option<usize>,
+    },
+    /// Everything received until the line has been quiet for `timeout_ms` is one frame.
+    Idle {
+        timeout_ms: u64,
+    },
+    /// Raw Modbus RTU as tunnelled by serial gateways: unit id, PDU, CRC-16/MODBUS low byte
+    /// first. Over TCP a frame ends where its CRC first checks out, or failing that once the
+    /// stream has been quiet for `silence_ms`; over UDP every datagram is one frame. Sending
+    /// appends the CRC, receiving checks it.
+    ModbusRtu {
+        silence_ms: Option<u64>,
+    },
+}
+
+impl FramerConfig {
+    /// Checks the config once up front so the read loops never see a bad one.
+    pub fn validate(&self) -> NetResult<()> {
+        match self {
+            FramerConfig::Delimiter { .. } if self.delimiter()?.is_empty() => {
+                return Err(NetError::framing("delimiter must not be empty".into()));
+            }
+            FramerConfig::Fixed { length: 0 } => {
+                return Err(NetError::framing("fixed frame length must be > 0".into()));
+            }
+            FramerConfig::LengthPrefixed { width, .. } if !matches!(width, 1 | 2 | 4) => {
+                return Err(NetError::framing(format!(
+                    "length field width must be 1, 2 or 4 (got {})",
+                    width
+                )));
+            }
+            FramerConfig::Idle { timeout_ms: 0 } => {
+                return Err(NetError::framing("idle timeout must be > 0".into()));
+            }
+            FramerConfig::ModbusRtu {
+                silence_ms: Some(0),
+            } => {
+                return Err(NetError::framing("RTU silence interval must be > 0".into()));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Like [`FramerConfig::validate`], for UDP sessions where a datagram is already a message
+    /// and only RTU framing has anything left to do.
+    pub fn validate_datagram(&self) -> NetResult<()> {
+        if !matches!(self, FramerConfig::ModbusRtu { .. }) {
+            return Err(NetError::framing(
+                "UDP sessions only support modbus_rtu framing".into(),
+            ));
+        }
+        self.validate()
+    }
+
+    fn delimiter(&self) -> NetResult<Vec<u8>> {
+        match self {
+            FramerConfig::Delimiter { delimiter_b64, .. } => {
+                base64::engine::general_purpose::STANDARD
+                    .decode(delimiter_b64)
+                    .map_err(NetError::decode)
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Wraps an outgoing payload so the peer's framer of the same kind reads it as one frame.
+    pub fn encode(&self, payload: &[u8]) -> NetResult<Vec<u8>> {
+        match self {
+            FramerConfig::Delimiter { .. } => {
+                let mut out = payload.to_vec();
+                out.extend_from_slice(&self.delimiter()?);
+                Ok(out)
+            }
+            FramerConfig::Fixed { length } => {
+                if payload.len() != *length {
+                    return Err(NetError::framing(format!(
+                        "payload is {} bytes but fixed frames are {} bytes",
+                        payload.len(),
+                        length
+                    )));
+                }
+                Ok(payload.to_vec())
+            }
+            FramerConfig::LengthPrefixed {
+                width,
+                endian,
+                header_offset,
+                length_adjustment,
+                ..
+            } => {
+                let value = payload.len() as i64 - length_adjustment;
+                let max = (1u64 << (8 * *width as u32)) - 1;
+                if value < 0 || value as u64 > max {
+                    return Err(NetError::framing(format!(
+                        "payload of {} bytes does not fit a {}-byte length field",
+                        payload.len(),
+                        width
+                    )));
+                }
+                let mut out = vec![0u8; *header_offset];
+                let bytes = (value as u32).to_be_bytes();
+                let mut field = bytes[4 - *width as usize..].to_vec();
+                if let Endian::Little = endian {
+                    field.reverse();
+                }
+                out.extend_from_slice(&field);
+                out.extend_from_slice(payload);
+                Ok(out)
+            }
+            FramerConfig::Idle { .. } => Ok(payload.to_vec()),
+            FramerConfig::ModbusRtu { .. } => {
+                if payload.len() < 2 || payload.len() > MAX_RTU_FRAME_LEN - 2 {
+                    return Err(NetError::framing(format!(
+                        "RTU payload must be 2 to {} bytes (unit id, function code, data), got {}",
+                        MAX_RTU_FRAME_LEN - 2,
+                        payload.len()
+                    )));
+                }
+                let mut out = payload.to_vec();
+                out.extend_from_slice(&rtu_crc(payload).to_le_bytes());
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// A received Modbus RTU frame split into its fields, attached to message events as `rtu`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RtuFrame {
+    pub unit_id: u8,
+    pub function_code: u8,
+    /// Set for exception responses, whose function code has the high bit set.
+    pub exception: bool,
+    /// Everything between the function code and the CRC.
+    pub data_b64: String,
+    /// The CRC as received and as computed over the rest of the frame.
+    pub crc: u16,
+    pub expected_crc: u16,
+    pub crc_valid: bool,
+}
+
+impl RtuFrame {
+    /// `None` for frames too short to hold a unit id, function code and CRC.
+    pub fn decode(frame: &[u8]) -> Option<Self> {
+        if frame.len() < 4 {
+            return None;
+        }
+        let (body, crc) = frame.split_at(frame.len() - 2);
+        let crc = u16::from_le_bytes([crc[0], crc[1]]);
+        let expected_crc = rtu_crc(body);
+        Some(RtuFrame {
+            unit_id: body[0],
+            function_code: body[1],
+            exception: body[1] & 0x80 != 0,
+            data_b64: base64::engine::general_purpose::STANDARD.encode(&body[2..]),
+            crc,
+            expected_crc,
+            crc_valid: crc == expected_crc,
+        })
+    }
+}
+
+fn rtu_crc(data: &[u8]) -> u16 {
+    Checksum::Crc(checksum::CRC16_MODBUS).compute(data) as u16
+}
+
+/// Adds what the framer knows about a received frame to its message event: the decoded
+/// fields under `rtu` (null when too short) for RTU framing, nothing otherwise.
+pub fn annotate(cfg: Option<&FramerConfig>, frame: &[u8], payload: &mut Value) {
+    if let Some(FramerConfig::ModbusRtu { .. }) = cfg {
+        payload["rtu"] = json!(RtuFrame::decode(frame));
+    }
+}
+
+/// Per-connection reassembly state for one [`FramerConfig`].
+pub struct Framer {
+    cfg: FramerConfig,
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    pub fn new(cfg: FramerConfig) -> Self {
+        let delimiter = cfg.delimiter().unwrap_or_default();
+        Framer {
+            cfg,
+            delimiter,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Set for idle and RTU framing: the read loop calls [`Framer::flush`] once no data arrived
+    /// for this long.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.cfg {
+            FramerConfig::Idle { timeout_ms } => Some(Duration::from_millis(timeout_ms)),
+            FramerConfig::ModbusRtu { silence_ms } => Some(Duration::from_millis(
+                silence_ms.unwrap_or(DEFAULT_RTU_SILENCE_MS),
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Takes whatever is buffered, complete or not.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.buf.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buf))
+        }
+    }
+
+    /// Appends `data` and moves every complete frame into `out`. An oversized or malformed
+    /// frame discards the buffer and is reported as an error; frames before it are kept.
+    pub fn push(&mut self, data: &[u8], out: &mut Vec<Vec<u8>>) -> NetResult<()> {
+        self.buf.extend_from_slice(data);
+        match &self.cfg {
+            FramerConfig::Delimiter {
+                include_delimiter,
+                max_frame_len,
+                ..
+            } => {
+                let (include_delimiter, max_frame_len) = (*include_delimiter, *max_frame_len);
+                let dlen = self.delimiter.len();
+                // only rescan the tail that could hold a new match
+                let mut from = self.buf.len().saturating_sub(data.len() + dlen - 1);
+                while let Some(pos) = find(&self.buf[from..], &self.delimiter) {
+                    let end = from + pos;
+                    let mut frame: Vec<u8> = self.buf.drain(..end + dlen).collect();
+                    if !include_delimiter {
+                        frame.truncate(end);
+                    }
+                    out.push(frame);
+                    from = 0;
+                }
+                self.check_len(max_frame_len)
+            }
+            FramerConfig::Fixed { length } => {
+                let length = *length;
+                while self.buf.len() >= length {
+                    out.push(self.buf.drain(..length).collect());
+                }
+                Ok(())
+            }
+            FramerConfig::LengthPrefixed {
+                width,
+                endian,
+                header_offset,
+                length_adjustment,
+                max_frame_len,
+            } => {
+                let (width, header_offset, length_adjustment) =
+                    (*width as usize, *header_offset, *length_adjustment);
+                let max = max_frame_len.unwrap_or(DEFAULT_MAX_FRAME_LEN);
+                while self.buf.len() >= header_offset + width {
+                    let field = &self.buf[header_offset..header_offset + width];
+                    let value = match endian {
+                        Endian::Big => field.iter().fold(0u64, |v, b| (v << 8) | *b as u64),
+                        Endian::Little => {
+                            field.iter().rev().fold(0u64, |v, b| (v << 8) | *b as u64)
+                        }
+                    };
+                    let total = (header_offset + width) as i64 + value as i64 + length_adjustment;
+                    if total < (header_offset + width) as i64 || total as usize > max {
+                        self.buf.clear();
+                        return Err(NetError::framing(format!(
+                            "bad frame length {} (length field {})",
+                            total, value
+                        )));
+                    }
+                    let total = total as usize;
+                    if self.buf.len() < total {
+                        break;
+                    }
+                    out.push(self.buf.drain(..total).collect());
+                }
+                Ok(())
+            }
+            FramerConfig::Idle { .. } => self.check_len(None),
+            FramerConfig::ModbusRtu { .. } => {
+                // gateways often coalesce back-to-back frames into one read
+                while let Some(end) = rtu_frame_end(&self.buf) {
+                    out.push(self.buf.drain(..end).collect());
+                }
+                self.check_len(Some(MAX_RTU_FRAME_LEN))
+            }
+        }
+    }
+
+    fn check_len(&mut self, max_frame_len: Option<usize>) -> NetResult<()> {
+        let max = max_frame_len.unwrap_or(DEFAULT_MAX_FRAME_LEN);
+        if self.buf.len() > max {
+            let len = self.buf.len();
+            self.buf.clear();
+            return Err(NetError::framing(format!(
+                "frame exceeds {} bytes ({} buffered), discarded",
+                max, len
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The length of the shortest prefix of `buf` that ends in its own CRC, if any.
+fn rtu_frame_end(buf: &[u8]) -> Option<usize> {
+    (4..=buf.len().min(MAX_RTU_FRAME_LEN))
+        .find(|&n| u16::from_le_bytes([buf[n - 2], buf[n - 1]]) == rtu_crc(&buf[..n - 2]))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Runs `data` through `framer`, or passes it on as a single frame when there is none.
+pub fn feed(framer: Option<&mut Framer>, data: &[u8], out: &mut Vec<Vec<u8>>) -> NetResult<()> {
+    match framer {
+        Some(f) => f.push(data, out),
+        None => {
+            out.push(data.to_vec());
+            Ok(())
+        }
+    }
+}
+
+/// Completes after `idle`, or never when there is nothing waiting to be flushed.
+pub async fn idle_expired(idle: Option<Duration>) {
+    match idle {
+        Some(d) => tokio::time::sleep(d).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every frame `cfg` cuts from `reads`, in order.
+    fn frames(cfg: FramerConfig, reads: &[&[u8]]) -> Vec<Vec<u8>> {
+        cfg.validate().unwrap();
+        let mut framer = Framer::new(cfg);
+        let mut out = Vec::new();
+        for data in reads {
+            framer.push(data, &mut out).unwrap();
+        }
+        out
+    }
+
+    fn delimiter(delimiter: &[u8], include_delimiter: bool) -> FramerConfig {
+        FramerConfig::Delimiter {
+            delimiter_b64: base64::engine::general_purpose::STANDARD.encode(delimiter),
+            include_delimiter,
+            max_frame_len: Some(16),
+        }
+    }
+
+    fn length_prefixed(
+        width: u8,
+        endian: Endian,
+        header_offset: usize,
+        length_adjustment: i64,
+    ) -> FramerConfig {
+        FramerConfig::LengthPrefixed {
+            width,
+            endian,
+            header_offset,
+            length_adjustment,
+            max_frame_len: Some(64),
+        }
+    }
+
+    #[test]
+    fn delimiter_split_and_coalesce() {
+        // several frames in one read, one frame over several reads
+        let out = frames(
+            delimiter(b"\r\n", false),
+            &[b"a\r\nbc\r\nd", b"e", b"f\r\n"],
+        );
+        assert_eq!(out, [&b"a"[..], b"bc", b"def"]);
+        // the delimiter itself straddles two reads, twice
+        let out = frames(delimiter(b"\r\n", true), &[b"one\r", b"\ntwo\r", b"\n"]);
+        assert_eq!(out, [&b"one\r\n"[..], b"two\r\n"]);
+        let out = frames(delimiter(b"END", false), &[b"xE", b"N", b"DyEN", b"D"]);
+        assert_eq!(out, [&b"x"[..], b"y"]);
+        // an empty frame between two delimiters is still a frame
+        let out = frames(delimiter(b"\n", false), &[b"\n\nz\n"]);
+        assert_eq!(out, [&b""[..], b"", b"z"]);
+    }
+
+    #[test]
+    fn delimiter_too_long() {
+        let mut framer = Framer::new(delimiter(b"\n", false));
+        let mut out = Vec::new();
+        let e = framer.push(b"ok\n0123456789abcdefg", &mut out).unwrap_err();
+        assert!(e.message.contains("exceeds 16 bytes"), "{}", e.message);
+        assert_eq!(out, [b"ok"]);
+        assert!(!framer.has_pending());
+        framer.push(b"next\n", &mut out).unwrap();
+        assert_eq!(out.last().unwrap(), b"next");
+    }
+
+    #[test]
+    fn fixed_length() {
+        let out = frames(FramerConfig::Fixed { length: 3 }, &[b"ab", b"cdefg", b"hi"]);
+        assert_eq!(out, [&b"abc"[..], b"def", b"ghi"]);
+        assert!(FramerConfig::Fixed { length: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn length_prefixed_widths_and_endians() {
+        let cases: &[(u8, Endian, &[u8])] = &[
+            (1, Endian::Big, &[3]),
+            (1, Endian::Little, &[3]),
+            (2, Endian::Big, &[0, 3]),
+            (2, Endian::Little, &[3, 0]),
+            (4, Endian::Big, &[0, 0, 0, 3]),
+            (4, Endian::Little, &[3, 0, 0, 0]),
+        ];
+        for (width, endian, field) in cases {
+            let cfg = length_prefixed(*width, *endian, 0, 0);
+            let frame = [*field, b"abc"].concat();
+            assert_eq!(cfg.encode(b"abc").unwrap(), frame);
+            // a second frame behind the first, arriving a byte at a time
+            let stream = [&frame[..], &frame[..]].concat();
+            let reads: Vec<&[u8]> = stream.chunks(1).collect();
+            assert_eq!(frames(cfg, &reads), [frame.clone(), frame.clone()]);
+        }
+        assert!(length_prefixed(3, Endian::Big, 0, 0).validate().is_err());
+    }
+
+    #[test]
+    fn length_prefixed_offset_and_adjustment() {
+        // a type byte ahead of a 2-byte length
+        let cfg = length_prefixed(2, Endian::Big, 1, 0);
+        let out = frames(cfg.clone(), &[&[0x7E, 0, 2, b'h'], &[b'i', 0x7F, 0, 0]]);
+        assert_eq!(out, [vec![0x7E, 0, 2, b'h', b'i'], vec![0x7F, 0, 0]]);
+        assert_eq!(cfg.encode(b"hi").unwrap(), [0, 0, 2, b'h', b'i']);
+
+        // the length counts the whole frame, header included
+        let cfg = length_prefixed(4, Endian::Big, 0, -4);
+        let out = frames(cfg.clone(), &[&[0, 0, 0, 6, b'a', b'b', 0, 0]]);
+        assert_eq!(out, [vec![0, 0, 0, 6, b'a', b'b']]);
+        assert_eq!(cfg.encode(b"ab").unwrap(), [0, 0, 0, 6, b'a', b'b']);
+
+        // a 2-byte trailer the length leaves out
+        let cfg = length_prefixed(1, Endian::Big, 0, 2);
+        let out = frames(cfg.clone(), &[&[1, b'x', 0xAA, 0xBB]]);
+        assert_eq!(out, [vec![1, b'x', 0xAA, 0xBB]]);
+        assert!(cfg.encode(b"x").is_err());
+        assert_eq!(cfg.encode(b"xyz").unwrap(), [1, b'x', b'y', b'z']);
+    }
+
+    #[test]
+    fn length_prefixed_rejects_bad_lengths() {
+        // over max_frame_len; the frame before it survives and the buffer is dropped
+        let mut framer = Framer::new(length_prefixed(1, Endian::Big, 0, 0));
+        let mut out = Vec::new();
+        let e = framer.push(&[1, b'a', 200, 0, 0], &mut out).unwrap_err();
+        assert!(e.message.contains("bad frame length 201"), "{}", e.message);
+        assert_eq!(out, [vec![1, b'a']]);
+        assert!(!framer.has_pending());
+
+        // an adjustment that would end the frame inside its own header
+        let mut framer = Framer::new(length_prefixed(2, Endian::Big, 1, -10));
+        assert!(framer.push(&[0, 0, 4], &mut out).is_err());
+
+        let cfg = length_prefixed(1, Endian::Big, 0, 0);
+        assert!(cfg.encode(&[0; 256]).is_err());
+        assert_eq!(cfg.encode(&[0; 255]).unwrap().len(), 256);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_flush() {
+        let mut framer = Framer::new(FramerConfig::Idle { timeout_ms: 50 });
+        let mut out = Vec::new();
+        framer.push(b"par", &mut out).unwrap();
+        framer.push(b"tial", &mut out).unwrap();
+        assert!(out.is_empty());
+        assert!(framer.has_pending());
+
+        let idle = framer.idle_timeout();
+        assert_eq!(idle, Some(Duration::from_millis(50)));
+        let start = tokio::time::Instant::now();
+        idle_expired(idle).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+        assert_eq!(framer.flush().unwrap(), b"partial");
+        assert_eq!(framer.flush(), None);
+
+        assert_eq!(
+            Framer::new(FramerConfig::Fixed { length: 1 }).idle_timeout(),
+            None
+        );
+        assert!(FramerConfig::Idle { timeout_ms: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(delimiter(b"\r\n", false).encode(b"hi").unwrap(), b"hi\r\n");
+        let fixed = FramerConfig::Fixed { length: 2 };
+        assert_eq!(fixed.encode(b"hi").unwrap(), b"hi");
+        assert!(fixed.encode(b"h").is_err());
+        let idle = FramerConfig::Idle { timeout_ms: 1 };
+        assert_eq!(idle.encode(b"hi").unwrap(), b"hi");
+        let rtu = FramerConfig::ModbusRtu { silence_ms: None };
+        let frame = rtu.encode(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        assert_eq!(frame, [0x01, 0x03, 0x00, 0x00, 0x00, 0x01, 0x84, 0x0A]);
+        assert!(RtuFrame::decode(&frame).unwrap().crc_valid);
+        assert!(rtu.encode(&[0x01]).is_err());
+    }
+
+    #[test]
+    fn rtu_splits_on_crc() {
+        let rtu = FramerConfig::ModbusRtu { silence_ms: None };
+        let request = rtu.encode(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        let response = rtu.encode(&[0x01, 0x03, 0x02, 0x12, 0x34]).unwrap();
+        let both = [request.clone(), response.clone()].concat();
+        assert_eq!(
+            frames(rtu.clone(), &[&both]),
+            [request.clone(), response.clone()]
+        );
+        assert_eq!(
+            frames(rtu.clone(), &[&both[..3], &both[3..10], &both[10..]]),
+            [request.clone(), response.clone()]
+        );
+
+        // no CRC checks out, so only the idle flush ends it
+        let mut framer = Framer::new(rtu);
+        let mut out = Vec::new();
+        let mut broken = both.clone();
+        broken[7] ^= 0xFF;
+        framer.push(&broken[..8], &mut out).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(framer.flush().unwrap(), &broken[..8]);
+
+        framer.push(&[0x01, 0x06, 0x00], &mut out).unwrap();
+        assert!(out.is_empty());
+        assert!(framer.push(&[0; MAX_RTU_FRAME_LEN], &mut out).is_err());
+        assert!(!framer.has_pending());
+    }
+
+    #[test]
+    fn feed_without_framer() {
+        let mut out = Vec::new();
+        feed(None, b"as is", &mut out).unwrap();
+        assert_eq!(out, [b"as is"]);
+    }
+}
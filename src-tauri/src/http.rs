@@ -0,0 +1,1284 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tauri::Emitter;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::recorder::{self, Direction};
+use crate::session;
+use crate::sockopt::{self, SocketOptions};
+use crate::tcp_client::DEFAULT_CONNECT_TIMEOUT_MS;
+use crate::tcp_server::Connection;
+use crate::template::Payload;
+use crate::tls::{self, BoxedStream, TlsClientConfig};
+use crate::ws;
+
+/// A head that has not ended by then is rejected.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_HEADERS: usize = 128;
+/// Bodies are buffered whole; larger ones are refused.
+const MAX_BODY_LEN: usize = 64 << 20;
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_MAX_REDIRECTS: u32 = 10;
+
+/// One request for `http_request`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct HttpRequest {
+    /// `GET` when omitted; sent as given otherwise.
+    pub method: Option<String>,
+    /// `http://` or `https://`.
+    pub url: String,
+    /// A `Host`, `Content-Length` or `Connection` header here replaces the one that would be added.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body_b64: Option<String>,
+    /// Renders the named payload template as the body instead of `body_b64`.
+    pub template: Option<String>,
+    /// Covers DNS, connect and the TLS handshake; the default is the TCP client's.
+    pub connect_timeout_ms: Option<u64>,
+    /// From the request being written to the end of the response body.
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub follow_redirects: bool,
+    pub max_redirects: Option<u32>,
+    /// Used for `https://` URLs, including ones redirected to.
+    pub tls: Option<TlsClientConfig>,
+    pub socket_options: Option<SocketOptions>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HttpResponse {
+    /// The URL that answered, after any redirects.
+    pub url: String,
+    pub remote: String,
+    pub local: String,
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    /// In the order received, with the trailers of a chunked body last.
+    pub headers: Vec<(String, String)>,
+    pub body_b64: String,
+    pub body_len: usize,
+    pub chunked: bool,
+    pub tls: Value,
+    pub redirects: Vec<Redirect>,
+    pub timing: Timing,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Redirect {
+    pub url: String,
+    pub status: u16,
+    pub location: String,
+}
+
+/// Milliseconds spent in each phase of the final request; `total_ms` also covers any redirects.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Timing {
+    pub dns_ms: f64,
+    pub connect_ms: f64,
+    /// `None` for plain HTTP.
+    pub tls_ms: Option<f64>,
+    /// From the request being written to the first byte of the response.
+    pub first_byte_ms: f64,
+    /// From the first byte to the end of the body.
+    pub download_ms: f64,
+    pub total_ms: f64,
+}
+
+/// Where an `http://` or `https://` URL points.
+#[derive(Clone, Debug)]
+struct Url {
+    tls: bool,
+    /// `host:port`, with IPv6 hosts in brackets, for connecting.
+    addr: String,
+    /// The `Host` header: the authority as written in the URL.
+    host: String,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> NetResult<Url> {
+        let (tls, rest) = if let Some(r) = strip_scheme(url, "http://") {
+            (false, r)
+        } else if let Some(r) = strip_scheme(url, "https://") {
+            (true, r)
+        } else {
+            return Err(NetError::http(format!(
+                "{} is not an http:// or https:// URL",
+                url
+            )));
+        };
+        let Some(ws::Authority { addr, host, path }) =
+            ws::split_authority(rest, if tls { 443 } else { 80 })
+        else {
+            return Err(NetError::http(format!("{} has no usable host", url)));
+        };
+        Ok(Url {
+            tls,
+            addr,
+            host,
+            path,
+        })
+    }
+
+    /// Resolves a `Location` header against this URL.
+    fn join(&self, location: &str) -> NetResult<Url> {
+        let scheme = if self.tls { "https:" } else { "http:" };
+        if strip_scheme(location, "http://").is_some()
+            || strip_scheme(location, "https://").is_some()
+        {
+            return Url::parse(location);
+        }
+        if location.starts_with("//") {
+            return Url::parse(&format!("{}{}", scheme, location));
+        }
+        if has_scheme(location) {
+            return Err(NetError::http(format!(
+                "cannot follow a redirect to {}",
+                location
+            )));
+        }
+        let location = location.split('#').next().unwrap_or_default();
+        let base = self.path.split('?').next().unwrap_or("/");
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else if location.is_empty() {
+            self.path.clone()
+        } else if location.starts_with('?') {
+            format!("{}{}", base, location)
+        } else {
+            // relative to the directory of the current path, without its query
+            let dir = &base[..base.rfind('/').map(|i| i + 1).unwrap_or(0)];
+            format!("{}{}", dir, location)
+        };
+        Ok(Url {
+            path: remove_dot_segments(&path),
+            ..self.clone()
+        })
+    }
+
+    fn to_url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}{}", scheme, self.host, self.path)
+    }
+}
+
+/// RFC 3986 section 3.1: `scheme:` before any `/`, `?` or `#`.
+fn has_scheme(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        None => false,
+    }
+}
+
+/// RFC 3986 section 5.2.4: resolves the `.` and `..` segments of an absolute path.
+fn remove_dot_segments(path: &str) -> String {
+    let (path, query) = path.split_at(path.find('?').unwrap_or(path.len()));
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    let mut out: Vec<&str> = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match *segment {
+            "." | ".." => {
+                if *segment == ".." {
+                    out.pop();
+                }
+                // a path ending in a dot segment names a directory
+                if i + 1 == segments.len() {
+                    out.push("");
+                }
+            }
+            s => out.push(s),
+        }
+    }
+    format!("/{}{}", out.join("/"), query)
+}
+
+fn strip_scheme<'a>(url: &'a str, scheme: &str) -> Option<&'a str> {
+    match url.get(..scheme.len()) {
+        Some(s) if s.eq_ignore_ascii_case(scheme) => Some(&url[scheme.len()..]),
+        _ => None,
+    }
+}
+
+/// Sends one request on a fresh connection and reads the whole response, following redirects
+/// when asked to.
+pub async fn request(req: HttpRequest) -> NetResult<HttpResponse> {
+    let mut method = req.method.clone().unwrap_or_else(|| "GET".into());
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(NetError::http(format!("invalid method {:?}", method)));
+    }
+    let mut body = match (&req.body_b64, &req.template) {
+        (None, None) => Vec::new(),
+        _ => Payload::new(req.body_b64.clone(), req.template.clone()).bytes()?,
+    };
+    let mut url = Url::parse(&req.url)?;
+    let mut headers = req.headers.clone();
+    let max_redirects = req.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS);
+    let mut redirects = Vec::new();
+    let started = Instant::now();
+
+    loop {
+        let mut res = exchange(&req, &url, &method, &headers, &body).await?;
+        let location = match (req.follow_redirects, res.status) {
+            (true, 301 | 302 | 303 | 307 | 308) => header(&res.headers, "location"),
+            _ => None,
+        };
+        let Some(location) = location.map(str::to_string) else {
+            res.redirects = redirects;
+            res.timing.total_ms = millis(started.elapsed());
+            return Ok(res);
+        };
+        if redirects.len() as u32 >= max_redirects {
+            return Err(NetError::http(format!(
+                "gave up after {} redirects",
+                max_redirects
+            )));
+        }
+        let next = url.join(&location)?;
+        redirects.push(Redirect {
+            url: url.to_url(),
+            status: res.status,
+            location,
+        });
+        // what browsers do: only 307 and 308 repeat the request as it was
+        let rewrite = res.status == 303 || (matches!(res.status, 301 | 302) && method == "POST");
+        if rewrite && method != "HEAD" {
+            method = "GET".into();
+            body.clear();
+            headers.retain(|k, _| {
+                !k.eq_ignore_ascii_case("content-type") && !k.eq_ignore_ascii_case("content-length")
+            });
+        }
+        if !next.host.eq_ignore_ascii_case(&url.host) {
+            headers.retain(|k, _| {
+                !k.eq_ignore_ascii_case("authorization") && !k.eq_ignore_ascii_case("cookie")
+            });
+        }
+        url = next;
+    }
+}
+
+async fn exchange(
+    req: &HttpRequest,
+    url: &Url,
+    method: &str,
+    headers: &HashMap<String, String>,
+    body: &[u8],
+) -> NetResult<HttpResponse> {
+    let tls = match url.tls {
+        true => Some(tls::client(
+            &req.tls.clone().unwrap_or_default(),
+            &url.addr,
+        )?),
+        false => None,
+    };
+    let connect_timeout_ms = req.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+    let timeout_ms = req.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+    let options = req.socket_options.clone().unwrap_or_default();
+    let mut timing = Timing::default();
+
+    let connecting = connect(url, tls.as_ref(), &options, &mut timing);
+    let (mut stream, remote, local, tls_info) =
+        tokio::time::timeout(Duration::from_millis(connect_timeout_ms), connecting)
+            .await
+            .unwrap_or_else(|_| Err(timed_out("connect", &url.addr, connect_timeout_ms)))?;
+
+    let sent = Instant::now();
+    let exchange = async {
+        stream
+            .write_all(&encode_request(url, method, headers, body))
+            .await
+            .map_err(|e| NetError::io("send", &url.addr, e))?;
+        let mut conn = Buffered::new(stream);
+        // waits for the first byte on its own so it can be timed
+        let first = conn.fill().await.map_err(|e| read_error(&url.addr, e))?;
+        if !first {
+            return Err(read_error(&url.addr, io::ErrorKind::UnexpectedEof.into()));
+        }
+        let first_byte = Instant::now();
+        let response = async {
+            loop {
+                let head = conn
+                    .head()
+                    .await?
+                    .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                let mut res = parse_response(&head)?;
+                // interim answers such as 100 Continue come before the real one
+                if (100..200).contains(&res.status) && res.status != 101 {
+                    continue;
+                }
+                let framing = match method == "HEAD" || matches!(res.status, 101..=199 | 204 | 304)
+                {
+                    true => BodyLen::Empty,
+                    false => body_len(&res.headers, true)?,
+                };
+                let body = conn.body(framing, &mut res.headers).await?;
+                return Ok((res, body, framing == BodyLen::Chunked));
+            }
+        };
+        let (res, body, chunked) = response
+            .await
+            .map_err(|e: io::Error| read_error(&url.addr, e))?;
+        Ok((res, body, chunked, first_byte))
+    };
+    let (res, body, chunked, first_byte) =
+        tokio::time::timeout(Duration::from_millis(timeout_ms), exchange)
+            .await
+            .unwrap_or_else(|_| Err(timed_out("response", &url.addr, timeout_ms)))?;
+    timing.first_byte_ms = millis(first_byte - sent);
+    timing.download_ms = millis(first_byte.elapsed());
+
+    Ok(HttpResponse {
+        url: url.to_url(),
+        remote,
+        local,
+        version: format!("HTTP/1.{}", res.version),
+        status: res.status,
+        reason: res.reason,
+        headers: res.headers,
+        body_b64: base64::engine::general_purpose::STANDARD.encode(&body),
+        body_len: body.len(),
+        chunked,
+        tls: tls_info,
+        redirects: Vec::new(),
+        timing,
+    })
+}
+
+/// Resolves, connects and handshakes one phase at a time, filling in `timing` as it goes.
+async fn connect(
+    url: &Url,
+    tls: Option<&tls::TlsClient>,
+    options: &SocketOptions,
+    timing: &mut Timing,
+) -> NetResult<(BoxedStream, String, String, Value)> {
+    let t = Instant::now();
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(&url.addr)
+        .await
+        .map_err(|e| NetError::io("resolve", &url.addr, e))?
+        .collect();
+    timing.dns_ms = millis(t.elapsed());
+
+    let t = Instant::now();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    let mut tcp = None;
+    for addr in addrs {
+        match sockopt::connect_tcp_to(addr, options).await {
+            Ok(s) => {
+                tcp = Some(s);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let tcp = tcp.ok_or_else(|| NetError::io("connect", &url.addr, last_error))?;
+    timing.connect_ms = millis(t.elapsed());
+    let remote = tcp.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let local = tcp.local_addr().map(|a| a.to_string()).unwrap_or_default();
+
+    match tls {
+        None => Ok((Box::new(tcp) as BoxedStream, remote, local, Value::Null)),
+        Some(t) => {
+            let start = Instant::now();
+            let stream = t
+                .connector
+                .connect(t.server_name.clone(), tcp)
+                .await
+                .map_err(|e| NetError::io("tls handshake", &url.addr, e))?;
+            timing.tls_ms = Some(millis(start.elapsed()));
+            let info = tls::describe(stream.get_ref().1);
+            Ok((Box::new(stream) as BoxedStream, remote, local, info))
+        }
+    }
+}
+
+fn encode_request(
+    url: &Url,
+    method: &str,
+    headers: &HashMap<String, String>,
+    body: &[u8],
+) -> Vec<u8> {
+    let has = |name: &str| headers.keys().any(|k| k.eq_ignore_ascii_case(name));
+    let mut head = format!("{} {} HTTP/1.1\r\n", method, url.path);
+    if !has("host") {
+        head.push_str(&format!("Host: {}\r\n", url.host));
+    }
+    let wants_length = !body.is_empty() || matches!(method, "POST" | "PUT" | "PATCH");
+    if wants_length && !has("content-length") && !has("transfer-encoding") {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    // one request per connection, so a response without a length still ends
+    if !has("connection") {
+        head.push_str("Connection: close\r\n");
+    }
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let mut out = head.into_bytes();
+    out.extend_from_slice(body);
+    out
+}
+
+/// One route of a mock HTTP server; the first route that matches a request answers it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    /// Any method when omitted; `GET` routes answer `HEAD` requests too.
+    pub method: Option<String>,
+    /// Compared with the request path without its query; a trailing `*` matches any rest.
+    pub path: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    /// The standard phrase for `status` when omitted.
+    pub reason: Option<String>,
+    /// A `Content-Length`, `Transfer-Encoding` or `Connection` header here replaces the one that
+    /// would be added.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body_b64: Option<String>,
+    /// Renders the named payload template as the body of every answer.
+    pub template: Option<String>,
+    /// Wait this long before answering.
+    pub delay_ms: Option<u64>,
+    /// Send the body with chunked transfer coding instead of a `Content-Length`.
+    #[serde(default)]
+    pub chunked: bool,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl Route {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        let method_ok = self
+            .method
+            .as_deref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method) || m == "GET" && method == "HEAD");
+        let path_ok = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        };
+        method_ok && path_ok
+    }
+}
+
+/// The routes of a TCP server session that answers as a mock HTTP server.
+pub struct Mock {
+    routes: Vec<Route>,
+}
+
+impl Mock {
+    pub fn new(routes: Vec<Route>) -> NetResult<Mock> {
+        for r in &routes {
+            if !(100..=999).contains(&r.status) {
+                return Err(NetError::http(format!("invalid status {}", r.status)));
+            }
+            if !r.path.starts_with('/') && r.path != "*" {
+                return Err(NetError::http(format!(
+                    "route path {:?} must start with /",
+                    r.path
+                )));
+            }
+            if r.template.is_some() || r.body_b64.is_some() {
+                Payload::new(r.body_b64.clone(), r.template.clone()).check()?;
+            }
+        }
+        Ok(Mock { routes })
+    }
+
+    fn route(&self, method: &str, path: &str) -> Option<(usize, &Route)> {
+        self.routes
+            .iter()
+            .enumerate()
+            .find(|(_, r)| r.matches(method, path))
+    }
+}
+
+/// Answers requests on one connection until the client closes it or asks to, or `cancel` fires;
+/// the caller closes the connection. Every request is logged as `http:server:request` once its
+/// answer is written.
+pub async fn serve<R, W>(
+    mock: &Mock,
+    conn: Connection<'_>,
+    reader: R,
+    writer: &tokio::sync::Mutex<W>,
+    cancel: &CancellationToken,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut input = Buffered::new(reader);
+    loop {
+        let head = tokio::select! {
+            _ = cancel.cancelled() => return,
+            r = input.head() => r,
+        };
+        let head = match head {
+            Ok(Some(h)) => h,
+            Ok(None) => return,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                conn.error("http:server:error", format!("bad request: {}", e));
+                let _ = write(writer, &plain_response(431, "request head too long")).await;
+                return;
+            }
+            Err(_) => return,
+        };
+        let started = Instant::now();
+        let ts_ms = session::now_ms();
+        let mut req = match parse_request(&head) {
+            Ok(r) => r,
+            Err(e) => {
+                conn.error("http:server:error", format!("bad request: {}", e));
+                let _ = write(writer, &plain_response(400, &e.to_string())).await;
+                return;
+            }
+        };
+        let expects_continue =
+            header(&req.headers, "expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue"));
+        if expects_continue
+            && write(writer, b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .is_err()
+        {
+            return;
+        }
+        let body = async {
+            let framing = body_len(&req.headers, false)?;
+            input.body(framing, &mut req.headers).await
+        };
+        let body = tokio::select! {
+            _ = cancel.cancelled() => return,
+            r = body => r,
+        };
+        let body = match body {
+            Ok(b) => b,
+            Err(e) => {
+                conn.error("http:server:error", format!("bad request body: {}", e));
+                if e.kind() == io::ErrorKind::InvalidData {
+                    let _ = write(writer, &plain_response(400, &e.to_string())).await;
+                }
+                return;
+            }
+        };
+
+        let seq = conn.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let mut raw = head;
+        raw.extend_from_slice(&body);
+        recorder::record(
+            conn.sid,
+            Direction::Rx,
+            conn.local,
+            conn.peer,
+            seq,
+            ts_ms,
+            &raw,
+        );
+
+        let connection = header(&req.headers, "connection").unwrap_or_default();
+        let mut keep_alive = match req.version {
+            0 => connection.eq_ignore_ascii_case("keep-alive"),
+            _ => !connection.eq_ignore_ascii_case("close"),
+        };
+        let route = mock.route(&req.method, &req.path);
+        let (status, mut response) = match route {
+            Some((_, r)) => {
+                if let Some(ms) = r.delay_ms {
+                    tokio::select! {
+                        _ = cancel.cancelled() => return,
+                        _ = tokio::time::sleep(Duration::from_millis(ms)) => {}
+                    }
+                }
+                match answer(r, &mut keep_alive) {
+                    Ok(a) => (r.status, a),
+                    Err(e) => {
+                        conn.error(
+                            "http:server:error",
+                            format!("route {} failed: {}", r.path, e.message),
+                        );
+                        (500, plain_response(500, &e.message))
+                    }
+                }
+            }
+            None => (404, plain_response(404, "no route matches this request")),
+        };
+        if req.method == "HEAD" {
+            if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+                response.truncate(end + 4);
+            }
+        }
+        let written = write(writer, &response).await;
+        recorder::record(
+            conn.sid,
+            Direction::Tx,
+            conn.local,
+            conn.peer,
+            0,
+            session::now_ms(),
+            &response,
+        );
+        let payload = json!({
+            "session": conn.sid,
+            "bind": conn.bind,
+            "peer": conn.peer,
+            "seq": seq,
+            "ts_ms": ts_ms,
+            "method": req.method,
+            "path": req.path,
+            "version": format!("HTTP/1.{}", req.version),
+            "headers": req.headers,
+            "body": base64::engine::general_purpose::STANDARD.encode(&body),
+            "route": route.map(|(i, _)| i),
+            "status": status,
+            "bytes": response.len(),
+            "duration_ms": millis(started.elapsed()),
+        });
+        let _ = conn.app.emit("http:server:request", payload);
+        if written.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+async fn write<W: AsyncWrite + Unpin>(
+    writer: &tokio::sync::Mutex<W>,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.lock().await.write_all(data).await
+}
+
+/// Builds a route's answer; clears `keep_alive` when the route's own headers close the connection.
+fn answer(route: &Route, keep_alive: &mut bool) -> NetResult<Vec<u8>> {
+    let body = match (&route.body_b64, &route.template) {
+        (None, None) => Vec::new(),
+        _ => Payload::new(route.body_b64.clone(), route.template.clone()).bytes()?,
+    };
+    let has = |name: &str| route.headers.keys().any(|k| k.eq_ignore_ascii_case(name));
+    let reason = route
+        .reason
+        .clone()
+        .unwrap_or_else(|| reason_phrase(route.status).to_string());
+    let mut head = format!("HTTP/1.1 {} {}\r\n", route.status, reason);
+    let bodyless = matches!(route.status, 100..=199 | 204 | 304);
+    if !bodyless && !has("content-length") && !has("transfer-encoding") {
+        match route.chunked {
+            true => head.push_str("Transfer-Encoding: chunked\r\n"),
+            false => head.push_str(&format!("Content-Length: {}\r\n", body.len())),
+        }
+    }
+    match route
+        .headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("connection"))
+    {
+        Some((_, v)) if v.eq_ignore_ascii_case("close") => *keep_alive = false,
+        Some(_) => {}
+        None if !*keep_alive => head.push_str("Connection: close\r\n"),
+        None => {}
+    }
+    for (name, value) in &route.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let mut out = head.into_bytes();
+    if bodyless {
+        return Ok(out);
+    }
+    match route.chunked && !body.is_empty() {
+        true => {
+            out.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            out.extend_from_slice(&body);
+            out.extend_from_slice(b"\r\n0\r\n\r\n");
+        }
+        false if route.chunked => out.extend_from_slice(b"0\r\n\r\n"),
+        false => out.extend_from_slice(&body),
+    }
+    Ok(out)
+}
+
+/// A short text answer for requests no route takes, or that could not be read.
+fn plain_response(status: u16, text: &str) -> Vec<u8> {
+    let body = format!("{}\n", text);
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        reason_phrase(status),
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+struct ParsedRequest {
+    method: String,
+    path: String,
+    version: u8,
+    headers: Vec<(String, String)>,
+}
+
+struct ParsedResponse {
+    version: u8,
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+fn parse_request(head: &[u8]) -> io::Result<ParsedRequest> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => Ok(ParsedRequest {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            version: req.version.unwrap_or(1),
+            headers: header_pairs(req.headers),
+        }),
+        Ok(httparse::Status::Partial) => Err(invalid_data("incomplete request head".into())),
+        Err(e) => Err(invalid_data(format!("malformed request head: {}", e))),
+    }
+}
+
+fn parse_response(head: &[u8]) -> io::Result<ParsedResponse> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    match res.parse(head) {
+        Ok(httparse::Status::Complete(_)) => Ok(ParsedResponse {
+            version: res.version.unwrap_or(1),
+            status: res.code.unwrap_or(0),
+            reason: res.reason.unwrap_or_default().to_string(),
+            headers: header_pairs(res.headers),
+        }),
+        Ok(httparse::Status::Partial) => Err(invalid_data("incomplete response head".into())),
+        Err(e) => Err(invalid_data(format!("malformed response head: {}", e))),
+    }
+}
+
+fn header_pairs(headers: &[httparse::Header<'_>]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| {
+            let value = String::from_utf8_lossy(h.value).trim().to_string();
+            (h.name.to_string(), value)
+        })
+        .collect()
+}
+
+/// The first header called `name`, ignoring case.
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BodyLen {
+    Empty,
+    Length(usize),
+    Chunked,
+    /// Responses only: everything until the server closes the connection.
+    UntilClose,
+}
+
+/// RFC 9112 section 6.3: chunked wins over `Content-Length`, which must be consistent.
+fn body_len(headers: &[(String, String)], response: bool) -> io::Result<BodyLen> {
+    let coding = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim)
+        .next_back();
+    match coding {
+        Some(c) if c.eq_ignore_ascii_case("chunked") => return Ok(BodyLen::Chunked),
+        Some(_) if response => return Ok(BodyLen::UntilClose),
+        Some(c) => {
+            return Err(invalid_data(format!(
+                "request body with transfer coding {} has no length",
+                c
+            )))
+        }
+        None => {}
+    }
+    let mut length = None;
+    for (_, v) in headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+    {
+        for part in v.split(',') {
+            let n: usize = part
+                .trim()
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid Content-Length {:?}", v)))?;
+            if length.is_some_and(|l| l != n) {
+                return Err(invalid_data("conflicting Content-Length headers".into()));
+            }
+            length = Some(n);
+        }
+    }
+    match length {
+        Some(n) if n > MAX_BODY_LEN => Err(invalid_data(format!(
+            "body of {} bytes is over the {} byte limit",
+            n, MAX_BODY_LEN
+        ))),
+        Some(n) => Ok(BodyLen::Length(n)),
+        None if response => Ok(BodyLen::UntilClose),
+        None => Ok(BodyLen::Empty),
+    }
+}
+
+/// A reader that keeps whatever arrived past the part asked for, for the next head or body.
+struct Buffered<R> {
+    inner: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Buffered<R> {
+    fn new(inner: R) -> Self {
+        Buffered {
+            inner,
+            buf: Vec::with_capacity(8192),
+        }
+    }
+
+    /// Appends what the next read returns; `false` at the end of the stream.
+    async fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; 8192];
+        let n = self.inner.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// Everything up to and including the blank line that ends a head; `None` when the stream
+    /// ends cleanly before another head starts. Cancel-safe.
+    async fn head(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // RFC 9112 section 2.2: empty lines before a request line are ignored
+            while self.buf.starts_with(b"\r\n") {
+                self.buf.drain(..2);
+            }
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let rest = self.buf.split_off(end + 4);
+                return Ok(Some(std::mem::replace(&mut self.buf, rest)));
+            }
+            if self.buf.len() > MAX_HEAD_LEN {
+                return Err(invalid_data("head too long".into()));
+            }
+            if !self.fill().await? {
+                return match self.buf.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+        }
+    }
+
+    async fn exact(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            if !self.fill().await? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        let rest = self.buf.split_off(n);
+        Ok(std::mem::replace(&mut self.buf, rest))
+    }
+
+    /// One line without its line ending.
+    async fn line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|&b| b == b'\n') {
+                let mut line = self.exact(i + 1).await?;
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_HEAD_LEN {
+                return Err(invalid_data("line too long".into()));
+            }
+            if !self.fill().await? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Reads a body framed as `len`; the trailers of a chunked one are added to `headers`.
+    async fn body(
+        &mut self,
+        len: BodyLen,
+        headers: &mut Vec<(String, String)>,
+    ) -> io::Result<Vec<u8>> {
+        match len {
+            BodyLen::Empty => Ok(Vec::new()),
+            BodyLen::Length(n) => self.exact(n).await,
+            BodyLen::UntilClose => {
+                while self.fill().await? {
+                    if self.buf.len() > MAX_BODY_LEN {
+                        return Err(invalid_data("body over the size limit".into()));
+                    }
+                }
+                Ok(std::mem::take(&mut self.buf))
+            }
+            BodyLen::Chunked => {
+                let mut body = Vec::new();
+                loop {
+                    let line = self.line().await?;
+                    let size = String::from_utf8_lossy(&line);
+                    let size = size.split(';').next().unwrap_or_default().trim();
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| invalid_data(format!("invalid chunk size {:?}", size)))?;
+                    if size == 0 {
+                        break;
+                    }
+                    match body.len().checked_add(size) {
+                        Some(total) if total <= MAX_BODY_LEN => {}
+                        _ => return Err(invalid_data("body over the size limit".into())),
+                    }
+                    let chunk = self.exact(size + 2).await?;
+                    if !chunk.ends_with(b"\r\n") {
+                        return Err(invalid_data("chunk not followed by CRLF".into()));
+                    }
+                    body.extend_from_slice(&chunk[..size]);
+                }
+                loop {
+                    let line = self.line().await?;
+                    if line.is_empty() {
+                        return Ok(body);
+                    }
+                    let line = String::from_utf8_lossy(&line);
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Malformed responses are `Http` errors; everything else is the OS error it was.
+fn read_error(addr: &str, e: io::Error) -> NetError {
+    match e.kind() {
+        io::ErrorKind::InvalidData => NetError::http(format!("bad response from {}: {}", addr, e)),
+        _ => NetError::io("receive", addr, e),
+    }
+}
+
+fn timed_out(context: &str, addr: &str, ms: u64) -> NetError {
+    NetError::io(
+        context,
+        addr,
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out after {} ms", ms),
+        ),
+    )
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    async fn read_body(
+        wire: &'static [u8],
+        len: BodyLen,
+    ) -> io::Result<(Vec<u8>, Vec<(String, String)>, Vec<u8>)> {
+        let mut input = Buffered::new(wire);
+        let mut trailers = Vec::new();
+        let body = input.body(len, &mut trailers).await?;
+        Ok((body, trailers, input.buf))
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies() {
+        let (body, trailers, rest) = read_body(
+            b"5;name=value\r\nhello\r\n7 ; x\r\n, world\r\nA\r\n0123456789\r\n0\r\n\r\nGET /next",
+            BodyLen::Chunked,
+        )
+        .await
+        .unwrap();
+        assert_eq!(body, b"hello, world0123456789");
+        assert!(trailers.is_empty());
+        // the next request stays buffered
+        assert_eq!(rest, b"GET /next");
+
+        let (body, trailers, _) = read_body(
+            b"3\r\nabc\r\n0\r\nExpires: never\r\nX-Sum:  42 \r\n\r\n",
+            BodyLen::Chunked,
+        )
+        .await
+        .unwrap();
+        assert_eq!(body, b"abc");
+        assert_eq!(trailers, headers(&[("Expires", "never"), ("X-Sum", "42")]));
+
+        // bare LF line endings are tolerated
+        let (body, _, _) = read_body(b"2\nhi\r\n0\n\n", BodyLen::Chunked)
+            .await
+            .unwrap();
+        assert_eq!(body, b"hi");
+    }
+
+    #[tokio::test]
+    async fn bad_chunks() {
+        for wire in [
+            &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+            b"\r\n",
+            b"-1\r\nx\r\n0\r\n\r\n",
+            // overflows usize
+            b"1ffffffffffffffffffff\r\n",
+            // within usize but over the body limit
+            b"fffffffffffffff\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+        ] {
+            let e = read_body(wire, BodyLen::Chunked).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", wire);
+        }
+        let e = read_body(b"5\r\nhel", BodyLen::Chunked).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn length_and_until_close_bodies() {
+        let (body, _, rest) = read_body(b"hello world", BodyLen::Length(5)).await.unwrap();
+        assert_eq!((&body[..], &rest[..]), (&b"hello"[..], &b" world"[..]));
+        let (body, _, _) = read_body(b"all of it", BodyLen::UntilClose).await.unwrap();
+        assert_eq!(body, b"all of it");
+        assert!(read_body(b"abc", BodyLen::Empty)
+            .await
+            .unwrap()
+            .0
+            .is_empty());
+        let e = read_body(b"abc", BodyLen::Length(4)).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn body_lengths() {
+        assert_eq!(body_len(&[], false).unwrap(), BodyLen::Empty);
+        assert_eq!(body_len(&[], true).unwrap(), BodyLen::UntilClose);
+        let h = headers(&[("content-length", "12")]);
+        assert_eq!(body_len(&h, false).unwrap(), BodyLen::Length(12));
+        // repeated but equal lengths are one length
+        let h = headers(&[("Content-Length", "7, 7"), ("content-length", "7")]);
+        assert_eq!(body_len(&h, true).unwrap(), BodyLen::Length(7));
+        for h in [
+            headers(&[("Content-Length", "7"), ("Content-Length", "8")]),
+            headers(&[("Content-Length", "7, 8")]),
+            headers(&[("Content-Length", "-1")]),
+            headers(&[("Content-Length", "")]),
+            headers(&[("Content-Length", "99999999999")]),
+        ] {
+            assert!(body_len(&h, false).is_err(), "{:?}", h);
+            assert!(body_len(&h, true).is_err(), "{:?}", h);
+        }
+
+        // chunked wins over a length, even a bad one
+        let h = headers(&[
+            ("Content-Length", "x"),
+            ("Transfer-Encoding", "gzip, chunked"),
+        ]);
+        assert_eq!(body_len(&h, false).unwrap(), BodyLen::Chunked);
+        // a final coding other than chunked: a response runs until close, a request has no length
+        let h = headers(&[
+            ("Transfer-Encoding", "chunked"),
+            ("Transfer-Encoding", "gzip"),
+        ]);
+        assert_eq!(body_len(&h, true).unwrap(), BodyLen::UntilClose);
+        assert!(body_len(&h, false).is_err());
+        let h = headers(&[("Transfer-Encoding", "gzip"), ("Content-Length", "3")]);
+        assert!(body_len(&h, false).is_err());
+    }
+
+    #[test]
+    fn join_locations() {
+        let base = Url::parse("http://example.com:8080/a/b/c?q=1#top").unwrap();
+        assert_eq!(base.path, "/a/b/c?q=1");
+        let cases = [
+            ("d", "http://example.com:8080/a/b/d"),
+            ("d/e?x=2#frag", "http://example.com:8080/a/b/d/e?x=2"),
+            ("../up", "http://example.com:8080/a/up"),
+            ("./d/../../..", "http://example.com:8080/"),
+            ("/x/./y/../z?a=../b", "http://example.com:8080/x/z?a=../b"),
+            ("", "http://example.com:8080/a/b/c?q=1"),
+            ("/root", "http://example.com:8080/root"),
+            ("?page=2", "http://example.com:8080/a/b/c?page=2"),
+            ("#part", "http://example.com:8080/a/b/c?q=1"),
+            ("//other.net/x", "http://other.net/x"),
+            ("https://secure.net", "https://secure.net/"),
+            ("HTTP://Upper.net:81/p", "http://Upper.net:81/p"),
+        ];
+        for (location, expected) in cases {
+            assert_eq!(
+                base.join(location).unwrap().to_url(),
+                expected,
+                "{}",
+                location
+            );
+        }
+        let joined = base.join("//other.net:9/x").unwrap();
+        assert_eq!((joined.addr.as_str(), joined.tls), ("other.net:9", false));
+        let secure = Url::parse("https://example.com").unwrap();
+        let joined = secure.join("//cdn.example.com/a").unwrap();
+        assert_eq!(
+            (joined.addr.as_str(), joined.tls),
+            ("cdn.example.com:443", true)
+        );
+        assert_eq!(secure.join("x").unwrap().to_url(), "https://example.com/x");
+        assert!(base.join("ftp://example.com/").is_err());
+        assert!(base.join("mailto:someone@example.com").is_err());
+        assert!(base.join("//user@host/").is_err());
+    }
+
+    fn route(value: Value) -> Route {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn route_matching() {
+        let get = route(json!({"method": "GET", "path": "/items"}));
+        assert!(get.matches("GET", "/items"));
+        assert!(get.matches("get", "/items?id=3"));
+        assert!(get.matches("HEAD", "/items"));
+        assert!(!get.matches("POST", "/items"));
+        assert!(!get.matches("GET", "/items/3"));
+        assert!(!get.matches("GET", "/item"));
+
+        let post = route(json!({"method": "POST", "path": "/items/*"}));
+        assert!(post.matches("POST", "/items/3"));
+        assert!(post.matches("POST", "/items/"));
+        assert!(!post.matches("POST", "/items"));
+        assert!(!post.matches("HEAD", "/items/3"));
+
+        let any = route(json!({"path": "*"}));
+        assert!(any.matches("DELETE", "/"));
+        assert!(any.matches("OPTIONS", "*"));
+
+        let mock = Mock::new(vec![get, post, any]).unwrap();
+        assert_eq!(mock.route("HEAD", "/items").unwrap().0, 0);
+        assert_eq!(mock.route("POST", "/items/9").unwrap().0, 1);
+        assert_eq!(mock.route("POST", "/items").unwrap().0, 2);
+        assert!(Mock::new(vec![route(json!({"path": "x"}))]).is_err());
+        assert!(Mock::new(vec![route(json!({"path": "/", "status": 99}))]).is_err());
+    }
+
+    fn answered(value: Value, keep_alive: bool) -> (String, bool) {
+        let mut keep_alive = keep_alive;
+        let out = answer(&route(value), &mut keep_alive).unwrap();
+        (String::from_utf8(out).unwrap(), keep_alive)
+    }
+
+    #[test]
+    fn answers() {
+        let (out, keep) = answered(json!({"path": "/", "body_b64": "aGVsbG8="}), true);
+        assert_eq!(out, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(keep);
+
+        let (out, _) = answered(
+            json!({"path": "/", "status": 201, "body_b64": "aGVsbG8=", "chunked": true}),
+            true,
+        );
+        assert_eq!(
+            out,
+            "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+        );
+        let (out, _) = answered(json!({"path": "/", "chunked": true}), true);
+        assert!(out.ends_with("Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n"));
+
+        for status in [101, 204, 304] {
+            let (out, _) = answered(
+                json!({"path": "/", "status": status, "body_b64": "aGVsbG8=", "chunked": true}),
+                true,
+            );
+            assert!(out.ends_with("\r\n\r\n"), "{}", out);
+            assert!(!out.contains("hello") && !out.contains("Content-Length"));
+            assert!(!out.contains("Transfer-Encoding"));
+        }
+
+        let (out, _) = answered(json!({"path": "/", "status": 599, "reason": "Odd"}), false);
+        assert_eq!(
+            out,
+            "HTTP/1.1 599 Odd\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+
+        // the route's own framing and connection headers replace the added ones
+        let (out, keep) = answered(
+            json!({"path": "/", "body_b64": "aGVsbG8=",
+                   "headers": {"content-length": "3", "Connection": "close"}}),
+            true,
+        );
+        assert!(!keep);
+        assert!(!out.contains("Content-Length: 5"));
+        assert!(out.contains("content-length: 3\r\n") && out.contains("Connection: close\r\n"));
+        assert_eq!(out.matches("onnection").count(), 1);
+        let (out, keep) = answered(
+            json!({"path": "/", "headers": {"Connection": "keep-alive"}}),
+            false,
+        );
+        assert!(!keep);
+        assert_eq!(out.matches("onnection").count(), 1);
+    }
+}
@@ -0,0 +1,519 @@
+use once_cell::sync::OnceCell;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tauri::async_runtime;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::error::{NetError, NetResult};
+use crate::session::{self, SessionKind, Updated};
+
+/// Reordered packets are held back this long unless `reorder_gap_ms` says otherwise.
+const DEFAULT_REORDER_GAP_MS: u64 = 10;
+/// With a bandwidth limit, datagrams that would wait longer than this to go out are dropped, as a
+/// full router queue would.
+const MAX_BACKLOG: Duration = Duration::from_secs(5);
+
+/// Network conditions to emulate on one direction of a session. Every field defaults to "off".
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Impairment {
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Each packet's delay varies uniformly by up to this much either side of `latency_ms`.
+    #[serde(default)]
+    pub jitter_ms: u64,
+    /// Percentages (0-100) of packets affected.
+    #[serde(default)]
+    pub loss_pct: f64,
+    #[serde(default)]
+    pub duplicate_pct: f64,
+    /// Reordered packets are held back by `reorder_gap_ms` so that later ones overtake them.
+    #[serde(default)]
+    pub reorder_pct: f64,
+    pub reorder_gap_ms: Option<u64>,
+    /// Corrupted packets get `corrupt_bits` (default 1) random bits flipped.
+    #[serde(default)]
+    pub corrupt_pct: f64,
+    pub corrupt_bits: Option<u32>,
+    /// Bandwidth limit in bits per second.
+    pub rate_bps: Option<u64>,
+    /// The same seed and the same traffic give the same drops, delays and bit flips. A random seed
+    /// is picked (and reported by `get_impairment`) when none is given.
+    pub seed: Option<u64>,
+}
+
+/// Outbound is what the session sends (towards the upstream, for proxies); inbound is what it
+/// receives (towards the client, for proxies).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Way {
+    Outbound,
+    Inbound,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub packets: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub corrupted: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImpairmentStatus {
+    pub direction: Way,
+    pub impairment: Impairment,
+    pub stats: Stats,
+}
+
+type Delivery = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// A packet waiting for its release time; ties go out in the order they were scheduled.
+struct Pending {
+    at: Instant,
+    seq: u64,
+    delivery: Delivery,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// One direction of one session. Datagram paths hand their packets to the stage's queue; stream
+/// paths (the TCP proxy) only ask it for a release time so the bytes stay in order.
+pub struct Stage {
+    spec: Impairment,
+    /// Byte streams cannot lose, duplicate or reorder data, and never overtake earlier chunks.
+    stream: bool,
+    state: Mutex<State>,
+    queue: mpsc::UnboundedSender<Pending>,
+}
+
+struct State {
+    rng: StdRng,
+    seq: u64,
+    /// When the emulated link finishes sending what it already has.
+    next_free: Instant,
+    last_release: Instant,
+    stats: Stats,
+}
+
+#[derive(Default)]
+struct Stages {
+    outbound: Option<Arc<Stage>>,
+    inbound: Option<Arc<Stage>>,
+}
+
+impl Stages {
+    fn slot(&mut self, way: Way) -> &mut Option<Arc<Stage>> {
+        match way {
+            Way::Outbound => &mut self.outbound,
+            Way::Inbound => &mut self.inbound,
+        }
+    }
+}
+
+static STAGES: OnceCell<Mutex<HashMap<String, Stages>>> = OnceCell::new();
+/// Lets the send and receive paths skip the lock while nothing is impaired.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn stages() -> NetResult<MutexGuard<'static, HashMap<String, Stages>>> {
+    STAGES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+/// Sets or (with `None`) removes the impairment of a UDP session or a proxy; `direction` defaults
+/// to both. Replacing an impairment drops the packets it still holds.
+pub fn set(
+    app: AppHandle,
+    session_id: String,
+    direction: Option<Way>,
+    impairment: Option<Impairment>,
+) -> NetResult<Updated> {
+    let info = session::get(&session_id)?;
+    let stream = match info.kind {
+        SessionKind::UdpServer | SessionKind::UdpClient | SessionKind::UdpProxy => false,
+        SessionKind::TcpProxy => true,
+        kind => {
+            return Err(NetError::unsupported(format!(
+                "impairment applies to UDP sessions and proxies, not a {}",
+                kind.label()
+            )));
+        }
+    };
+    if let Some(spec) = &impairment {
+        validate(spec, stream, &info.addr)?;
+    }
+    let ways = match direction {
+        Some(way) => vec![way],
+        None => vec![Way::Outbound, Way::Inbound],
+    };
+
+    let mut all = stages()?;
+    let entry = all.entry(session_id.clone()).or_default();
+    for way in ways {
+        *entry.slot(way) = impairment
+            .clone()
+            .map(|spec| Arc::new(Stage::new(app.clone(), &session_id, way, spec, stream)));
+    }
+    if entry.outbound.is_none() && entry.inbound.is_none() {
+        all.remove(&session_id);
+    }
+    ACTIVE.store(!all.is_empty(), Ordering::Relaxed);
+    let what = match direction {
+        Some(Way::Outbound) => "outbound ",
+        Some(Way::Inbound) => "inbound ",
+        None => "",
+    };
+    Ok(Updated {
+        session_id,
+        message: match impairment {
+            Some(_) => format!("{}impairment set", what),
+            None => format!("{}impairment removed", what),
+        },
+    })
+}
+
+pub fn status(session_id: &str) -> NetResult<Vec<ImpairmentStatus>> {
+    session::get(session_id)?;
+    let mut all = stages()?;
+    let Some(entry) = all.get_mut(session_id) else {
+        return Ok(Vec::new());
+    };
+    Ok([Way::Outbound, Way::Inbound]
+        .into_iter()
+        .filter_map(|way| {
+            let stage = entry.slot(way).as_ref()?;
+            let stats = stage.state.lock().map(|s| s.stats).unwrap_or_default();
+            Some(ImpairmentStatus {
+                direction: way,
+                impairment: stage.spec.clone(),
+                stats,
+            })
+        })
+        .collect())
+}
+
+/// Drops the impairments of a session that went away, along with any packets still held.
+pub fn forget(session_id: &str) {
+    if let Ok(mut all) = stages() {
+        all.remove(session_id);
+        ACTIVE.store(!all.is_empty(), Ordering::Relaxed);
+    }
+}
+
+fn stage(session_id: &str, way: Way) -> Option<Arc<Stage>> {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut all = stages().ok()?;
+    all.get_mut(session_id)?.slot(way).clone()
+}
+
+/// Hands a datagram to the session's impairment, which calls `deliver` for every copy that
+/// survives once its time comes. Returns false when the session has none for `way`, in which
+/// case the caller sends `data` itself.
+pub fn divert<F, Fut>(session_id: &str, way: Way, data: &[u8], deliver: F) -> bool
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    let Some(stage) = stage(session_id, way) else {
+        return false;
+    };
+    for (at, seq, copy) in stage.schedule(data) {
+        let _ = stage.queue.send(Pending {
+            at,
+            seq,
+            delivery: Box::pin(deliver(copy)),
+        });
+    }
+    true
+}
+
+/// For byte streams: corrupts `data` in place as configured and returns when it may be written,
+/// or `None` when the session has no impairment for `way`.
+pub fn release_time(session_id: &str, way: Way, data: &mut Vec<u8>) -> Option<Instant> {
+    let stage = stage(session_id, way)?;
+    let (at, _, copy) = stage.schedule(data).pop()?;
+    *data = copy;
+    Some(at)
+}
+
+impl Stage {
+    fn new(app: AppHandle, session_id: &str, way: Way, spec: Impairment, stream: bool) -> Self {
+        let (stage, rx) = Stage::detached(spec, stream);
+        async_runtime::spawn(release(app, session_id.to_string(), way, rx));
+        stage
+    }
+
+    /// The stage without its release task; whoever holds the receiver sends the queued packets.
+    fn detached(mut spec: Impairment, stream: bool) -> (Self, mpsc::UnboundedReceiver<Pending>) {
+        let seed = *spec.seed.get_or_insert_with(|| rand::thread_rng().gen());
+        let (tx, rx) = mpsc::unbounded_channel();
+        let now = Instant::now();
+        let stage = Stage {
+            spec,
+            stream,
+            state: Mutex::new(State {
+                rng: StdRng::seed_from_u64(seed),
+                seq: 0,
+                next_free: now,
+                last_release: now,
+                stats: Stats::default(),
+            }),
+            queue: tx,
+        };
+        (stage, rx)
+    }
+
+    /// Decides the fate of one packet: the copies to send, each with its release time.
+    fn schedule(&self, data: &[u8]) -> Vec<(Instant, u64, Vec<u8>)> {
+        let Ok(mut st) = self.state.lock() else {
+            return Vec::new();
+        };
+        let st = &mut *st;
+        let spec = &self.spec;
+        st.stats.packets += 1;
+        // draw the same numbers for every packet so one setting does not shift the others' dice
+        let loss: f64 = st.rng.gen_range(0.0..100.0);
+        let corrupt: f64 = st.rng.gen_range(0.0..100.0);
+        let duplicate: f64 = st.rng.gen_range(0.0..100.0);
+        let reorder: f64 = st.rng.gen_range(0.0..100.0);
+        let jitter: f64 = st.rng.gen_range(-1.0..=1.0);
+
+        if loss < spec.loss_pct {
+            st.stats.dropped += 1;
+            return Vec::new();
+        }
+        let mut data = data.to_vec();
+        if corrupt < spec.corrupt_pct && !data.is_empty() {
+            for _ in 0..spec.corrupt_bits.unwrap_or(1) {
+                let bit = st.rng.gen_range(0..data.len() * 8);
+                data[bit / 8] ^= 1 << (bit % 8);
+            }
+            st.stats.corrupted += 1;
+        }
+
+        let now = Instant::now();
+        let mut sent = now;
+        if let Some(rate) = spec.rate_bps {
+            let start = st.next_free.max(now);
+            if !self.stream && start - now > MAX_BACKLOG {
+                st.stats.dropped += 1;
+                return Vec::new();
+            }
+            st.next_free = start + Duration::from_secs_f64(data.len() as f64 * 8.0 / rate as f64);
+            sent = st.next_free;
+        }
+        let delay_ms = spec.latency_ms as f64 + jitter * spec.jitter_ms as f64;
+        let mut at = sent + Duration::from_secs_f64(delay_ms.max(0.0) / 1000.0);
+        if reorder < spec.reorder_pct {
+            at += Duration::from_millis(spec.reorder_gap_ms.unwrap_or(DEFAULT_REORDER_GAP_MS));
+            st.stats.reordered += 1;
+        }
+        if self.stream {
+            at = at.max(st.last_release);
+        }
+        st.last_release = at;
+
+        let mut out = Vec::with_capacity(2);
+        if duplicate < spec.duplicate_pct {
+            st.seq += 1;
+            out.push((at, st.seq, data.clone()));
+            st.stats.duplicated += 1;
+        }
+        st.seq += 1;
+        out.push((at, st.seq, data));
+        out
+    }
+}
+
+/// Sends each held packet when its time comes, until the stage is replaced or removed.
+async fn release(app: AppHandle, sid: String, way: Way, mut rx: mpsc::UnboundedReceiver<Pending>) {
+    let mut held: BinaryHeap<Reverse<Pending>> = BinaryHeap::new();
+    loop {
+        let next = held.peek().map(|Reverse(p)| p.at);
+        tokio::select! {
+            p = rx.recv() => match p {
+                Some(p) => held.push(Reverse(p)),
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let Some(Reverse(p)) = held.pop() else {
+                    continue;
+                };
+                if let Err(e) = p.delivery.await {
+                    let payload = json!({"session": sid, "direction": way, "error": format!("send error: {}", e)});
+                    let _ = app.emit("impair:error", payload);
+                }
+            }
+        }
+    }
+}
+
+fn validate(spec: &Impairment, stream: bool, addr: &str) -> NetResult<()> {
+    let percents = [
+        ("loss_pct", spec.loss_pct),
+        ("duplicate_pct", spec.duplicate_pct),
+        ("reorder_pct", spec.reorder_pct),
+        ("corrupt_pct", spec.corrupt_pct),
+    ];
+    for (name, pct) in percents {
+        if !(0.0..=100.0).contains(&pct) {
+            return Err(NetError::invalid_input(
+                "impairment",
+                addr,
+                format!("{} must be between 0 and 100", name),
+            ));
+        }
+        if stream && pct > 0.0 && name != "corrupt_pct" {
+            return Err(NetError::unsupported(format!(
+                "{} does not apply to a TCP stream, which delivers every byte once and in order",
+                name
+            )));
+        }
+    }
+    if spec.rate_bps == Some(0) {
+        return Err(NetError::invalid_input(
+            "impairment",
+            addr,
+            "rate_bps must be at least 1".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(seed: u64) -> Impairment {
+        Impairment {
+            latency_ms: 20,
+            jitter_ms: 10,
+            loss_pct: 20.0,
+            duplicate_pct: 20.0,
+            reorder_pct: 20.0,
+            corrupt_pct: 20.0,
+            corrupt_bits: Some(3),
+            seed: Some(seed),
+            ..Impairment::default()
+        }
+    }
+
+    /// The copies of one packet as (delay from now, bytes).
+    type Fate = Vec<(Duration, Vec<u8>)>;
+
+    fn run(spec: Impairment, packets: usize) -> (Vec<Fate>, Stats) {
+        let (stage, _rx) = Stage::detached(spec, false);
+        let now = Instant::now();
+        let fates = (0..packets)
+            .map(|i| {
+                let data = format!("packet {}", i).into_bytes();
+                stage
+                    .schedule(&data)
+                    .into_iter()
+                    .map(|(at, _, copy)| (at - now, copy))
+                    .collect()
+            })
+            .collect();
+        let stats = stage.state.lock().unwrap().stats;
+        (fates, stats)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn same_seed_same_decisions() {
+        let (first, stats) = run(spec(7), 500);
+        let (again, stats_again) = run(spec(7), 500);
+        assert_eq!(first, again);
+        assert_eq!(stats, stats_again);
+        assert_eq!(stats.packets, 500);
+        assert!(stats.dropped > 0 && stats.duplicated > 0);
+        assert!(stats.reordered > 0 && stats.corrupted > 0);
+
+        let dropped = first.iter().filter(|copies| copies.is_empty()).count();
+        let doubled = first.iter().filter(|copies| copies.len() == 2).count();
+        assert_eq!(dropped as u64, stats.dropped);
+        assert_eq!(doubled as u64, stats.duplicated);
+        for (i, copies) in first.iter().enumerate() {
+            for (delay, data) in copies {
+                // 20ms +/- 10ms of jitter, plus the default gap when reordered
+                assert!(*delay >= Duration::from_millis(10));
+                assert!(*delay <= Duration::from_millis(30 + DEFAULT_REORDER_GAP_MS));
+                let sent = format!("packet {}", i).into_bytes();
+                let flipped: u32 = data
+                    .iter()
+                    .zip(&sent)
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum();
+                assert!(flipped <= 3);
+            }
+        }
+
+        let (other, _) = run(spec(8), 500);
+        assert_ne!(first, other);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_bandwidth_and_latency() {
+        let spec = Impairment {
+            latency_ms: 50,
+            rate_bps: Some(8_000),
+            seed: Some(1),
+            ..Impairment::default()
+        };
+        let (stage, _rx) = Stage::detached(spec, true);
+        let start = Instant::now();
+        let release = |len: usize| {
+            let data = vec![0u8; len];
+            let (at, _, copy) = stage.schedule(&data).pop().unwrap();
+            assert_eq!(copy, data);
+            at
+        };
+
+        // 1000 bytes per second: each chunk waits for the ones before it, then the latency
+        assert_eq!(release(500), start + Duration::from_millis(550));
+        assert_eq!(release(250), start + Duration::from_millis(800));
+        assert_eq!(release(1000), start + Duration::from_millis(1800));
+
+        // once the link has drained, a chunk only pays for its own size
+        tokio::time::advance(Duration::from_secs(5)).await;
+        let now = Instant::now();
+        assert_eq!(release(100), now + Duration::from_millis(150));
+
+        tokio::time::sleep_until(now + Duration::from_millis(150)).await;
+        assert_eq!(Instant::now(), now + Duration::from_millis(150));
+        assert_eq!(stage.state.lock().unwrap().stats.packets, 4);
+    }
+}
@@ -0,0 +1,131 @@
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use tauri::async_runtime::JoinHandle;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::session;
+
+/// Background work that drives an existing session (replays, scheduled sends). Jobs live in
+/// their own registry so stopping one never stops the session it sends through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobKind {
+    Replay,
+    PeriodicSend,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub kind: JobKind,
+    /// Session the job sends through, or the target address when it uses a temporary socket.
+    pub target: String,
+    pub created_ms: u64,
+    pub paused: bool,
+}
+
+/// Returned by `stop_job`.
+#[derive(Clone, Debug, Serialize)]
+pub struct JobStopped {
+    pub job_id: String,
+    pub message: String,
+}
+
+struct Job {
+    info: JobInfo,
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    /// Set for jobs that can be paused; the task watches it between sends.
+    pause: Option<watch::Sender<bool>>,
+}
+
+static JOBS: OnceCell<Mutex<Vec<Job>>> = OnceCell::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn jobs() -> NetResult<MutexGuard<'static, Vec<Job>>> {
+    JOBS.get_or_init(|| Mutex::new(Vec::new()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+pub fn next_id() -> String {
+    format!("j{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn insert(
+    id: String,
+    kind: JobKind,
+    target: String,
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    pause: Option<watch::Sender<bool>>,
+) -> NetResult<JobInfo> {
+    let info = JobInfo {
+        id,
+        kind,
+        target,
+        created_ms: session::now_ms(),
+        paused: false,
+    };
+    jobs()?.push(Job {
+        info: info.clone(),
+        cancel,
+        task,
+        pause,
+    });
+    Ok(info)
+}
+
+/// Called by a job's own task when it runs to completion.
+pub fn finished(id: &str) {
+    if let Ok(mut js) = jobs() {
+        js.retain(|j| j.info.id != id);
+    }
+}
+
+pub fn list() -> NetResult<Vec<JobInfo>> {
+    Ok(jobs()?.iter().map(|j| j.info.clone()).collect())
+}
+
+pub fn pause(id: &str) -> NetResult<JobInfo> {
+    set_paused(id, true)
+}
+
+pub fn resume(id: &str) -> NetResult<JobInfo> {
+    set_paused(id, false)
+}
+
+fn set_paused(id: &str, paused: bool) -> NetResult<JobInfo> {
+    let mut js = jobs()?;
+    let job = js
+        .iter_mut()
+        .find(|j| j.info.id == id)
+        .ok_or_else(|| NetError::job_not_found(id))?;
+    let tx = job.pause.as_ref().ok_or_else(|| {
+        NetError::unsupported(format!("{:?} jobs cannot be paused", job.info.kind))
+    })?;
+    tx.send_replace(paused);
+    job.info.paused = paused;
+    Ok(job.info.clone())
+}
+
+pub async fn stop(id: &str) -> NetResult<JobStopped> {
+    let job = {
+        let mut js = jobs()?;
+        let i = js
+            .iter()
+            .position(|j| j.info.id == id)
+            .ok_or_else(|| NetError::job_not_found(id))?;
+        js.remove(i)
+    };
+    job.cancel.cancel();
+    let _ = job.task.await;
+    Ok(JobStopped {
+        job_id: job.info.id,
+        message: format!("job {} stopped", id),
+    })
+}
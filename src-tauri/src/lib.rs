@@ -1,80 +1,590 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+mod checksum;
+mod error;
+mod framing;
+mod http;
+mod impair;
+mod jobs;
+mod modbus;
+mod modbus_client;
+mod mqtt;
+mod mqtt_client;
+mod multicast;
+mod pcap;
+mod periodic;
+mod proxy;
+mod recorder;
+mod replay;
+mod responder;
+mod services;
+mod session;
+mod sockopt;
 mod tcp_client;
 mod tcp_server;
+mod template;
+mod tls;
 mod udp_client;
 mod udp_server;
+mod ws;
+mod ws_client;
+mod ws_server;
+
+use error::NetResult;
+use session::{Sent, SessionInfo, Started, Stopped, Updated};
+use template::Payload;
 
 #[tauri::command]
-fn start_udp_server(app: tauri::AppHandle, bind_addr: String) -> Result<String, String> {
-    udp_server::start(app, bind_addr)
+async fn start_udp_server(
+    app: tauri::AppHandle,
+    bind_addr: String,
+    options: Option<sockopt::BindOptions>,
+    socket_options: Option<sockopt::SocketOptions>,
+    mode: Option<services::ServerMode>,
+    framing: Option<framing::FramerConfig>,
+) -> NetResult<Started> {
+    udp_server::start(app, bind_addr, options, socket_options, mode, framing).await
 }
 
 #[tauri::command]
-fn udp_send(to_addr: String, data_b64: String) -> Result<String, String> {
-    udp_server::send_to(to_addr, data_b64)
+async fn udp_send(
+    to_addr: String,
+    data_b64: Option<String>,
+    template: Option<String>,
+) -> NetResult<Sent> {
+    udp_server::send_to(to_addr, Payload::new(data_b64, template)).await
 }
 
 #[tauri::command]
-fn udp_send_from(bind_addr: String, to_addr: String, data_b64: String) -> Result<String, String> {
-    udp_server::send_from(bind_addr, to_addr, data_b64)
+async fn udp_send_from(
+    bind_addr: String,
+    to_addr: String,
+    data_b64: Option<String>,
+    template: Option<String>,
+    framed: Option<bool>,
+) -> NetResult<Sent> {
+    let payload = Payload::new(data_b64, template);
+    udp_server::send_from(bind_addr, to_addr, payload, framed.unwrap_or(false)).await
 }
 
 #[tauri::command]
-fn stop_udp_server(bind_addr: Option<String>) -> Result<String, String> {
-    udp_server::stop(bind_addr)
+async fn stop_udp_server(bind_addr: Option<String>) -> NetResult<Stopped> {
+    udp_server::stop(bind_addr).await
 }
 
 #[tauri::command]
-fn start_udp_client(app: tauri::AppHandle, bind_addr: String) -> Result<String, String> {
-    udp_client::start(app, bind_addr)
+async fn start_udp_client(
+    app: tauri::AppHandle,
+    bind_addr: String,
+    options: Option<sockopt::BindOptions>,
+    socket_options: Option<sockopt::SocketOptions>,
+    framing: Option<framing::FramerConfig>,
+) -> NetResult<Started> {
+    udp_client::start(app, bind_addr, options, socket_options, framing).await
 }
 
 #[tauri::command]
-fn stop_udp_client(bind_addr: Option<String>) -> Result<String, String> {
-    udp_client::stop(bind_addr)
+async fn stop_udp_client(bind_addr: Option<String>) -> NetResult<Stopped> {
+    udp_client::stop(bind_addr).await
 }
 
 #[tauri::command]
-fn udp_client_send_from(
+async fn udp_client_send_from(
     bind_addr: String,
     to_addr: String,
-    data_b64: String,
-) -> Result<String, String> {
-    udp_client::send_from(bind_addr, to_addr, data_b64)
+    data_b64: Option<String>,
+    template: Option<String>,
+    framed: Option<bool>,
+) -> NetResult<Sent> {
+    let payload = Payload::new(data_b64, template);
+    udp_client::send_from(bind_addr, to_addr, payload, framed.unwrap_or(false)).await
+}
+
+#[tauri::command]
+fn udp_join_multicast(
+    session_id: String,
+    group: String,
+    interface: Option<String>,
+) -> NetResult<Updated> {
+    multicast::join(session_id, group, interface)
+}
+
+#[tauri::command]
+fn udp_leave_multicast(
+    session_id: String,
+    group: String,
+    interface: Option<String>,
+) -> NetResult<Updated> {
+    multicast::leave(session_id, group, interface)
 }
 
 #[tauri::command]
-fn start_tcp_server(app: tauri::AppHandle, bind_addr: String) -> Result<String, String> {
-    tcp_server::start(app, bind_addr)
+fn udp_set_multicast_options(
+    session_id: String,
+    options: multicast::MulticastOptions,
+) -> NetResult<Updated> {
+    multicast::set_options(session_id, options)
 }
 
 #[tauri::command]
-fn stop_tcp_server(bind_addr: Option<String>) -> Result<String, String> {
-    tcp_server::stop(bind_addr)
+async fn start_tcp_server(
+    app: tauri::AppHandle,
+    bind_addr: String,
+    tls: Option<tls::TlsServerConfig>,
+    framing: Option<framing::FramerConfig>,
+    socket_options: Option<sockopt::SocketOptions>,
+    mode: Option<services::ServerMode>,
+) -> NetResult<Started> {
+    tcp_server::start(app, bind_addr, tls, framing, socket_options, mode).await
 }
 
 #[tauri::command]
-fn tcp_server_send(
+async fn start_http_server(
+    app: tauri::AppHandle,
+    bind_addr: String,
+    tls: Option<tls::TlsServerConfig>,
+    routes: Vec<http::Route>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    tcp_server::start_http(app, bind_addr, tls, routes, socket_options).await
+}
+
+#[tauri::command]
+async fn stop_tcp_server(bind_addr: Option<String>) -> NetResult<Stopped> {
+    tcp_server::stop(bind_addr).await
+}
+
+#[tauri::command]
+async fn tcp_server_send(
     bind_addr: String,
     to_peer: Option<String>,
+    data_b64: Option<String>,
+    template: Option<String>,
+    framed: Option<bool>,
+) -> NetResult<Sent> {
+    let payload = Payload::new(data_b64, template);
+    tcp_server::send(bind_addr, to_peer, payload, framed.unwrap_or(false)).await
+}
+
+#[tauri::command]
+async fn start_tcp_client(
+    app: tauri::AppHandle,
+    remote_addr: String,
+    connect_timeout_ms: Option<u64>,
+    reconnect: Option<tcp_client::ReconnectPolicy>,
+    tls: Option<tls::TlsClientConfig>,
+    framing: Option<framing::FramerConfig>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    tcp_client::start(
+        app,
+        remote_addr,
+        connect_timeout_ms,
+        reconnect,
+        tls,
+        framing,
+        socket_options,
+    )
+    .await
+}
+
+#[tauri::command]
+async fn stop_tcp_client(remote_addr: Option<String>) -> NetResult<Stopped> {
+    tcp_client::stop(remote_addr).await
+}
+
+#[tauri::command]
+async fn tcp_client_send(
+    remote_addr: String,
+    data_b64: Option<String>,
+    template: Option<String>,
+    framed: Option<bool>,
+) -> NetResult<Sent> {
+    let payload = Payload::new(data_b64, template);
+    tcp_client::send(remote_addr, payload, framed.unwrap_or(false)).await
+}
+
+#[tauri::command]
+fn list_sessions() -> NetResult<Vec<SessionInfo>> {
+    session::list()
+}
+
+#[tauri::command]
+fn get_session(session_id: String) -> NetResult<SessionInfo> {
+    session::get(&session_id)
+}
+
+#[tauri::command]
+async fn stop_session(session_id: String) -> NetResult<Stopped> {
+    session::stop(&session_id).await
+}
+
+#[tauri::command]
+fn set_socket_options(session_id: String, options: sockopt::SocketOptions) -> NetResult<Updated> {
+    sockopt::set(session_id, options)
+}
+
+#[tauri::command]
+fn get_socket_options(session_id: String) -> NetResult<Vec<sockopt::SocketOptionValues>> {
+    sockopt::get(&session_id)
+}
+
+#[tauri::command]
+fn start_recording(
+    app: tauri::AppHandle,
+    session_id: String,
+    options: recorder::RecordOptions,
+) -> NetResult<recorder::Recording> {
+    recorder::start(app, session_id, options)
+}
+
+#[tauri::command]
+async fn stop_recording(session_id: String) -> NetResult<recorder::RecordingStopped> {
+    recorder::stop(&session_id).await
+}
+
+#[tauri::command]
+async fn query_recording(query: recorder::RecordQuery) -> NetResult<Vec<recorder::Record>> {
+    recorder::query(query).await
+}
+
+#[tauri::command]
+async fn export_pcapng(export: pcap::PcapExport) -> NetResult<pcap::Exported> {
+    pcap::export(export).await
+}
+
+#[tauri::command]
+async fn start_replay(
+    app: tauri::AppHandle,
+    request: replay::ReplayRequest,
+) -> NetResult<jobs::JobInfo> {
+    replay::start(app, request).await
+}
+
+#[tauri::command]
+fn list_jobs() -> NetResult<Vec<jobs::JobInfo>> {
+    jobs::list()
+}
+
+#[tauri::command]
+fn start_periodic_send(
+    app: tauri::AppHandle,
+    request: periodic::PeriodicSend,
+) -> NetResult<jobs::JobInfo> {
+    periodic::start(app, request)
+}
+
+#[tauri::command]
+fn pause_job(job_id: String) -> NetResult<jobs::JobInfo> {
+    jobs::pause(&job_id)
+}
+
+#[tauri::command]
+fn resume_job(job_id: String) -> NetResult<jobs::JobInfo> {
+    jobs::resume(&job_id)
+}
+
+#[tauri::command]
+async fn stop_job(job_id: String) -> NetResult<jobs::JobStopped> {
+    jobs::stop(&job_id).await
+}
+
+#[tauri::command]
+fn set_template(
+    name: String,
+    template: template::TemplateSpec,
+) -> NetResult<template::TemplateInfo> {
+    template::set(name, template)
+}
+
+#[tauri::command]
+fn remove_template(name: String) -> NetResult<template::TemplateInfo> {
+    template::remove(&name)
+}
+
+#[tauri::command]
+fn list_templates() -> NetResult<Vec<String>> {
+    template::list()
+}
+
+#[tauri::command]
+fn preview_template(name: String, count: Option<usize>) -> NetResult<Vec<String>> {
+    template::preview(&name, count.unwrap_or(1))
+}
+
+#[tauri::command]
+fn list_checksum_presets() -> Vec<checksum::CrcPreset> {
+    checksum::PRESETS.to_vec()
+}
+
+#[tauri::command]
+fn compute_checksum(
     data_b64: String,
-) -> Result<String, String> {
-    tcp_server::send(bind_addr, to_peer, data_b64)
+    algorithm: checksum::Algorithm,
+    endian: Option<framing::Endian>,
+) -> NetResult<checksum::ChecksumResult> {
+    checksum::compute(&data_b64, &algorithm, endian)
+}
+
+#[tauri::command]
+fn set_send_checksum(
+    session_id: String,
+    checksum: Option<checksum::AppendChecksum>,
+) -> NetResult<Updated> {
+    checksum::set_append(session_id, checksum)
+}
+
+#[tauri::command]
+fn set_responder_rules(session_id: String, rules: Vec<responder::RuleSpec>) -> NetResult<Updated> {
+    responder::set(session_id, rules)
+}
+
+#[tauri::command]
+fn get_responder_rules(session_id: String) -> NetResult<Vec<responder::RuleStatus>> {
+    responder::status(&session_id)
+}
+
+#[tauri::command]
+fn clear_responder_rules(session_id: String) -> NetResult<Updated> {
+    responder::clear(session_id)
+}
+
+#[tauri::command]
+async fn start_proxy(
+    app: tauri::AppHandle,
+    protocol: proxy::ProxyProtocol,
+    listen_addr: String,
+    upstream_addr: String,
+    options: Option<proxy::ProxyOptions>,
+) -> NetResult<Started> {
+    proxy::start(app, protocol, listen_addr, upstream_addr, options).await
+}
+
+#[tauri::command]
+fn set_impairment(
+    app: tauri::AppHandle,
+    session_id: String,
+    direction: Option<impair::Way>,
+    impairment: Option<impair::Impairment>,
+) -> NetResult<Updated> {
+    impair::set(app, session_id, direction, impairment)
+}
+
+#[tauri::command]
+fn get_impairment(session_id: String) -> NetResult<Vec<impair::ImpairmentStatus>> {
+    impair::status(&session_id)
+}
+
+#[tauri::command]
+async fn start_ws_server(
+    app: tauri::AppHandle,
+    bind_addr: String,
+    tls: Option<tls::TlsServerConfig>,
+    ws: Option<ws::WsOptions>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    ws_server::start(app, bind_addr, tls, ws, socket_options).await
+}
+
+#[tauri::command]
+async fn stop_ws_server(bind_addr: Option<String>) -> NetResult<Stopped> {
+    ws_server::stop(bind_addr).await
+}
+
+#[tauri::command]
+async fn ws_server_send(
+    bind_addr: String,
+    to_peer: Option<String>,
+    data_b64: Option<String>,
+    template: Option<String>,
+    opcode: Option<ws::Opcode>,
+) -> NetResult<Sent> {
+    ws_server::send(bind_addr, to_peer, Payload::new(data_b64, template), opcode).await
+}
+
+#[tauri::command]
+async fn ws_server_close(
+    bind_addr: String,
+    to_peer: Option<String>,
+    code: Option<u16>,
+    reason: Option<String>,
+) -> NetResult<Sent> {
+    ws_server::close(bind_addr, to_peer, code, reason).await
+}
+
+#[tauri::command]
+async fn start_ws_client(
+    app: tauri::AppHandle,
+    url: String,
+    connect_timeout_ms: Option<u64>,
+    tls: Option<tls::TlsClientConfig>,
+    ws: Option<ws::WsOptions>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    ws_client::start(app, url, connect_timeout_ms, tls, ws, socket_options).await
+}
+
+#[tauri::command]
+async fn stop_ws_client(url: Option<String>) -> NetResult<Stopped> {
+    ws_client::stop(url).await
+}
+
+#[tauri::command]
+async fn ws_client_send(
+    url: String,
+    data_b64: Option<String>,
+    template: Option<String>,
+    opcode: Option<ws::Opcode>,
+) -> NetResult<Sent> {
+    ws_client::send(url, Payload::new(data_b64, template), opcode).await
+}
+
+#[tauri::command]
+async fn ws_client_close(
+    url: String,
+    code: Option<u16>,
+    reason: Option<String>,
+) -> NetResult<Sent> {
+    ws_client::close(url, code, reason).await
+}
+
+#[tauri::command]
+async fn http_request(request: http::HttpRequest) -> NetResult<http::HttpResponse> {
+    http::request(request).await
+}
+
+#[tauri::command]
+async fn start_modbus_server(
+    app: tauri::AppHandle,
+    bind_addr: String,
+    tls: Option<tls::TlsServerConfig>,
+    slave: Option<modbus::SlaveConfig>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    tcp_server::start_modbus(app, bind_addr, tls, slave, socket_options).await
+}
+
+#[tauri::command]
+fn modbus_read_table(
+    session_id: String,
+    table: modbus::Table,
+    address: u16,
+    count: u16,
+) -> NetResult<Vec<u16>> {
+    modbus::read_table(session_id, table, address, count)
+}
+
+#[tauri::command]
+fn modbus_write_table(
+    session_id: String,
+    table: modbus::Table,
+    address: u16,
+    values: Vec<u16>,
+) -> NetResult<Updated> {
+    modbus::write_table(session_id, table, address, values)
+}
+
+#[tauri::command]
+async fn start_modbus_client(
+    app: tauri::AppHandle,
+    remote_addr: String,
+    unit_id: Option<u8>,
+    timeout_ms: Option<u64>,
+    connect_timeout_ms: Option<u64>,
+    tls: Option<tls::TlsClientConfig>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    modbus_client::start(
+        app,
+        remote_addr,
+        unit_id,
+        timeout_ms,
+        connect_timeout_ms,
+        tls,
+        socket_options,
+    )
+    .await
+}
+
+#[tauri::command]
+async fn stop_modbus_client(remote_addr: Option<String>) -> NetResult<Stopped> {
+    modbus_client::stop(remote_addr).await
+}
+
+#[tauri::command]
+async fn modbus_request(
+    remote_addr: String,
+    request: modbus::Request,
+    unit_id: Option<u8>,
+    timeout_ms: Option<u64>,
+) -> NetResult<modbus_client::Transaction> {
+    modbus_client::request(remote_addr, request, unit_id, timeout_ms).await
+}
+
+#[tauri::command]
+async fn start_mqtt_client(
+    app: tauri::AppHandle,
+    url: String,
+    options: Option<mqtt_client::ConnectOptions>,
+    connect_timeout_ms: Option<u64>,
+    tls: Option<tls::TlsClientConfig>,
+    ws: Option<ws::WsOptions>,
+    socket_options: Option<sockopt::SocketOptions>,
+) -> NetResult<Started> {
+    mqtt_client::start(
+        app,
+        url,
+        options,
+        connect_timeout_ms,
+        tls,
+        ws,
+        socket_options,
+    )
+    .await
+}
+
+#[tauri::command]
+async fn stop_mqtt_client(url: Option<String>) -> NetResult<Stopped> {
+    mqtt_client::stop(url).await
 }
 
 #[tauri::command]
-fn start_tcp_client(app: tauri::AppHandle, remote_addr: String) -> Result<String, String> {
-    tcp_client::start(app, remote_addr)
+async fn mqtt_subscribe(
+    url: String,
+    subscriptions: Vec<mqtt::Subscription>,
+    properties: Option<mqtt::Properties>,
+) -> NetResult<mqtt_client::Acknowledged> {
+    mqtt_client::subscribe(url, subscriptions, properties).await
 }
 
 #[tauri::command]
-fn stop_tcp_client(remote_addr: Option<String>) -> Result<String, String> {
-    tcp_client::stop(remote_addr)
+async fn mqtt_unsubscribe(
+    url: String,
+    filters: Vec<String>,
+    properties: Option<mqtt::Properties>,
+) -> NetResult<mqtt_client::Acknowledged> {
+    mqtt_client::unsubscribe(url, filters, properties).await
 }
 
 #[tauri::command]
-fn tcp_client_send(remote_addr: String, data_b64: String) -> Result<String, String> {
-    tcp_client::send(remote_addr, data_b64)
+async fn mqtt_publish(
+    url: String,
+    topic: String,
+    data_b64: Option<String>,
+    template: Option<String>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+    properties: Option<mqtt::Properties>,
+) -> NetResult<mqtt_client::Published> {
+    let payload = Payload::new(data_b64, template);
+    mqtt_client::publish(
+        url,
+        topic,
+        payload,
+        qos,
+        retain.unwrap_or(false),
+        properties,
+    )
+    .await
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -89,12 +599,64 @@ pub fn run() {
             start_udp_client,
             stop_udp_client,
             udp_client_send_from,
+            udp_join_multicast,
+            udp_leave_multicast,
+            udp_set_multicast_options,
             start_tcp_server,
             stop_tcp_server,
             tcp_server_send,
             start_tcp_client,
             stop_tcp_client,
-            tcp_client_send
+            tcp_client_send,
+            list_sessions,
+            get_session,
+            stop_session,
+            set_socket_options,
+            get_socket_options,
+            start_recording,
+            stop_recording,
+            query_recording,
+            export_pcapng,
+            start_replay,
+            list_jobs,
+            stop_job,
+            start_periodic_send,
+            pause_job,
+            resume_job,
+            set_template,
+            remove_template,
+            list_templates,
+            preview_template,
+            list_checksum_presets,
+            compute_checksum,
+            set_send_checksum,
+            set_responder_rules,
+            get_responder_rules,
+            clear_responder_rules,
+            start_proxy,
+            set_impairment,
+            get_impairment,
+            start_ws_server,
+            stop_ws_server,
+            ws_server_send,
+            ws_server_close,
+            start_ws_client,
+            stop_ws_client,
+            ws_client_send,
+            ws_client_close,
+            start_http_server,
+            http_request,
+            start_modbus_server,
+            modbus_read_table,
+            modbus_write_table,
+            start_modbus_client,
+            stop_modbus_client,
+            modbus_request,
+            start_mqtt_client,
+            stop_mqtt_client,
+            mqtt_subscribe,
+            mqtt_unsubscribe,
+            mqtt_publish
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,1312 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::Emitter;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Updated};
+use crate::tcp_server::Connection;
+
+/// MBAP header: transaction id, protocol id (always 0), length, unit id.
+pub const MBAP_LEN: usize = 7;
+/// The 256-byte serial ADU minus the address and CRC; Modbus TCP keeps the same limit.
+pub const MAX_PDU_LEN: usize = 253;
+/// Entries in a full table: every 16-bit address.
+const TABLE_LEN: u32 = 0x1_0000;
+
+// quantity limits from the spec, so that every request and response fits in one PDU
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_WRITE_BITS: usize = 1968;
+const MAX_WRITE_REGISTERS: usize = 123;
+const MAX_READ_WRITE_REGISTERS: usize = 121;
+
+pub const ILLEGAL_FUNCTION: u8 = 0x01;
+pub const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+pub const ILLEGAL_DATA_VALUE: u8 = 0x03;
+pub const SERVER_DEVICE_FAILURE: u8 = 0x04;
+
+/// One request PDU; serialized with its name in `function`, e.g.
+/// `{"function":"read_holding_registers","address":0,"count":10}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "function", rename_all = "snake_case")]
+pub enum Request {
+    /// 0x01
+    ReadCoils { address: u16, count: u16 },
+    /// 0x02
+    ReadDiscreteInputs { address: u16, count: u16 },
+    /// 0x03
+    ReadHoldingRegisters { address: u16, count: u16 },
+    /// 0x04
+    ReadInputRegisters { address: u16, count: u16 },
+    /// 0x05
+    WriteSingleCoil { address: u16, value: bool },
+    /// 0x06
+    WriteSingleRegister { address: u16, value: u16 },
+    /// 0x0F
+    WriteMultipleCoils { address: u16, values: Vec<bool> },
+    /// 0x10
+    WriteMultipleRegisters { address: u16, values: Vec<u16> },
+    /// 0x17: the write is done before the read.
+    ReadWriteMultipleRegisters {
+        read_address: u16,
+        read_count: u16,
+        write_address: u16,
+        values: Vec<u16>,
+    },
+}
+
+/// One response PDU, named like the request it answers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "function", rename_all = "snake_case")]
+pub enum Response {
+    ReadCoils {
+        values: Vec<bool>,
+    },
+    ReadDiscreteInputs {
+        values: Vec<bool>,
+    },
+    ReadHoldingRegisters {
+        values: Vec<u16>,
+    },
+    ReadInputRegisters {
+        values: Vec<u16>,
+    },
+    WriteSingleCoil {
+        address: u16,
+        value: bool,
+    },
+    WriteSingleRegister {
+        address: u16,
+        value: u16,
+    },
+    WriteMultipleCoils {
+        address: u16,
+        count: u16,
+    },
+    WriteMultipleRegisters {
+        address: u16,
+        count: u16,
+    },
+    ReadWriteMultipleRegisters {
+        values: Vec<u16>,
+    },
+    /// `function_code` is the one of the request, without the 0x80 flag.
+    Exception {
+        function_code: u8,
+        code: u8,
+        name: &'static str,
+    },
+}
+
+impl Request {
+    pub fn function_code(&self) -> u8 {
+        match self {
+            Request::ReadCoils { .. } => 0x01,
+            Request::ReadDiscreteInputs { .. } => 0x02,
+            Request::ReadHoldingRegisters { .. } => 0x03,
+            Request::ReadInputRegisters { .. } => 0x04,
+            Request::WriteSingleCoil { .. } => 0x05,
+            Request::WriteSingleRegister { .. } => 0x06,
+            Request::WriteMultipleCoils { .. } => 0x0F,
+            Request::WriteMultipleRegisters { .. } => 0x10,
+            Request::ReadWriteMultipleRegisters { .. } => 0x17,
+        }
+    }
+
+    /// The quantity limits of the spec; a slave answers a request that breaks them with
+    /// exception 03.
+    fn check_quantity(&self) -> Result<(), String> {
+        let (what, n, max) = match self {
+            Request::ReadCoils { count, .. } | Request::ReadDiscreteInputs { count, .. } => {
+                ("count", *count as usize, MAX_READ_BITS as usize)
+            }
+            Request::ReadHoldingRegisters { count, .. }
+            | Request::ReadInputRegisters { count, .. } => {
+                ("count", *count as usize, MAX_READ_REGISTERS as usize)
+            }
+            Request::WriteSingleCoil { .. } | Request::WriteSingleRegister { .. } => return Ok(()),
+            Request::WriteMultipleCoils { values, .. } => ("values", values.len(), MAX_WRITE_BITS),
+            Request::WriteMultipleRegisters { values, .. } => {
+                ("values", values.len(), MAX_WRITE_REGISTERS)
+            }
+            Request::ReadWriteMultipleRegisters {
+                read_count, values, ..
+            } => {
+                if *read_count == 0 || *read_count > MAX_READ_REGISTERS {
+                    return Err(format!(
+                        "read_count must be 1 to {}, not {}",
+                        MAX_READ_REGISTERS, read_count
+                    ));
+                }
+                ("values", values.len(), MAX_READ_WRITE_REGISTERS)
+            }
+        };
+        match n {
+            0 => Err(format!("{} must not be empty", what)),
+            n if n > max => Err(format!("{} is limited to {}, not {}", what, max, n)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the quantities and that no range runs past address 65535.
+    pub fn validate(&self) -> NetResult<()> {
+        self.check_quantity().map_err(NetError::modbus)?;
+        let ranges = match self {
+            Request::ReadCoils { address, count }
+            | Request::ReadDiscreteInputs { address, count }
+            | Request::ReadHoldingRegisters { address, count }
+            | Request::ReadInputRegisters { address, count } => vec![(*address, *count as usize)],
+            Request::WriteSingleCoil { .. } | Request::WriteSingleRegister { .. } => Vec::new(),
+            Request::WriteMultipleCoils { address, values } => vec![(*address, values.len())],
+            Request::WriteMultipleRegisters { address, values } => vec![(*address, values.len())],
+            Request::ReadWriteMultipleRegisters {
+                read_address,
+                read_count,
+                write_address,
+                values,
+            } => vec![
+                (*read_address, *read_count as usize),
+                (*write_address, values.len()),
+            ],
+        };
+        for (address, n) in ranges {
+            if address as u32 + n as u32 > TABLE_LEN {
+                return Err(NetError::modbus(format!(
+                    "{} entries from address {} run past 65535",
+                    n, address
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.function_code()];
+        match self {
+            Request::ReadCoils { address, count }
+            | Request::ReadDiscreteInputs { address, count }
+            | Request::ReadHoldingRegisters { address, count }
+            | Request::ReadInputRegisters { address, count } => {
+                push_words(&mut out, &[*address, *count]);
+            }
+            Request::WriteSingleCoil { address, value } => {
+                push_words(&mut out, &[*address, coil_word(*value)]);
+            }
+            Request::WriteSingleRegister { address, value } => {
+                push_words(&mut out, &[*address, *value]);
+            }
+            Request::WriteMultipleCoils { address, values } => {
+                push_words(&mut out, &[*address, values.len() as u16]);
+                let bits = pack_bits(values);
+                out.push(bits.len() as u8);
+                out.extend_from_slice(&bits);
+            }
+            Request::WriteMultipleRegisters { address, values } => {
+                push_words(&mut out, &[*address, values.len() as u16]);
+                out.push((values.len() * 2) as u8);
+                push_words(&mut out, values);
+            }
+            Request::ReadWriteMultipleRegisters {
+                read_address,
+                read_count,
+                write_address,
+                values,
+            } => {
+                push_words(
+                    &mut out,
+                    &[
+                        *read_address,
+                        *read_count,
+                        *write_address,
+                        values.len() as u16,
+                    ],
+                );
+                out.push((values.len() * 2) as u8);
+                push_words(&mut out, values);
+            }
+        }
+        out
+    }
+
+    /// Parses a request PDU as a slave does; the error is the exception code to answer with.
+    pub fn decode(pdu: &[u8]) -> Result<Request, u8> {
+        let (&function, data) = pdu.split_first().ok_or(ILLEGAL_FUNCTION)?;
+        let word = |i: usize| {
+            data.get(i..i + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or(ILLEGAL_DATA_VALUE)
+        };
+        // the byte count at `at`, and that many bytes after it
+        let counted = |at: usize| {
+            let n = *data.get(at).ok_or(ILLEGAL_DATA_VALUE)? as usize;
+            match data.get(at + 1..) {
+                Some(rest) if rest.len() == n => Ok(rest),
+                _ => Err(ILLEGAL_DATA_VALUE),
+            }
+        };
+        let request = match function {
+            0x01 => Request::ReadCoils {
+                address: word(0)?,
+                count: word(2)?,
+            },
+            0x02 => Request::ReadDiscreteInputs {
+                address: word(0)?,
+                count: word(2)?,
+            },
+            0x03 => Request::ReadHoldingRegisters {
+                address: word(0)?,
+                count: word(2)?,
+            },
+            0x04 => Request::ReadInputRegisters {
+                address: word(0)?,
+                count: word(2)?,
+            },
+            0x05 => Request::WriteSingleCoil {
+                address: word(0)?,
+                value: match word(2)? {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(ILLEGAL_DATA_VALUE),
+                },
+            },
+            0x06 => Request::WriteSingleRegister {
+                address: word(0)?,
+                value: word(2)?,
+            },
+            0x0F => {
+                let count = word(2)?;
+                let bits = counted(4)?;
+                if bits.len() != (count as usize).div_ceil(8) {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                Request::WriteMultipleCoils {
+                    address: word(0)?,
+                    values: unpack_bits(bits, count),
+                }
+            }
+            0x10 => {
+                let count = word(2)?;
+                let bytes = counted(4)?;
+                if bytes.len() != count as usize * 2 {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                Request::WriteMultipleRegisters {
+                    address: word(0)?,
+                    values: words(bytes),
+                }
+            }
+            0x17 => {
+                let write_count = word(6)?;
+                let bytes = counted(8)?;
+                if bytes.len() != write_count as usize * 2 {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                Request::ReadWriteMultipleRegisters {
+                    read_address: word(0)?,
+                    read_count: word(2)?,
+                    write_address: word(4)?,
+                    values: words(bytes),
+                }
+            }
+            _ => return Err(ILLEGAL_FUNCTION),
+        };
+        request.check_quantity().map_err(|_| ILLEGAL_DATA_VALUE)?;
+        Ok(request)
+    }
+
+    /// Parses the slave's answer to this request.
+    pub fn decode_response(&self, pdu: &[u8]) -> NetResult<Response> {
+        let function = self.function_code();
+        let (&got, data) = pdu
+            .split_first()
+            .ok_or_else(|| NetError::modbus("empty response".into()))?;
+        if got == function | 0x80 {
+            let code = *data
+                .first()
+                .ok_or_else(|| NetError::modbus("exception response without a code".into()))?;
+            return Ok(Response::exception(function, code));
+        }
+        if got != function {
+            return Err(NetError::modbus(format!(
+                "response is for function 0x{:02X}, not 0x{:02X}",
+                got, function
+            )));
+        }
+        let malformed =
+            || NetError::modbus(format!("malformed response to function 0x{:02X}", function));
+        let counted = || match data.split_first() {
+            Some((&n, rest)) if rest.len() == n as usize => Ok(rest),
+            _ => Err(malformed()),
+        };
+        let fixed = || match data {
+            [a, b, c, d] => Ok((u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d]))),
+            _ => Err(malformed()),
+        };
+        let registers = |count: u16| {
+            let bytes = counted()?;
+            match bytes.len() == count as usize * 2 {
+                true => Ok(words(bytes)),
+                false => Err(malformed()),
+            }
+        };
+        let bits = |count: u16| {
+            let bytes = counted()?;
+            match bytes.len() == (count as usize).div_ceil(8) {
+                true => Ok(unpack_bits(bytes, count)),
+                false => Err(malformed()),
+            }
+        };
+        Ok(match self {
+            Request::ReadCoils { count, .. } => Response::ReadCoils {
+                values: bits(*count)?,
+            },
+            Request::ReadDiscreteInputs { count, .. } => Response::ReadDiscreteInputs {
+                values: bits(*count)?,
+            },
+            Request::ReadHoldingRegisters { count, .. } => Response::ReadHoldingRegisters {
+                values: registers(*count)?,
+            },
+            Request::ReadInputRegisters { count, .. } => Response::ReadInputRegisters {
+                values: registers(*count)?,
+            },
+            Request::ReadWriteMultipleRegisters { read_count, .. } => {
+                Response::ReadWriteMultipleRegisters {
+                    values: registers(*read_count)?,
+                }
+            }
+            Request::WriteSingleCoil { .. } => {
+                let (address, value) = fixed()?;
+                Response::WriteSingleCoil {
+                    address,
+                    value: value == 0xFF00,
+                }
+            }
+            Request::WriteSingleRegister { .. } => {
+                let (address, value) = fixed()?;
+                Response::WriteSingleRegister { address, value }
+            }
+            Request::WriteMultipleCoils { .. } => {
+                let (address, count) = fixed()?;
+                Response::WriteMultipleCoils { address, count }
+            }
+            Request::WriteMultipleRegisters { .. } => {
+                let (address, count) = fixed()?;
+                Response::WriteMultipleRegisters { address, count }
+            }
+        })
+    }
+}
+
+impl Response {
+    pub fn exception(function_code: u8, code: u8) -> Response {
+        Response::Exception {
+            function_code,
+            code,
+            name: exception_name(code),
+        }
+    }
+
+    fn function_code(&self) -> u8 {
+        match self {
+            Response::ReadCoils { .. } => 0x01,
+            Response::ReadDiscreteInputs { .. } => 0x02,
+            Response::ReadHoldingRegisters { .. } => 0x03,
+            Response::ReadInputRegisters { .. } => 0x04,
+            Response::WriteSingleCoil { .. } => 0x05,
+            Response::WriteSingleRegister { .. } => 0x06,
+            Response::WriteMultipleCoils { .. } => 0x0F,
+            Response::WriteMultipleRegisters { .. } => 0x10,
+            Response::ReadWriteMultipleRegisters { .. } => 0x17,
+            Response::Exception { function_code, .. } => function_code | 0x80,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.function_code()];
+        match self {
+            Response::ReadCoils { values } | Response::ReadDiscreteInputs { values } => {
+                let bits = pack_bits(values);
+                out.push(bits.len() as u8);
+                out.extend_from_slice(&bits);
+            }
+            Response::ReadHoldingRegisters { values }
+            | Response::ReadInputRegisters { values }
+            | Response::ReadWriteMultipleRegisters { values } => {
+                out.push((values.len() * 2) as u8);
+                push_words(&mut out, values);
+            }
+            Response::WriteSingleCoil { address, value } => {
+                push_words(&mut out, &[*address, coil_word(*value)]);
+            }
+            Response::WriteSingleRegister { address, value } => {
+                push_words(&mut out, &[*address, *value]);
+            }
+            Response::WriteMultipleCoils { address, count }
+            | Response::WriteMultipleRegisters { address, count } => {
+                push_words(&mut out, &[*address, *count]);
+            }
+            Response::Exception { code, .. } => out.push(*code),
+        }
+        out
+    }
+}
+
+pub fn exception_name(code: u8) -> &'static str {
+    match code {
+        0x01 => "illegal function",
+        0x02 => "illegal data address",
+        0x03 => "illegal data value",
+        0x04 => "server device failure",
+        0x05 => "acknowledge",
+        0x06 => "server device busy",
+        0x08 => "memory parity error",
+        0x0A => "gateway path unavailable",
+        0x0B => "gateway target device failed to respond",
+        _ => "unknown exception",
+    }
+}
+
+fn coil_word(value: bool) -> u16 {
+    match value {
+        true => 0xFF00,
+        false => 0x0000,
+    }
+}
+
+fn push_words(out: &mut Vec<u8>, values: &[u16]) {
+    for v in values {
+        out.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+fn words(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .collect()
+}
+
+/// First bit in the low bit of the first byte; the last byte is zero-padded.
+fn pack_bits(values: &[bool]) -> Vec<u8> {
+    let mut out = vec![0u8; values.len().div_ceil(8)];
+    for (i, _) in values.iter().enumerate().filter(|(_, v)| **v) {
+        out[i / 8] |= 1 << (i % 8);
+    }
+    out
+}
+
+fn unpack_bits(bytes: &[u8], count: u16) -> Vec<bool> {
+    (0..count as usize)
+        .map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+        .collect()
+}
+
+/// A Modbus TCP frame: MBAP header plus PDU.
+#[derive(Clone, Debug)]
+pub struct Adu {
+    pub transaction_id: u16,
+    pub unit_id: u8,
+    pub pdu: Vec<u8>,
+}
+
+impl Adu {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MBAP_LEN + self.pdu.len());
+        push_words(
+            &mut out,
+            &[self.transaction_id, 0, self.pdu.len() as u16 + 1],
+        );
+        out.push(self.unit_id);
+        out.extend_from_slice(&self.pdu);
+        out
+    }
+
+    /// Reads one frame. A header that is not Modbus TCP is `InvalidData`: the stream cannot be
+    /// resynchronised after it.
+    pub async fn read<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Adu> {
+        let mut header = [0u8; MBAP_LEN];
+        r.read_exact(&mut header).await?;
+        let protocol = u16::from_be_bytes([header[2], header[3]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if protocol != 0 || len < 2 || len - 1 > MAX_PDU_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("not a Modbus TCP header: {:02X?}", header),
+            ));
+        }
+        let mut pdu = vec![0u8; len - 1];
+        r.read_exact(&mut pdu).await?;
+        Ok(Adu {
+            transaction_id: u16::from_be_bytes([header[0], header[1]]),
+            unit_id: header[6],
+            pdu,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Table {
+    Coils,
+    DiscreteInputs,
+    HoldingRegisters,
+    InputRegisters,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SlaveConfig {
+    /// Requests for other units get no answer; every unit is answered when empty.
+    #[serde(default)]
+    pub unit_ids: Vec<u8>,
+    /// Entries per table, from address 0; 65536 when omitted. Requests past the end get
+    /// exception 02.
+    pub coil_count: Option<u32>,
+    pub discrete_input_count: Option<u32>,
+    pub holding_register_count: Option<u32>,
+    pub input_register_count: Option<u32>,
+    /// Wait this long before answering.
+    pub delay_ms: Option<u64>,
+}
+
+/// The data model of a simulated slave; the tables can be edited while it runs.
+pub struct Slave {
+    unit_ids: Vec<u8>,
+    delay: Option<Duration>,
+    tables: Mutex<Tables>,
+}
+
+struct Tables {
+    coils: Vec<bool>,
+    discrete_inputs: Vec<bool>,
+    holding_registers: Vec<u16>,
+    input_registers: Vec<u16>,
+}
+
+impl Slave {
+    pub fn new(config: SlaveConfig) -> NetResult<Slave> {
+        let size = |n: Option<u32>| match n.unwrap_or(TABLE_LEN) {
+            n if n > TABLE_LEN => Err(NetError::modbus(format!(
+                "a table has at most {} entries, not {}",
+                TABLE_LEN, n
+            ))),
+            n => Ok(n as usize),
+        };
+        let tables = Tables {
+            coils: vec![false; size(config.coil_count)?],
+            discrete_inputs: vec![false; size(config.discrete_input_count)?],
+            holding_registers: vec![0; size(config.holding_register_count)?],
+            input_registers: vec![0; size(config.input_register_count)?],
+        };
+        Ok(Slave {
+            unit_ids: config.unit_ids,
+            delay: config.delay_ms.map(Duration::from_millis),
+            tables: Mutex::new(tables),
+        })
+    }
+
+    fn answers(&self, unit_id: u8) -> bool {
+        self.unit_ids.is_empty() || self.unit_ids.contains(&unit_id)
+    }
+
+    /// Applies one request PDU to the tables. Returns the request, when it could be decoded, and
+    /// the answer.
+    pub fn handle(&self, pdu: &[u8]) -> (Option<Request>, Response) {
+        let function = pdu.first().copied().unwrap_or_default();
+        let request = match Request::decode(pdu) {
+            Ok(r) => r,
+            Err(code) => return (None, Response::exception(function, code)),
+        };
+        let response = match self.tables.lock() {
+            Ok(mut t) => t
+                .apply(&request)
+                .unwrap_or_else(|code| Response::exception(function, code)),
+            Err(_) => Response::exception(function, SERVER_DEVICE_FAILURE),
+        };
+        (Some(request), response)
+    }
+
+    /// `count` entries from `address`; bits read as 0 or 1.
+    pub fn read(&self, table: Table, address: u16, count: u16) -> NetResult<Vec<u16>> {
+        let t = self.lock()?;
+        let out = match table {
+            Table::Coils => bits_as_words(slice(&t.coils, address, count as usize)?),
+            Table::DiscreteInputs => {
+                bits_as_words(slice(&t.discrete_inputs, address, count as usize)?)
+            }
+            Table::HoldingRegisters => {
+                slice(&t.holding_registers, address, count as usize)?.to_vec()
+            }
+            Table::InputRegisters => slice(&t.input_registers, address, count as usize)?.to_vec(),
+        };
+        Ok(out)
+    }
+
+    /// Overwrites entries from `address`; for the bit tables any non-zero value sets the bit.
+    pub fn write(&self, table: Table, address: u16, values: &[u16]) -> NetResult<()> {
+        let mut t = self.lock()?;
+        let bits: Vec<bool> = values.iter().map(|v| *v != 0).collect();
+        match table {
+            Table::Coils => slice_mut(&mut t.coils, address, bits.len())?.copy_from_slice(&bits),
+            Table::DiscreteInputs => {
+                slice_mut(&mut t.discrete_inputs, address, bits.len())?.copy_from_slice(&bits)
+            }
+            Table::HoldingRegisters => {
+                slice_mut(&mut t.holding_registers, address, values.len())?.copy_from_slice(values)
+            }
+            Table::InputRegisters => {
+                slice_mut(&mut t.input_registers, address, values.len())?.copy_from_slice(values)
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> NetResult<std::sync::MutexGuard<'_, Tables>> {
+        self.tables
+            .lock()
+            .map_err(|e| NetError::internal(format!("lock tables error: {}", e)))
+    }
+}
+
+impl Tables {
+    fn apply(&mut self, request: &Request) -> Result<Response, u8> {
+        let range = |len: usize, address: u16, n: usize| match address as usize + n <= len {
+            true => Ok(address as usize..address as usize + n),
+            false => Err(ILLEGAL_DATA_ADDRESS),
+        };
+        Ok(match request {
+            Request::ReadCoils { address, count } => Response::ReadCoils {
+                values: self.coils[range(self.coils.len(), *address, *count as usize)?].to_vec(),
+            },
+            Request::ReadDiscreteInputs { address, count } => {
+                let r = range(self.discrete_inputs.len(), *address, *count as usize)?;
+                Response::ReadDiscreteInputs {
+                    values: self.discrete_inputs[r].to_vec(),
+                }
+            }
+            Request::ReadHoldingRegisters { address, count } => {
+                let r = range(self.holding_registers.len(), *address, *count as usize)?;
+                Response::ReadHoldingRegisters {
+                    values: self.holding_registers[r].to_vec(),
+                }
+            }
+            Request::ReadInputRegisters { address, count } => {
+                let r = range(self.input_registers.len(), *address, *count as usize)?;
+                Response::ReadInputRegisters {
+                    values: self.input_registers[r].to_vec(),
+                }
+            }
+            Request::WriteSingleCoil { address, value } => {
+                let r = range(self.coils.len(), *address, 1)?;
+                self.coils[r.start] = *value;
+                Response::WriteSingleCoil {
+                    address: *address,
+                    value: *value,
+                }
+            }
+            Request::WriteSingleRegister { address, value } => {
+                let r = range(self.holding_registers.len(), *address, 1)?;
+                self.holding_registers[r.start] = *value;
+                Response::WriteSingleRegister {
+                    address: *address,
+                    value: *value,
+                }
+            }
+            Request::WriteMultipleCoils { address, values } => {
+                let r = range(self.coils.len(), *address, values.len())?;
+                self.coils[r].copy_from_slice(values);
+                Response::WriteMultipleCoils {
+                    address: *address,
+                    count: values.len() as u16,
+                }
+            }
+            Request::WriteMultipleRegisters { address, values } => {
+                let r = range(self.holding_registers.len(), *address, values.len())?;
+                self.holding_registers[r].copy_from_slice(values);
+                Response::WriteMultipleRegisters {
+                    address: *address,
+                    count: values.len() as u16,
+                }
+            }
+            Request::ReadWriteMultipleRegisters {
+                read_address,
+                read_count,
+                write_address,
+                values,
+            } => {
+                let len = self.holding_registers.len();
+                // both ranges are checked before anything is written
+                let read = range(len, *read_address, *read_count as usize)?;
+                let write = range(len, *write_address, values.len())?;
+                self.holding_registers[write].copy_from_slice(values);
+                Response::ReadWriteMultipleRegisters {
+                    values: self.holding_registers[read].to_vec(),
+                }
+            }
+        })
+    }
+}
+
+/// Reads entries of a running slave's table; bits read as 0 or 1.
+pub fn read_table(
+    session_id: String,
+    table: Table,
+    address: u16,
+    count: u16,
+) -> NetResult<Vec<u16>> {
+    session::modbus_slave(&session_id)?.read(table, address, count)
+}
+
+pub fn write_table(
+    session_id: String,
+    table: Table,
+    address: u16,
+    values: Vec<u16>,
+) -> NetResult<Updated> {
+    session::modbus_slave(&session_id)?.write(table, address, &values)?;
+    Ok(Updated {
+        message: format!(
+            "wrote {} {:?} entries from address {}",
+            values.len(),
+            table,
+            address
+        ),
+        session_id,
+    })
+}
+
+fn slice<T>(table: &[T], address: u16, n: usize) -> NetResult<&[T]> {
+    let len = table.len();
+    table
+        .get(address as usize..address as usize + n)
+        .ok_or_else(|| past_end(len, address, n))
+}
+
+fn slice_mut<T>(table: &mut [T], address: u16, n: usize) -> NetResult<&mut [T]> {
+    let len = table.len();
+    table
+        .get_mut(address as usize..address as usize + n)
+        .ok_or_else(|| past_end(len, address, n))
+}
+
+fn past_end(len: usize, address: u16, n: usize) -> NetError {
+    NetError::modbus(format!(
+        "{} entries from address {} run past the end of a {}-entry table",
+        n, address, len
+    ))
+}
+
+fn bits_as_words(bits: &[bool]) -> Vec<u16> {
+    bits.iter().map(|b| *b as u16).collect()
+}
+
+/// Answers requests on one connection until the master closes it or `cancel` fires; the caller
+/// closes the connection. Every request is logged as `modbus:server:request`, together with the
+/// answer, if any.
+pub async fn serve<R, W>(
+    slave: &Slave,
+    conn: Connection<'_>,
+    mut reader: R,
+    writer: &tokio::sync::Mutex<W>,
+    cancel: &CancellationToken,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let read = tokio::select! {
+            _ = cancel.cancelled() => return,
+            r = Adu::read(&mut reader) => r,
+        };
+        let adu = match read {
+            Ok(a) => a,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                conn.error("modbus:server:error", e.to_string());
+                return;
+            }
+            Err(_) => return,
+        };
+        let started = Instant::now();
+        let ts_ms = session::now_ms();
+        let seq = conn.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        recorder::record(
+            conn.sid,
+            Direction::Rx,
+            conn.local,
+            conn.peer,
+            seq,
+            ts_ms,
+            &adu.encode(),
+        );
+
+        let (request, response) = match slave.answers(adu.unit_id) {
+            true => {
+                let (request, response) = slave.handle(&adu.pdu);
+                (request, Some(response))
+            }
+            false => (Request::decode(&adu.pdu).ok(), None),
+        };
+        let mut written = Ok(());
+        if let Some(response) = &response {
+            if let Some(delay) = slave.delay {
+                tokio::select! {
+                    _ = cancel.cancelled() => return,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+            let reply = Adu {
+                pdu: response.encode(),
+                ..adu.clone()
+            }
+            .encode();
+            written = writer.lock().await.write_all(&reply).await;
+            recorder::record(
+                conn.sid,
+                Direction::Tx,
+                conn.local,
+                conn.peer,
+                0,
+                session::now_ms(),
+                &reply,
+            );
+        }
+        let payload = json!({
+            "session": conn.sid,
+            "bind": conn.bind,
+            "peer": conn.peer,
+            "seq": seq,
+            "ts_ms": ts_ms,
+            "transaction_id": adu.transaction_id,
+            "unit_id": adu.unit_id,
+            "function_code": adu.pdu.first(),
+            "request": request,
+            "response": response,
+            "duration_ms": started.elapsed().as_secs_f64() * 1000.0,
+        });
+        let _ = conn.app.emit("modbus:server:request", payload);
+        if written.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::ReadCoils {
+                address: 0x0013,
+                count: 19,
+            },
+            Request::ReadDiscreteInputs {
+                address: 0x00C4,
+                count: 22,
+            },
+            Request::ReadHoldingRegisters {
+                address: 0x006B,
+                count: 3,
+            },
+            Request::ReadInputRegisters {
+                address: 0x0008,
+                count: 1,
+            },
+            Request::WriteSingleCoil {
+                address: 0x00AC,
+                value: true,
+            },
+            Request::WriteSingleRegister {
+                address: 0x0001,
+                value: 0x0003,
+            },
+            Request::WriteMultipleCoils {
+                address: 0x0013,
+                values: vec![
+                    true, false, true, true, false, false, true, true, true, false,
+                ],
+            },
+            Request::WriteMultipleRegisters {
+                address: 0x0001,
+                values: vec![0x000A, 0x0102],
+            },
+            Request::ReadWriteMultipleRegisters {
+                read_address: 0x0003,
+                read_count: 6,
+                write_address: 0x000E,
+                values: vec![0x00FF, 0x00FF, 0x00FF],
+            },
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        // the examples of the Modbus application protocol specification
+        let wire: [&[u8]; 9] = [
+            &[0x01, 0x00, 0x13, 0x00, 0x13],
+            &[0x02, 0x00, 0xC4, 0x00, 0x16],
+            &[0x03, 0x00, 0x6B, 0x00, 0x03],
+            &[0x04, 0x00, 0x08, 0x00, 0x01],
+            &[0x05, 0x00, 0xAC, 0xFF, 0x00],
+            &[0x06, 0x00, 0x01, 0x00, 0x03],
+            &[0x0F, 0x00, 0x13, 0x00, 0x0A, 0x02, 0xCD, 0x01],
+            &[0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02],
+            &[
+                0x17, 0x00, 0x03, 0x00, 0x06, 0x00, 0x0E, 0x00, 0x03, 0x06, 0x00, 0xFF, 0x00, 0xFF,
+                0x00, 0xFF,
+            ],
+        ];
+        for (request, wire) in requests().iter().zip(wire) {
+            assert_eq!(request.encode(), wire, "{:?}", request);
+            assert_eq!(Request::decode(wire).as_ref(), Ok(request));
+            assert!(request.validate().is_ok());
+        }
+        let off = Request::WriteSingleCoil {
+            address: 1,
+            value: false,
+        };
+        assert_eq!(off.encode(), [0x05, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(Request::decode(&off.encode()), Ok(off));
+    }
+
+    #[test]
+    fn decode_exceptions() {
+        let cases: [(&[u8], u8); 16] = [
+            (&[], ILLEGAL_FUNCTION),
+            (&[0x07], ILLEGAL_FUNCTION),
+            (&[0x2B, 0x0E, 0x01, 0x00], ILLEGAL_FUNCTION),
+            // too short
+            (&[0x03, 0x00, 0x00, 0x00], ILLEGAL_DATA_VALUE),
+            // a coil is written with FF00 or 0000 only
+            (&[0x05, 0x00, 0x01, 0x12, 0x34], ILLEGAL_DATA_VALUE),
+            // quantities of 0 and past the limits
+            (&[0x01, 0x00, 0x00, 0x00, 0x00], ILLEGAL_DATA_VALUE),
+            (&[0x01, 0x00, 0x00, 0x07, 0xD1], ILLEGAL_DATA_VALUE),
+            (&[0x03, 0x00, 0x00, 0x00, 0x7E], ILLEGAL_DATA_VALUE),
+            (&[0x0F, 0x00, 0x00, 0x00, 0x00, 0x00], ILLEGAL_DATA_VALUE),
+            // byte count does not match the bytes that follow
+            (
+                &[0x0F, 0x00, 0x00, 0x00, 0x08, 0x02, 0xFF],
+                ILLEGAL_DATA_VALUE,
+            ),
+            // byte count does not match the quantity
+            (
+                &[0x0F, 0x00, 0x00, 0x00, 0x09, 0x01, 0xFF],
+                ILLEGAL_DATA_VALUE,
+            ),
+            (
+                &[0x10, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x01],
+                ILLEGAL_DATA_VALUE,
+            ),
+            (
+                &[0x10, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x01, 0x00],
+                ILLEGAL_DATA_VALUE,
+            ),
+            (
+                &[
+                    0x17, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x01,
+                ],
+                ILLEGAL_DATA_VALUE,
+            ),
+            // read count of 0
+            (
+                &[
+                    0x17, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x01,
+                ],
+                ILLEGAL_DATA_VALUE,
+            ),
+            // 124 registers: more than fit beside a read in one PDU
+            (
+                &[0x10, 0x00, 0x00, 0x00, 0x7C, 0xF8, 0x00, 0x00],
+                ILLEGAL_DATA_VALUE,
+            ),
+        ];
+        for (pdu, code) in cases {
+            assert_eq!(Request::decode(pdu), Err(code), "{:02X?}", pdu);
+        }
+    }
+
+    #[test]
+    fn validate_ranges() {
+        let last = Request::ReadHoldingRegisters {
+            address: 0xFFFF,
+            count: 1,
+        };
+        assert!(last.validate().is_ok());
+        let past = Request::ReadHoldingRegisters {
+            address: 0xFFFF,
+            count: 2,
+        };
+        assert!(past.validate().is_err());
+        let write = Request::ReadWriteMultipleRegisters {
+            read_address: 0,
+            read_count: 1,
+            write_address: 0xFFFE,
+            values: vec![1, 2, 3],
+        };
+        assert!(write.validate().is_err());
+    }
+
+    #[test]
+    fn responses() {
+        let read = Request::ReadCoils {
+            address: 0x0013,
+            count: 19,
+        };
+        let values = read
+            .decode_response(&[0x01, 0x03, 0xCD, 0x6B, 0x05])
+            .unwrap();
+        let Response::ReadCoils { values } = values else {
+            panic!("wrong response");
+        };
+        assert_eq!(values.len(), 19);
+        assert_eq!(
+            Response::ReadCoils { values }.encode(),
+            [0x01, 0x03, 0xCD, 0x6B, 0x05]
+        );
+
+        let registers = Request::ReadHoldingRegisters {
+            address: 0x006B,
+            count: 3,
+        };
+        assert_eq!(
+            registers
+                .decode_response(&[0x03, 0x06, 0x02, 0x2B, 0x00, 0x00, 0x00, 0x64])
+                .unwrap(),
+            Response::ReadHoldingRegisters {
+                values: vec![0x022B, 0, 0x64]
+            }
+        );
+        // the slave answered with fewer registers than asked for
+        assert!(registers
+            .decode_response(&[0x03, 0x04, 0x02, 0x2B, 0x00, 0x00])
+            .is_err());
+        assert!(registers
+            .decode_response(&[0x03, 0x06, 0x02, 0x2B])
+            .is_err());
+
+        assert_eq!(
+            registers.decode_response(&[0x83, 0x02]).unwrap(),
+            Response::Exception {
+                function_code: 0x03,
+                code: ILLEGAL_DATA_ADDRESS,
+                name: "illegal data address"
+            }
+        );
+        assert_eq!(
+            Response::exception(0x03, ILLEGAL_DATA_ADDRESS).encode(),
+            [0x83, 0x02]
+        );
+        assert!(registers.decode_response(&[0x83]).is_err());
+        assert!(registers.decode_response(&[]).is_err());
+        // an answer to another function, or its exception
+        assert!(registers
+            .decode_response(&[0x04, 0x02, 0x00, 0x01])
+            .is_err());
+        assert!(registers.decode_response(&[0x84, 0x02]).is_err());
+
+        let coil = Request::WriteSingleCoil {
+            address: 0x00AC,
+            value: true,
+        };
+        let echo = coil.decode_response(&coil.encode()).unwrap();
+        assert_eq!(
+            echo,
+            Response::WriteSingleCoil {
+                address: 0x00AC,
+                value: true
+            }
+        );
+        assert_eq!(echo.encode(), coil.encode());
+        assert!(coil.decode_response(&[0x05, 0x00, 0xAC, 0xFF]).is_err());
+    }
+
+    fn tables(len: usize) -> Tables {
+        Tables {
+            coils: vec![false; len],
+            discrete_inputs: vec![true; len],
+            holding_registers: (0..len as u16).collect(),
+            input_registers: vec![7; len],
+        }
+    }
+
+    #[test]
+    fn apply_at_the_table_end() {
+        let mut t = tables(10);
+        let ok = [
+            Request::ReadCoils {
+                address: 9,
+                count: 1,
+            },
+            Request::ReadDiscreteInputs {
+                address: 0,
+                count: 10,
+            },
+            Request::ReadInputRegisters {
+                address: 5,
+                count: 5,
+            },
+            Request::WriteSingleCoil {
+                address: 9,
+                value: true,
+            },
+            Request::WriteMultipleRegisters {
+                address: 8,
+                values: vec![80, 90],
+            },
+        ];
+        for request in &ok {
+            assert!(t.apply(request).is_ok(), "{:?}", request);
+        }
+        assert!(t.coils[9]);
+        assert_eq!(t.holding_registers[8..], [80, 90]);
+
+        let past = [
+            Request::ReadCoils {
+                address: 9,
+                count: 2,
+            },
+            Request::ReadDiscreteInputs {
+                address: 10,
+                count: 1,
+            },
+            Request::ReadHoldingRegisters {
+                address: 0xFFFF,
+                count: 1,
+            },
+            Request::ReadInputRegisters {
+                address: 6,
+                count: 5,
+            },
+            Request::WriteSingleCoil {
+                address: 10,
+                value: true,
+            },
+            Request::WriteSingleRegister {
+                address: 10,
+                value: 1,
+            },
+            Request::WriteMultipleCoils {
+                address: 9,
+                values: vec![true, true],
+            },
+            Request::WriteMultipleRegisters {
+                address: 9,
+                values: vec![1, 2],
+            },
+            // the read is out of range, so the write must not happen
+            Request::ReadWriteMultipleRegisters {
+                read_address: 9,
+                read_count: 2,
+                write_address: 0,
+                values: vec![100],
+            },
+            Request::ReadWriteMultipleRegisters {
+                read_address: 0,
+                read_count: 1,
+                write_address: 10,
+                values: vec![100],
+            },
+        ];
+        for request in &past {
+            assert_eq!(t.apply(request), Err(ILLEGAL_DATA_ADDRESS), "{:?}", request);
+        }
+        assert_eq!(t.holding_registers[0], 0);
+        assert_eq!(t.holding_registers[9], 90);
+        assert!(!t.coils[8]);
+
+        // writes happen before the read
+        let rw = Request::ReadWriteMultipleRegisters {
+            read_address: 1,
+            read_count: 2,
+            write_address: 2,
+            values: vec![200],
+        };
+        assert_eq!(
+            t.apply(&rw),
+            Ok(Response::ReadWriteMultipleRegisters {
+                values: vec![1, 200]
+            })
+        );
+    }
+
+    #[test]
+    fn slave_handles_pdus() {
+        let slave = Slave::new(SlaveConfig {
+            holding_register_count: Some(4),
+            ..Default::default()
+        })
+        .unwrap();
+        let (request, response) = slave.handle(&[0x06, 0x00, 0x03, 0x12, 0x34]);
+        assert!(request.is_some());
+        assert_eq!(response.encode(), [0x06, 0x00, 0x03, 0x12, 0x34]);
+        assert_eq!(
+            slave.read(Table::HoldingRegisters, 0, 4).unwrap(),
+            [0, 0, 0, 0x1234]
+        );
+        let (_, response) = slave.handle(&[0x03, 0x00, 0x03, 0x00, 0x02]);
+        assert_eq!(response.encode(), [0x83, ILLEGAL_DATA_ADDRESS]);
+        let (request, response) = slave.handle(&[0x41]);
+        assert!(request.is_none());
+        assert_eq!(response.encode(), [0xC1, ILLEGAL_FUNCTION]);
+        assert!(Slave::new(SlaveConfig {
+            coil_count: Some(TABLE_LEN + 1),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn adu_framing() {
+        let adu = Adu {
+            transaction_id: 0x1234,
+            unit_id: 0x11,
+            pdu: vec![0x03, 0x00, 0x6B, 0x00, 0x03],
+        };
+        let wire = adu.encode();
+        assert_eq!(
+            wire,
+            [0x12, 0x34, 0x00, 0x00, 0x00, 0x06, 0x11, 0x03, 0x00, 0x6B, 0x00, 0x03]
+        );
+        let mut two = wire.clone();
+        two.extend_from_slice(&wire);
+        let mut r = &two[..];
+        for _ in 0..2 {
+            let read = Adu::read(&mut r).await.unwrap();
+            assert_eq!(
+                (read.transaction_id, read.unit_id, read.pdu),
+                (0x1234, 0x11, adu.pdu.clone())
+            );
+        }
+        assert_eq!(
+            Adu::read(&mut r).await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let bad: [&[u8]; 4] = [
+            // protocol id 1
+            &[0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0x01, 0x03],
+            // a length of 1 leaves no room for a function code
+            &[0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01],
+            &[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01],
+            // a PDU over 253 bytes
+            &[0x00, 0x01, 0x00, 0x00, 0x00, 0xFF, 0x01],
+        ];
+        for mut wire in bad {
+            let e = Adu::read(&mut wire).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:02X?}", wire);
+        }
+        let mut truncated = &wire[..9];
+        assert_eq!(
+            Adu::read(&mut truncated).await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
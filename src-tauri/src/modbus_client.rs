@@ -0,0 +1,349 @@
+use serde::Serialize;
+use serde_json::json;
+use socket2::Socket;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::async_runtime::{self, JoinHandle};
+use tauri::{AppHandle, Emitter};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::modbus::{Adu, Request, Response};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Session, SessionHandle, SessionKind, Started, Stopped};
+use crate::sockopt::SocketOptions;
+use crate::tcp_client::{self, DEFAULT_CONNECT_TIMEOUT_MS};
+use crate::tls::{self, BoxedStream, TlsClientConfig};
+
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_UNIT_ID: u8 = 1;
+
+/// State shared between the session handle, the read task and the request commands.
+struct Shared {
+    app: AppHandle,
+    writer: tokio::sync::Mutex<WriteHalf<BoxedStream>>,
+    /// Requests waiting for their answer, by transaction id.
+    pending: Mutex<HashMap<u16, oneshot::Sender<Adu>>>,
+    next_transaction: AtomicU16,
+    /// Duplicate of the connected socket for the options commands.
+    sock: Mutex<Option<Socket>>,
+    local: String,
+    unit_id: u8,
+    timeout: Duration,
+}
+
+pub struct ClientHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+
+    pub fn sockets(&self) -> Vec<(String, Socket)> {
+        let sock = match self.shared.sock.lock() {
+            Ok(s) => s.as_ref().and_then(|s| s.try_clone().ok()),
+            Err(_) => None,
+        };
+        let label = sock
+            .as_ref()
+            .and_then(|s| s.peer_addr().ok())
+            .and_then(|a| a.as_socket())
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        sock.map(|s| vec![(label, s)]).unwrap_or_default()
+    }
+}
+
+/// One answered request, as returned by `modbus_request`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Transaction {
+    pub session_id: String,
+    pub transaction_id: u16,
+    pub unit_id: u8,
+    pub request: Request,
+    pub response: Response,
+    pub rtt_ms: f64,
+}
+
+/// `unit_id` and `timeout_ms` are the defaults for requests that don't set their own. A dropped
+/// connection ends the session.
+pub async fn start(
+    app: AppHandle,
+    remote_addr: String,
+    unit_id: Option<u8>,
+    timeout_ms: Option<u64>,
+    connect_timeout_ms: Option<u64>,
+    tls: Option<TlsClientConfig>,
+    socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+    if session::registry()?.contains(SessionKind::ModbusClient, &remote_addr) {
+        return Err(NetError::already_running(
+            SessionKind::ModbusClient,
+            &remote_addr,
+        ));
+    }
+    let tls = tls.map(|cfg| tls::client(&cfg, &remote_addr)).transpose()?;
+    let (stream, sock, local, tls_info) = tcp_client::connect(
+        &remote_addr,
+        connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS),
+        tls.as_ref(),
+        &socket_options.unwrap_or_default(),
+        &CancellationToken::new(),
+    )
+    .await?;
+
+    let (reader, writer) = tokio::io::split(stream);
+    let shared = Arc::new(Shared {
+        app: app.clone(),
+        writer: tokio::sync::Mutex::new(writer),
+        pending: Mutex::new(HashMap::new()),
+        next_transaction: AtomicU16::new(1),
+        sock: Mutex::new(sock),
+        local: local.clone(),
+        unit_id: unit_id.unwrap_or(DEFAULT_UNIT_ID),
+        timeout: Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+    });
+
+    let cancel = CancellationToken::new();
+    let id = session::next_id();
+    let (registered_tx, registered_rx) = oneshot::channel::<()>();
+    let task = async_runtime::spawn(run(
+        id.clone(),
+        remote_addr.clone(),
+        reader,
+        shared.clone(),
+        cancel.clone(),
+        registered_rx,
+    ));
+    let handle = ClientHandle {
+        cancel,
+        task,
+        shared,
+    };
+    let rejected = session::registry()?.insert(
+        id.clone(),
+        SessionKind::ModbusClient,
+        remote_addr.clone(),
+        SessionHandle::ModbusClient(handle),
+    );
+    if let Err(h) = rejected {
+        h.stop().await;
+        return Err(NetError::already_running(
+            SessionKind::ModbusClient,
+            &remote_addr,
+        ));
+    }
+    let payload = json!({"session": id, "remote": remote_addr, "local": local, "tls": tls_info});
+    let _ = app.emit("modbus:client:connected", payload);
+    let _ = registered_tx.send(());
+
+    Ok(Started {
+        session_id: id,
+        kind: SessionKind::ModbusClient,
+        message: format!("Modbus TCP client connected to {}", remote_addr),
+        addr: remote_addr,
+    })
+}
+
+/// Hands every frame to the request waiting for its transaction id.
+async fn run(
+    sid: String,
+    addr: String,
+    mut reader: ReadHalf<BoxedStream>,
+    shared: Arc<Shared>,
+    cancel: CancellationToken,
+    registered: oneshot::Receiver<()>,
+) {
+    // the session must be in the registry before this task can remove it
+    if registered.await.is_err() {
+        return;
+    }
+    let reason = loop {
+        let read = tokio::select! {
+            _ = cancel.cancelled() => {
+                let _ = shared.writer.lock().await.shutdown().await;
+                return;
+            }
+            r = Adu::read(&mut reader) => r,
+        };
+        let adu = match read {
+            Ok(a) => a,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                break "connection closed".to_string()
+            }
+            Err(e) => break e.to_string(),
+        };
+        recorder::record(
+            &sid,
+            Direction::Rx,
+            &shared.local,
+            &addr,
+            0,
+            session::now_ms(),
+            &adu.encode(),
+        );
+        let waiter = match shared.pending.lock() {
+            Ok(mut p) => p.remove(&adu.transaction_id),
+            Err(_) => None,
+        };
+        match waiter {
+            Some(tx) => {
+                let _ = tx.send(adu);
+            }
+            None => {
+                let error = format!(
+                    "response with unknown transaction id {} (late or unsolicited)",
+                    adu.transaction_id
+                );
+                let payload = json!({"session": sid, "remote": addr, "error": error});
+                let _ = shared.app.emit("modbus:client:error", payload);
+            }
+        }
+    };
+
+    let _ = shared.writer.lock().await.shutdown().await;
+    // waiting requests fail as soon as their sender is dropped
+    if let Ok(mut p) = shared.pending.lock() {
+        p.clear();
+    }
+    let payload = json!({"session": sid, "remote": addr, "reason": reason});
+    let _ = shared.app.emit("modbus:client:closed", payload);
+    // nothing will revive this connection, so don't leave a dead session behind
+    session::drop_dead(&sid);
+}
+
+pub async fn stop(remote_addr: Option<String>) -> NetResult<Stopped> {
+    if let Some(a) = remote_addr {
+        let removed = session::registry()?.remove(SessionKind::ModbusClient, &a);
+        let s = removed.ok_or_else(|| NetError::not_running(SessionKind::ModbusClient, &a))?;
+        let info = s.stop().await;
+        Ok(Stopped {
+            session_ids: vec![info.id],
+            message: format!("Modbus TCP client disconnected from {}", a),
+        })
+    } else {
+        let previous = session::registry()?.remove_kind(SessionKind::ModbusClient);
+        Ok(Stopped {
+            session_ids: session::stop_all(previous).await,
+            message: "All Modbus TCP clients disconnected".into(),
+        })
+    }
+}
+
+/// Sends one request and waits for its answer; an exception answer is a successful transaction
+/// whose response is `exception`.
+pub async fn request(
+    remote_addr: String,
+    request: Request,
+    unit_id: Option<u8>,
+    timeout_ms: Option<u64>,
+) -> NetResult<Transaction> {
+    request.validate()?;
+    let (sid, shared) = running(&remote_addr)?;
+    let unit_id = unit_id.unwrap_or(shared.unit_id);
+    let timeout = timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(shared.timeout);
+    let transaction_id = shared.next_transaction.fetch_add(1, Ordering::Relaxed);
+    let adu = Adu {
+        transaction_id,
+        unit_id,
+        pdu: request.encode(),
+    }
+    .encode();
+
+    let (tx, rx) = oneshot::channel();
+    shared
+        .pending
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock pending error: {}", e)))?
+        .insert(transaction_id, tx);
+    let forget = || {
+        if let Ok(mut p) = shared.pending.lock() {
+            p.remove(&transaction_id);
+        }
+    };
+    let sent = Instant::now();
+    if let Err(e) = shared.writer.lock().await.write_all(&adu).await {
+        forget();
+        return Err(NetError::io("send", &remote_addr, e));
+    }
+    recorder::record(
+        &sid,
+        Direction::Tx,
+        &shared.local,
+        &remote_addr,
+        0,
+        session::now_ms(),
+        &adu,
+    );
+
+    let answer = match tokio::time::timeout(timeout, rx).await {
+        Ok(Ok(a)) => a,
+        Ok(Err(_)) => {
+            return Err(NetError::io(
+                "modbus request",
+                &remote_addr,
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed before the response",
+                ),
+            ))
+        }
+        Err(_) => {
+            forget();
+            return Err(NetError::io(
+                "modbus request",
+                &remote_addr,
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no response within {} ms", timeout.as_millis()),
+                ),
+            ));
+        }
+    };
+    let rtt_ms = sent.elapsed().as_secs_f64() * 1000.0;
+    let response = request.decode_response(&answer.pdu)?;
+    let transaction = Transaction {
+        session_id: sid,
+        transaction_id,
+        unit_id: answer.unit_id,
+        request,
+        response,
+        rtt_ms,
+    };
+    let payload = json!({
+        "session": transaction.session_id,
+        "remote": remote_addr,
+        "ts_ms": session::now_ms(),
+        "transaction_id": transaction_id,
+        "unit_id": transaction.unit_id,
+        "request": transaction.request,
+        "response": transaction.response,
+        "rtt_ms": rtt_ms,
+    });
+    let _ = shared.app.emit("modbus:client:response", payload);
+    Ok(transaction)
+}
+
+fn running(addr: &str) -> NetResult<(String, Arc<Shared>)> {
+    let reg = session::registry()?;
+    match reg.get(SessionKind::ModbusClient, addr) {
+        Some(Session {
+            info,
+            handle: SessionHandle::ModbusClient(h),
+        }) => Ok((info.id.clone(), h.shared.clone())),
+        _ => Err(NetError::not_running(SessionKind::ModbusClient, addr)),
+    }
+}
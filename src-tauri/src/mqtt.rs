@@ -0,0 +1,1383 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{NetError, NetResult};
+
+/// The most a four-byte variable byte integer can hold, and so the longest packet body.
+pub const MAX_VARINT: u32 = 268_435_455;
+/// Incoming packets larger than this end the connection.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 16 << 20;
+
+/// Protocol level sent in CONNECT; serialized as `"3.1.1"` or `"5"`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Version {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+impl Version {
+    fn level(self) -> u8 {
+        match self {
+            Version::V311 => 4,
+            Version::V5 => 5,
+        }
+    }
+}
+
+/// MQTT 5 properties; every field is optional and only the ones that apply to a packet are
+/// sent. Ignored on MQTT 3.1.1 connections.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Properties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_format_indicator: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_expiry_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_data_b64: Option<String>,
+    /// Set by the broker on PUBLISH, one per matching subscription that had an identifier.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subscription_identifiers: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_expiry_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assigned_client_identifier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_keep_alive: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authentication_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authentication_data_b64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_problem_information: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub will_delay_interval: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_response_information: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_information: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason_string: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receive_maximum: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic_alias_maximum: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic_alias: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum_qos: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retain_available: Option<u8>,
+    /// Name/value pairs in the order they were sent; names may repeat.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub user_properties: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum_packet_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wildcard_subscription_available: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_identifier_available: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_subscription_available: Option<u8>,
+}
+
+impl Properties {
+    fn encode(&self, out: &mut Vec<u8>) -> NetResult<()> {
+        let mut p = Vec::new();
+        byte_prop(&mut p, 0x01, self.payload_format_indicator);
+        u32_prop(&mut p, 0x02, self.message_expiry_interval);
+        str_prop(&mut p, 0x03, &self.content_type)?;
+        str_prop(&mut p, 0x08, &self.response_topic)?;
+        bin_prop(&mut p, 0x09, &self.correlation_data_b64)?;
+        for id in &self.subscription_identifiers {
+            p.push(0x0B);
+            put_varint(&mut p, *id)?;
+        }
+        u32_prop(&mut p, 0x11, self.session_expiry_interval);
+        str_prop(&mut p, 0x12, &self.assigned_client_identifier)?;
+        u16_prop(&mut p, 0x13, self.server_keep_alive);
+        str_prop(&mut p, 0x15, &self.authentication_method)?;
+        bin_prop(&mut p, 0x16, &self.authentication_data_b64)?;
+        byte_prop(&mut p, 0x17, self.request_problem_information);
+        u32_prop(&mut p, 0x18, self.will_delay_interval);
+        byte_prop(&mut p, 0x19, self.request_response_information);
+        str_prop(&mut p, 0x1A, &self.response_information)?;
+        str_prop(&mut p, 0x1C, &self.server_reference)?;
+        str_prop(&mut p, 0x1F, &self.reason_string)?;
+        u16_prop(&mut p, 0x21, self.receive_maximum);
+        u16_prop(&mut p, 0x22, self.topic_alias_maximum);
+        u16_prop(&mut p, 0x23, self.topic_alias);
+        byte_prop(&mut p, 0x24, self.maximum_qos);
+        byte_prop(&mut p, 0x25, self.retain_available);
+        for (name, value) in &self.user_properties {
+            p.push(0x26);
+            put_str(&mut p, name)?;
+            put_str(&mut p, value)?;
+        }
+        u32_prop(&mut p, 0x27, self.maximum_packet_size);
+        byte_prop(&mut p, 0x28, self.wildcard_subscription_available);
+        byte_prop(&mut p, 0x29, self.subscription_identifier_available);
+        byte_prop(&mut p, 0x2A, self.shared_subscription_available);
+        put_varint(out, p.len() as u32)?;
+        out.extend_from_slice(&p);
+        Ok(())
+    }
+
+    fn decode(input: &mut Input<'_>) -> NetResult<Self> {
+        let len = input.varint()? as usize;
+        let mut p = Input::new(input.take(len)?);
+        let mut props = Properties::default();
+        while !p.is_empty() {
+            match p.varint()? {
+                0x01 => props.payload_format_indicator = Some(p.u8()?),
+                0x02 => props.message_expiry_interval = Some(p.u32()?),
+                0x03 => props.content_type = Some(p.string()?),
+                0x08 => props.response_topic = Some(p.string()?),
+                0x09 => props.correlation_data_b64 = Some(b64(p.binary()?)),
+                0x0B => props.subscription_identifiers.push(p.varint()?),
+                0x11 => props.session_expiry_interval = Some(p.u32()?),
+                0x12 => props.assigned_client_identifier = Some(p.string()?),
+                0x13 => props.server_keep_alive = Some(p.u16()?),
+                0x15 => props.authentication_method = Some(p.string()?),
+                0x16 => props.authentication_data_b64 = Some(b64(p.binary()?)),
+                0x17 => props.request_problem_information = Some(p.u8()?),
+                0x18 => props.will_delay_interval = Some(p.u32()?),
+                0x19 => props.request_response_information = Some(p.u8()?),
+                0x1A => props.response_information = Some(p.string()?),
+                0x1C => props.server_reference = Some(p.string()?),
+                0x1F => props.reason_string = Some(p.string()?),
+                0x21 => props.receive_maximum = Some(p.u16()?),
+                0x22 => props.topic_alias_maximum = Some(p.u16()?),
+                0x23 => props.topic_alias = Some(p.u16()?),
+                0x24 => props.maximum_qos = Some(p.u8()?),
+                0x25 => props.retain_available = Some(p.u8()?),
+                0x26 => props.user_properties.push((p.string()?, p.string()?)),
+                0x27 => props.maximum_packet_size = Some(p.u32()?),
+                0x28 => props.wildcard_subscription_available = Some(p.u8()?),
+                0x29 => props.subscription_identifier_available = Some(p.u8()?),
+                0x2A => props.shared_subscription_available = Some(p.u8()?),
+                other => return Err(malformed(format!("unknown property 0x{:02X}", other))),
+            }
+        }
+        Ok(props)
+    }
+}
+
+fn byte_prop(p: &mut Vec<u8>, id: u8, value: Option<u8>) {
+    if let Some(v) = value {
+        p.push(id);
+        p.push(v);
+    }
+}
+
+fn u16_prop(p: &mut Vec<u8>, id: u8, value: Option<u16>) {
+    if let Some(v) = value {
+        p.push(id);
+        p.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+fn u32_prop(p: &mut Vec<u8>, id: u8, value: Option<u32>) {
+    if let Some(v) = value {
+        p.push(id);
+        p.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+fn str_prop(p: &mut Vec<u8>, id: u8, value: &Option<String>) -> NetResult<()> {
+    if let Some(v) = value {
+        p.push(id);
+        put_str(p, v)?;
+    }
+    Ok(())
+}
+
+fn bin_prop(p: &mut Vec<u8>, id: u8, value_b64: &Option<String>) -> NetResult<()> {
+    if let Some(v) = value_b64 {
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(v)
+            .map_err(NetError::decode)?;
+        p.push(id);
+        put_binary(p, &data)?;
+    }
+    Ok(())
+}
+
+/// The message a broker publishes on the client's behalf if it disappears without DISCONNECT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub properties: Properties,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connect {
+    pub version: Version,
+    pub client_id: String,
+    /// Clean session in 3.1.1, clean start in 5.
+    pub clean_start: bool,
+    pub keep_alive: u16,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    pub will: Option<Will>,
+    pub properties: Properties,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    /// Only for QoS 1 and 2.
+    pub packet_id: Option<u16>,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    pub payload: Vec<u8>,
+    pub properties: Properties,
+}
+
+/// One SUBSCRIBE entry; the options after `qos` only exist in MQTT 5.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscription {
+    pub filter: String,
+    #[serde(default)]
+    pub qos: u8,
+    /// Don't deliver the client's own publishes back to it.
+    #[serde(default)]
+    pub no_local: bool,
+    #[serde(default)]
+    pub retain_as_published: bool,
+    /// 0: send retained messages on subscribe, 1: only for a new subscription, 2: never.
+    #[serde(default)]
+    pub retain_handling: u8,
+}
+
+/// The four packets that acknowledge a QoS 1 or 2 publish; they share one layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AckKind {
+    Puback,
+    Pubrec,
+    Pubrel,
+    Pubcomp,
+}
+
+impl AckKind {
+    fn packet_type(self) -> u8 {
+        match self {
+            AckKind::Puback => 4,
+            AckKind::Pubrec => 5,
+            AckKind::Pubrel => 6,
+            AckKind::Pubcomp => 7,
+        }
+    }
+}
+
+/// A control packet; `decode` and `encode` cover both directions so the same codec can play
+/// either side.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    Connect(Box<Connect>),
+    ConnAck {
+        session_present: bool,
+        reason_code: u8,
+        properties: Properties,
+    },
+    Publish(Publish),
+    Ack {
+        kind: AckKind,
+        packet_id: u16,
+        reason_code: u8,
+        properties: Properties,
+    },
+    Subscribe {
+        packet_id: u16,
+        subscriptions: Vec<Subscription>,
+        properties: Properties,
+    },
+    SubAck {
+        packet_id: u16,
+        reason_codes: Vec<u8>,
+        properties: Properties,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+        properties: Properties,
+    },
+    /// `reason_codes` is empty on MQTT 3.1.1, where UNSUBACK carries none.
+    UnsubAck {
+        packet_id: u16,
+        reason_codes: Vec<u8>,
+        properties: Properties,
+    },
+    PingReq,
+    PingResp,
+    Disconnect {
+        reason_code: u8,
+        properties: Properties,
+    },
+    Auth {
+        reason_code: u8,
+        properties: Properties,
+    },
+}
+
+impl Packet {
+    pub fn ack(kind: AckKind, packet_id: u16) -> Self {
+        Packet::Ack {
+            kind,
+            packet_id,
+            reason_code: 0,
+            properties: Properties::default(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Packet::Connect(_) => "CONNECT",
+            Packet::ConnAck { .. } => "CONNACK",
+            Packet::Publish(_) => "PUBLISH",
+            Packet::Ack { kind, .. } => match kind {
+                AckKind::Puback => "PUBACK",
+                AckKind::Pubrec => "PUBREC",
+                AckKind::Pubrel => "PUBREL",
+                AckKind::Pubcomp => "PUBCOMP",
+            },
+            Packet::Subscribe { .. } => "SUBSCRIBE",
+            Packet::SubAck { .. } => "SUBACK",
+            Packet::Unsubscribe { .. } => "UNSUBSCRIBE",
+            Packet::UnsubAck { .. } => "UNSUBACK",
+            Packet::PingReq => "PINGREQ",
+            Packet::PingResp => "PINGRESP",
+            Packet::Disconnect { .. } => "DISCONNECT",
+            Packet::Auth { .. } => "AUTH",
+        }
+    }
+
+    /// The whole packet, fixed header included. Fails on fields that don't fit their length
+    /// prefix and on undecodable base64 properties.
+    pub fn encode(&self, version: Version) -> NetResult<Vec<u8>> {
+        let v5 = version == Version::V5;
+        let mut body = Vec::new();
+        let first = match self {
+            Packet::Connect(c) => {
+                put_str(&mut body, "MQTT")?;
+                body.push(c.version.level());
+                let mut flags = 0u8;
+                if c.username.is_some() {
+                    flags |= 0x80;
+                }
+                if c.password.is_some() {
+                    flags |= 0x40;
+                }
+                if let Some(w) = &c.will {
+                    flags |= 0x04 | (w.qos << 3);
+                    if w.retain {
+                        flags |= 0x20;
+                    }
+                }
+                if c.clean_start {
+                    flags |= 0x02;
+                }
+                body.push(flags);
+                body.extend_from_slice(&c.keep_alive.to_be_bytes());
+                if c.version == Version::V5 {
+                    c.properties.encode(&mut body)?;
+                }
+                put_str(&mut body, &c.client_id)?;
+                if let Some(w) = &c.will {
+                    if c.version == Version::V5 {
+                        w.properties.encode(&mut body)?;
+                    }
+                    put_str(&mut body, &w.topic)?;
+                    put_binary(&mut body, &w.payload)?;
+                }
+                if let Some(u) = &c.username {
+                    put_str(&mut body, u)?;
+                }
+                if let Some(p) = &c.password {
+                    put_binary(&mut body, p)?;
+                }
+                0x10
+            }
+            Packet::ConnAck {
+                session_present,
+                reason_code,
+                properties,
+            } => {
+                body.push(*session_present as u8);
+                body.push(*reason_code);
+                if v5 {
+                    properties.encode(&mut body)?;
+                }
+                0x20
+            }
+            Packet::Publish(p) => {
+                put_str(&mut body, &p.topic)?;
+                if p.qos > 0 {
+                    body.extend_from_slice(&p.packet_id.unwrap_or(0).to_be_bytes());
+                }
+                if v5 {
+                    p.properties.encode(&mut body)?;
+                }
+                body.extend_from_slice(&p.payload);
+                0x30 | (p.dup as u8) << 3 | p.qos << 1 | p.retain as u8
+            }
+            Packet::Ack {
+                kind,
+                packet_id,
+                reason_code,
+                properties,
+            } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                // the short forms mean success without properties
+                if v5 && (*reason_code != 0 || *properties != Properties::default()) {
+                    body.push(*reason_code);
+                    properties.encode(&mut body)?;
+                }
+                let flags = if *kind == AckKind::Pubrel { 0x02 } else { 0 };
+                kind.packet_type() << 4 | flags
+            }
+            Packet::Subscribe {
+                packet_id,
+                subscriptions,
+                properties,
+            } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                if v5 {
+                    properties.encode(&mut body)?;
+                }
+                for s in subscriptions {
+                    put_str(&mut body, &s.filter)?;
+                    let mut options = s.qos;
+                    if v5 {
+                        options |= (s.no_local as u8) << 2
+                            | (s.retain_as_published as u8) << 3
+                            | s.retain_handling << 4;
+                    }
+                    body.push(options);
+                }
+                0x82
+            }
+            Packet::SubAck {
+                packet_id,
+                reason_codes,
+                properties,
+            }
+            | Packet::UnsubAck {
+                packet_id,
+                reason_codes,
+                properties,
+            } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                let sub = matches!(self, Packet::SubAck { .. });
+                if v5 {
+                    properties.encode(&mut body)?;
+                }
+                if v5 || sub {
+                    body.extend_from_slice(reason_codes);
+                }
+                if sub {
+                    0x90
+                } else {
+                    0xB0
+                }
+            }
+            Packet::Unsubscribe {
+                packet_id,
+                filters,
+                properties,
+            } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                if v5 {
+                    properties.encode(&mut body)?;
+                }
+                for f in filters {
+                    put_str(&mut body, f)?;
+                }
+                0xA2
+            }
+            Packet::PingReq => 0xC0,
+            Packet::PingResp => 0xD0,
+            Packet::Disconnect {
+                reason_code,
+                properties,
+            }
+            | Packet::Auth {
+                reason_code,
+                properties,
+            } => {
+                if v5 && (*reason_code != 0 || *properties != Properties::default()) {
+                    body.push(*reason_code);
+                    properties.encode(&mut body)?;
+                }
+                if matches!(self, Packet::Disconnect { .. }) {
+                    0xE0
+                } else {
+                    0xF0
+                }
+            }
+        };
+        let mut out = Vec::with_capacity(body.len() + 5);
+        out.push(first);
+        put_varint(&mut out, body.len() as u32)?;
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    /// Parses one whole packet as cut by [`Decoder::next_frame`]. CONNECT carries its own
+    /// version; everything else is read as `version`.
+    pub fn decode(frame: &[u8], version: Version) -> NetResult<Self> {
+        let mut input = Input::new(frame);
+        let first = input.u8()?;
+        let len = input.varint()? as usize;
+        let mut b = Input::new(input.take(len)?);
+        let (kind, flags) = (first >> 4, first & 0x0F);
+        let v5 = version == Version::V5;
+        let expected_flags = match kind {
+            3 => flags,
+            6 | 8 | 10 => 0x02,
+            _ => 0,
+        };
+        if flags != expected_flags {
+            return Err(malformed(format!(
+                "reserved flags 0x{:X} on packet type {}",
+                flags, kind
+            )));
+        }
+        let packet = match kind {
+            1 => {
+                if b.string()? != "MQTT" {
+                    return Err(malformed("protocol name is not MQTT".into()));
+                }
+                let version = match b.u8()? {
+                    4 => Version::V311,
+                    5 => Version::V5,
+                    l => return Err(malformed(format!("unsupported protocol level {}", l))),
+                };
+                let flags = b.u8()?;
+                let keep_alive = b.u16()?;
+                let properties = if version == Version::V5 {
+                    Properties::decode(&mut b)?
+                } else {
+                    Properties::default()
+                };
+                let client_id = b.string()?;
+                let will = if flags & 0x04 != 0 {
+                    let properties = if version == Version::V5 {
+                        Properties::decode(&mut b)?
+                    } else {
+                        Properties::default()
+                    };
+                    Some(Will {
+                        topic: b.string()?,
+                        payload: b.binary()?.to_vec(),
+                        qos: (flags >> 3) & 0x03,
+                        retain: flags & 0x20 != 0,
+                        properties,
+                    })
+                } else {
+                    None
+                };
+                let username = if flags & 0x80 != 0 {
+                    Some(b.string()?)
+                } else {
+                    None
+                };
+                let password = if flags & 0x40 != 0 {
+                    Some(b.binary()?.to_vec())
+                } else {
+                    None
+                };
+                Packet::Connect(Box::new(Connect {
+                    version,
+                    client_id,
+                    clean_start: flags & 0x02 != 0,
+                    keep_alive,
+                    username,
+                    password,
+                    will,
+                    properties,
+                }))
+            }
+            2 => Packet::ConnAck {
+                session_present: b.u8()? & 0x01 != 0,
+                reason_code: b.u8()?,
+                properties: b.properties(v5)?,
+            },
+            3 => {
+                let qos = (flags >> 1) & 0x03;
+                if qos == 3 {
+                    return Err(malformed("PUBLISH with QoS 3".into()));
+                }
+                let topic = b.string()?;
+                let packet_id = if qos > 0 { Some(b.u16()?) } else { None };
+                let properties = b.properties(v5)?;
+                Packet::Publish(Publish {
+                    topic,
+                    packet_id,
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    dup: flags & 0x08 != 0,
+                    payload: b.rest().to_vec(),
+                    properties,
+                })
+            }
+            4..=7 => {
+                let kind = match kind {
+                    4 => AckKind::Puback,
+                    5 => AckKind::Pubrec,
+                    6 => AckKind::Pubrel,
+                    _ => AckKind::Pubcomp,
+                };
+                let packet_id = b.u16()?;
+                let reason_code = if b.is_empty() { 0 } else { b.u8()? };
+                Packet::Ack {
+                    kind,
+                    packet_id,
+                    reason_code,
+                    properties: b.properties(v5)?,
+                }
+            }
+            8 => {
+                let packet_id = b.u16()?;
+                let properties = b.properties(v5)?;
+                let mut subscriptions = Vec::new();
+                while !b.is_empty() {
+                    let filter = b.string()?;
+                    let options = b.u8()?;
+                    subscriptions.push(Subscription {
+                        filter,
+                        qos: options & 0x03,
+                        no_local: options & 0x04 != 0,
+                        retain_as_published: options & 0x08 != 0,
+                        retain_handling: (options >> 4) & 0x03,
+                    });
+                }
+                Packet::Subscribe {
+                    packet_id,
+                    subscriptions,
+                    properties,
+                }
+            }
+            9 | 11 => {
+                let packet_id = b.u16()?;
+                let properties = if v5 {
+                    Properties::decode(&mut b)?
+                } else {
+                    Properties::default()
+                };
+                let reason_codes = b.rest().to_vec();
+                if kind == 9 {
+                    Packet::SubAck {
+                        packet_id,
+                        reason_codes,
+                        properties,
+                    }
+                } else {
+                    Packet::UnsubAck {
+                        packet_id,
+                        reason_codes,
+                        properties,
+                    }
+                }
+            }
+            10 => {
+                let packet_id = b.u16()?;
+                let properties = b.properties(v5)?;
+                let mut filters = Vec::new();
+                while !b.is_empty() {
+                    filters.push(b.string()?);
+                }
+                Packet::Unsubscribe {
+                    packet_id,
+                    filters,
+                    properties,
+                }
+            }
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 | 15 => {
+                let reason_code = if b.is_empty() { 0 } else { b.u8()? };
+                let properties = b.properties(v5)?;
+                if kind == 14 {
+                    Packet::Disconnect {
+                        reason_code,
+                        properties,
+                    }
+                } else {
+                    Packet::Auth {
+                        reason_code,
+                        properties,
+                    }
+                }
+            }
+            _ => return Err(malformed("packet type 0 is reserved".into())),
+        };
+        if !b.is_empty() && !matches!(packet, Packet::Publish(_)) {
+            return Err(malformed(format!(
+                "{} bytes left over in {}",
+                b.rest().len(),
+                packet.name()
+            )));
+        }
+        Ok(packet)
+    }
+}
+
+/// Cuts a byte stream into whole packets.
+pub struct Decoder {
+    buf: Vec<u8>,
+    max_packet_size: usize,
+}
+
+impl Decoder {
+    pub fn new(max_packet_size: usize) -> Self {
+        Decoder {
+            buf: Vec::new(),
+            max_packet_size,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete packet, fixed header included, once all of it has arrived.
+    pub fn next_frame(&mut self) -> NetResult<Option<Vec<u8>>> {
+        let mut len = 0usize;
+        let mut at = 1;
+        loop {
+            let Some(b) = self.buf.get(at) else {
+                return Ok(None);
+            };
+            len |= ((b & 0x7F) as usize) << (7 * (at - 1));
+            at += 1;
+            if b & 0x80 == 0 {
+                break;
+            }
+            if at > 4 {
+                return Err(malformed("remaining length longer than 4 bytes".into()));
+            }
+        }
+        let total = at + len;
+        if total > self.max_packet_size {
+            return Err(NetError::mqtt(format!(
+                "packet of {} bytes exceeds the {} byte limit",
+                total, self.max_packet_size
+            )));
+        }
+        if self.buf.len() < total {
+            return Ok(None);
+        }
+        Ok(Some(self.buf.drain(..total).collect()))
+    }
+}
+
+/// Bounds-checked reads over a packet body.
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Input { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> NetResult<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(malformed("packet ends early".into()));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+
+    fn u8(&mut self) -> NetResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> NetResult<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> NetResult<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn varint(&mut self) -> NetResult<u32> {
+        let mut value = 0u32;
+        for i in 0..4 {
+            let b = self.u8()?;
+            value |= ((b & 0x7F) as u32) << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed(
+            "variable byte integer longer than 4 bytes".into(),
+        ))
+    }
+
+    fn binary(&mut self) -> NetResult<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> NetResult<String> {
+        let bytes = self.binary()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| malformed("string is not UTF-8".into()))
+    }
+
+    /// MQTT 5 properties where the packet has them; an absent block (allowed after a reason
+    /// code) reads as empty.
+    fn properties(&mut self, v5: bool) -> NetResult<Properties> {
+        if v5 && !self.is_empty() {
+            Properties::decode(self)
+        } else {
+            Ok(Properties::default())
+        }
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u32) -> NetResult<()> {
+    if value > MAX_VARINT {
+        return Err(NetError::mqtt(format!(
+            "{} does not fit a variable byte integer",
+            value
+        )));
+    }
+    loop {
+        let mut b = (value & 0x7F) as u8;
+        value >>= 7;
+        if value > 0 {
+            b |= 0x80;
+        }
+        out.push(b);
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn put_binary(out: &mut Vec<u8>, data: &[u8]) -> NetResult<()> {
+    let len = u16::try_from(data.len()).map_err(|_| {
+        NetError::mqtt(format!(
+            "field of {} bytes is longer than 65535",
+            data.len()
+        ))
+    })?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> NetResult<()> {
+    put_binary(out, s.as_bytes())
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+fn malformed(what: String) -> NetError {
+    NetError::mqtt(format!("malformed packet: {}", what))
+}
+
+pub fn check_qos(qos: u8) -> NetResult<()> {
+    if qos > 2 {
+        return Err(NetError::mqtt(format!(
+            "QoS must be 0, 1 or 2, not {}",
+            qos
+        )));
+    }
+    Ok(())
+}
+
+/// A topic to publish to: no wildcards, no NUL, at most 65535 bytes.
+pub fn check_topic(topic: &str) -> NetResult<()> {
+    if topic.is_empty() || topic.len() > u16::MAX as usize {
+        return Err(NetError::mqtt("topic must be 1 to 65535 bytes long".into()));
+    }
+    if topic.contains(['+', '#', '\0']) {
+        return Err(NetError::mqtt(format!(
+            "topic {} must not contain wildcards or NUL",
+            topic
+        )));
+    }
+    Ok(())
+}
+
+/// A subscription filter: `+` must fill a whole level and `#` must be the whole last one.
+/// Shared subscriptions (`$share/group/filter`) are checked on the filter part.
+pub fn check_filter(filter: &str) -> NetResult<()> {
+    let bad = |why: &str| NetError::mqtt(format!("topic filter {} is invalid: {}", filter, why));
+    if filter.is_empty() || filter.len() > u16::MAX as usize {
+        return Err(bad("must be 1 to 65535 bytes long"));
+    }
+    if filter.contains('\0') {
+        return Err(bad("contains NUL"));
+    }
+    let inner = match shared_filter(filter) {
+        Some((group, inner)) => {
+            if group.is_empty() || group.contains(['+', '#']) || inner.is_empty() {
+                return Err(bad("shared subscriptions need a group name and a filter"));
+            }
+            inner
+        }
+        None => filter,
+    };
+    let levels: Vec<&str> = inner.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != "#" || i != levels.len() - 1) {
+            return Err(bad("# must be the whole last level"));
+        }
+        if level.contains('+') && *level != "+" {
+            return Err(bad("+ must be a whole level"));
+        }
+    }
+    Ok(())
+}
+
+fn shared_filter(filter: &str) -> Option<(&str, &str)> {
+    filter.strip_prefix("$share/")?.split_once('/')
+}
+
+/// Whether a message on `topic` is delivered to a subscription on `filter`. Wildcards at the
+/// start of a filter don't match `$` topics such as `$SYS/...`.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    let filter = shared_filter(filter).map_or(filter, |(_, f)| f);
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (l, Some(t)) if l == t => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// The CONNACK result in words; 3.1.1 has its own small set of return codes.
+pub fn connack_reason(version: Version, code: u8) -> &'static str {
+    match (version, code) {
+        (Version::V311, 1) => "unacceptable protocol version",
+        (Version::V311, 2) => "identifier rejected",
+        (Version::V311, 3) => "server unavailable",
+        (Version::V311, 4) => "bad user name or password",
+        (Version::V311, 5) => "not authorized",
+        (_, code) => reason_name(code),
+    }
+}
+
+/// Reason codes as named by MQTT 5; 3.1.1 SUBACK codes are a subset.
+pub fn reason_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "success",
+        0x01 => "granted QoS 1",
+        0x02 => "granted QoS 2",
+        0x04 => "disconnect with will message",
+        0x10 => "no matching subscribers",
+        0x11 => "no subscription existed",
+        0x18 => "continue authentication",
+        0x19 => "re-authenticate",
+        0x80 => "unspecified error",
+        0x81 => "malformed packet",
+        0x82 => "protocol error",
+        0x83 => "implementation specific error",
+        0x84 => "unsupported protocol version",
+        0x85 => "client identifier not valid",
+        0x86 => "bad user name or password",
+        0x87 => "not authorized",
+        0x88 => "server unavailable",
+        0x89 => "server busy",
+        0x8A => "banned",
+        0x8B => "server shutting down",
+        0x8C => "bad authentication method",
+        0x8D => "keep alive timeout",
+        0x8E => "session taken over",
+        0x8F => "topic filter invalid",
+        0x90 => "topic name invalid",
+        0x91 => "packet identifier in use",
+        0x92 => "packet identifier not found",
+        0x93 => "receive maximum exceeded",
+        0x94 => "topic alias invalid",
+        0x95 => "packet too large",
+        0x96 => "message rate too high",
+        0x97 => "quota exceeded",
+        0x98 => "administrative action",
+        0x99 => "payload format invalid",
+        0x9A => "retain not supported",
+        0x9B => "QoS not supported",
+        0x9C => "use another server",
+        0x9D => "server moved",
+        0x9E => "shared subscriptions not supported",
+        0x9F => "connection rate exceeded",
+        0xA0 => "maximum connect time",
+        0xA1 => "subscription identifiers not supported",
+        0xA2 => "wildcard subscriptions not supported",
+        _ => "unknown reason code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_properties() -> Properties {
+        Properties {
+            payload_format_indicator: Some(1),
+            message_expiry_interval: Some(3600),
+            content_type: Some("text/plain".into()),
+            response_topic: Some("reply/here".into()),
+            correlation_data_b64: Some(b64(b"corr")),
+            subscription_identifiers: vec![1, 300, MAX_VARINT],
+            session_expiry_interval: Some(60),
+            assigned_client_identifier: Some("assigned".into()),
+            server_keep_alive: Some(30),
+            authentication_method: Some("SCRAM-SHA-1".into()),
+            authentication_data_b64: Some(b64(&[0, 1, 2, 255])),
+            request_problem_information: Some(0),
+            will_delay_interval: Some(5),
+            request_response_information: Some(1),
+            response_information: Some("info".into()),
+            server_reference: Some("other:1883".into()),
+            reason_string: Some("because".into()),
+            receive_maximum: Some(10),
+            topic_alias_maximum: Some(20),
+            topic_alias: Some(3),
+            maximum_qos: Some(1),
+            retain_available: Some(0),
+            user_properties: vec![("k".into(), "v".into()), ("k".into(), "again".into())],
+            maximum_packet_size: Some(1 << 20),
+            wildcard_subscription_available: Some(1),
+            subscription_identifier_available: Some(1),
+            shared_subscription_available: Some(0),
+        }
+    }
+
+    /// One of every packet, using only what `version` can carry.
+    fn every_packet(version: Version) -> Vec<Packet> {
+        let v5 = version == Version::V5;
+        let props = || {
+            if v5 {
+                full_properties()
+            } else {
+                Properties::default()
+            }
+        };
+        let reason = |code: u8| if v5 { code } else { 0 };
+        let connect = |will: Option<Will>, username: Option<&str>, password: Option<&[u8]>| {
+            Packet::Connect(Box::new(Connect {
+                version,
+                client_id: "client".into(),
+                clean_start: will.is_none(),
+                keep_alive: 60,
+                username: username.map(String::from),
+                password: password.map(<[u8]>::to_vec),
+                will,
+                properties: props(),
+            }))
+        };
+        let publish = |qos: u8, packet_id: Option<u16>, dup: bool, retain: bool| {
+            Packet::Publish(Publish {
+                topic: "a/b".into(),
+                packet_id,
+                qos,
+                retain,
+                dup,
+                payload: b"payload \x00\xff".to_vec(),
+                properties: props(),
+            })
+        };
+        let mut packets = vec![
+            connect(None, None, None),
+            connect(
+                Some(Will {
+                    topic: "will/topic".into(),
+                    payload: b"gone".to_vec(),
+                    qos: 2,
+                    retain: true,
+                    properties: props(),
+                }),
+                Some("user"),
+                Some(b"secret"),
+            ),
+            Packet::ConnAck {
+                session_present: true,
+                reason_code: reason(0x87),
+                properties: props(),
+            },
+            publish(0, None, false, false),
+            publish(1, Some(1), false, true),
+            publish(2, Some(65535), true, false),
+            Packet::Subscribe {
+                packet_id: 7,
+                subscriptions: vec![
+                    Subscription {
+                        filter: "a/+".into(),
+                        qos: 1,
+                        no_local: v5,
+                        retain_as_published: v5,
+                        retain_handling: reason(2),
+                    },
+                    Subscription {
+                        filter: "#".into(),
+                        qos: 2,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: 0,
+                    },
+                ],
+                properties: props(),
+            },
+            Packet::SubAck {
+                packet_id: 7,
+                reason_codes: vec![1, 2, 0x80],
+                properties: props(),
+            },
+            Packet::Unsubscribe {
+                packet_id: 8,
+                filters: vec!["a/+".into(), "#".into()],
+                properties: props(),
+            },
+            Packet::UnsubAck {
+                packet_id: 8,
+                reason_codes: if v5 { vec![0, 0x11] } else { Vec::new() },
+                properties: props(),
+            },
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect {
+                reason_code: reason(0x8E),
+                properties: props(),
+            },
+            Packet::Disconnect {
+                reason_code: 0,
+                properties: Properties::default(),
+            },
+            Packet::Auth {
+                reason_code: reason(0x18),
+                properties: props(),
+            },
+        ];
+        for kind in [
+            AckKind::Puback,
+            AckKind::Pubrec,
+            AckKind::Pubrel,
+            AckKind::Pubcomp,
+        ] {
+            packets.push(Packet::ack(kind, 42));
+            packets.push(Packet::Ack {
+                kind,
+                packet_id: 43,
+                reason_code: reason(0x10),
+                properties: props(),
+            });
+        }
+        packets
+    }
+
+    #[test]
+    fn round_trip() {
+        for version in [Version::V311, Version::V5] {
+            for packet in every_packet(version) {
+                let bytes = packet.encode(version).unwrap();
+                let decoded = Packet::decode(&bytes, version)
+                    .unwrap_or_else(|e| panic!("{:?} {}: {}", version, packet.name(), e));
+                assert_eq!(decoded, packet, "{:?}", version);
+                assert_eq!(decoded.encode(version).unwrap(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn v311_drops_v5_fields() {
+        let ack = Packet::Ack {
+            kind: AckKind::Puback,
+            packet_id: 1,
+            reason_code: 0x10,
+            properties: full_properties(),
+        };
+        assert_eq!(ack.encode(Version::V311).unwrap(), [0x40, 2, 0, 1]);
+        let unsuback = Packet::UnsubAck {
+            packet_id: 1,
+            reason_codes: vec![0x11],
+            properties: Properties::default(),
+        };
+        assert_eq!(unsuback.encode(Version::V311).unwrap(), [0xB0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        // PUBREL without its reserved 0x02 flags
+        assert!(Packet::decode(&[0x60, 2, 0, 1], Version::V311).is_err());
+        // QoS 3
+        assert!(Packet::decode(&[0x36, 3, 0, 1, b'a'], Version::V311).is_err());
+        // body shorter than its remaining length
+        assert!(Packet::decode(&[0x40, 3, 0, 1], Version::V311).is_err());
+        // trailing bytes after a PINGRESP
+        assert!(Packet::decode(&[0xD0, 1, 0], Version::V311).is_err());
+        // unknown property
+        assert!(Packet::decode(&[0x20, 5, 0, 0, 2, 0x7F, 0], Version::V5).is_err());
+    }
+
+    #[test]
+    fn decoder_split_input() {
+        let packets = every_packet(Version::V5);
+        let stream: Vec<u8> = packets
+            .iter()
+            .flat_map(|p| p.encode(Version::V5).unwrap())
+            .collect();
+
+        // byte by byte, so every fixed header and remaining length arrives in pieces
+        let mut decoder = Decoder::new(DEFAULT_MAX_PACKET_SIZE);
+        let mut frames = Vec::new();
+        for b in &stream {
+            decoder.push(&[*b]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(Packet::decode(&frame, Version::V5).unwrap());
+            }
+        }
+        assert_eq!(frames, packets);
+
+        // all at once
+        let mut decoder = Decoder::new(DEFAULT_MAX_PACKET_SIZE);
+        decoder.push(&stream);
+        let mut count = 0;
+        while decoder.next_frame().unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, packets.len());
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn decoder_oversized_input() {
+        let publish = Packet::Publish(Publish {
+            topic: "t".into(),
+            packet_id: None,
+            qos: 0,
+            retain: false,
+            dup: false,
+            payload: vec![0; 200],
+            properties: Properties::default(),
+        });
+        let bytes = publish.encode(Version::V311).unwrap();
+        assert_eq!(bytes.len(), 206);
+
+        // refused as soon as the remaining length is known
+        let mut decoder = Decoder::new(205);
+        decoder.push(&bytes[..3]);
+        let e = decoder.next_frame().unwrap_err().to_string();
+        assert!(
+            e.contains("packet of 206 bytes exceeds the 205 byte limit"),
+            "{}",
+            e
+        );
+
+        let mut decoder = Decoder::new(206);
+        decoder.push(&bytes);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), bytes);
+
+        // a fifth remaining length byte
+        let mut decoder = Decoder::new(DEFAULT_MAX_PACKET_SIZE);
+        decoder.push(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert!(decoder.next_frame().is_err());
+
+        // the longest body there is still fits in four bytes
+        let mut out = Vec::new();
+        put_varint(&mut out, MAX_VARINT).unwrap();
+        assert_eq!(out, [0xFF, 0xFF, 0xFF, 0x7F]);
+        assert!(put_varint(&mut out, MAX_VARINT + 1).is_err());
+    }
+
+    #[test]
+    fn filters() {
+        for ok in [
+            "a",
+            "a/b",
+            "/",
+            "+",
+            "#",
+            "a/+/c",
+            "a/#",
+            "+/+",
+            "+/#",
+            "$SYS/#",
+            "$share/g/a/#",
+            "$share/g/+",
+        ] {
+            assert!(check_filter(ok).is_ok(), "{}", ok);
+        }
+        for bad in [
+            "",
+            "a#",
+            "a/#/b",
+            "#/a",
+            "a+",
+            "a/b+/c",
+            "a\0b",
+            "$share//a",
+            "$share/g/",
+            "$share/g+/a",
+            "$share/g/a#",
+        ] {
+            assert!(check_filter(bad).is_err(), "{:?}", bad);
+        }
+        assert!(check_topic("a/b").is_ok());
+        assert!(check_topic("a/+").is_err());
+        assert!(check_topic("").is_err());
+    }
+
+    #[test]
+    fn wildcard_matching() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a/b/c", false),
+            ("a/b/c", "a/b", false),
+            ("a/+/c", "a/b/c", true),
+            ("a/+", "a/b/c", false),
+            ("+/+", "/a", true),
+            ("+", "a", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b/c", true),
+            ("#", "a/b", true),
+            ("#", "$SYS/broker/uptime", false),
+            ("+/broker/uptime", "$SYS/broker/uptime", false),
+            ("$SYS/#", "$SYS/broker/uptime", true),
+            ("$SYS/+/uptime", "$SYS/broker/uptime", true),
+            ("a/#", "$SYS/a", false),
+            ("$share/g/a/+", "a/b", true),
+            ("$share/g/#", "$SYS/x", false),
+        ];
+        for (filter, topic, expected) in cases {
+            assert_eq!(matches(filter, topic), expected, "{} {}", filter, topic);
+        }
+    }
+}
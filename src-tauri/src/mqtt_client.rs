@@ -0,0 +1,1317 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socket2::Socket;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::async_runtime::{self, JoinHandle};
+use tauri::{AppHandle, Emitter, Runtime, Wry};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::mqtt::{
+    self, AckKind, Connect, Decoder, Packet, Properties, Publish, Subscription, Version, Will,
+};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Session, SessionHandle, SessionKind, Started, Stopped};
+use crate::sockopt::SocketOptions;
+use crate::tcp_client::{self, DEFAULT_CONNECT_TIMEOUT_MS};
+use crate::template::Payload;
+use crate::tls::{self, BoxedStream, TlsClientConfig};
+use crate::ws::{self, Message, Opcode, ReadError, WsOptions};
+use crate::ws_client;
+
+pub const DEFAULT_KEEP_ALIVE_SECS: u16 = 60;
+pub const DEFAULT_ACK_TIMEOUT_MS: u64 = 10_000;
+const MQTT_PORT: u16 = 1883;
+const MQTTS_PORT: u16 = 8883;
+
+/// CONNECT settings; everything is optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ConnectOptions {
+    pub version: Version,
+    /// A random `netdebugger-…` id when unset; an empty id asks the broker to assign one.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 0 turns keepalive pings off.
+    pub keep_alive_secs: Option<u16>,
+    /// Start without any state the broker kept from an earlier connection; defaults to true.
+    pub clean_session: Option<bool>,
+    pub will: Option<WillOptions>,
+    /// CONNECT properties (MQTT 5), e.g. `session_expiry_interval`.
+    pub properties: Properties,
+    /// How long subscribe, unsubscribe and QoS 1/2 publishes wait for the broker.
+    pub ack_timeout_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WillOptions {
+    pub topic: String,
+    pub data_b64: Option<String>,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+    #[serde(default)]
+    pub properties: Properties,
+}
+
+/// Where a broker URL points.
+enum Broker {
+    Tcp { addr: String, tls: bool },
+    Ws(ws::Url),
+}
+
+/// `mqtt://host[:1883]`, `mqtts://host[:8883]`, `ws://…` or `wss://…`; a bare `host:port` is
+/// plain TCP unless TLS options are given.
+fn parse_broker(url: &str, tls: bool) -> NetResult<Broker> {
+    if url.starts_with("ws://") || url.starts_with("wss://") {
+        return ws::parse_url(url).map(Broker::Ws);
+    }
+    let (scheme_tls, rest, port) = if let Some(r) = url.strip_prefix("mqtts://") {
+        (Some(true), r, MQTTS_PORT)
+    } else if let Some(r) = url.strip_prefix("mqtt://") {
+        (Some(false), r, MQTT_PORT)
+    } else {
+        (None, url, MQTT_PORT)
+    };
+    let addr = match ws::split_authority(rest, port) {
+        Some(a) if a.path == "/" && !rest.contains(['?', '#']) => a.addr,
+        _ => {
+            return Err(NetError::mqtt(format!(
+                "{} is not a host:port or an mqtt://, mqtts://, ws:// or wss:// URL",
+                url
+            )))
+        }
+    };
+    Ok(Broker::Tcp {
+        addr,
+        tls: scheme_tls.unwrap_or(tls),
+    })
+}
+
+/// The byte stream MQTT runs over: the TCP (or TLS) stream itself, or binary WebSocket messages.
+enum Reader {
+    Tcp(ReadHalf<BoxedStream>, Vec<u8>),
+    Ws(ws::Reader<ReadHalf<BoxedStream>>),
+}
+
+enum Incoming {
+    Data,
+    /// A WebSocket ping, to be answered with this payload.
+    Ping(Vec<u8>),
+    Eof,
+}
+
+impl Reader {
+    /// Hands the next bytes to `decoder`. Cancel safe.
+    async fn read(&mut self, decoder: &mut Decoder) -> io::Result<Incoming> {
+        match self {
+            Reader::Tcp(r, buf) => {
+                let n = r.read(buf).await?;
+                if n == 0 {
+                    return Ok(Incoming::Eof);
+                }
+                decoder.push(&buf[..n]);
+                Ok(Incoming::Data)
+            }
+            Reader::Ws(r) => match r.next().await {
+                Ok(Message {
+                    opcode: Opcode::Binary | Opcode::Text,
+                    data,
+                }) => {
+                    decoder.push(&data);
+                    Ok(Incoming::Data)
+                }
+                Ok(Message {
+                    opcode: Opcode::Ping,
+                    data,
+                }) => Ok(Incoming::Ping(data)),
+                Ok(Message {
+                    opcode: Opcode::Close,
+                    ..
+                })
+                | Err(ReadError::Eof) => Ok(Incoming::Eof),
+                Ok(_) => Ok(Incoming::Data),
+                Err(ReadError::Io(e)) => Err(e),
+                Err(ReadError::Protocol { reason, .. }) => {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+                }
+            },
+        }
+    }
+}
+
+enum Writer {
+    Tcp(WriteHalf<BoxedStream>),
+    Ws(ws::Writer<WriteHalf<BoxedStream>>),
+}
+
+impl Writer {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Tcp(w) => w.write_all(data).await,
+            Writer::Ws(w) => w.send(Opcode::Binary, data).await.map(|_| ()),
+        }
+    }
+
+    async fn pong(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Tcp(_) => Ok(()),
+            Writer::Ws(w) => w.send(Opcode::Pong, data).await.map(|_| ()),
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        match self {
+            Writer::Tcp(w) => {
+                let _ = w.shutdown().await;
+            }
+            Writer::Ws(w) => {
+                if !w.close_sent() {
+                    let _ = w.close(ws::CLOSE_NORMAL, "").await;
+                }
+                let _ = w.shutdown().await;
+            }
+        }
+    }
+}
+
+/// State shared between the session handle, the read task and the publish/subscribe commands.
+/// Generic over the runtime only so tests can drive a connection with a mock app.
+struct Shared<R: Runtime = Wry> {
+    app: AppHandle<R>,
+    writer: tokio::sync::Mutex<Writer>,
+    version: Version,
+    /// Commands waiting for their acknowledgement, by packet id.
+    pending: Mutex<HashMap<u16, oneshot::Sender<Packet>>>,
+    next_packet_id: AtomicU16,
+    /// Filters the broker granted, for tagging incoming messages with what they matched.
+    subscriptions: Mutex<Vec<String>>,
+    /// Duplicate of the connected socket for the options commands.
+    sock: Mutex<Option<Socket>>,
+    local: String,
+    ack_timeout: Duration,
+    seq: AtomicU64,
+}
+
+impl<R: Runtime> Shared<R> {
+    async fn send(&self, sid: &str, url: &str, packet: &Packet) -> NetResult<usize> {
+        let data = packet.encode(self.version)?;
+        self.writer
+            .lock()
+            .await
+            .write(&data)
+            .await
+            .map_err(|e| NetError::io("send", url, e))?;
+        recorder::record(
+            sid,
+            Direction::Tx,
+            &self.local,
+            url,
+            0,
+            session::now_ms(),
+            &data,
+        );
+        Ok(data.len())
+    }
+
+    /// Picks a packet id no other command is waiting on and registers for its answer.
+    fn register(&self) -> NetResult<(u16, oneshot::Receiver<Packet>)> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|e| NetError::internal(format!("lock pending error: {}", e)))?;
+        for _ in 0..u16::MAX {
+            let id = self.next_packet_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 && !pending.contains_key(&id) {
+                let (tx, rx) = oneshot::channel();
+                pending.insert(id, tx);
+                return Ok((id, rx));
+            }
+        }
+        Err(NetError::mqtt("all packet ids are in use".into()))
+    }
+
+    fn forget(&self, packet_id: u16) {
+        if let Ok(mut p) = self.pending.lock() {
+            p.remove(&packet_id);
+        }
+    }
+
+    fn resolve(&self, packet_id: u16, packet: Packet) -> bool {
+        let waiter = match self.pending.lock() {
+            Ok(mut p) => p.remove(&packet_id),
+            Err(_) => None,
+        };
+        match waiter {
+            Some(tx) => {
+                let _ = tx.send(packet);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends `packet` and waits for whatever answers `packet_id`; returns it with the round trip.
+    async fn exchange(
+        &self,
+        sid: &str,
+        url: &str,
+        packet: &Packet,
+        packet_id: u16,
+        answer: oneshot::Receiver<Packet>,
+    ) -> NetResult<(usize, Packet, f64)> {
+        let sent = Instant::now();
+        let bytes = match self.send(sid, url, packet).await {
+            Ok(n) => n,
+            Err(e) => {
+                self.forget(packet_id);
+                return Err(e);
+            }
+        };
+        let context = format!("mqtt {}", packet.name().to_ascii_lowercase());
+        match tokio::time::timeout(self.ack_timeout, answer).await {
+            Ok(Ok(p)) => Ok((bytes, p, sent.elapsed().as_secs_f64() * 1000.0)),
+            Ok(Err(_)) => Err(NetError::io(
+                &context,
+                url,
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed before the acknowledgement",
+                ),
+            )),
+            Err(_) => {
+                self.forget(packet_id);
+                Err(NetError::io(
+                    &context,
+                    url,
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "no acknowledgement within {} ms",
+                            self.ack_timeout.as_millis()
+                        ),
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+pub struct ClientHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+
+    pub fn sockets(&self) -> Vec<(String, Socket)> {
+        let sock = match self.shared.sock.lock() {
+            Ok(s) => s.as_ref().and_then(|s| s.try_clone().ok()),
+            Err(_) => None,
+        };
+        let label = sock
+            .as_ref()
+            .and_then(|s| s.peer_addr().ok())
+            .and_then(|a| a.as_socket())
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        sock.map(|s| vec![(label, s)]).unwrap_or_default()
+    }
+}
+
+/// Connects and waits for CONNACK before the session is registered; `url` is the session's
+/// address. A refused CONNECT fails the command; a dropped connection ends the session.
+pub async fn start(
+    app: AppHandle,
+    url: String,
+    options: Option<ConnectOptions>,
+    connect_timeout_ms: Option<u64>,
+    tls: Option<TlsClientConfig>,
+    ws: Option<WsOptions>,
+    socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+    if session::registry()?.contains(SessionKind::MqttClient, &url) {
+        return Err(NetError::already_running(SessionKind::MqttClient, &url));
+    }
+    let options = options.unwrap_or_default();
+    let connect = connect_packet(&options)?;
+    let version = options.version;
+    let broker = parse_broker(&url, tls.is_some())?;
+    let (addr, use_tls) = match &broker {
+        Broker::Tcp { addr, tls } => (addr.clone(), *tls),
+        Broker::Ws(u) => (u.addr.clone(), u.tls),
+    };
+    let tls = match (use_tls, tls) {
+        (true, cfg) => Some(tls::client(&cfg.unwrap_or_default(), &addr)?),
+        (false, None) => None,
+        (false, Some(_)) => {
+            return Err(NetError::mqtt(
+                "TLS options need an mqtts:// or wss:// URL".into(),
+            ))
+        }
+    };
+    let timeout_ms = connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+
+    let (stream, sock, local, tls_info) = tcp_client::connect(
+        &addr,
+        timeout_ms,
+        tls.as_ref(),
+        &socket_options.unwrap_or_default(),
+        &CancellationToken::new(),
+    )
+    .await?;
+    let max_packet_size = match version {
+        Version::V5 => options.properties.maximum_packet_size.map(|n| n as usize),
+        Version::V311 => None,
+    };
+    let mut decoder = Decoder::new(max_packet_size.unwrap_or(mqtt::DEFAULT_MAX_PACKET_SIZE));
+    let opening = tokio::time::timeout(
+        Duration::from_millis(timeout_ms),
+        open(stream, &broker, ws, &connect, version, &mut decoder),
+    );
+    let (reader, mut writer, subprotocol, connack) = opening.await.unwrap_or_else(|_| {
+        Err(NetError::io(
+            "mqtt connect",
+            &url,
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {} ms", timeout_ms),
+            ),
+        ))
+    })?;
+    let (session_present, properties) = match connack {
+        Packet::ConnAck {
+            reason_code: 0,
+            session_present,
+            properties,
+        } => (session_present, properties),
+        Packet::ConnAck { reason_code, .. } => {
+            writer.shutdown().await;
+            return Err(NetError::mqtt(format!(
+                "broker refused the connection: {} (0x{:02X})",
+                mqtt::connack_reason(version, reason_code),
+                reason_code
+            )));
+        }
+        other => {
+            writer.shutdown().await;
+            return Err(NetError::mqtt(format!(
+                "expected CONNACK, got {}",
+                other.name()
+            )));
+        }
+    };
+    // the broker may override the keepalive it was asked for
+    let keep_alive = properties.server_keep_alive.unwrap_or(connect.keep_alive);
+    let client_id = properties
+        .assigned_client_identifier
+        .clone()
+        .unwrap_or_else(|| connect.client_id.clone());
+
+    let shared = Arc::new(Shared {
+        app: app.clone(),
+        writer: tokio::sync::Mutex::new(writer),
+        version,
+        pending: Mutex::new(HashMap::new()),
+        next_packet_id: AtomicU16::new(1),
+        subscriptions: Mutex::new(Vec::new()),
+        sock: Mutex::new(sock),
+        local: local.clone(),
+        ack_timeout: Duration::from_millis(
+            options.ack_timeout_ms.unwrap_or(DEFAULT_ACK_TIMEOUT_MS),
+        ),
+        seq: AtomicU64::new(0),
+    });
+
+    let cancel = CancellationToken::new();
+    let id = session::next_id();
+    let (registered_tx, registered_rx) = oneshot::channel::<()>();
+    let task = async_runtime::spawn(run(
+        id.clone(),
+        url.clone(),
+        reader,
+        decoder,
+        keep_alive,
+        shared.clone(),
+        cancel.clone(),
+        registered_rx,
+    ));
+    let handle = ClientHandle {
+        cancel,
+        task,
+        shared,
+    };
+    let rejected = session::registry()?.insert(
+        id.clone(),
+        SessionKind::MqttClient,
+        url.clone(),
+        SessionHandle::MqttClient(handle),
+    );
+    if let Err(h) = rejected {
+        h.stop().await;
+        return Err(NetError::already_running(SessionKind::MqttClient, &url));
+    }
+    let payload = json!({
+        "session": id,
+        "remote": url,
+        "local": local,
+        "tls": tls_info,
+        "subprotocol": subprotocol,
+        "version": version,
+        "client_id": client_id,
+        "session_present": session_present,
+        "keep_alive_secs": keep_alive,
+        "properties": properties,
+    });
+    let _ = app.emit("mqtt:client:connected", payload);
+    let _ = registered_tx.send(());
+
+    Ok(Started {
+        session_id: id,
+        kind: SessionKind::MqttClient,
+        message: format!("MQTT client connected to {}", url),
+        addr: url,
+    })
+}
+
+fn connect_packet(options: &ConnectOptions) -> NetResult<Connect> {
+    let will = match &options.will {
+        Some(w) => {
+            mqtt::check_topic(&w.topic)?;
+            mqtt::check_qos(w.qos)?;
+            let payload = match &w.data_b64 {
+                Some(b64) => base64::engine::general_purpose::STANDARD
+                    .decode(b64)
+                    .map_err(NetError::decode)?,
+                None => Vec::new(),
+            };
+            Some(Will {
+                topic: w.topic.clone(),
+                payload,
+                qos: w.qos,
+                retain: w.retain,
+                properties: w.properties.clone(),
+            })
+        }
+        None => None,
+    };
+    let client_id = options
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("netdebugger-{:08x}", rand::random::<u32>()));
+    Ok(Connect {
+        version: options.version,
+        client_id,
+        clean_start: options.clean_session.unwrap_or(true),
+        keep_alive: options.keep_alive_secs.unwrap_or(DEFAULT_KEEP_ALIVE_SECS),
+        username: options.username.clone(),
+        password: options.password.as_ref().map(|p| p.as_bytes().to_vec()),
+        will,
+        properties: options.properties.clone(),
+    })
+}
+
+/// Upgrades to WebSocket when the broker URL asks for it, then sends CONNECT and reads the
+/// broker's first packet. Bytes after it stay in `decoder`.
+async fn open(
+    stream: BoxedStream,
+    broker: &Broker,
+    ws: Option<WsOptions>,
+    connect: &Connect,
+    version: Version,
+    decoder: &mut Decoder,
+) -> NetResult<(Reader, Writer, Option<String>, Packet)> {
+    let (mut reader, mut writer, subprotocol, addr) = match broker {
+        Broker::Tcp { addr, .. } => {
+            let (r, w) = tokio::io::split(stream);
+            (
+                Reader::Tcp(r, vec![0u8; 65536]),
+                Writer::Tcp(w),
+                None,
+                addr.as_str(),
+            )
+        }
+        Broker::Ws(target) => {
+            let mut options = ws.unwrap_or_default();
+            if options.subprotocols.is_empty() {
+                options.subprotocols.push("mqtt".into());
+            }
+            let (stream, leftover, negotiated) =
+                ws_client::handshake(stream, target, &options).await?;
+            let (r, w) = tokio::io::split(stream);
+            (
+                Reader::Ws(ws::Reader::new(
+                    r,
+                    leftover,
+                    false,
+                    &options,
+                    negotiated.deflate,
+                )),
+                Writer::Ws(ws::Writer::new(w, false, negotiated.deflate)),
+                negotiated.subprotocol,
+                target.addr.as_str(),
+            )
+        }
+    };
+    let data = Packet::Connect(Box::new(connect.clone())).encode(version)?;
+    writer
+        .write(&data)
+        .await
+        .map_err(|e| NetError::io("mqtt connect", addr, e))?;
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            let packet = Packet::decode(&frame, version)?;
+            return Ok((reader, writer, subprotocol, packet));
+        }
+        let read = reader.read(decoder).await;
+        match read.map_err(|e| NetError::io("mqtt connect", addr, e))? {
+            Incoming::Data => {}
+            Incoming::Ping(data) => {
+                let _ = writer.pong(&data).await;
+            }
+            Incoming::Eof => {
+                return Err(NetError::io(
+                    "mqtt connect",
+                    addr,
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before CONNACK",
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+/// Completes at the next keepalive tick, or never with keepalive off.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(i) => {
+            i.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads packets until the connection ends, acknowledging incoming publishes and handing
+/// acknowledgements to the command waiting for them.
+#[allow(clippy::too_many_arguments)]
+async fn run<R: Runtime>(
+    sid: String,
+    url: String,
+    mut reader: Reader,
+    mut decoder: Decoder,
+    keep_alive: u16,
+    shared: Arc<Shared<R>>,
+    cancel: CancellationToken,
+    registered: oneshot::Receiver<()>,
+) {
+    // the session must be in the registry before this task can remove it
+    if registered.await.is_err() {
+        return;
+    }
+    let mut ping = (keep_alive > 0).then(|| {
+        let period = Duration::from_secs(keep_alive as u64);
+        let mut i = tokio::time::interval_at(Instant::now() + period, period);
+        i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        i
+    });
+    let mut awaiting_pong = false;
+    // topic aliases the broker set up, and QoS 2 messages received but not yet released
+    let mut aliases: HashMap<u16, String> = HashMap::new();
+    let mut inbound: HashSet<u16> = HashSet::new();
+
+    let (reason, reason_code) = 'conn: loop {
+        let read = tokio::select! {
+            _ = cancel.cancelled() => {
+                let bye = Packet::Disconnect { reason_code: 0, properties: Properties::default() };
+                let _ = shared.send(&sid, &url, &bye).await;
+                shared.writer.lock().await.shutdown().await;
+                return;
+            }
+            _ = tick(&mut ping) => {
+                if awaiting_pong {
+                    break (format!("no PINGRESP within {} s", keep_alive), None);
+                }
+                if let Err(e) = shared.send(&sid, &url, &Packet::PingReq).await {
+                    break (e.message, None);
+                }
+                awaiting_pong = true;
+                continue;
+            }
+            r = reader.read(&mut decoder) => r,
+        };
+        match read {
+            Ok(Incoming::Data) => {}
+            Ok(Incoming::Ping(data)) => {
+                let _ = shared.writer.lock().await.pong(&data).await;
+                continue;
+            }
+            Ok(Incoming::Eof) => break ("connection closed".to_string(), None),
+            Err(e) => {
+                emit_error(&shared.app, &sid, &url, format!("read error: {}", e));
+                break (e.to_string(), None);
+            }
+        }
+
+        loop {
+            let frame = match decoder.next_frame() {
+                Ok(Some(f)) => f,
+                Ok(None) => break,
+                Err(e) => {
+                    emit_error(&shared.app, &sid, &url, e.message.clone());
+                    break 'conn (e.message, None);
+                }
+            };
+            recorder::record(
+                &sid,
+                Direction::Rx,
+                &shared.local,
+                &url,
+                0,
+                session::now_ms(),
+                &frame,
+            );
+            let packet = match Packet::decode(&frame, shared.version) {
+                Ok(p) => p,
+                Err(e) => {
+                    emit_error(&shared.app, &sid, &url, e.message.clone());
+                    break 'conn (e.message, None);
+                }
+            };
+            let reply = match packet {
+                Packet::Publish(mut p) => {
+                    if let Some(alias) = p.properties.topic_alias {
+                        if !p.topic.is_empty() {
+                            aliases.insert(alias, p.topic.clone());
+                        } else if let Some(topic) = aliases.get(&alias) {
+                            p.topic = topic.clone();
+                        } else {
+                            let error = format!("PUBLISH with unknown topic alias {}", alias);
+                            emit_error(&shared.app, &sid, &url, error.clone());
+                            break 'conn (error, None);
+                        }
+                    }
+                    let (fresh, reply) = match (p.qos, p.packet_id) {
+                        (1, Some(id)) => (true, Some(Packet::ack(AckKind::Puback, id))),
+                        // a redelivery of a message not yet released is only acknowledged again
+                        (2, Some(id)) => {
+                            (inbound.insert(id), Some(Packet::ack(AckKind::Pubrec, id)))
+                        }
+                        _ => (true, None),
+                    };
+                    if fresh {
+                        emit_message(&sid, &url, &shared, &p);
+                    }
+                    reply
+                }
+                Packet::Ack {
+                    kind: AckKind::Pubrel,
+                    packet_id,
+                    ..
+                } => {
+                    inbound.remove(&packet_id);
+                    Some(Packet::ack(AckKind::Pubcomp, packet_id))
+                }
+                Packet::Ack {
+                    kind: AckKind::Pubrec,
+                    packet_id,
+                    reason_code,
+                    ..
+                } if reason_code < 0x80 => {
+                    // the publish stays pending until PUBCOMP
+                    Some(Packet::ack(AckKind::Pubrel, packet_id))
+                }
+                Packet::Ack { packet_id, .. }
+                | Packet::SubAck { packet_id, .. }
+                | Packet::UnsubAck { packet_id, .. } => {
+                    if !shared.resolve(packet_id, packet.clone()) {
+                        let error = format!(
+                            "{} for unknown packet id {} (late or unsolicited)",
+                            packet.name(),
+                            packet_id
+                        );
+                        emit_error(&shared.app, &sid, &url, error);
+                    }
+                    None
+                }
+                Packet::PingResp => {
+                    awaiting_pong = false;
+                    None
+                }
+                Packet::Disconnect { reason_code, .. } => {
+                    let reason = format!("broker disconnected: {}", mqtt::reason_name(reason_code));
+                    break 'conn (reason, Some(reason_code));
+                }
+                other => {
+                    let error = format!("unexpected {} from the broker", other.name());
+                    emit_error(&shared.app, &sid, &url, error.clone());
+                    break 'conn (error, None);
+                }
+            };
+            if let Some(r) = reply {
+                if let Err(e) = shared.send(&sid, &url, &r).await {
+                    break 'conn (e.message, None);
+                }
+            }
+        }
+    };
+
+    shared.writer.lock().await.shutdown().await;
+    // waiting commands fail as soon as their sender is dropped
+    if let Ok(mut p) = shared.pending.lock() {
+        p.clear();
+    }
+    let payload =
+        json!({"session": sid, "remote": url, "reason": reason, "reason_code": reason_code});
+    let _ = shared.app.emit("mqtt:client:closed", payload);
+    // nothing will revive this connection, so don't leave a dead session behind
+    session::drop_dead(&sid);
+}
+
+fn emit_error<R: Runtime>(app: &AppHandle<R>, sid: &str, url: &str, error: String) {
+    let payload = json!({"session": sid, "remote": url, "error": error});
+    let _ = app.emit("mqtt:client:error", payload);
+}
+
+fn emit_message<R: Runtime>(sid: &str, url: &str, shared: &Shared<R>, p: &Publish) {
+    let seq = shared.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    let matched: Vec<String> = match shared.subscriptions.lock() {
+        Ok(s) => s
+            .iter()
+            .filter(|f| mqtt::matches(f, &p.topic))
+            .cloned()
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let payload = json!({
+        "session": sid,
+        "remote": url,
+        "topic": p.topic,
+        "data": base64::engine::general_purpose::STANDARD.encode(&p.payload),
+        "qos": p.qos,
+        "retain": p.retain,
+        "dup": p.dup,
+        "packet_id": p.packet_id,
+        "properties": p.properties,
+        "subscriptions": matched,
+        "seq": seq,
+        "ts_ms": session::now_ms(),
+    });
+    let _ = shared.app.emit("mqtt:client:message", payload);
+}
+
+pub async fn stop(url: Option<String>) -> NetResult<Stopped> {
+    if let Some(u) = url {
+        let removed = session::registry()?.remove(SessionKind::MqttClient, &u);
+        let s = removed.ok_or_else(|| NetError::not_running(SessionKind::MqttClient, &u))?;
+        let info = s.stop().await;
+        Ok(Stopped {
+            session_ids: vec![info.id],
+            message: format!("MQTT client disconnected from {}", u),
+        })
+    } else {
+        let previous = session::registry()?.remove_kind(SessionKind::MqttClient);
+        Ok(Stopped {
+            session_ids: session::stop_all(previous).await,
+            message: "All MQTT clients disconnected".into(),
+        })
+    }
+}
+
+/// The broker's verdict on one filter of a subscribe or unsubscribe.
+#[derive(Clone, Debug, Serialize)]
+pub struct FilterResult {
+    pub filter: String,
+    pub reason_code: u8,
+    pub reason: &'static str,
+}
+
+/// Answer to `mqtt_subscribe` or `mqtt_unsubscribe`; refused filters are reported per filter
+/// rather than failing the command.
+#[derive(Clone, Debug, Serialize)]
+pub struct Acknowledged {
+    pub session_id: String,
+    pub packet_id: u16,
+    pub results: Vec<FilterResult>,
+    pub properties: Properties,
+    pub rtt_ms: f64,
+}
+
+/// Answer to `mqtt_publish`; QoS 0 publishes have no packet id and no reason code.
+#[derive(Clone, Debug, Serialize)]
+pub struct Published {
+    pub session_id: String,
+    pub packet_id: Option<u16>,
+    pub bytes: usize,
+    pub reason_code: Option<u8>,
+    pub reason: Option<&'static str>,
+    pub rtt_ms: Option<f64>,
+}
+
+pub async fn subscribe(
+    url: String,
+    subscriptions: Vec<Subscription>,
+    properties: Option<Properties>,
+) -> NetResult<Acknowledged> {
+    if subscriptions.is_empty() {
+        return Err(NetError::mqtt("nothing to subscribe to".into()));
+    }
+    for s in &subscriptions {
+        mqtt::check_filter(&s.filter)?;
+        mqtt::check_qos(s.qos)?;
+        if s.retain_handling > 2 {
+            return Err(NetError::mqtt(format!(
+                "retain_handling must be 0, 1 or 2, not {}",
+                s.retain_handling
+            )));
+        }
+    }
+    let (sid, shared) = running(&url)?;
+    let (packet_id, answer) = shared.register()?;
+    let packet = Packet::Subscribe {
+        packet_id,
+        subscriptions,
+        properties: properties.unwrap_or_default(),
+    };
+    let (_, reply, rtt_ms) = shared
+        .exchange(&sid, &url, &packet, packet_id, answer)
+        .await?;
+    let (
+        Packet::Subscribe { subscriptions, .. },
+        Packet::SubAck {
+            reason_codes,
+            properties,
+            ..
+        },
+    ) = (packet, reply)
+    else {
+        return Err(NetError::mqtt("SUBSCRIBE not answered by SUBACK".into()));
+    };
+    if reason_codes.len() != subscriptions.len() {
+        return Err(NetError::mqtt(format!(
+            "SUBACK has {} reason codes for {} filters",
+            reason_codes.len(),
+            subscriptions.len()
+        )));
+    }
+    let results: Vec<FilterResult> = subscriptions
+        .into_iter()
+        .zip(reason_codes)
+        .map(|(s, code)| FilterResult {
+            filter: s.filter,
+            reason_code: code,
+            reason: mqtt::reason_name(code),
+        })
+        .collect();
+    if let Ok(mut subs) = shared.subscriptions.lock() {
+        for r in results.iter().filter(|r| r.reason_code < 0x80) {
+            if !subs.contains(&r.filter) {
+                subs.push(r.filter.clone());
+            }
+        }
+    }
+    Ok(Acknowledged {
+        session_id: sid,
+        packet_id,
+        results,
+        properties,
+        rtt_ms,
+    })
+}
+
+pub async fn unsubscribe(
+    url: String,
+    filters: Vec<String>,
+    properties: Option<Properties>,
+) -> NetResult<Acknowledged> {
+    if filters.is_empty() {
+        return Err(NetError::mqtt("nothing to unsubscribe from".into()));
+    }
+    for f in &filters {
+        mqtt::check_filter(f)?;
+    }
+    let (sid, shared) = running(&url)?;
+    let (packet_id, answer) = shared.register()?;
+    let packet = Packet::Unsubscribe {
+        packet_id,
+        filters,
+        properties: properties.unwrap_or_default(),
+    };
+    let (_, reply, rtt_ms) = shared
+        .exchange(&sid, &url, &packet, packet_id, answer)
+        .await?;
+    let (
+        Packet::Unsubscribe { filters, .. },
+        Packet::UnsubAck {
+            reason_codes,
+            properties,
+            ..
+        },
+    ) = (packet, reply)
+    else {
+        return Err(NetError::mqtt(
+            "UNSUBSCRIBE not answered by UNSUBACK".into(),
+        ));
+    };
+    // 3.1.1 has no per-filter result: the whole request succeeded
+    let reason_codes = match shared.version {
+        Version::V311 => vec![0; filters.len()],
+        Version::V5 => reason_codes,
+    };
+    if reason_codes.len() != filters.len() {
+        return Err(NetError::mqtt(format!(
+            "UNSUBACK has {} reason codes for {} filters",
+            reason_codes.len(),
+            filters.len()
+        )));
+    }
+    let results: Vec<FilterResult> = filters
+        .into_iter()
+        .zip(reason_codes)
+        .map(|(filter, code)| FilterResult {
+            filter,
+            reason_code: code,
+            reason: mqtt::reason_name(code),
+        })
+        .collect();
+    if let Ok(mut subs) = shared.subscriptions.lock() {
+        subs.retain(|f| {
+            !results
+                .iter()
+                .any(|r| r.reason_code < 0x80 && &r.filter == f)
+        });
+    }
+    Ok(Acknowledged {
+        session_id: sid,
+        packet_id,
+        results,
+        properties,
+        rtt_ms,
+    })
+}
+
+/// QoS 1 waits for PUBACK and QoS 2 for PUBCOMP (PUBREL is sent in between); a refusal is
+/// reported in `reason_code` rather than failing the command.
+pub async fn publish(
+    url: String,
+    topic: String,
+    payload: Payload,
+    qos: Option<u8>,
+    retain: bool,
+    properties: Option<Properties>,
+) -> NetResult<Published> {
+    let qos = qos.unwrap_or(0);
+    mqtt::check_topic(&topic)?;
+    mqtt::check_qos(qos)?;
+    let data = payload.bytes()?;
+    let (sid, shared) = running(&url)?;
+    let mut publish = Publish {
+        topic,
+        packet_id: None,
+        qos,
+        retain,
+        dup: false,
+        payload: data,
+        properties: properties.unwrap_or_default(),
+    };
+    if qos == 0 {
+        let bytes = shared.send(&sid, &url, &Packet::Publish(publish)).await?;
+        return Ok(Published {
+            session_id: sid,
+            packet_id: None,
+            bytes,
+            reason_code: None,
+            reason: None,
+            rtt_ms: None,
+        });
+    }
+    let (packet_id, answer) = shared.register()?;
+    publish.packet_id = Some(packet_id);
+    let (bytes, reply, rtt_ms) = shared
+        .exchange(&sid, &url, &Packet::Publish(publish), packet_id, answer)
+        .await?;
+    let reason_code = match reply {
+        Packet::Ack { reason_code, .. } => reason_code,
+        other => {
+            return Err(NetError::mqtt(format!(
+                "PUBLISH answered by {}",
+                other.name()
+            )))
+        }
+    };
+    Ok(Published {
+        session_id: sid,
+        packet_id: Some(packet_id),
+        bytes,
+        reason_code: Some(reason_code),
+        reason: Some(mqtt::reason_name(reason_code)),
+        rtt_ms: Some(rtt_ms),
+    })
+}
+
+fn running(url: &str) -> NetResult<(String, Arc<Shared>)> {
+    let reg = session::registry()?;
+    match reg.get(SessionKind::MqttClient, url) {
+        Some(Session {
+            info,
+            handle: SessionHandle::MqttClient(h),
+        }) => Ok((info.id.clone(), h.shared.clone())),
+        _ => Err(NetError::not_running(SessionKind::MqttClient, url)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// The broker's end of a test connection.
+    struct Peer {
+        stream: TcpStream,
+        decoder: Decoder,
+        version: Version,
+    }
+
+    impl Peer {
+        async fn recv(&mut self) -> Option<Packet> {
+            loop {
+                if let Some(frame) = self.decoder.next_frame().unwrap() {
+                    return Some(Packet::decode(&frame, self.version).unwrap());
+                }
+                let mut buf = [0u8; 4096];
+                let n = self.stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    return None;
+                }
+                self.decoder.push(&buf[..n]);
+            }
+        }
+
+        async fn send(&mut self, packet: Packet) {
+            let data = packet.encode(self.version).unwrap();
+            self.stream.write_all(&data).await.unwrap();
+        }
+
+        async fn expect(&mut self, packet: Packet) {
+            assert_eq!(self.recv().await, Some(packet));
+        }
+    }
+
+    fn publish(qos: u8, packet_id: Option<u16>, dup: bool) -> Publish {
+        Publish {
+            topic: "a/b".into(),
+            packet_id,
+            qos,
+            retain: false,
+            dup,
+            payload: b"hello".to_vec(),
+            properties: Properties::default(),
+        }
+    }
+
+    /// Answers one client's CONNECT, SUBSCRIBE and publishes, then delivers a QoS 1 and a
+    /// (redelivered) QoS 2 message to it and waits for DISCONNECT.
+    async fn broker(listener: TcpListener, version: Version, delivered: oneshot::Sender<()>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut peer = Peer {
+            stream,
+            decoder: Decoder::new(mqtt::DEFAULT_MAX_PACKET_SIZE),
+            version,
+        };
+        let Some(Packet::Connect(connect)) = peer.recv().await else {
+            panic!("expected CONNECT");
+        };
+        assert_eq!(connect.version, version);
+        assert_eq!(connect.client_id, "tester");
+        peer.send(Packet::ConnAck {
+            session_present: false,
+            reason_code: 0,
+            properties: Properties::default(),
+        })
+        .await;
+
+        let Some(Packet::Subscribe {
+            packet_id,
+            subscriptions,
+            ..
+        }) = peer.recv().await
+        else {
+            panic!("expected SUBSCRIBE");
+        };
+        let filters: Vec<&str> = subscriptions.iter().map(|s| s.filter.as_str()).collect();
+        assert_eq!(filters, ["a/+", "deny/#"]);
+        peer.send(Packet::SubAck {
+            packet_id,
+            reason_codes: vec![1, 0x80],
+            properties: Properties::default(),
+        })
+        .await;
+
+        let Some(Packet::Publish(p)) = peer.recv().await else {
+            panic!("expected a QoS 1 PUBLISH");
+        };
+        assert_eq!(p.qos, 1);
+        peer.send(Packet::ack(AckKind::Puback, p.packet_id.unwrap()))
+            .await;
+
+        let Some(Packet::Publish(p)) = peer.recv().await else {
+            panic!("expected a QoS 2 PUBLISH");
+        };
+        assert_eq!(p.qos, 2);
+        let id = p.packet_id.unwrap();
+        peer.send(Packet::ack(AckKind::Pubrec, id)).await;
+        peer.expect(Packet::ack(AckKind::Pubrel, id)).await;
+        peer.send(Packet::ack(AckKind::Pubcomp, id)).await;
+
+        peer.send(Packet::Publish(publish(1, Some(10), false)))
+            .await;
+        peer.expect(Packet::ack(AckKind::Puback, 10)).await;
+        // the redelivery is acknowledged again but not reported twice
+        peer.send(Packet::Publish(publish(2, Some(11), false)))
+            .await;
+        peer.expect(Packet::ack(AckKind::Pubrec, 11)).await;
+        peer.send(Packet::Publish(publish(2, Some(11), true))).await;
+        peer.expect(Packet::ack(AckKind::Pubrec, 11)).await;
+        peer.send(Packet::ack(AckKind::Pubrel, 11)).await;
+        peer.expect(Packet::ack(AckKind::Pubcomp, 11)).await;
+        delivered.send(()).unwrap();
+
+        peer.expect(Packet::Disconnect {
+            reason_code: 0,
+            properties: Properties::default(),
+        })
+        .await;
+        assert_eq!(peer.recv().await, None);
+    }
+
+    async fn session(version: Version) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (delivered_tx, delivered_rx) = oneshot::channel();
+        let broker = tokio::spawn(broker(listener, version, delivered_tx));
+
+        let url = format!("mqtt://{}", addr);
+        let target = parse_broker(&url, false).unwrap();
+        let options = ConnectOptions {
+            version,
+            client_id: Some("tester".into()),
+            ..ConnectOptions::default()
+        };
+        let connect = connect_packet(&options).unwrap();
+        let stream = TcpStream::connect(&addr).await.unwrap();
+        let local = stream.local_addr().unwrap().to_string();
+        let mut decoder = Decoder::new(mqtt::DEFAULT_MAX_PACKET_SIZE);
+        let (reader, writer, subprotocol, connack) = open(
+            Box::new(stream),
+            &target,
+            None,
+            &connect,
+            version,
+            &mut decoder,
+        )
+        .await
+        .unwrap();
+        assert_eq!(subprotocol, None);
+        assert!(matches!(connack, Packet::ConnAck { reason_code: 0, .. }));
+
+        let app = tauri::test::mock_app();
+        let shared = Arc::new(Shared {
+            app: app.handle().clone(),
+            writer: tokio::sync::Mutex::new(writer),
+            version,
+            pending: Mutex::new(HashMap::new()),
+            next_packet_id: AtomicU16::new(1),
+            subscriptions: Mutex::new(Vec::new()),
+            sock: Mutex::new(None),
+            local,
+            ack_timeout: Duration::from_secs(5),
+            seq: AtomicU64::new(0),
+        });
+        let sid = "mqtt-test".to_string();
+        let cancel = CancellationToken::new();
+        let (registered_tx, registered_rx) = oneshot::channel();
+        let task = tokio::spawn(run(
+            sid.clone(),
+            url.clone(),
+            reader,
+            decoder,
+            0,
+            shared.clone(),
+            cancel.clone(),
+            registered_rx,
+        ));
+        registered_tx.send(()).unwrap();
+
+        let (id, answer) = shared.register().unwrap();
+        let subscribe = Packet::Subscribe {
+            packet_id: id,
+            subscriptions: ["a/+", "deny/#"]
+                .map(|filter| Subscription {
+                    filter: filter.into(),
+                    qos: 1,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: 0,
+                })
+                .to_vec(),
+            properties: Properties::default(),
+        };
+        let (_, reply, _) = shared
+            .exchange(&sid, &url, &subscribe, id, answer)
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            Packet::SubAck {
+                packet_id: id,
+                reason_codes: vec![1, 0x80],
+                properties: Properties::default(),
+            }
+        );
+
+        // QoS 1 is done at PUBACK; QoS 2 at PUBCOMP, with the PUBREL sent by the read task
+        for (qos, done) in [(1, AckKind::Puback), (2, AckKind::Pubcomp)] {
+            let (id, answer) = shared.register().unwrap();
+            let packet = Packet::Publish(publish(qos, Some(id), false));
+            let (_, reply, _) = shared
+                .exchange(&sid, &url, &packet, id, answer)
+                .await
+                .unwrap();
+            assert_eq!(reply, Packet::ack(done, id));
+        }
+
+        delivered_rx.await.unwrap();
+        assert_eq!(shared.seq.load(Ordering::Relaxed), 2);
+        assert!(shared.pending.lock().unwrap().is_empty());
+
+        cancel.cancel();
+        task.await.unwrap();
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn v311_session() {
+        session(Version::V311).await;
+    }
+
+    #[tokio::test]
+    async fn v5_session() {
+        session(Version::V5).await;
+    }
+}
@@ -0,0 +1,239 @@
+use serde::Deserialize;
+use socket2::SockRef;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+use crate::error::{NetError, NetResult};
+use crate::session::{self, Updated};
+
+/// Settings for multicast datagrams sent from a UDP session.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct MulticastOptions {
+    /// IPv4 TTL or IPv6 hop limit.
+    pub ttl: Option<u32>,
+    /// Whether our own multicast datagrams are delivered back to local listeners.
+    pub loopback: Option<bool>,
+    /// Outgoing interface: an IPv4 address, or an IPv6 interface index.
+    pub interface: Option<String>,
+}
+
+pub fn join(session_id: String, group: String, interface: Option<String>) -> NetResult<Updated> {
+    let sock = session::udp_socket(&session_id)?;
+    join_group(&sock, &group, interface.as_deref())?;
+    Ok(Updated {
+        message: format!("joined {}{}", group, on_interface(&interface)),
+        session_id,
+    })
+}
+
+pub fn leave(session_id: String, group: String, interface: Option<String>) -> NetResult<Updated> {
+    let sock = session::udp_socket(&session_id)?;
+    leave_group(&sock, &group, interface.as_deref())?;
+    Ok(Updated {
+        message: format!("left {}{}", group, on_interface(&interface)),
+        session_id,
+    })
+}
+
+pub fn set_options(session_id: String, options: MulticastOptions) -> NetResult<Updated> {
+    let sock = session::udp_socket(&session_id)?;
+    let local = apply_options(&sock, &options)?;
+    Ok(Updated {
+        message: format!("multicast options updated on {}", local),
+        session_id,
+    })
+}
+
+fn join_group(sock: &UdpSocket, group: &str, interface: Option<&str>) -> NetResult<()> {
+    match parse_group(group)? {
+        IpAddr::V4(g) => sock.join_multicast_v4(g, parse_v4_interface(interface)?),
+        IpAddr::V6(g) => sock.join_multicast_v6(&g, parse_v6_interface(interface)?),
+    }
+    .map_err(|e| NetError::io("join multicast", group, e))
+}
+
+fn leave_group(sock: &UdpSocket, group: &str, interface: Option<&str>) -> NetResult<()> {
+    match parse_group(group)? {
+        IpAddr::V4(g) => sock.leave_multicast_v4(g, parse_v4_interface(interface)?),
+        IpAddr::V6(g) => sock.leave_multicast_v6(&g, parse_v6_interface(interface)?),
+    }
+    .map_err(|e| NetError::io("leave multicast", group, e))
+}
+
+/// Returns the socket's local address, for the caller's message.
+fn apply_options(sock: &UdpSocket, options: &MulticastOptions) -> NetResult<SocketAddr> {
+    let local = local_addr(sock)?;
+    let addr = local.to_string();
+    let io_err = |e| NetError::io("set multicast options", &addr, e);
+    let sref = SockRef::from(sock);
+    let v6 = local.is_ipv6();
+
+    if let Some(ttl) = options.ttl {
+        if v6 {
+            sref.set_multicast_hops_v6(ttl).map_err(io_err)?;
+        } else {
+            sref.set_multicast_ttl_v4(ttl).map_err(io_err)?;
+        }
+    }
+    if let Some(lo) = options.loopback {
+        if v6 {
+            sref.set_multicast_loop_v6(lo).map_err(io_err)?;
+        } else {
+            sref.set_multicast_loop_v4(lo).map_err(io_err)?;
+        }
+    }
+    if let Some(iface) = options.interface.as_deref() {
+        if v6 {
+            let index = parse_v6_interface(Some(iface))?;
+            sref.set_multicast_if_v6(index).map_err(io_err)?;
+        } else {
+            let ip = parse_v4_interface(Some(iface))?;
+            sref.set_multicast_if_v4(&ip).map_err(io_err)?;
+        }
+    }
+    Ok(local)
+}
+
+fn local_addr(sock: &UdpSocket) -> NetResult<SocketAddr> {
+    sock.local_addr()
+        .map_err(|e| NetError::internal(format!("local_addr error: {}", e)))
+}
+
+fn parse_group(group: &str) -> NetResult<IpAddr> {
+    match group.parse::<IpAddr>() {
+        Ok(ip) if ip.is_multicast() => Ok(ip),
+        _ => Err(NetError::invalid_input(
+            "multicast",
+            group,
+            format!("{} is not a multicast address", group),
+        )),
+    }
+}
+
+/// IPv4 memberships pick the interface by its address; `None` lets the OS choose.
+fn parse_v4_interface(interface: Option<&str>) -> NetResult<Ipv4Addr> {
+    match interface {
+        None => Ok(Ipv4Addr::UNSPECIFIED),
+        Some(s) => s.parse().map_err(|_| {
+            NetError::invalid_input(
+                "multicast",
+                s,
+                format!("{} is not an IPv4 interface address", s),
+            )
+        }),
+    }
+}
+
+/// IPv6 memberships pick the interface by index; `0` lets the OS choose.
+fn parse_v6_interface(interface: Option<&str>) -> NetResult<u32> {
+    match interface {
+        None => Ok(0),
+        Some(s) => s.parse().map_err(|_| {
+            NetError::invalid_input(
+                "multicast",
+                s,
+                format!("{} is not an IPv6 interface index", s),
+            )
+        }),
+    }
+}
+
+fn on_interface(interface: &Option<String>) -> String {
+    match interface {
+        Some(i) => format!(" on interface {}", i),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use std::time::Duration;
+
+    async fn recv(sock: &UdpSocket, wait_ms: u64) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        match tokio::time::timeout(Duration::from_millis(wait_ms), sock.recv(&mut buf)).await {
+            Ok(Ok(n)) => Some(buf[..n].to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Joins `group` on a receiver, sends to it, then leaves and checks nothing more arrives.
+    async fn join_send_leave(
+        any: &str,
+        sender_bind: &str,
+        group: &str,
+        interface: Option<&str>,
+    ) -> NetResult<()> {
+        let receiver = UdpSocket::bind(any).await.unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        join_group(&receiver, group, interface)?;
+
+        let sender = UdpSocket::bind(sender_bind).await.unwrap();
+        let options = MulticastOptions {
+            ttl: Some(1),
+            loopback: Some(true),
+            interface: interface.map(str::to_string),
+        };
+        apply_options(&sender, &options)?;
+        let to = SocketAddr::new(group.parse().unwrap(), port);
+        sender
+            .send_to(b"joined", to)
+            .await
+            .map_err(|e| NetError::io("send", group, e))?;
+        assert_eq!(recv(&receiver, 1000).await.as_deref(), Some(&b"joined"[..]));
+
+        leave_group(&receiver, group, interface)?;
+        sender
+            .send_to(b"left", to)
+            .await
+            .map_err(|e| NetError::io("send", group, e))?;
+        assert_eq!(recv(&receiver, 200).await, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipv4_loopback() {
+        join_send_leave(
+            "0.0.0.0:0",
+            "127.0.0.1:0",
+            "239.255.77.1",
+            Some("127.0.0.1"),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ipv6_loopback() {
+        // interface-local scope never leaves the host; skipped where IPv6 is unavailable
+        if UdpSocket::bind("[::1]:0").await.is_err() {
+            return;
+        }
+        match join_send_leave("[::]:0", "[::]:0", "ff01::4e44:1", None).await {
+            Ok(()) => {}
+            Err(e) if no_v6_multicast_route(&e) => {}
+            Err(e) => panic!("IPv6 multicast failed: {}", e.message),
+        }
+    }
+
+    /// A host without any route for IPv6 multicast, like a container with only loopback: the
+    /// join finds no device (ENODEV) or the send reports the network unreachable.
+    fn no_v6_multicast_route(e: &NetError) -> bool {
+        const ENODEV: i32 = 19;
+        e.errno == Some(ENODEV)
+            || matches!(&e.code, ErrorCode::Io { kind } if kind == "NetworkUnreachable")
+    }
+
+    #[test]
+    fn bad_input() {
+        assert_eq!(
+            parse_group("10.0.0.1").unwrap_err().message,
+            "multicast error: 10.0.0.1 is not a multicast address"
+        );
+        assert!(parse_v4_interface(Some("eth0")).is_err());
+        assert!(parse_v6_interface(Some("::1")).is_err());
+        assert_eq!(parse_v6_interface(None).unwrap(), 0);
+    }
+}
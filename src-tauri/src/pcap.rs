@@ -0,0 +1,851 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use tauri::async_runtime;
+
+use crate::error::{NetError, NetResult};
+use crate::recorder::{self, Direction, Record, Transport};
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+/// Locally administered MACs for the two ends; Wireshark only needs them to be distinct.
+const MAC_LOCAL: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const MAC_PEER: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+/// Larger TCP chunks are split so every synthesized IP packet stays under 64 KiB.
+const MAX_TCP_SEGMENT: usize = 32 * 1024;
+const TCP_ISN: u32 = 1;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PcapExport {
+    /// Export the active recording of this session.
+    pub session_id: Option<String>,
+    /// Recording files to export, in addition to (or instead of) `session_id`.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Destination `.pcapng` file.
+    pub output: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Exported {
+    pub path: String,
+    pub packets: usize,
+    /// Records whose addresses could not be turned into IP headers (host names, mixed families).
+    pub skipped: usize,
+    pub message: String,
+}
+
+/// Writes the recorded traffic as pcapng. Headers are synthesized from the recorded local and
+/// peer addresses; TCP gets per-direction sequence and ack numbers that follow the payload.
+pub async fn export(req: PcapExport) -> NetResult<Exported> {
+    let mut paths = req.paths;
+    if let Some(id) = &req.session_id {
+        paths.extend(recorder::files(id).await?);
+    }
+    if paths.is_empty() {
+        return Err(NetError::invalid_input(
+            "export",
+            &req.output,
+            "no session_id or recording paths given".into(),
+        ));
+    }
+    let output = req.output;
+    async_runtime::spawn_blocking(move || export_files(&paths, &output))
+        .await
+        .map_err(|e| NetError::internal(format!("export task error: {}", e)))?
+}
+
+fn export_files(paths: &[String], output: &str) -> NetResult<Exported> {
+    let mut records = Vec::new();
+    for p in paths {
+        records.extend(recorder::read_records(p)?);
+    }
+    // rotated files may be listed in any order; the sort is stable within a file
+    records.sort_by_key(|r| r.ts_ms);
+
+    let io_err = |e| NetError::io("write pcapng", output, e);
+    let f = File::create(output).map_err(io_err)?;
+    let mut w = PcapngWriter::new(BufWriter::new(f)).map_err(io_err)?;
+    let mut tcp_next: HashMap<(SocketAddr, SocketAddr), u32> = HashMap::new();
+    let mut packets = 0;
+    let mut skipped = 0;
+
+    for rec in &records {
+        let Some((src, dst, src_mac, dst_mac, payload)) = endpoints(rec) else {
+            skipped += 1;
+            continue;
+        };
+        let ts_us = rec.ts_ms.saturating_mul(1000);
+        match rec.transport {
+            Transport::Udp => {
+                let frame = ethernet(
+                    src_mac,
+                    dst_mac,
+                    src,
+                    dst,
+                    IPPROTO_UDP,
+                    &udp(src, dst, &payload),
+                );
+                w.packet(ts_us, &frame).map_err(io_err)?;
+                packets += 1;
+            }
+            Transport::Tcp => {
+                for chunk in payload.chunks(MAX_TCP_SEGMENT) {
+                    let seq = *tcp_next.entry((src, dst)).or_insert(TCP_ISN);
+                    let ack = *tcp_next.entry((dst, src)).or_insert(TCP_ISN);
+                    tcp_next.insert((src, dst), seq.wrapping_add(chunk.len() as u32));
+                    let segment = tcp(src, dst, seq, ack, chunk);
+                    let frame = ethernet(src_mac, dst_mac, src, dst, IPPROTO_TCP, &segment);
+                    w.packet(ts_us, &frame).map_err(io_err)?;
+                    packets += 1;
+                }
+            }
+        }
+    }
+    w.finish().map_err(io_err)?;
+
+    Ok(Exported {
+        path: output.to_string(),
+        packets,
+        skipped,
+        message: format!("exported {} packet(s) to {}", packets, output),
+    })
+}
+
+type Endpoints = (SocketAddr, SocketAddr, [u8; 6], [u8; 6], Vec<u8>);
+
+/// Source and destination of a record as socket addresses of the same family, plus its bytes.
+fn endpoints(rec: &Record) -> Option<Endpoints> {
+    let local: SocketAddr = rec.local.parse().ok()?;
+    let peer: SocketAddr = rec.peer.parse().ok()?;
+    let (local, peer) = same_family(local, peer)?;
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(&rec.data)
+        .ok()?;
+    Some(match rec.direction {
+        Direction::Tx => (local, peer, MAC_LOCAL, MAC_PEER, data),
+        Direction::Rx => (peer, local, MAC_PEER, MAC_LOCAL, data),
+    })
+}
+
+/// Dual-stack sockets report IPv4 peers as `::ffff:a.b.c.d`; fold those back to IPv4.
+fn same_family(a: SocketAddr, b: SocketAddr) -> Option<(SocketAddr, SocketAddr)> {
+    let fold = |s: SocketAddr| match s.ip() {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), s.port()),
+            None => s,
+        },
+        IpAddr::V4(_) => s,
+    };
+    let (a, b) = if a.is_ipv4() != b.is_ipv4() {
+        (fold(a), fold(b))
+    } else {
+        (a, b)
+    };
+    if a.is_ipv4() != b.is_ipv4() {
+        // an unspecified IPv6 bind talking to an IPv4 peer: use the IPv4 wildcard instead
+        if a.ip().is_unspecified() {
+            return Some((SocketAddr::new(IpAddr::V4(0.into()), a.port()), b));
+        }
+        if b.ip().is_unspecified() {
+            return Some((a, SocketAddr::new(IpAddr::V4(0.into()), b.port())));
+        }
+        return None;
+    }
+    Some((a, b))
+}
+
+fn ethernet(
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    src: SocketAddr,
+    dst: SocketAddr,
+    proto: u8,
+    l4: &[u8],
+) -> Vec<u8> {
+    let mut f = Vec::with_capacity(14 + 40 + l4.len());
+    f.extend_from_slice(&dst_mac);
+    f.extend_from_slice(&src_mac);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            f.extend_from_slice(&0x0800u16.to_be_bytes());
+            let mut ip = [0u8; 20];
+            ip[0] = 0x45;
+            ip[2..4].copy_from_slice(&((20 + l4.len()) as u16).to_be_bytes());
+            ip[6] = 0x40; // don't fragment
+            ip[8] = 64;
+            ip[9] = proto;
+            ip[12..16].copy_from_slice(&s.octets());
+            ip[16..20].copy_from_slice(&d.octets());
+            let sum = checksum(&[&ip]);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            f.extend_from_slice(&ip);
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            f.extend_from_slice(&0x86DDu16.to_be_bytes());
+            let mut ip = [0u8; 40];
+            ip[0] = 0x60;
+            ip[4..6].copy_from_slice(&(l4.len() as u16).to_be_bytes());
+            ip[6] = proto;
+            ip[7] = 64;
+            ip[8..24].copy_from_slice(&s.octets());
+            ip[24..40].copy_from_slice(&d.octets());
+            f.extend_from_slice(&ip);
+        }
+        _ => unreachable!("endpoints() only pairs addresses of one family"),
+    }
+    f.extend_from_slice(l4);
+    f
+}
+
+fn udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut seg = Vec::with_capacity(8 + payload.len());
+    seg.extend_from_slice(&src.port().to_be_bytes());
+    seg.extend_from_slice(&dst.port().to_be_bytes());
+    seg.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    seg.extend_from_slice(&[0, 0]);
+    seg.extend_from_slice(payload);
+    let sum = match l4_checksum(src, dst, IPPROTO_UDP, &seg) {
+        0 => 0xFFFF,
+        s => s,
+    };
+    seg[6..8].copy_from_slice(&sum.to_be_bytes());
+    seg
+}
+
+fn tcp(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let mut seg = Vec::with_capacity(20 + payload.len());
+    seg.extend_from_slice(&src.port().to_be_bytes());
+    seg.extend_from_slice(&dst.port().to_be_bytes());
+    seg.extend_from_slice(&seq.to_be_bytes());
+    seg.extend_from_slice(&ack.to_be_bytes());
+    seg.push(5 << 4); // 20-byte header, no options
+    seg.push(0x18); // PSH | ACK
+    seg.extend_from_slice(&0xFFFFu16.to_be_bytes()); // window
+    seg.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+    seg.extend_from_slice(payload);
+    let sum = l4_checksum(src, dst, IPPROTO_TCP, &seg);
+    seg[16..18].copy_from_slice(&sum.to_be_bytes());
+    seg
+}
+
+/// TCP/UDP checksum including the IPv4 or IPv6 pseudo-header.
+fn l4_checksum(src: SocketAddr, dst: SocketAddr, proto: u8, seg: &[u8]) -> u16 {
+    let len = seg.len() as u32;
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let mut pseudo = [0u8; 12];
+            pseudo[0..4].copy_from_slice(&s.octets());
+            pseudo[4..8].copy_from_slice(&d.octets());
+            pseudo[9] = proto;
+            pseudo[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+            checksum(&[&pseudo, seg])
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let mut pseudo = [0u8; 40];
+            pseudo[0..16].copy_from_slice(&s.octets());
+            pseudo[16..32].copy_from_slice(&d.octets());
+            pseudo[32..36].copy_from_slice(&len.to_be_bytes());
+            pseudo[39] = proto;
+            checksum(&[&pseudo, seg])
+        }
+        _ => 0,
+    }
+}
+
+/// Internet checksum (RFC 1071) over the concatenation of `parts`; each part but the last
+/// must have even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        let mut words = part.chunks_exact(2);
+        for w in &mut words {
+            sum += u16::from_be_bytes([w[0], w[1]]) as u32;
+        }
+        if let [last] = words.remainder() {
+            sum += (*last as u32) << 8;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A UDP datagram or TCP segment payload pulled out of a capture file.
+#[derive(Clone, Debug)]
+pub struct CapturedPacket {
+    pub ts_us: u64,
+    pub transport: Transport,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Reads every UDP datagram and non-empty TCP segment from a pcap or pcapng file. Frames that
+/// are not IP, are fragments, or use an unknown link type are skipped.
+pub fn read_packets(path: &str) -> NetResult<Vec<CapturedPacket>> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| NetError::io("read capture", path, e))?;
+    let bad = |msg: &str| {
+        NetError::io(
+            "read capture",
+            path,
+            io::Error::new(io::ErrorKind::InvalidData, msg.to_string()),
+        )
+    };
+    if buf.len() < 4 {
+        return Err(bad("file too short"));
+    }
+    let mut out = Vec::new();
+    if buf[0..4] == [0x0A, 0x0D, 0x0D, 0x0A] {
+        read_pcapng(&buf, &mut out).ok_or_else(|| bad("truncated or malformed pcapng"))?;
+    } else {
+        read_pcap(&buf, &mut out).ok_or_else(|| bad("not a pcap or pcapng file"))?;
+    }
+    Ok(out)
+}
+
+/// Byte-order-aware reads from a capture buffer.
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, b: &[u8], at: usize) -> Option<u16> {
+        let v: [u8; 2] = b.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big {
+            u16::from_be_bytes(v)
+        } else {
+            u16::from_le_bytes(v)
+        })
+    }
+
+    fn u32(self, b: &[u8], at: usize) -> Option<u32> {
+        let v: [u8; 4] = b.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big {
+            u32::from_be_bytes(v)
+        } else {
+            u32::from_le_bytes(v)
+        })
+    }
+}
+
+fn read_pcap(buf: &[u8], out: &mut Vec<CapturedPacket>) -> Option<()> {
+    let (e, nanos) = match buf.get(0..4)? {
+        [0xD4, 0xC3, 0xB2, 0xA1] => (Endian { big: false }, false),
+        [0xA1, 0xB2, 0xC3, 0xD4] => (Endian { big: true }, false),
+        [0x4D, 0x3C, 0xB2, 0xA1] => (Endian { big: false }, true),
+        [0xA1, 0xB2, 0x3C, 0x4D] => (Endian { big: true }, true),
+        _ => return None,
+    };
+    let linktype = e.u32(buf, 20)? as u16;
+    let mut at = 24;
+    while at + 16 <= buf.len() {
+        let sec = e.u32(buf, at)? as u64;
+        let frac = e.u32(buf, at + 4)? as u64;
+        let caplen = e.u32(buf, at + 8)? as usize;
+        let data = buf.get(at + 16..at + 16 + caplen)?;
+        let ts_us = sec * 1_000_000 + if nanos { frac / 1000 } else { frac };
+        out.extend(decode_link(linktype, data, ts_us));
+        at += 16 + caplen;
+    }
+    // a record header cut short is as truncated as a record
+    (at == buf.len()).then_some(())
+}
+
+fn read_pcapng(buf: &[u8], out: &mut Vec<CapturedPacket>) -> Option<()> {
+    let mut e = Endian { big: false };
+    // link type and if_tsresol of each interface in the current section
+    let mut ifaces: Vec<(u16, u8)> = Vec::new();
+    let mut at = 0;
+    while at + 12 <= buf.len() {
+        if buf[at..at + 4] == [0x0A, 0x0D, 0x0D, 0x0A] {
+            // section header: byte order may change, interface ids start over
+            e = match buf.get(at + 8..at + 12)? {
+                [0x4D, 0x3C, 0x2B, 0x1A] => Endian { big: false },
+                [0x1A, 0x2B, 0x3C, 0x4D] => Endian { big: true },
+                _ => return None,
+            };
+            ifaces.clear();
+        }
+        let btype = e.u32(buf, at)?;
+        let len = e.u32(buf, at + 4)? as usize;
+        if len < 12 || at + len > buf.len() {
+            return None;
+        }
+        let body = &buf[at + 8..at + len - 4];
+        match btype {
+            // interface description
+            1 => {
+                let linktype = e.u16(body, 0)?;
+                let mut tsresol = 6;
+                let mut o = 8;
+                while o + 4 <= body.len() {
+                    let code = e.u16(body, o)?;
+                    let olen = e.u16(body, o + 2)? as usize;
+                    if code == 0 {
+                        break;
+                    }
+                    if code == 9 && olen == 1 {
+                        tsresol = *body.get(o + 4)?;
+                    }
+                    o += 4 + olen.div_ceil(4) * 4;
+                }
+                ifaces.push((linktype, tsresol));
+            }
+            // enhanced packet
+            6 => {
+                let (linktype, tsresol) = *ifaces.get(e.u32(body, 0)? as usize)?;
+                let ts = (e.u32(body, 4)? as u64) << 32 | e.u32(body, 8)? as u64;
+                let caplen = e.u32(body, 12)? as usize;
+                let data = body.get(20..20 + caplen)?;
+                out.extend(decode_link(linktype, data, to_micros(ts, tsresol)));
+            }
+            // simple packet: interface 0, no timestamp
+            3 => {
+                let (linktype, _) = *ifaces.first()?;
+                let origlen = e.u32(body, 0)? as usize;
+                let data = body.get(4..(4 + origlen).min(body.len()))?;
+                out.extend(decode_link(linktype, data, 0));
+            }
+            _ => {}
+        }
+        at += len;
+    }
+    (at == buf.len()).then_some(())
+}
+
+/// Converts a pcapng timestamp to microseconds; `tsresol` is a negative power of 10, or of 2
+/// when the high bit is set.
+fn to_micros(ts: u64, tsresol: u8) -> u64 {
+    let exp = (tsresol & 0x7F) as u32;
+    if tsresol & 0x80 != 0 {
+        ((ts as u128 * 1_000_000) >> exp.min(127)) as u64
+    } else if exp >= 6 {
+        ts / 10u64.saturating_pow(exp - 6)
+    } else {
+        ts.saturating_mul(10u64.pow(6 - exp))
+    }
+}
+
+fn decode_link(linktype: u16, data: &[u8], ts_us: u64) -> Option<CapturedPacket> {
+    let ip = match linktype {
+        // BSD loopback / OpenBSD loop: 4-byte address family
+        0 | 109 => data.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            // 802.1Q and QinQ tags
+            while matches!(be16(data, at)?, 0x8100 | 0x88A8) {
+                at += 4;
+            }
+            data.get(at + 2..)?
+        }
+        // raw IP, IPv4, IPv6
+        12 | 14 | 101 | 228 | 229 => data,
+        // Linux cooked capture v1 and v2
+        113 => data.get(16..)?,
+        276 => data.get(20..)?,
+        _ => return None,
+    };
+    decode_ip(ip, ts_us)
+}
+
+fn decode_ip(ip: &[u8], ts_us: u64) -> Option<CapturedPacket> {
+    let (src_ip, dst_ip, mut proto, mut l4): (IpAddr, IpAddr, u8, &[u8]) = match ip.first()? >> 4 {
+        4 => {
+            let ihl = (ip[0] & 0x0F) as usize * 4;
+            let total = (be16(ip, 2)? as usize).min(ip.len());
+            // more-fragments flag or a fragment offset
+            if be16(ip, 6)? & 0x3FFF != 0 {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            (src.into(), dst.into(), ip[9], ip.get(ihl..total)?)
+        }
+        6 => {
+            let end = (40 + be16(ip, 4)? as usize).min(ip.len());
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            (src.into(), dst.into(), *ip.get(6)?, ip.get(40..end)?)
+        }
+        _ => return None,
+    };
+    // IPv6 hop-by-hop, routing and destination options; fragments (44) fall through and are dropped
+    while matches!(proto, 0 | 43 | 60) && src_ip.is_ipv6() {
+        let next = *l4.first()?;
+        l4 = l4.get((*l4.get(1)? as usize + 1) * 8..)?;
+        proto = next;
+    }
+    let (transport, payload) = match proto {
+        IPPROTO_UDP => {
+            let end = (be16(l4, 4)? as usize).min(l4.len());
+            (Transport::Udp, l4.get(8..end)?)
+        }
+        IPPROTO_TCP => {
+            let off = (*l4.get(12)? >> 4) as usize * 4;
+            (Transport::Tcp, l4.get(off..)?)
+        }
+        _ => return None,
+    };
+    // pure ACKs and handshakes carry nothing to replay
+    if transport == Transport::Tcp && payload.is_empty() {
+        return None;
+    }
+    Some(CapturedPacket {
+        ts_us,
+        transport,
+        src: SocketAddr::new(src_ip, be16(l4, 0)?),
+        dst: SocketAddr::new(dst_ip, be16(l4, 2)?),
+        payload: payload.to_vec(),
+    })
+}
+
+fn be16(b: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(b.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// Minimal pcapng writer: one section, one Ethernet interface, enhanced packet blocks with
+/// microsecond timestamps (the default resolution).
+struct PcapngWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    fn new(mut out: W) -> io::Result<Self> {
+        // section header block
+        let mut shb = Vec::with_capacity(28);
+        shb.extend_from_slice(&0x0A0D0D0Au32.to_le_bytes());
+        shb.extend_from_slice(&28u32.to_le_bytes());
+        shb.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+        shb.extend_from_slice(&28u32.to_le_bytes());
+        out.write_all(&shb)?;
+
+        // interface description block
+        let mut idb = Vec::with_capacity(20);
+        idb.extend_from_slice(&1u32.to_le_bytes());
+        idb.extend_from_slice(&20u32.to_le_bytes());
+        idb.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes()); // no snap length limit
+        idb.extend_from_slice(&20u32.to_le_bytes());
+        out.write_all(&idb)?;
+
+        Ok(PcapngWriter { out })
+    }
+
+    fn packet(&mut self, ts_us: u64, data: &[u8]) -> io::Result<()> {
+        let padded = data.len().div_ceil(4) * 4;
+        let total = (32 + padded) as u32;
+        let mut epb = Vec::with_capacity(total as usize);
+        epb.extend_from_slice(&6u32.to_le_bytes());
+        epb.extend_from_slice(&total.to_le_bytes());
+        epb.extend_from_slice(&0u32.to_le_bytes()); // interface 0
+        epb.extend_from_slice(&((ts_us >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts_us as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        epb.resize(28 + padded, 0);
+        epb.extend_from_slice(&total.to_le_bytes());
+        self.out.write_all(&epb)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("netdebugger-pcap-{}-{}", std::process::id(), name))
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// The frames of every enhanced packet block, with their timestamps.
+    fn epb_frames(buf: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let e = Endian { big: false };
+        let mut out = Vec::new();
+        let mut at = 0;
+        while at < buf.len() {
+            let len = e.u32(buf, at).and(e.u32(buf, at + 4)).unwrap() as usize;
+            assert_eq!(e.u32(buf, at + len - 4), Some(len as u32));
+            if e.u32(buf, at) == Some(6) {
+                let ts = (e.u32(buf, at + 12).unwrap() as u64) << 32
+                    | e.u32(buf, at + 16).unwrap() as u64;
+                let caplen = e.u32(buf, at + 20).unwrap() as usize;
+                out.push((ts, buf[at + 28..at + 28 + caplen].to_vec()));
+            }
+            at += len;
+        }
+        out
+    }
+
+    #[test]
+    fn pcapng_round_trip() {
+        let path = temp("round-trip.pcapng");
+        let v4 = (addr("10.0.0.1:5000"), addr("10.0.0.2:502"));
+        let v6 = (addr("[2001:db8::1]:4000"), addr("[2001:db8::2]:53"));
+        let frames = [
+            (
+                1_000_000,
+                ethernet(
+                    MAC_LOCAL,
+                    MAC_PEER,
+                    v4.0,
+                    v4.1,
+                    IPPROTO_TCP,
+                    &tcp(v4.0, v4.1, 1, 1, b"hello"),
+                ),
+            ),
+            (
+                (1u64 << 32) + 7,
+                ethernet(
+                    MAC_PEER,
+                    MAC_LOCAL,
+                    v6.1,
+                    v6.0,
+                    IPPROTO_UDP,
+                    &udp(v6.1, v6.0, b"odd"),
+                ),
+            ),
+            // a pure ACK is not reported
+            (
+                2_000_000,
+                ethernet(
+                    MAC_LOCAL,
+                    MAC_PEER,
+                    v4.0,
+                    v4.1,
+                    IPPROTO_TCP,
+                    &tcp(v4.0, v4.1, 6, 1, b""),
+                ),
+            ),
+            (
+                3_000_000,
+                ethernet(
+                    MAC_LOCAL,
+                    MAC_PEER,
+                    v4.0,
+                    v4.1,
+                    IPPROTO_UDP,
+                    &udp(v4.0, v4.1, b""),
+                ),
+            ),
+        ];
+        let mut w = PcapngWriter::new(File::create(&path).unwrap()).unwrap();
+        for (ts, frame) in &frames {
+            w.packet(*ts, frame).unwrap();
+        }
+        w.finish().unwrap();
+
+        let buf = std::fs::read(&path).unwrap();
+        // blocks are padded to 32 bits and the frames come back byte for byte
+        assert_eq!(buf.len() % 4, 0);
+        let written: Vec<(u64, Vec<u8>)> = frames.to_vec();
+        assert_eq!(epb_frames(&buf), written);
+
+        let packets = read_packets(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(packets.len(), 3);
+        let p = &packets[0];
+        assert_eq!(
+            (p.ts_us, p.transport, p.src, p.dst),
+            (1_000_000, Transport::Tcp, v4.0, v4.1)
+        );
+        assert_eq!(p.payload, b"hello");
+        let p = &packets[1];
+        assert_eq!(
+            (p.ts_us, p.transport, p.src, p.dst),
+            ((1u64 << 32) + 7, Transport::Udp, v6.1, v6.0)
+        );
+        assert_eq!(p.payload, b"odd");
+        assert!(packets[2].payload.is_empty());
+        assert_eq!(packets[2].transport, Transport::Udp);
+    }
+
+    #[test]
+    fn read_rejects_garbage() {
+        let path = temp("garbage.pcap");
+        let mut pcap = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0];
+        pcap.resize(24, 0);
+        pcap[20] = LINKTYPE_ETHERNET as u8;
+        std::fs::write(&path, &pcap).unwrap();
+        assert!(read_packets(path.to_str().unwrap()).unwrap().is_empty());
+        // a record header cut short
+        pcap.extend_from_slice(&[0; 10]);
+        for bytes in [
+            &b"abc"[..],
+            b"not a capture file",
+            &[0x0A, 0x0D, 0x0D, 0x0A, 0xFF],
+            &pcap[..20],
+            &pcap,
+        ] {
+            std::fs::write(&path, bytes).unwrap();
+            assert!(read_packets(path.to_str().unwrap()).is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn record(
+        ts_ms: u64,
+        direction: Direction,
+        transport: Transport,
+        local: &str,
+        peer: &str,
+        data: &[u8],
+    ) -> String {
+        let rec = Record {
+            ts_ms,
+            session: "s".into(),
+            direction,
+            transport,
+            local: local.into(),
+            peer: peer.into(),
+            seq: 0,
+            len: data.len(),
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+        };
+        serde_json::to_string(&rec).unwrap()
+    }
+
+    #[test]
+    fn export_follows_tcp_sequence_numbers() {
+        let input = temp("tcp.jsonl");
+        let output = temp("tcp.pcapng");
+        let (local, peer) = ("192.168.1.10:40000", "192.168.1.20:8080");
+        let lines = [
+            record(10, Direction::Tx, Transport::Tcp, local, peer, b"GET /"),
+            record(20, Direction::Rx, Transport::Tcp, local, peer, b"200"),
+            record(30, Direction::Tx, Transport::Tcp, local, peer, b"next"),
+            record(40, Direction::Rx, Transport::Tcp, local, peer, b"ok"),
+            // records are sorted by time across the files
+            record(
+                5,
+                Direction::Rx,
+                Transport::Tcp,
+                local,
+                "peer.example:80",
+                b"?",
+            ),
+        ];
+        std::fs::write(&input, lines.join("\n")).unwrap();
+        let exported = export_files(
+            &[input.to_str().unwrap().to_string()],
+            output.to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!((exported.packets, exported.skipped), (4, 1));
+
+        let frames = epb_frames(&std::fs::read(&output).unwrap());
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        let expected = [
+            // timestamp, source port, seq, ack, payload
+            (10_000, 40000, 1, 1, &b"GET /"[..]),
+            (20_000, 8080, 1, 6, b"200"),
+            (30_000, 40000, 6, 4, b"next"),
+            (40_000, 8080, 4, 10, b"ok"),
+        ];
+        assert_eq!(frames.len(), expected.len());
+        for ((ts, frame), (ts_us, sport, seq, ack, payload)) in frames.iter().zip(expected) {
+            assert_eq!(*ts, ts_us);
+            // Ethernet: the sender's MAC second, IPv4
+            let from_us = sport == 40000;
+            assert_eq!(frame[6..12], if from_us { MAC_LOCAL } else { MAC_PEER });
+            assert_eq!(frame[12..14], [0x08, 0x00]);
+            let ip = &frame[14..34];
+            assert_eq!(checksum(&[ip]), 0);
+            let seg = &frame[34..];
+            assert_eq!(be16(seg, 0), Some(sport));
+            assert_eq!(u32::from_be_bytes(seg[4..8].try_into().unwrap()), seq);
+            assert_eq!(u32::from_be_bytes(seg[8..12].try_into().unwrap()), ack);
+            assert_eq!(&seg[20..], payload);
+            let (src, dst) = match from_us {
+                true => (addr(local), addr(peer)),
+                false => (addr(peer), addr(local)),
+            };
+            assert_eq!(l4_checksum(src, dst, IPPROTO_TCP, seg), 0);
+        }
+    }
+
+    #[test]
+    fn udp_over_ipv6() {
+        let src = addr("[fe80::1]:5353");
+        let dst = addr("[ff02::fb]:5353");
+        let payload = b"query";
+        let frame = ethernet(
+            MAC_LOCAL,
+            MAC_PEER,
+            src,
+            dst,
+            IPPROTO_UDP,
+            &udp(src, dst, payload),
+        );
+        assert_eq!(frame.len(), 14 + 40 + 8 + payload.len());
+        assert_eq!(frame[12..14], [0x86, 0xDD]);
+        let ip = &frame[14..54];
+        assert_eq!(ip[0] >> 4, 6);
+        assert_eq!(be16(ip, 4), Some(8 + payload.len() as u16));
+        assert_eq!((ip[6], ip[7]), (IPPROTO_UDP, 64));
+        assert_eq!(
+            ip[8..24],
+            match src.ip() {
+                IpAddr::V6(v6) => v6.octets(),
+                IpAddr::V4(_) => unreachable!(),
+            }
+        );
+        let seg = &frame[54..];
+        assert_eq!(be16(seg, 0), Some(5353));
+        assert_eq!(be16(seg, 4), Some(8 + payload.len() as u16));
+        assert_ne!(be16(seg, 6), Some(0));
+        assert_eq!(l4_checksum(src, dst, IPPROTO_UDP, seg), 0);
+        assert_eq!(&seg[8..], payload);
+
+        let p = decode_link(LINKTYPE_ETHERNET, &frame, 9).unwrap();
+        assert_eq!((p.src, p.dst, p.transport), (src, dst, Transport::Udp));
+        assert_eq!(p.payload, payload);
+    }
+
+    #[test]
+    fn mapped_addresses_fold_to_ipv4() {
+        assert_eq!(
+            same_family(addr("[::ffff:10.0.0.1]:1"), addr("10.0.0.2:2")),
+            Some((addr("10.0.0.1:1"), addr("10.0.0.2:2")))
+        );
+        assert_eq!(
+            same_family(addr("[::]:1"), addr("10.0.0.2:2")),
+            Some((addr("0.0.0.0:1"), addr("10.0.0.2:2")))
+        );
+        assert_eq!(
+            same_family(addr("[2001:db8::1]:1"), addr("10.0.0.2:2")),
+            None
+        );
+    }
+
+    #[test]
+    fn timestamp_resolutions() {
+        assert_eq!(to_micros(1_500_000, 6), 1_500_000);
+        assert_eq!(to_micros(1_500_000_000, 9), 1_500_000);
+        assert_eq!(to_micros(15, 1), 1_500_000);
+        assert_eq!(to_micros(3 << 20, 0x80 | 20), 3_000_000);
+    }
+}
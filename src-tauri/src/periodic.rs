@@ -0,0 +1,293 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use tauri::async_runtime;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::jobs::{self, JobInfo, JobKind};
+use crate::session::{self, SessionKind};
+use crate::template::Payload;
+use crate::{tcp_client, tcp_server, udp_client, udp_server};
+
+/// Minimum gap between two `periodic:progress` events, so fast schedules don't flood the UI.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PeriodicSend {
+    pub session_id: String,
+    /// Destination for UDP sessions, or a single peer of a TCP server (all peers when unset).
+    /// Ignored for TCP clients.
+    pub target: Option<String>,
+    /// `data_b64` or `template`; templates are rendered again for every send.
+    #[serde(flatten)]
+    pub payload: Payload,
+    pub interval_ms: u64,
+    /// Number of sends; runs until stopped when unset.
+    pub count: Option<u64>,
+    /// Wrap each payload with the session's framer.
+    #[serde(default)]
+    pub framed: bool,
+}
+
+/// How late sends were against their schedule, in microseconds.
+#[derive(Clone, Copy, Debug, Default)]
+struct Jitter {
+    last: u64,
+    min: u64,
+    max: u64,
+    total: u64,
+    samples: u64,
+}
+
+impl Jitter {
+    fn add(&mut self, late_us: u64) {
+        self.min = if self.samples == 0 {
+            late_us
+        } else {
+            self.min.min(late_us)
+        };
+        self.max = self.max.max(late_us);
+        self.last = late_us;
+        self.total += late_us;
+        self.samples += 1;
+    }
+
+    fn json(&self) -> serde_json::Value {
+        json!({
+            "last_us": self.last,
+            "min_us": self.min,
+            "max_us": self.max,
+            "mean_us": self.total.checked_div(self.samples).unwrap_or(0),
+        })
+    }
+}
+
+pub fn start(app: AppHandle, req: PeriodicSend) -> NetResult<JobInfo> {
+    let info = session::get(&req.session_id)?;
+    req.payload.check()?;
+    if req.interval_ms == 0 {
+        return Err(NetError::invalid_input(
+            "periodic send",
+            &info.addr,
+            "interval_ms must be at least 1".into(),
+        ));
+    }
+    let target = match (info.kind, &req.target) {
+        (
+            SessionKind::TcpProxy
+            | SessionKind::UdpProxy
+            | SessionKind::WsServer
+            | SessionKind::WsClient
+            | SessionKind::ModbusClient
+            | SessionKind::MqttClient,
+            _,
+        ) => {
+            return Err(NetError::unsupported(format!(
+                "periodic sends need a TCP or UDP server or client, not a {}",
+                info.kind.label()
+            )));
+        }
+        (SessionKind::UdpServer | SessionKind::UdpClient, None) => {
+            return Err(NetError::invalid_input(
+                "periodic send",
+                &info.addr,
+                "UDP sessions need a target address".into(),
+            ));
+        }
+        (SessionKind::TcpClient, _) => None,
+        (_, t) => t.clone(),
+    };
+
+    let id = jobs::next_id();
+    let cancel = CancellationToken::new();
+    let (pause_tx, pause_rx) = watch::channel(false);
+    let (registered_tx, registered_rx) = oneshot::channel::<()>();
+    let sender = Sender {
+        app,
+        job: id.clone(),
+        session: info.id.clone(),
+        kind: info.kind,
+        addr: info.addr.clone(),
+        target,
+        payload: req.payload,
+        framed: req.framed,
+    };
+    let interval = Duration::from_millis(req.interval_ms);
+    let count = req.count;
+    let task = {
+        let cancel = cancel.clone();
+        async_runtime::spawn(async move {
+            // don't finish (and unregister) before start() has registered the job
+            if registered_rx.await.is_err() {
+                return;
+            }
+            sender.run(interval, count, pause_rx, cancel).await;
+            jobs::finished(&sender.job);
+        })
+    };
+    let info = jobs::insert(
+        id,
+        JobKind::PeriodicSend,
+        info.id,
+        cancel,
+        task,
+        Some(pause_tx),
+    )?;
+    let _ = registered_tx.send(());
+    Ok(info)
+}
+
+struct Sender {
+    app: AppHandle,
+    job: String,
+    session: String,
+    kind: SessionKind,
+    /// Bind or remote address of the session, as the send functions look it up.
+    addr: String,
+    target: Option<String>,
+    payload: Payload,
+    framed: bool,
+}
+
+impl Sender {
+    async fn run(
+        &self,
+        interval: Duration,
+        count: Option<u64>,
+        mut paused: watch::Receiver<bool>,
+        cancel: CancellationToken,
+    ) {
+        let started = Instant::now();
+        let mut due = started;
+        let mut last_progress: Option<Instant> = None;
+        let mut jitter = Jitter::default();
+        let (mut sent, mut missed) = (0u64, 0u64);
+        let mut cancelled = false;
+
+        while count.is_none_or(|c| sent < c) {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    cancelled = true;
+                    break;
+                }
+                _ = tokio::time::sleep_until(due) => {}
+            }
+            if *paused.borrow() {
+                let payload = json!({"session": self.session, "job": self.job, "sent": sent});
+                let _ = self.app.emit("periodic:paused", payload);
+                tokio::select! {
+                    _ = cancel.cancelled() => {
+                        cancelled = true;
+                        break;
+                    }
+                    r = paused.wait_for(|p| !*p) => {
+                        if r.is_err() {
+                            break;
+                        }
+                    }
+                }
+                let payload = json!({"session": self.session, "job": self.job, "sent": sent});
+                let _ = self.app.emit("periodic:resumed", payload);
+                // restart the schedule instead of bursting the ticks missed while paused
+                due = Instant::now();
+                continue;
+            }
+
+            let now = Instant::now();
+            jitter.add(now.saturating_duration_since(due).as_micros() as u64);
+            if let Err(e) = self.send().await {
+                let payload = json!({
+                    "session": self.session,
+                    "job": self.job,
+                    "sent": sent,
+                    "error": e.message,
+                });
+                let _ = self.app.emit("periodic:error", payload);
+                break;
+            }
+            sent += 1;
+
+            // stay on the original grid; when a whole interval or more was lost (slow send,
+            // suspended machine), skip those ticks rather than sending them in a burst
+            due += interval;
+            let now = Instant::now();
+            if now >= due + interval {
+                let behind = ((now - due).as_nanos() / interval.as_nanos()) as u32;
+                missed += behind as u64;
+                due += interval * behind;
+            }
+
+            let last_tick = count.is_some_and(|c| sent >= c);
+            if last_tick || last_progress.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL) {
+                last_progress = Some(Instant::now());
+                let payload = json!({
+                    "session": self.session,
+                    "job": self.job,
+                    "sent": sent,
+                    "count": count,
+                    "missed": missed,
+                    "jitter": jitter.json(),
+                    "elapsed_ms": started.elapsed().as_millis() as u64,
+                });
+                let _ = self.app.emit("periodic:progress", payload);
+            }
+        }
+
+        let payload = json!({
+            "session": self.session,
+            "job": self.job,
+            "sent": sent,
+            "count": count,
+            "missed": missed,
+            "jitter": jitter.json(),
+            "cancelled": cancelled,
+            "elapsed_ms": started.elapsed().as_millis() as u64,
+        });
+        let _ = self.app.emit("periodic:done", payload);
+    }
+
+    async fn send(&self) -> NetResult<()> {
+        // the UDP send functions fall back to a temporary socket; don't outlive the session
+        session::get(&self.session)?;
+        let target = self.target.as_deref();
+        let data = self.payload.bytes()?;
+        match self.kind {
+            SessionKind::UdpServer => {
+                udp_server::send_bytes_from(
+                    &self.addr,
+                    target.unwrap_or_default(),
+                    &data,
+                    self.framed,
+                )
+                .await?;
+            }
+            SessionKind::UdpClient => {
+                udp_client::send_bytes_from(
+                    &self.addr,
+                    target.unwrap_or_default(),
+                    &data,
+                    self.framed,
+                )
+                .await?;
+            }
+            SessionKind::TcpServer => {
+                tcp_server::send_bytes(&self.addr, target, data, self.framed).await?;
+            }
+            SessionKind::TcpClient => {
+                tcp_client::send_bytes(&self.addr, data, self.framed).await?;
+            }
+            // rejected in start()
+            SessionKind::TcpProxy
+            | SessionKind::UdpProxy
+            | SessionKind::WsServer
+            | SessionKind::WsClient
+            | SessionKind::ModbusClient
+            | SessionKind::MqttClient => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,749 @@
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::async_runtime::{self, JoinHandle};
+use tauri::{AppHandle, Emitter, Runtime, Wry};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::error::{NetError, NetResult};
+use crate::impair::{self, Way};
+use crate::recorder::{self, Direction};
+use crate::session::{self, SessionHandle, SessionKind, Started};
+use crate::sockopt::{self, BindOptions, SocketOptions};
+
+/// UDP mappings with no traffic in either direction for this long are dropped.
+const DEFAULT_UDP_IDLE_MS: u64 = 60_000;
+/// Chunks read but not yet written, per direction of a TCP connection.
+const PUMP_QUEUE: usize = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProxyOptions {
+    /// TCP only; defaults to the TCP client's connect timeout.
+    pub connect_timeout_ms: Option<u64>,
+    /// UDP only: how long a client's upstream socket is kept without traffic.
+    pub udp_idle_timeout_ms: Option<u64>,
+    pub socket_options: Option<SocketOptions>,
+}
+
+/// Which way a relayed chunk travelled, tagged on every `proxy:message`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flow {
+    ToUpstream,
+    ToClient,
+}
+
+impl Flow {
+    /// Towards the upstream is the proxy's outbound side.
+    fn way(self) -> Way {
+        match self {
+            Flow::ToUpstream => Way::Outbound,
+            Flow::ToClient => Way::Inbound,
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Flow::ToUpstream => "to_upstream",
+            Flow::ToClient => "to_client",
+        }
+    }
+}
+
+pub struct ProxyHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    listener: Option<socket2::Socket>,
+}
+
+impl ProxyHandle {
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+
+    pub fn sockets(&self) -> Vec<(String, socket2::Socket)> {
+        self.listener
+            .as_ref()
+            .and_then(|l| l.try_clone().ok())
+            .map(|s| vec![("listener".to_string(), s)])
+            .unwrap_or_default()
+    }
+}
+
+/// State shared by every connection or mapping of one proxy.
+struct Shared<R: Runtime = Wry> {
+    app: AppHandle<R>,
+    sid: String,
+    addr: String,
+    upstream: String,
+    seq: AtomicU64,
+}
+
+impl<R: Runtime> Shared<R> {
+    /// Emits a relayed chunk with the fields of `tcp:server:message` plus its direction, and
+    /// records it from the client's point of view.
+    fn relayed(&self, client: &str, flow: Flow, data: &[u8]) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let ts_ms = session::now_ms();
+        let (from, to) = match flow {
+            Flow::ToUpstream => (client, self.upstream.as_str()),
+            Flow::ToClient => (self.upstream.as_str(), client),
+        };
+        let payload = json!({
+            "session": self.sid,
+            "bind": self.addr,
+            "peer": client,
+            "from": from,
+            "to": to,
+            "direction": flow.tag(),
+            "data": base64::engine::general_purpose::STANDARD.encode(data),
+            "seq": seq,
+            "ts_ms": ts_ms,
+        });
+        let _ = self.app.emit("proxy:message", payload);
+        let direction = match flow {
+            Flow::ToUpstream => Direction::Rx,
+            Flow::ToClient => Direction::Tx,
+        };
+        recorder::record(&self.sid, direction, &self.addr, client, seq, ts_ms, data);
+    }
+
+    fn error(&self, client: Option<&str>, error: String) {
+        let payload =
+            json!({"session": self.sid, "bind": self.addr, "peer": client, "error": error});
+        let _ = self.app.emit("proxy:error", payload);
+    }
+}
+
+pub async fn start(
+    app: AppHandle,
+    protocol: ProxyProtocol,
+    listen_addr: String,
+    upstream_addr: String,
+    options: Option<ProxyOptions>,
+) -> NetResult<Started> {
+    let kind = match protocol {
+        ProxyProtocol::Tcp => SessionKind::TcpProxy,
+        ProxyProtocol::Udp => SessionKind::UdpProxy,
+    };
+    if session::registry()?.contains(kind, &listen_addr) {
+        return Err(NetError::already_running(kind, &listen_addr));
+    }
+    let options = options.unwrap_or_default();
+    let socket_options = options.socket_options.clone().unwrap_or_default();
+
+    let id = session::next_id();
+    let cancel = CancellationToken::new();
+    let shared = Arc::new(Shared {
+        app,
+        sid: id.clone(),
+        addr: listen_addr.clone(),
+        upstream: upstream_addr.clone(),
+        seq: AtomicU64::new(0),
+    });
+    let (task, listener) = match protocol {
+        ProxyProtocol::Tcp => {
+            let listener = sockopt::bind_tcp(&listen_addr, &socket_options)
+                .await
+                .map_err(|e| NetError::io("bind", &listen_addr, e))?;
+            let dup = sockopt::dup(&listener).ok();
+            let timeout = Duration::from_millis(
+                options
+                    .connect_timeout_ms
+                    .unwrap_or(crate::tcp_client::DEFAULT_CONNECT_TIMEOUT_MS),
+            );
+            let task = async_runtime::spawn(tcp_accept_loop(
+                shared,
+                listener,
+                timeout,
+                socket_options,
+                cancel.clone(),
+            ));
+            (task, dup)
+        }
+        ProxyProtocol::Udp => {
+            // resolved once here; a lookup per new client would stall every mapping's datagrams
+            let upstream = tokio::net::lookup_host(&upstream_addr)
+                .await
+                .map_err(|e| NetError::io("resolve", &upstream_addr, e))?
+                .next()
+                .ok_or_else(|| {
+                    NetError::invalid_input(
+                        "resolve",
+                        &upstream_addr,
+                        "upstream resolved to nothing".into(),
+                    )
+                })?;
+            let sock = sockopt::bind_udp(&listen_addr, &BindOptions::default(), &socket_options)
+                .await
+                .map_err(|e| NetError::io("bind", &listen_addr, e))?;
+            let dup = sockopt::dup(&sock).ok();
+            let idle =
+                Duration::from_millis(options.udp_idle_timeout_ms.unwrap_or(DEFAULT_UDP_IDLE_MS));
+            let task = async_runtime::spawn(udp_loop(
+                shared,
+                Arc::new(sock),
+                upstream,
+                idle,
+                cancel.clone(),
+            ));
+            (task, dup)
+        }
+    };
+
+    let handle = ProxyHandle {
+        cancel,
+        task,
+        listener,
+    };
+    let rejected = session::registry()?.insert(
+        id.clone(),
+        kind,
+        listen_addr.clone(),
+        SessionHandle::Proxy(handle),
+    );
+    if let Err(h) = rejected {
+        h.stop().await;
+        return Err(NetError::already_running(kind, &listen_addr));
+    }
+
+    Ok(Started {
+        session_id: id,
+        kind,
+        message: format!(
+            "{} on {} relaying to {}",
+            kind.label(),
+            listen_addr,
+            upstream_addr
+        ),
+        addr: listen_addr,
+    })
+}
+
+async fn tcp_accept_loop<R: Runtime>(
+    shared: Arc<Shared<R>>,
+    listener: TcpListener,
+    timeout: Duration,
+    options: SocketOptions,
+    cancel: CancellationToken,
+) {
+    let tracker = TaskTracker::new();
+    loop {
+        let accepted = tokio::select! {
+            _ = cancel.cancelled() => break,
+            r = listener.accept() => r,
+        };
+        match accepted {
+            Ok((client, peer)) => {
+                let _ = client.set_nodelay(true);
+                if let Err(e) = sockopt::apply(&socket2::SockRef::from(&client), &options) {
+                    shared.error(
+                        Some(&peer.to_string()),
+                        format!("socket options error: {}", e),
+                    );
+                }
+                async_runtime::spawn(tracker.track_future(relay_tcp(
+                    shared.clone(),
+                    client,
+                    peer.to_string(),
+                    timeout,
+                    cancel.child_token(),
+                )));
+            }
+            Err(e) => shared.error(None, format!("accept error: {}", e)),
+        }
+    }
+    drop(listener);
+    tracker.close();
+    tracker.wait().await;
+}
+
+async fn relay_tcp<R: Runtime>(
+    shared: Arc<Shared<R>>,
+    client: TcpStream,
+    peer: String,
+    timeout: Duration,
+    cancel: CancellationToken,
+) {
+    let connect = tokio::time::timeout(timeout, TcpStream::connect(&shared.upstream));
+    let upstream = tokio::select! {
+        _ = cancel.cancelled() => return,
+        r = connect => r,
+    };
+    let upstream = match upstream {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            shared.error(Some(&peer), format!("upstream connect error: {}", e));
+            return;
+        }
+        Err(_) => {
+            shared.error(Some(&peer), "upstream connect timed out".into());
+            return;
+        }
+    };
+    let _ = upstream.set_nodelay(true);
+    let local = upstream
+        .local_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "upstream": shared.upstream, "local": local});
+    let _ = shared.app.emit("proxy:connected", payload);
+
+    let (client_r, client_w) = client.into_split();
+    let (up_r, up_w) = upstream.into_split();
+    let (sent, received) = tokio::join!(
+        pump(&shared, &peer, Flow::ToUpstream, client_r, up_w, &cancel),
+        pump(&shared, &peer, Flow::ToClient, up_r, client_w, &cancel),
+    );
+
+    let payload = json!({
+        "session": shared.sid,
+        "bind": shared.addr,
+        "peer": peer,
+        "bytes_to_upstream": sent,
+        "bytes_to_client": received,
+    });
+    let _ = shared.app.emit("proxy:disconnected", payload);
+}
+
+/// Copies one direction until EOF, an error or cancellation, then half-closes the other side so
+/// the opposite direction can drain. Reading and writing are decoupled so an impairment can hold
+/// chunks back without stalling the reads. Returns the bytes relayed.
+async fn pump<R: Runtime>(
+    shared: &Shared<R>,
+    peer: &str,
+    flow: Flow,
+    mut reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    cancel: &CancellationToken,
+) -> u64 {
+    let (tx, mut rx) = mpsc::channel::<(Option<Instant>, Vec<u8>)>(PUMP_QUEUE);
+    let read = async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let read = tokio::select! {
+                _ = cancel.cancelled() => break,
+                r = reader.read(&mut buf) => r,
+            };
+            let n = match read {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    shared.error(Some(peer), format!("{} read error: {}", flow.tag(), e));
+                    break;
+                }
+            };
+            shared.relayed(peer, flow, &buf[..n]);
+            let mut chunk = buf[..n].to_vec();
+            let at = impair::release_time(&shared.sid, flow.way(), &mut chunk);
+            // the writer has given up
+            if tx.send((at, chunk)).await.is_err() {
+                break;
+            }
+        }
+    };
+    let write = async {
+        let mut total = 0u64;
+        while let Some((at, chunk)) = rx.recv().await {
+            if let Some(at) = at {
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep_until(at) => {}
+                }
+            }
+            if let Err(e) = writer.write_all(&chunk).await {
+                shared.error(Some(peer), format!("{} write error: {}", flow.tag(), e));
+                break;
+            }
+            total += chunk.len() as u64;
+        }
+        rx.close();
+        let _ = writer.shutdown().await;
+        total
+    };
+    tokio::join!(read, write).1
+}
+
+/// A client's own upstream socket, so replies can be told apart per client.
+struct Mapping {
+    sock: Arc<UdpSocket>,
+    /// `now_ms()` of the last datagram in either direction.
+    last: Arc<AtomicU64>,
+}
+
+async fn udp_loop<R: Runtime>(
+    shared: Arc<Shared<R>>,
+    listen: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    idle: Duration,
+    cancel: CancellationToken,
+) {
+    let mappings: Arc<Mutex<HashMap<SocketAddr, Mapping>>> = Arc::new(Mutex::new(HashMap::new()));
+    let tracker = TaskTracker::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let received = tokio::select! {
+            _ = cancel.cancelled() => break,
+            r = listen.recv_from(&mut buf) => r,
+        };
+        let (n, client) = match received {
+            Ok(r) => r,
+            Err(e) => {
+                shared.error(None, format!("recv error: {}", e));
+                continue;
+            }
+        };
+        // refreshed under the lock that expiring a mapping takes, so an idle mapping is either
+        // kept for this datagram or already gone and opened again
+        let existing = mappings.lock().ok().and_then(|m| {
+            m.get(&client).map(|m| {
+                m.last.store(session::now_ms(), Ordering::Relaxed);
+                m.sock.clone()
+            })
+        });
+        let sock = match existing {
+            Some(sock) => sock,
+            None => match open_mapping(&shared, client, upstream).await {
+                Ok(sock) => {
+                    let last = Arc::new(AtomicU64::new(session::now_ms()));
+                    if let Ok(mut m) = mappings.lock() {
+                        m.insert(
+                            client,
+                            Mapping {
+                                sock: sock.clone(),
+                                last: last.clone(),
+                            },
+                        );
+                    }
+                    async_runtime::spawn(tracker.track_future(udp_return(
+                        shared.clone(),
+                        listen.clone(),
+                        client,
+                        sock.clone(),
+                        last,
+                        mappings.clone(),
+                        idle,
+                        cancel.child_token(),
+                    )));
+                    sock
+                }
+                Err(e) => {
+                    shared.error(Some(&client.to_string()), e);
+                    continue;
+                }
+            },
+        };
+        shared.relayed(&client.to_string(), Flow::ToUpstream, &buf[..n]);
+        let diverted = impair::divert(&shared.sid, Way::Outbound, &buf[..n], |d| {
+            let sock = sock.clone();
+            async move { sock.send(&d).await.map(drop) }
+        });
+        if diverted {
+            continue;
+        }
+        if let Err(e) = sock.send(&buf[..n]).await {
+            shared.error(
+                Some(&client.to_string()),
+                format!("upstream send error: {}", e),
+            );
+        }
+    }
+    tracker.close();
+    tracker.wait().await;
+}
+
+async fn open_mapping<R: Runtime>(
+    shared: &Shared<R>,
+    client: SocketAddr,
+    upstream: SocketAddr,
+) -> Result<Arc<UdpSocket>, String> {
+    let any = if upstream.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let sock = sockopt::bind_udp(any, &BindOptions::default(), &SocketOptions::default())
+        .await
+        .map_err(|e| format!("bind error: {}", e))?;
+    sock.connect(upstream)
+        .await
+        .map_err(|e| format!("upstream connect error: {}", e))?;
+    let local = sock.local_addr().map(|a| a.to_string()).unwrap_or_default();
+    let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": client.to_string(), "upstream": shared.upstream, "local": local});
+    let _ = shared.app.emit("proxy:connected", payload);
+    Ok(Arc::new(sock))
+}
+
+/// Relays upstream replies back to one client until the mapping goes idle.
+#[allow(clippy::too_many_arguments)]
+async fn udp_return<R: Runtime>(
+    shared: Arc<Shared<R>>,
+    listen: Arc<UdpSocket>,
+    client: SocketAddr,
+    sock: Arc<UdpSocket>,
+    last: Arc<AtomicU64>,
+    mappings: Arc<Mutex<HashMap<SocketAddr, Mapping>>>,
+    idle: Duration,
+    cancel: CancellationToken,
+) {
+    let peer = client.to_string();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let Some(remaining) = remaining(&last, idle) else {
+            if expire(&mappings, client, &last, idle) {
+                break;
+            }
+            continue;
+        };
+        let received = tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(Duration::from_millis(remaining)) => continue,
+            r = sock.recv(&mut buf) => r,
+        };
+        match received {
+            Ok(n) => {
+                last.store(session::now_ms(), Ordering::Relaxed);
+                shared.relayed(&peer, Flow::ToClient, &buf[..n]);
+                let diverted = impair::divert(&shared.sid, Way::Inbound, &buf[..n], |d| {
+                    let listen = listen.clone();
+                    async move { listen.send_to(&d, client).await.map(drop) }
+                });
+                if diverted {
+                    continue;
+                }
+                if let Err(e) = listen.send_to(&buf[..n], client).await {
+                    shared.error(Some(&peer), format!("client send error: {}", e));
+                }
+            }
+            // e.g. ICMP port unreachable from the upstream; keep the mapping
+            Err(e) => shared.error(Some(&peer), format!("upstream recv error: {}", e)),
+        }
+    }
+    let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer});
+    let _ = shared.app.emit("proxy:disconnected", payload);
+}
+
+/// How much longer a mapping last used at `last` may stay idle; `None` once it has expired.
+fn remaining(last: &AtomicU64, idle: Duration) -> Option<u64> {
+    let quiet = session::now_ms().saturating_sub(last.load(Ordering::Relaxed));
+    (idle.as_millis() as u64)
+        .checked_sub(quiet)
+        .filter(|r| *r > 0)
+}
+
+/// Drops an idle mapping, unless the receive loop refreshed it while the lock was not held.
+fn expire(
+    mappings: &Mutex<HashMap<SocketAddr, Mapping>>,
+    client: SocketAddr,
+    last: &AtomicU64,
+    idle: Duration,
+) -> bool {
+    let Ok(mut m) = mappings.lock() else {
+        return true;
+    };
+    if remaining(last, idle).is_some() {
+        return false;
+    }
+    m.remove(&client);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tauri::test::MockRuntime;
+
+    fn shared(app: &tauri::App<MockRuntime>, upstream: SocketAddr) -> Arc<Shared<MockRuntime>> {
+        Arc::new(Shared {
+            app: app.handle().clone(),
+            sid: format!("proxy-test-{}", upstream.port()),
+            addr: "127.0.0.1:0".into(),
+            upstream: upstream.to_string(),
+            seq: AtomicU64::new(0),
+        })
+    }
+
+    #[tokio::test]
+    async fn tcp_relay() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        // echoes until the proxy half-closes, then closes its side
+        tokio::spawn(async move {
+            let (mut s, _) = upstream.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            loop {
+                match s.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => s.write_all(&buf[..n]).await.unwrap(),
+                }
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let app = tauri::test::mock_app();
+        let shared = shared(&app, upstream_addr);
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(tcp_accept_loop(
+            shared.clone(),
+            listener,
+            Duration::from_secs(5),
+            SocketOptions::default(),
+            cancel.clone(),
+        ));
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        for message in [&b"ping"[..], &big] {
+            client.write_all(message).await.unwrap();
+            let mut back = vec![0u8; message.len()];
+            client.read_exact(&mut back).await.unwrap();
+            assert_eq!(back, message);
+        }
+        // a half-close travels through, and the upstream's close comes back
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        // every chunk was numbered, both ways
+        assert!(shared.seq.load(Ordering::Relaxed) >= 4);
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_upstream_refused() {
+        // a port nothing listens on
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let app = tauri::test::mock_app();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(tcp_accept_loop(
+            shared(&app, upstream_addr),
+            listener,
+            Duration::from_secs(5),
+            SocketOptions::default(),
+            cancel.clone(),
+        ));
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest));
+        assert!(read.await.unwrap().is_ok_and(|n| n == 0));
+        cancel.cancel();
+        task.await.unwrap();
+    }
+
+    /// Answers every datagram with the address it came from.
+    async fn udp_upstream() -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((_, from)) = sock.recv_from(&mut buf).await {
+                let _ = sock.send_to(from.to_string().as_bytes(), from).await;
+            }
+        });
+        addr
+    }
+
+    /// The upstream-side address of `client`'s mapping.
+    async fn mapped(client: &UdpSocket, proxy: SocketAddr) -> String {
+        client.send_to(b"hello", proxy).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, from) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, proxy);
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn udp_relay_keeps_a_mapping_per_client_until_idle() {
+        let upstream = udp_upstream().await;
+        let listen = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listen.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let idle = Duration::from_millis(300);
+        let app = tauri::test::mock_app();
+        let task = tokio::spawn(udp_loop(
+            shared(&app, upstream),
+            Arc::new(listen),
+            upstream,
+            idle,
+            cancel.clone(),
+        ));
+
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = mapped(&a, proxy).await;
+        assert_ne!(mapped(&b, proxy).await, first);
+        // traffic more often than the idle timeout keeps the mapping
+        for _ in 0..6 {
+            tokio::time::sleep(idle / 3).await;
+            assert_eq!(mapped(&a, proxy).await, first);
+        }
+        // a quiet mapping is dropped and the next datagram opens a new one
+        tokio::time::sleep(idle * 2).await;
+        assert_ne!(mapped(&a, proxy).await, first);
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn expire_skips_a_refreshed_mapping() {
+        let idle = Duration::from_millis(1000);
+        let client: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+        let last = Arc::new(AtomicU64::new(0));
+        let mappings = Mutex::new(HashMap::from([(
+            client,
+            Mapping {
+                sock: Arc::new(UdpSocket::from_std(sock).unwrap()),
+                last: last.clone(),
+            },
+        )]));
+        // refreshed after the return task saw it idle: kept
+        last.store(session::now_ms(), Ordering::Relaxed);
+        assert!(!expire(&mappings, client, &last, idle));
+        assert!(mappings.lock().unwrap().contains_key(&client));
+        last.store(0, Ordering::Relaxed);
+        assert!(expire(&mappings, client, &last, idle));
+        assert!(mappings.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,498 @@
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{async_runtime, AppHandle, Emitter};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::{NetError, NetResult};
+use crate::session::{self, SessionKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl From<SessionKind> for Transport {
+    fn from(kind: SessionKind) -> Self {
+        match kind {
+            SessionKind::TcpServer
+            | SessionKind::TcpClient
+            | SessionKind::TcpProxy
+            | SessionKind::WsServer
+            | SessionKind::WsClient
+            | SessionKind::ModbusClient
+            | SessionKind::MqttClient => Transport::Tcp,
+            SessionKind::UdpServer | SessionKind::UdpClient | SessionKind::UdpProxy => {
+                Transport::Udp
+            }
+        }
+    }
+}
+
+/// One line of a recording.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub ts_ms: u64,
+    pub session: String,
+    pub direction: Direction,
+    pub transport: Transport,
+    /// Our end of the traffic: the bind address, or the local address of a TCP connection.
+    pub local: String,
+    pub peer: String,
+    pub seq: u64,
+    pub len: usize,
+    /// Base64, like the `data` field of the message events.
+    pub data: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RecordOptions {
+    /// Directory for the `.jsonl` files; created if missing.
+    pub dir: String,
+    /// Start a new file once the current one reaches this many bytes.
+    pub max_file_bytes: Option<u64>,
+    /// Start a new file once the current one is this old.
+    pub max_file_age_ms: Option<u64>,
+    /// Gzip each file once it is rotated out or the recording stops.
+    #[serde(default)]
+    pub compress: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Recording {
+    pub session_id: String,
+    /// File currently written to.
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RecordingStopped {
+    pub session_id: String,
+    /// Every file the recording produced, oldest first (`.gz` paths when compressed).
+    pub files: Vec<String>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RecordQuery {
+    /// A `.jsonl` or `.jsonl.gz` file written by the recorder.
+    pub path: String,
+    pub from_ms: Option<u64>,
+    pub to_ms: Option<u64>,
+    pub peer: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// The file side of a recording, owned by its writer task.
+struct Recorder {
+    app: AppHandle,
+    session_id: String,
+    opts: RecordOptions,
+    path: PathBuf,
+    out: BufWriter<File>,
+    opened_ms: u64,
+    written: u64,
+    files: Vec<String>,
+}
+
+/// What the send and receive paths see of a recording: the numbering of sent messages and the
+/// queue to its writer, so a slow disk never holds up a session.
+struct Handle {
+    transport: Transport,
+    tx_seq: u64,
+    queue: mpsc::UnboundedSender<Op>,
+}
+
+enum Op {
+    Write(Record),
+    /// Flush, then answer with the files so far, the one being written last.
+    Flush(oneshot::Sender<Vec<String>>),
+    /// Flush and seal the last file, then answer with every file.
+    Finish(Option<oneshot::Sender<Vec<String>>>),
+    /// Rotate if the current file is past `max_file_age_ms`, even when nothing is written.
+    Tick,
+}
+
+static RECORDERS: OnceCell<Mutex<HashMap<String, Handle>>> = OnceCell::new();
+/// Lets the hot receive paths skip the lock while nothing is being recorded.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn recorders() -> NetResult<std::sync::MutexGuard<'static, HashMap<String, Handle>>> {
+    RECORDERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+pub fn start(app: AppHandle, session_id: String, opts: RecordOptions) -> NetResult<Recording> {
+    // make sure the session exists so a typo doesn't silently record nothing
+    let info = session::get(&session_id)?;
+    let mut recs = recorders()?;
+    if recs.contains_key(&session_id) {
+        return Err(NetError::already_recording(&session_id));
+    }
+    fs::create_dir_all(&opts.dir).map_err(|e| NetError::io("create directory", &opts.dir, e))?;
+    let (path, out) = open_file(&opts.dir, &session_id)?;
+    let path_str = path.display().to_string();
+    let rec = Recorder {
+        app,
+        session_id: session_id.clone(),
+        opts,
+        path,
+        out,
+        opened_ms: session::now_ms(),
+        written: 0,
+        files: Vec::new(),
+    };
+    let (queue, ops) = mpsc::unbounded_channel();
+    if let Some(age) = rec.opts.max_file_age_ms {
+        async_runtime::spawn(tick(queue.downgrade(), age));
+    }
+    async_runtime::spawn_blocking(move || rec.run(ops));
+    recs.insert(
+        session_id.clone(),
+        Handle {
+            transport: info.kind.into(),
+            tx_seq: 0,
+            queue,
+        },
+    );
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(Recording {
+        message: format!("recording session {} to {}", session_id, path_str),
+        session_id,
+        path: path_str,
+    })
+}
+
+fn take(session_id: &str) -> NetResult<Option<Handle>> {
+    let mut recs = recorders()?;
+    let handle = recs.remove(session_id);
+    ACTIVE.store(!recs.is_empty(), Ordering::Relaxed);
+    Ok(handle)
+}
+
+/// Has an idle recording checked for rotation at least once a second, until its writer is gone.
+async fn tick(queue: mpsc::WeakUnboundedSender<Op>, max_age_ms: u64) {
+    let period = Duration::from_millis(max_age_ms.clamp(1, 1000));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let Some(queue) = queue.upgrade() else {
+            return;
+        };
+        if queue.send(Op::Tick).is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends `op` to a writer and waits for its answer.
+async fn ask(
+    queue: &mpsc::UnboundedSender<Op>,
+    op: impl FnOnce(oneshot::Sender<Vec<String>>) -> Op,
+) -> NetResult<Vec<String>> {
+    let (tx, rx) = oneshot::channel();
+    queue
+        .send(op(tx))
+        .map_err(|_| NetError::internal("recorder writer is gone".into()))?;
+    rx.await
+        .map_err(|_| NetError::internal("recorder writer is gone".into()))
+}
+
+pub async fn stop(session_id: &str) -> NetResult<RecordingStopped> {
+    let handle = take(session_id)?.ok_or_else(|| NetError::not_recording(session_id))?;
+    let files = ask(&handle.queue, |tx| Op::Finish(Some(tx))).await?;
+    Ok(RecordingStopped {
+        session_id: session_id.to_string(),
+        message: format!(
+            "recording of session {} stopped ({} file(s))",
+            session_id,
+            files.len()
+        ),
+        files,
+    })
+}
+
+/// Files of an active recording so far, oldest first, including the one being written.
+pub async fn files(session_id: &str) -> NetResult<Vec<String>> {
+    let queue = recorders()?
+        .get(session_id)
+        .map(|h| h.queue.clone())
+        .ok_or_else(|| NetError::not_recording(session_id))?;
+    ask(&queue, Op::Flush).await
+}
+
+/// Closes the recording of a session that went away; no-op when it was not recorded.
+pub fn close(session_id: &str) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(Some(handle)) = take(session_id) {
+        let _ = handle.queue.send(Op::Finish(None));
+    }
+}
+
+/// Queues one message for the session's recording, if it has one. `seq` is ignored for
+/// `Tx`; sent messages are numbered by the recorder.
+pub fn record(
+    session_id: &str,
+    direction: Direction,
+    local: &str,
+    peer: &str,
+    seq: u64,
+    ts_ms: u64,
+    data: &[u8],
+) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(mut recs) = recorders() else {
+        return;
+    };
+    let Some(rec) = recs.get_mut(session_id) else {
+        return;
+    };
+    let seq = match direction {
+        Direction::Rx => seq,
+        Direction::Tx => {
+            rec.tx_seq = rec.tx_seq.wrapping_add(1);
+            rec.tx_seq
+        }
+    };
+    let line = Record {
+        ts_ms,
+        session: session_id.to_string(),
+        direction,
+        transport: rec.transport,
+        local: local.to_string(),
+        peer: peer.to_string(),
+        seq,
+        len: data.len(),
+        data: base64::engine::general_purpose::STANDARD.encode(data),
+    };
+    let _ = rec.queue.send(Op::Write(line));
+}
+
+/// Failures after `start` returned have no command to report to, so they go out as events.
+fn emit_error(app: &AppHandle, session_id: &str, path: &str, error: String) {
+    let payload = json!({"session": session_id, "path": path, "error": error});
+    let _ = app.emit("recorder:error", payload);
+}
+
+impl Recorder {
+    /// Writes queued records until the recording finishes or its handle is dropped. Lines are
+    /// flushed whenever the queue runs dry rather than one at a time.
+    fn run(mut self, mut ops: mpsc::UnboundedReceiver<Op>) {
+        while let Some(op) = ops.blocking_recv() {
+            match op {
+                Op::Write(line) => {
+                    if let Err(e) = self.write(&line) {
+                        self.error(format!("write error: {}", e));
+                    }
+                    if ops.is_empty() {
+                        self.flush();
+                    }
+                }
+                Op::Flush(reply) => {
+                    self.flush();
+                    let mut files = self.files.clone();
+                    files.push(self.path.display().to_string());
+                    let _ = reply.send(files);
+                }
+                Op::Finish(reply) => {
+                    let files = self.finish();
+                    if let Some(reply) = reply {
+                        let _ = reply.send(files);
+                    }
+                    return;
+                }
+                Op::Tick => {
+                    if self.due_for_rotation() {
+                        if let Err(e) = self.rotate() {
+                            self.error(format!("rotate error: {}", e));
+                        }
+                    }
+                }
+            }
+        }
+        self.finish();
+    }
+
+    fn error(&self, error: String) {
+        let path = self.path.display().to_string();
+        emit_error(&self.app, &self.session_id, &path, error);
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.out.flush() {
+            self.error(format!("write error: {}", e));
+        }
+    }
+
+    fn write(&mut self, line: &Record) -> io::Result<()> {
+        if self.due_for_rotation() {
+            self.rotate()?;
+        }
+        let mut buf = serde_json::to_vec(line)?;
+        buf.push(b'\n');
+        self.out.write_all(&buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn due_for_rotation(&self) -> bool {
+        if self.written == 0 {
+            return false;
+        }
+        let too_big = self
+            .opts
+            .max_file_bytes
+            .is_some_and(|max| self.written >= max);
+        let too_old = self
+            .opts
+            .max_file_age_ms
+            .is_some_and(|max| session::now_ms().saturating_sub(self.opened_ms) >= max);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let (path, out) = open_file(&self.opts.dir, &self.session_id).map_err(io::Error::other)?;
+        let old = std::mem::replace(&mut self.path, path);
+        let mut old_out = std::mem::replace(&mut self.out, out);
+        old_out.flush()?;
+        drop(old_out);
+        self.files.push(self.seal(old));
+        self.opened_ms = session::now_ms();
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Returns the final name of a finished file, compressing it first if asked to. This runs
+    /// on the writer thread, so a name is never handed out before its file is complete; when
+    /// compression fails the plain file is kept.
+    fn seal(&self, path: PathBuf) -> String {
+        if !self.opts.compress {
+            return path.display().to_string();
+        }
+        let mut gz = path.clone().into_os_string();
+        gz.push(".gz");
+        let gz = PathBuf::from(gz);
+        match compress(&path, &gz) {
+            Ok(()) => gz.display().to_string(),
+            Err(e) => {
+                let _ = fs::remove_file(&gz);
+                let path = path.display().to_string();
+                emit_error(
+                    &self.app,
+                    &self.session_id,
+                    &path,
+                    format!("compress error: {}", e),
+                );
+                path
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush();
+        let path = std::mem::take(&mut self.path);
+        let last = self.seal(path);
+        self.files.push(last);
+        self.files
+    }
+}
+
+fn open_file(dir: &str, session_id: &str) -> NetResult<(PathBuf, BufWriter<File>)> {
+    let mut path = Path::new(dir).join(format!("{}-{}.jsonl", session_id, session::now_ms()));
+    // two rotations within the same millisecond
+    let mut n = 1;
+    while path.exists() {
+        path = Path::new(dir).join(format!("{}-{}-{}.jsonl", session_id, session::now_ms(), n));
+        n += 1;
+    }
+    let f = File::create(&path)
+        .map_err(|e| NetError::io("create file", &path.display().to_string(), e))?;
+    Ok((path, BufWriter::new(f)))
+}
+
+fn compress(src: &Path, dst: &Path) -> io::Result<()> {
+    let mut input = File::open(src)?;
+    let mut enc = GzEncoder::new(BufWriter::new(File::create(dst)?), Compression::default());
+    io::copy(&mut input, &mut enc)?;
+    enc.finish()?.flush()?;
+    fs::remove_file(src)
+}
+
+/// Reads a recording back, keeping records in `[from_ms, to_ms]` from `peer`, in file order.
+pub async fn query(q: RecordQuery) -> NetResult<Vec<Record>> {
+    // the file may be one still being written; make what is queued for it readable first
+    let queues: Vec<_> = recorders()?.values().map(|h| h.queue.clone()).collect();
+    for queue in queues {
+        let _ = ask(&queue, Op::Flush).await;
+    }
+    async_runtime::spawn_blocking(move || query_file(&q))
+        .await
+        .map_err(|e| NetError::internal(format!("query task error: {}", e)))?
+}
+
+/// Every record of one recording file.
+pub fn read_records(path: &str) -> NetResult<Vec<Record>> {
+    query_file(&RecordQuery {
+        path: path.to_string(),
+        from_ms: None,
+        to_ms: None,
+        peer: None,
+        limit: None,
+    })
+}
+
+fn query_file(q: &RecordQuery) -> NetResult<Vec<Record>> {
+    let f = File::open(&q.path).map_err(|e| NetError::io("open recording", &q.path, e))?;
+    let reader: Box<dyn Read> = if q.path.ends_with(".gz") {
+        Box::new(GzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    let limit = q.limit.unwrap_or(usize::MAX);
+    let mut out = Vec::new();
+    for line in BufReader::new(reader).lines() {
+        let line = line.map_err(|e| NetError::io("read recording", &q.path, e))?;
+        // a line cut short by a crash is skipped rather than failing the whole query
+        let Ok(rec) = serde_json::from_str::<Record>(&line) else {
+            continue;
+        };
+        if q.from_ms.is_some_and(|from| rec.ts_ms < from)
+            || q.to_ms.is_some_and(|to| rec.ts_ms > to)
+            || q.peer.as_ref().is_some_and(|p| *p != rec.peer)
+        {
+            continue;
+        }
+        out.push(rec);
+        if out.len() >= limit {
+            break;
+        }
+    }
+    Ok(out)
+}
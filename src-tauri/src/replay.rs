@@ -0,0 +1,592 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tauri::async_runtime;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::jobs::{self, JobInfo, JobKind};
+use crate::pcap::{self, CapturedPacket};
+use crate::recorder::Transport;
+use crate::session::{self, SessionKind};
+use crate::tcp_client;
+use crate::udp_client;
+
+/// Minimum gap between two `replay:progress` events.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// Nesting of `not` and parentheses a filter expression may use; keeps the parser's recursion
+/// off the end of the stack.
+const MAX_EXPR_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplayRequest {
+    /// A `.pcap` or `.pcapng` file.
+    pub path: String,
+    #[serde(default)]
+    pub filter: PacketFilter,
+    /// BPF-like expression, e.g. `udp and dst port 5000 and not src host 10.0.0.1`.
+    pub expression: Option<String>,
+    pub target: ReplayTarget,
+    #[serde(default)]
+    pub timing: Timing,
+}
+
+/// Keeps packets matching every field that is set.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PacketFilter {
+    pub transport: Option<Transport>,
+    pub src_ip: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub dst_ip: Option<IpAddr>,
+    pub dst_port: Option<u16>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplayTarget {
+    /// Sent like `udp_client_send_from`: through the UDP client on `bind_addr` if one is running.
+    Udp { bind_addr: String, to_addr: String },
+    /// Written to a connected TCP client session.
+    TcpClient {
+        remote_addr: String,
+        #[serde(default)]
+        framed: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Timing {
+    /// Keep the captured gaps between packets, divided by `speed` (1.0 when unset).
+    Original { speed: Option<f64> },
+    /// Send each payload as soon as the previous one went out.
+    #[default]
+    Fast,
+}
+
+pub async fn start(app: AppHandle, req: ReplayRequest) -> NetResult<JobInfo> {
+    let expr = match req.expression.as_deref().map(str::trim) {
+        Some(s) if !s.is_empty() => Some(Expr::parse(s)?),
+        _ => None,
+    };
+    let speed = match req.timing {
+        Timing::Original { speed } => {
+            let speed = speed.unwrap_or(1.0);
+            if !(speed.is_finite() && speed > 0.0) {
+                return Err(NetError::invalid_input(
+                    "replay",
+                    &req.path,
+                    format!("invalid speed {}", speed),
+                ));
+            }
+            Some(speed)
+        }
+        Timing::Fast => None,
+    };
+    let session_id = match &req.target {
+        ReplayTarget::Udp { bind_addr, .. } => session::registry()?
+            .get(SessionKind::UdpClient, bind_addr)
+            .map(|s| s.info.id.clone()),
+        ReplayTarget::TcpClient { remote_addr, .. } => Some(
+            session::registry()?
+                .get(SessionKind::TcpClient, remote_addr)
+                .map(|s| s.info.id.clone())
+                .ok_or_else(|| NetError::not_running(SessionKind::TcpClient, remote_addr))?,
+        ),
+    };
+
+    let path = req.path.clone();
+    let packets = async_runtime::spawn_blocking(move || pcap::read_packets(&path))
+        .await
+        .map_err(|e| NetError::internal(format!("read task error: {}", e)))??;
+    let read = packets.len();
+    let packets: Vec<CapturedPacket> = packets
+        .into_iter()
+        .filter(|p| req.filter.matches(p) && expr.as_ref().is_none_or(|e| e.matches(p)))
+        .collect();
+
+    let id = jobs::next_id();
+    let cancel = CancellationToken::new();
+    let (registered_tx, registered_rx) = oneshot::channel::<()>();
+    let replay = Replay {
+        app,
+        job: id.clone(),
+        session: session_id,
+        target: req.target.clone(),
+        speed,
+        skipped: read - packets.len(),
+    };
+    let task = {
+        let cancel = cancel.clone();
+        async_runtime::spawn(async move {
+            // don't finish (and unregister) before start() has registered the job
+            if registered_rx.await.is_err() {
+                return;
+            }
+            replay.run(packets, cancel).await;
+            jobs::finished(&replay.job);
+        })
+    };
+    let target = match &req.target {
+        ReplayTarget::Udp { to_addr, .. } => to_addr.clone(),
+        ReplayTarget::TcpClient { remote_addr, .. } => remote_addr.clone(),
+    };
+    let info = jobs::insert(id, JobKind::Replay, target, cancel, task, None)?;
+    let _ = registered_tx.send(());
+    Ok(info)
+}
+
+struct Replay {
+    app: AppHandle,
+    job: String,
+    session: Option<String>,
+    target: ReplayTarget,
+    speed: Option<f64>,
+    /// Packets dropped by the filters.
+    skipped: usize,
+}
+
+impl Replay {
+    async fn run(&self, packets: Vec<CapturedPacket>, cancel: CancellationToken) {
+        let total = packets.len();
+        let first_ts = packets.first().map(|p| p.ts_us).unwrap_or(0);
+        let started = Instant::now();
+        let mut last_progress = started;
+        let (mut sent, mut bytes) = (0usize, 0usize);
+        let mut cancelled = false;
+
+        for p in &packets {
+            if let Some(speed) = self.speed {
+                // captures are not always in timestamp order; never wait for an earlier packet
+                let offset = p.ts_us.saturating_sub(first_ts) as f64 / speed;
+                let due = started + Duration::from_micros(offset as u64);
+                tokio::select! {
+                    _ = cancel.cancelled() => {
+                        cancelled = true;
+                        break;
+                    }
+                    _ = tokio::time::sleep_until(due) => {}
+                }
+            } else if cancel.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+
+            match self.send(&p.payload).await {
+                Ok(n) => {
+                    sent += 1;
+                    bytes += n;
+                }
+                Err(e) => {
+                    let payload = json!({
+                        "session": self.session,
+                        "job": self.job,
+                        "sent": sent,
+                        "error": e.message,
+                    });
+                    let _ = self.app.emit("replay:error", payload);
+                    break;
+                }
+            }
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL || sent == total {
+                last_progress = Instant::now();
+                let payload = json!({
+                    "session": self.session,
+                    "job": self.job,
+                    "sent": sent,
+                    "total": total,
+                    "bytes": bytes,
+                    "elapsed_ms": started.elapsed().as_millis() as u64,
+                });
+                let _ = self.app.emit("replay:progress", payload);
+            }
+        }
+
+        let payload = json!({
+            "session": self.session,
+            "job": self.job,
+            "sent": sent,
+            "total": total,
+            "bytes": bytes,
+            "skipped": self.skipped,
+            "cancelled": cancelled,
+            "elapsed_ms": started.elapsed().as_millis() as u64,
+        });
+        let _ = self.app.emit("replay:done", payload);
+    }
+
+    async fn send(&self, data: &[u8]) -> NetResult<usize> {
+        let sent = match &self.target {
+            ReplayTarget::Udp { bind_addr, to_addr } => {
+                udp_client::send_bytes_from(bind_addr, to_addr, data, false).await?
+            }
+            ReplayTarget::TcpClient {
+                remote_addr,
+                framed,
+            } => tcp_client::send_bytes(remote_addr, data.to_vec(), *framed).await?,
+        };
+        Ok(sent.bytes)
+    }
+}
+
+impl PacketFilter {
+    fn matches(&self, p: &CapturedPacket) -> bool {
+        self.transport.is_none_or(|t| t == p.transport)
+            && self.src_ip.is_none_or(|ip| same_ip(ip, p.src))
+            && self.src_port.is_none_or(|port| port == p.src.port())
+            && self.dst_ip.is_none_or(|ip| same_ip(ip, p.dst))
+            && self.dst_port.is_none_or(|port| port == p.dst.port())
+    }
+}
+
+fn same_ip(ip: IpAddr, addr: SocketAddr) -> bool {
+    ip.to_canonical() == addr.ip().to_canonical()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Dir {
+    Src,
+    Dst,
+    Either,
+}
+
+/// Parsed filter expression. Supports `tcp`, `udp`, `ip`, `ip6`, `[src|dst] host ADDR`,
+/// `[src|dst] port N`, `and`/`&&`, `or`/`||`, `not`/`!` and parentheses.
+#[derive(Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Transport(Transport),
+    Ipv6(bool),
+    Host(Dir, IpAddr),
+    Port(Dir, u16),
+}
+
+impl Expr {
+    fn parse(s: &str) -> NetResult<Expr> {
+        let tokens = tokenize(s);
+        let mut p = Parser {
+            source: s,
+            tokens: &tokens,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = p.or()?;
+        match p.peek() {
+            None => Ok(expr),
+            Some(t) => Err(p.error(format!("unexpected '{}'", t))),
+        }
+    }
+
+    fn matches(&self, p: &CapturedPacket) -> bool {
+        let by_dir = |dir: Dir, f: &dyn Fn(SocketAddr) -> bool| match dir {
+            Dir::Src => f(p.src),
+            Dir::Dst => f(p.dst),
+            Dir::Either => f(p.src) || f(p.dst),
+        };
+        match self {
+            Expr::Or(a, b) => a.matches(p) || b.matches(p),
+            Expr::And(a, b) => a.matches(p) && b.matches(p),
+            Expr::Not(a) => !a.matches(p),
+            Expr::Transport(t) => p.transport == *t,
+            Expr::Ipv6(v6) => p.src.is_ipv6() == *v6,
+            Expr::Host(dir, ip) => by_dir(*dir, &|a| same_ip(*ip, a)),
+            Expr::Port(dir, port) => by_dir(*dir, &|a| a.port() == *port),
+        }
+    }
+}
+
+/// Splits on whitespace, parentheses and `!`, and around `&&` and `||` even without spaces.
+fn tokenize(s: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let pair = matches!(c, '&' | '|') && chars.peek() == Some(&c);
+        if c.is_whitespace() || c == '(' || c == ')' || c == '!' || pair {
+            if !cur.is_empty() {
+                out.push(std::mem::take(&mut cur));
+            }
+            if pair {
+                chars.next();
+                out.push(format!("{}{}", c, c));
+            } else if !c.is_whitespace() {
+                out.push(c.to_string());
+            }
+        } else {
+            cur.push(c);
+        }
+    }
+    if !cur.is_empty() {
+        out.push(cur);
+    }
+    out
+}
+
+fn is_operator(token: &str) -> bool {
+    ["and", "&&", "or", "||", "not", "!", "(", ")"]
+        .iter()
+        .any(|op| token.eq_ignore_ascii_case(op))
+}
+
+/// Recursive descent over `or > and > not > primitive`.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: &'a [String],
+    pos: usize,
+    /// `not`s and parentheses open around the current token.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        let t = self.tokens.get(self.pos).map(String::as_str);
+        self.pos += 1;
+        t
+    }
+
+    fn eat(&mut self, words: &[&str]) -> bool {
+        match self.peek() {
+            Some(t) if words.iter().any(|w| t.eq_ignore_ascii_case(w)) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> NetResult<Expr> {
+        let mut left = self.and()?;
+        while self.eat(&["or", "||"]) {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> NetResult<Expr> {
+        let mut left = self.not()?;
+        while self.eat(&["and", "&&"]) {
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> NetResult<Expr> {
+        if self.depth >= MAX_EXPR_DEPTH {
+            return Err(self.error(format!("nested deeper than {} levels", MAX_EXPR_DEPTH)));
+        }
+        self.depth += 1;
+        let expr = self.nested();
+        self.depth -= 1;
+        expr
+    }
+
+    fn nested(&mut self) -> NetResult<Expr> {
+        if self.eat(&["not", "!"]) {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.eat(&["("]) {
+            let inner = self.or()?;
+            if !self.eat(&[")"]) {
+                return Err(self.error("missing ')'".into()));
+            }
+            return Ok(inner);
+        }
+        self.primitive()
+    }
+
+    fn primitive(&mut self) -> NetResult<Expr> {
+        let dir = if self.eat(&["src"]) {
+            Dir::Src
+        } else if self.eat(&["dst"]) {
+            Dir::Dst
+        } else {
+            Dir::Either
+        };
+        let word = match self.next() {
+            Some(t) => t.to_ascii_lowercase(),
+            None => return Err(self.error("unexpected end of expression".into())),
+        };
+        match (word.as_str(), dir) {
+            ("tcp", Dir::Either) => Ok(Expr::Transport(Transport::Tcp)),
+            ("udp", Dir::Either) => Ok(Expr::Transport(Transport::Udp)),
+            ("ip", Dir::Either) => Ok(Expr::Ipv6(false)),
+            ("ip6", Dir::Either) => Ok(Expr::Ipv6(true)),
+            ("host", _) => {
+                let arg = self.argument("host")?;
+                arg.parse()
+                    .map(|ip| Expr::Host(dir, ip))
+                    .map_err(|_| self.error(format!("'{}' is not an IP address", arg)))
+            }
+            ("port", _) => {
+                let arg = self.argument("port")?;
+                arg.parse()
+                    .map(|port| Expr::Port(dir, port))
+                    .map_err(|_| self.error(format!("'{}' is not a port", arg)))
+            }
+            _ => Err(self.error(format!("unexpected '{}'", word))),
+        }
+    }
+
+    /// The operand of `host` or `port`; an operator or parenthesis is not one.
+    fn argument(&mut self, keyword: &str) -> NetResult<String> {
+        match self.peek() {
+            Some(t) if !is_operator(t) => {
+                let t = t.to_string();
+                self.pos += 1;
+                Ok(t)
+            }
+            _ => Err(self.error(format!("'{}' needs a value", keyword))),
+        }
+    }
+
+    fn error(&self, message: String) -> NetError {
+        NetError::invalid_input(
+            "replay",
+            self.source,
+            format!("filter expression: {}", message),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(transport: Transport, src: &str, dst: &str) -> CapturedPacket {
+        CapturedPacket {
+            ts_us: 0,
+            transport,
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            payload: Vec::new(),
+        }
+    }
+
+    fn matches(expr: &str, p: &CapturedPacket) -> bool {
+        Expr::parse(expr).unwrap().matches(p)
+    }
+
+    #[test]
+    fn primitives() {
+        let p = packet(Transport::Udp, "10.0.0.1:5000", "10.0.0.2:53");
+        for expr in [
+            "udp",
+            "ip",
+            "host 10.0.0.1",
+            "src host 10.0.0.1",
+            "dst host 10.0.0.2",
+            "port 53",
+            "src port 5000",
+            "DST PORT 53",
+            "host ::ffff:10.0.0.2",
+        ] {
+            assert!(matches(expr, &p), "{}", expr);
+        }
+        for expr in ["tcp", "ip6", "dst host 10.0.0.1", "src port 53", "port 80"] {
+            assert!(!matches(expr, &p), "{}", expr);
+        }
+        let v6 = packet(Transport::Tcp, "[2001:db8::1]:1", "[2001:db8::2]:2");
+        assert!(matches("ip6 and tcp and src host 2001:db8::1", &v6));
+    }
+
+    #[test]
+    fn precedence() {
+        let p = packet(Transport::Tcp, "10.0.0.1:1000", "10.0.0.2:80");
+        // and binds tighter than or: udp or (tcp and port 80)
+        assert!(matches("udp or tcp and port 80", &p));
+        assert!(matches("tcp and port 80 or udp", &p));
+        // (udp and tcp) or port 22 is false; udp and (tcp or port 22) too
+        assert!(!matches("udp and tcp or port 22", &p));
+        // not binds tighter than and and or
+        assert!(!matches("not tcp and port 80", &p));
+        assert!(matches("not udp and port 80", &p));
+        assert!(matches("not udp or udp", &p));
+        assert!(matches("! ! tcp", &p));
+        assert!(matches("!udp&&tcp", &p));
+        assert!(matches("udp || tcp && ! port 22", &p));
+    }
+
+    #[test]
+    fn parentheses() {
+        let p = packet(Transport::Tcp, "10.0.0.1:1000", "10.0.0.2:80");
+        assert!(!matches("(udp or tcp) and port 22", &p));
+        assert!(matches("udp or (tcp and port 80)", &p));
+        assert!(!matches("not (tcp and port 80)", &p));
+        assert!(matches("((((tcp))))", &p));
+        assert!(matches("not(udp)and(port 80)", &p));
+        let deep = format!("{}tcp{}", "(".repeat(60), ")".repeat(60));
+        assert!(matches(&deep, &p));
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        for expr in [
+            "",
+            "   ",
+            "bogus",
+            "tcp bogus",
+            "udp and",
+            "or udp",
+            "udp or or tcp",
+            "not",
+            "(udp",
+            "udp)",
+            "()",
+            "src tcp",
+            "dst",
+            "host",
+            "host and udp",
+            "port (53)",
+            "host 10.0.0",
+            "host example.com",
+            "port 65536",
+            "port -1",
+            "port 5x",
+        ] {
+            let e = Expr::parse(expr).unwrap_err();
+            assert!(e.message.contains("filter expression"), "{}", expr);
+        }
+        assert!(Expr::parse("host")
+            .unwrap_err()
+            .message
+            .contains("'host' needs a value"));
+
+        // deep nesting is refused before it exhausts the stack
+        for expr in [
+            format!("{}tcp{}", "(".repeat(100_000), ")".repeat(100_000)),
+            format!("{}tcp", "not ".repeat(100_000)),
+            "!".repeat(100_000),
+        ] {
+            assert!(Expr::parse(&expr).unwrap_err().message.contains("nested"));
+        }
+    }
+
+    #[test]
+    fn packet_filter_fields() {
+        let p = packet(Transport::Udp, "[::ffff:10.0.0.1]:5000", "10.0.0.2:53");
+        let filter = PacketFilter {
+            transport: Some(Transport::Udp),
+            src_ip: Some("10.0.0.1".parse().unwrap()),
+            dst_port: Some(53),
+            ..Default::default()
+        };
+        assert!(filter.matches(&p));
+        assert!(PacketFilter::default().matches(&p));
+        let other = PacketFilter {
+            src_port: Some(53),
+            ..Default::default()
+        };
+        assert!(!other.matches(&p));
+    }
+}
@@ -0,0 +1,328 @@
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tauri::async_runtime;
+use tauri::{AppHandle, Emitter};
+
+use crate::error::{NetError, NetResult};
+use crate::session::{self, SessionKind, Updated};
+use crate::{tcp_server, template, udp_server};
+
+/// One auto-responder rule; the first rule whose `match` accepts a message replies to it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RuleSpec {
+    pub name: Option<String>,
+    #[serde(rename = "match")]
+    pub matcher: Matcher,
+    pub reply: Reply,
+    /// Wait this long before replying.
+    pub delay_ms: Option<u64>,
+    /// Wrap the reply with the session's framer.
+    #[serde(default)]
+    pub framed: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Matcher {
+    Exact {
+        data_b64: String,
+    },
+    Prefix {
+        data_b64: String,
+    },
+    /// Searched anywhere in the message, which is treated as UTF-8 text.
+    Regex {
+        pattern: String,
+    },
+    /// Hex bytes compared against the start of the message, e.g. `"01 03 ?? ?? 00 0A"` where `??`
+    /// matches any byte. `mask_hex`, when given, is ANDed onto both sides before comparing.
+    HexMask {
+        value_hex: String,
+        mask_hex: Option<String>,
+    },
+    Any,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Fixed {
+        data_b64: String,
+    },
+    /// Renders the named payload template for every reply.
+    Template {
+        name: String,
+    },
+    /// Sends the received message back.
+    Echo,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RuleStatus {
+    pub index: usize,
+    pub name: Option<String>,
+    pub hits: u64,
+}
+
+struct Rule {
+    name: Option<String>,
+    matcher: Compiled,
+    reply: Reply,
+    delay: Option<Duration>,
+    framed: bool,
+    hits: u64,
+}
+
+enum Compiled {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+    Regex(Regex),
+    Masked { value: Vec<u8>, mask: Vec<u8> },
+    Any,
+}
+
+static RULES: OnceCell<Mutex<HashMap<String, Vec<Rule>>>> = OnceCell::new();
+/// Lets the receive paths skip the lock while no session has rules.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn rules() -> NetResult<MutexGuard<'static, HashMap<String, Vec<Rule>>>> {
+    RULES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+/// Replaces the rules of a TCP or UDP server session; hit counters start from zero.
+pub fn set(session_id: String, specs: Vec<RuleSpec>) -> NetResult<Updated> {
+    let info = session::get(&session_id)?;
+    if !matches!(info.kind, SessionKind::TcpServer | SessionKind::UdpServer) {
+        return Err(NetError::unsupported(format!(
+            "auto-responder rules apply to servers, not a {}",
+            info.kind.label()
+        )));
+    }
+    let compiled = specs
+        .into_iter()
+        .map(compile)
+        .collect::<NetResult<Vec<_>>>()?;
+    let n = compiled.len();
+    let mut all = rules()?;
+    all.insert(session_id.clone(), compiled);
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(Updated {
+        session_id,
+        message: format!("{} responder rule(s) set", n),
+    })
+}
+
+pub fn clear(session_id: String) -> NetResult<Updated> {
+    forget(&session_id);
+    Ok(Updated {
+        session_id,
+        message: "responder rules cleared".into(),
+    })
+}
+
+pub fn status(session_id: &str) -> NetResult<Vec<RuleStatus>> {
+    session::get(session_id)?;
+    let all = rules()?;
+    Ok(all
+        .get(session_id)
+        .map(|rs| {
+            rs.iter()
+                .enumerate()
+                .map(|(index, r)| RuleStatus {
+                    index,
+                    name: r.name.clone(),
+                    hits: r.hits,
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Drops the rules of a session that went away.
+pub fn forget(session_id: &str) {
+    if let Ok(mut all) = rules() {
+        all.remove(session_id);
+        ACTIVE.store(!all.is_empty(), Ordering::Relaxed);
+    }
+}
+
+/// Called for every message a server receives; replies in the background when a rule matches.
+pub fn handle(
+    app: &AppHandle,
+    session_id: &str,
+    kind: SessionKind,
+    addr: &str,
+    peer: &str,
+    data: &[u8],
+) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let (index, name, hits, reply, delay, framed) = {
+        let Ok(mut all) = rules() else {
+            return;
+        };
+        let Some(rs) = all.get_mut(session_id) else {
+            return;
+        };
+        let Some((index, rule)) = rs
+            .iter_mut()
+            .enumerate()
+            .find(|(_, r)| r.matcher.matches(data))
+        else {
+            return;
+        };
+        rule.hits += 1;
+        (
+            index,
+            rule.name.clone(),
+            rule.hits,
+            rule.reply.clone(),
+            rule.delay,
+            rule.framed,
+        )
+    };
+
+    let payload = json!({
+        "session": session_id,
+        "rule": index,
+        "name": name,
+        "peer": peer,
+        "hits": hits,
+    });
+    let _ = app.emit("responder:hit", payload);
+
+    let app = app.clone();
+    let (sid, addr, peer) = (session_id.to_string(), addr.to_string(), peer.to_string());
+    let data = data.to_vec();
+    async_runtime::spawn(async move {
+        if let Some(d) = delay {
+            tokio::time::sleep(d).await;
+        }
+        let sent = match reply_bytes(&reply, data) {
+            Ok(bytes) => match kind {
+                SessionKind::TcpServer => {
+                    tcp_server::send_bytes(&addr, Some(&peer), bytes, framed).await
+                }
+                _ => udp_server::send_bytes_from(&addr, &peer, &bytes, framed).await,
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            let payload = json!({"session": sid, "rule": index, "peer": peer, "error": e.message});
+            let _ = app.emit("responder:error", payload);
+        }
+    });
+}
+
+fn reply_bytes(reply: &Reply, received: Vec<u8>) -> NetResult<Vec<u8>> {
+    match reply {
+        Reply::Fixed { data_b64 } => decode(data_b64),
+        Reply::Template { name } => template::render(name),
+        Reply::Echo => Ok(received),
+    }
+}
+
+fn compile(spec: RuleSpec) -> NetResult<Rule> {
+    let matcher = match spec.matcher {
+        Matcher::Exact { data_b64 } => Compiled::Exact(decode(&data_b64)?),
+        Matcher::Prefix { data_b64 } => Compiled::Prefix(decode(&data_b64)?),
+        Matcher::Regex { pattern } => Compiled::Regex(
+            Regex::new(&pattern)
+                .map_err(|e| NetError::invalid_input("responder rule", &pattern, e.to_string()))?,
+        ),
+        Matcher::HexMask {
+            value_hex,
+            mask_hex,
+        } => {
+            let (value, mut mask) = parse_hex(&value_hex)?;
+            if let Some(m) = mask_hex {
+                let (m, _) = parse_hex(&m)?;
+                if m.len() != value.len() {
+                    return Err(NetError::invalid_input(
+                        "responder rule",
+                        &value_hex,
+                        format!("mask has {} bytes but value has {}", m.len(), value.len()),
+                    ));
+                }
+                mask.iter_mut().zip(m).for_each(|(a, b)| *a &= b);
+            }
+            Compiled::Masked { value, mask }
+        }
+        Matcher::Any => Compiled::Any,
+    };
+    if let Reply::Fixed { data_b64 } = &spec.reply {
+        decode(data_b64)?;
+    }
+    Ok(Rule {
+        name: spec.name,
+        matcher,
+        reply: spec.reply,
+        delay: spec.delay_ms.filter(|d| *d > 0).map(Duration::from_millis),
+        framed: spec.framed,
+        hits: 0,
+    })
+}
+
+impl Compiled {
+    fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            Compiled::Exact(b) => data == b.as_slice(),
+            Compiled::Prefix(b) => data.starts_with(b),
+            Compiled::Regex(re) => re.is_match(data),
+            Compiled::Masked { value, mask } => {
+                data.len() >= value.len()
+                    && value
+                        .iter()
+                        .zip(mask)
+                        .zip(data)
+                        .all(|((v, m), d)| d & m == v & m)
+            }
+            Compiled::Any => true,
+        }
+    }
+}
+
+fn decode(data_b64: &str) -> NetResult<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(data_b64)
+        .map_err(NetError::decode)
+}
+
+/// Parses whitespace-separated or contiguous hex; `??` yields a zero mask byte.
+fn parse_hex(s: &str) -> NetResult<(Vec<u8>, Vec<u8>)> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(NetError::invalid_input(
+            "responder rule",
+            s,
+            "odd number of hex digits".into(),
+        ));
+    }
+    let mut value = Vec::with_capacity(digits.len() / 2);
+    let mut mask = Vec::with_capacity(digits.len() / 2);
+    for pair in digits.chunks(2) {
+        if pair == ['?', '?'] {
+            value.push(0);
+            mask.push(0);
+            continue;
+        }
+        let byte: String = pair.iter().collect();
+        let b = u8::from_str_radix(&byte, 16).map_err(|_| {
+            NetError::invalid_input("responder rule", s, format!("invalid hex byte {}", byte))
+        })?;
+        value.push(b);
+        mask.push(0xFF);
+    }
+    Ok((value, mask))
+}
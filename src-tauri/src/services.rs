@@ -0,0 +1,139 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::template;
+
+/// Seconds from 1900-01-01 (the RFC 868 epoch) to 1970-01-01.
+const RFC868_OFFSET: u64 = 2_208_988_800;
+/// RFC 864 lines: 72 characters out of the 95 printable ASCII ones, then CRLF.
+const CHARGEN_LINE: usize = 72;
+const PRINTABLE: u8 = 95;
+
+/// What a server does on its own with the traffic it receives; `normal` leaves everything to the
+/// UI (and the responder rules).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    #[default]
+    Normal,
+    /// RFC 862: sends back whatever arrives.
+    Echo,
+    /// RFC 863: accepts and drops everything.
+    Discard,
+    /// RFC 864: TCP peers get an endless stream of character lines; UDP datagrams are answered
+    /// with one random-length datagram of them.
+    Chargen,
+    /// RFC 867: the current date and time as text, then the connection is closed.
+    Daytime,
+    /// RFC 868: seconds since 1900 as a 32-bit big-endian number, then the connection is closed.
+    Time,
+}
+
+impl ServerMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            ServerMode::Normal => "",
+            ServerMode::Echo => "echo ",
+            ServerMode::Discard => "discard ",
+            ServerMode::Chargen => "chargen ",
+            ServerMode::Daytime => "daytime ",
+            ServerMode::Time => "time ",
+        }
+    }
+
+    /// Sent to a TCP peer as soon as it connects, for the modes that close right after.
+    pub fn greeting(self) -> Option<Vec<u8>> {
+        match self {
+            ServerMode::Daytime => Some(daytime().into_bytes()),
+            ServerMode::Time => Some(time().to_vec()),
+            _ => None,
+        }
+    }
+
+    /// The answer to one UDP datagram.
+    pub fn udp_reply(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            ServerMode::Normal | ServerMode::Discard => None,
+            ServerMode::Echo => Some(data.to_vec()),
+            ServerMode::Chargen => {
+                let mut gen = Chargen::default();
+                let len = rand::thread_rng().gen_range(0..=512);
+                let mut out = Vec::with_capacity(len + CHARGEN_LINE);
+                while out.len() < len {
+                    gen.line(&mut out);
+                }
+                out.truncate(len);
+                Some(out)
+            }
+            ServerMode::Daytime | ServerMode::Time => self.greeting(),
+        }
+    }
+}
+
+/// The rotating RFC 864 character pattern; each line starts one character further along.
+#[derive(Default)]
+pub struct Chargen {
+    start: u8,
+}
+
+impl Chargen {
+    pub fn line(&mut self, out: &mut Vec<u8>) {
+        out.extend((0..CHARGEN_LINE as u8).map(|i| b' ' + (self.start + i) % PRINTABLE));
+        out.extend_from_slice(b"\r\n");
+        self.start = (self.start + 1) % PRINTABLE;
+    }
+}
+
+/// RFC 867 leaves the format open; this is the one it gives as an example, in UTC.
+fn daytime() -> String {
+    const DAYS: [&str; 7] = [
+        "Thursday",
+        "Friday",
+        "Saturday",
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+    ];
+    const MONTHS: [&str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+    let secs = unix_secs();
+    let days = (secs / 86_400) as i64;
+    let (year, month, day) = template::civil_date(days);
+    let rem = secs % 86_400;
+    format!(
+        "{}, {} {}, {} {:02}:{:02}:{:02}-UTC\r\n",
+        DAYS[days.rem_euclid(7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        year,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+fn time() -> [u8; 4] {
+    // wraps in 2036, as the protocol does
+    ((unix_secs() + RFC868_OFFSET) as u32).to_be_bytes()
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
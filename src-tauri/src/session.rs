@@ -0,0 +1,366 @@
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+use crate::checksum;
+use crate::error::{NetError, NetResult};
+use crate::impair;
+use crate::modbus::Slave;
+use crate::recorder;
+use crate::responder;
+use crate::sockopt::SocketOptions;
+use crate::{
+    modbus_client, mqtt_client, proxy, tcp_client, tcp_server, udp_client, udp_server, ws_client,
+    ws_server,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionKind {
+    TcpServer,
+    TcpClient,
+    UdpServer,
+    UdpClient,
+    TcpProxy,
+    UdpProxy,
+    WsServer,
+    WsClient,
+    ModbusClient,
+    MqttClient,
+}
+
+impl SessionKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            SessionKind::TcpServer => "TCP server",
+            SessionKind::TcpClient => "TCP client",
+            SessionKind::UdpServer => "UDP server",
+            SessionKind::UdpClient => "UDP client",
+            SessionKind::TcpProxy => "TCP proxy",
+            SessionKind::UdpProxy => "UDP proxy",
+            SessionKind::WsServer => "WebSocket server",
+            SessionKind::WsClient => "WebSocket client",
+            SessionKind::ModbusClient => "Modbus TCP client",
+            SessionKind::MqttClient => "MQTT client",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    /// A TCP client whose connect is still in flight.
+    Connecting,
+    Running,
+    /// A TCP client that lost its connection and is waiting to retry.
+    Reconnecting,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub kind: SessionKind,
+    pub addr: String,
+    pub created_ms: u64,
+    pub state: SessionState,
+}
+
+/// Returned by every `start_*` command.
+#[derive(Clone, Debug, Serialize)]
+pub struct Started {
+    pub session_id: String,
+    pub kind: SessionKind,
+    pub addr: String,
+    pub message: String,
+}
+
+/// Returned by every `stop_*` command; lists the sessions that were shut down.
+#[derive(Clone, Debug, Serialize)]
+pub struct Stopped {
+    pub session_ids: Vec<String>,
+    pub message: String,
+}
+
+/// Returned by every send command.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sent {
+    /// `None` when the data went out through a temporary socket rather than a running session.
+    pub session_id: Option<String>,
+    pub bytes: usize,
+    pub peers: usize,
+    pub message: String,
+}
+
+/// Returned by commands that change the settings of a running session.
+#[derive(Clone, Debug, Serialize)]
+pub struct Updated {
+    pub session_id: String,
+    pub message: String,
+}
+
+pub enum SessionHandle {
+    TcpServer(tcp_server::ServerHandle),
+    TcpClient(tcp_client::ClientHandle),
+    UdpServer(udp_server::ServerHandle),
+    UdpClient(udp_client::ClientHandle),
+    /// Both TCP and UDP proxies; the session kind tells them apart.
+    Proxy(proxy::ProxyHandle),
+    WsServer(ws_server::ServerHandle),
+    WsClient(ws_client::ClientHandle),
+    ModbusClient(modbus_client::ClientHandle),
+    MqttClient(mqtt_client::ClientHandle),
+}
+
+impl SessionHandle {
+    pub async fn stop(self) {
+        match self {
+            SessionHandle::TcpServer(h) => h.stop().await,
+            SessionHandle::TcpClient(h) => h.stop().await,
+            SessionHandle::UdpServer(h) => h.stop().await,
+            SessionHandle::UdpClient(h) => h.stop().await,
+            SessionHandle::Proxy(h) => h.stop().await,
+            SessionHandle::WsServer(h) => h.stop().await,
+            SessionHandle::WsClient(h) => h.stop().await,
+            SessionHandle::ModbusClient(h) => h.stop().await,
+            SessionHandle::MqttClient(h) => h.stop().await,
+        }
+    }
+
+    /// Duplicates of every OS socket behind the session, labelled for `get_socket_options`.
+    pub fn sockets(&self) -> Vec<(String, socket2::Socket)> {
+        match self {
+            SessionHandle::TcpServer(h) => h.sockets(),
+            SessionHandle::TcpClient(h) => h.sockets(),
+            SessionHandle::UdpServer(h) => h.sockets(),
+            SessionHandle::UdpClient(h) => h.sockets(),
+            SessionHandle::Proxy(h) => h.sockets(),
+            SessionHandle::WsServer(h) => h.sockets(),
+            SessionHandle::WsClient(h) => h.sockets(),
+            SessionHandle::ModbusClient(h) => h.sockets(),
+            SessionHandle::MqttClient(h) => h.sockets(),
+        }
+    }
+
+    /// TCP sessions apply these to connections opened later; UDP sessions have just the one socket.
+    pub fn remember_socket_options(&self, options: &SocketOptions) {
+        match self {
+            SessionHandle::TcpServer(h) => h.remember_socket_options(options),
+            SessionHandle::TcpClient(h) => h.remember_socket_options(options),
+            SessionHandle::WsServer(h) => h.remember_socket_options(options),
+            SessionHandle::UdpServer(_)
+            | SessionHandle::UdpClient(_)
+            | SessionHandle::Proxy(_)
+            | SessionHandle::WsClient(_)
+            | SessionHandle::ModbusClient(_)
+            | SessionHandle::MqttClient(_) => {}
+        }
+    }
+
+    pub fn udp_socket(&self) -> Option<Arc<UdpSocket>> {
+        match self {
+            SessionHandle::UdpServer(h) => Some(h.socket()),
+            SessionHandle::UdpClient(h) => Some(h.socket()),
+            _ => None,
+        }
+    }
+
+    pub fn modbus_slave(&self) -> Option<Arc<Slave>> {
+        match self {
+            SessionHandle::TcpServer(h) => h.modbus_slave(),
+            _ => None,
+        }
+    }
+}
+
+pub struct Session {
+    pub info: SessionInfo,
+    pub handle: SessionHandle,
+}
+
+impl Session {
+    pub async fn stop(self) -> SessionInfo {
+        self.handle.stop().await;
+        forget(&self.info.id);
+        self.info
+    }
+}
+
+/// For a session whose task ended on its own: takes it out of the registry without stopping it
+/// (the caller is that task) and drops the same per-session state `Session::stop` does.
+pub fn drop_dead(id: &str) {
+    if let Ok(mut reg) = registry() {
+        reg.remove_id(id);
+    }
+    forget(id);
+}
+
+fn forget(id: &str) {
+    recorder::close(id);
+    checksum::forget(id);
+    responder::forget(id);
+    impair::forget(id);
+}
+
+/// All running sockets, in the order they were started.
+#[derive(Default)]
+pub struct Registry {
+    sessions: Vec<Session>,
+}
+
+impl Registry {
+    pub fn contains(&self, kind: SessionKind, addr: &str) -> bool {
+        self.position(kind, addr).is_some()
+    }
+
+    pub fn get(&self, kind: SessionKind, addr: &str) -> Option<&Session> {
+        self.position(kind, addr).map(|i| &self.sessions[i])
+    }
+
+    pub fn get_id(&self, id: &str) -> Option<&Session> {
+        self.sessions.iter().find(|s| s.info.id == id)
+    }
+
+    /// Hands the handle back if another session of the same kind grabbed `addr` in the meantime.
+    pub fn insert(
+        &mut self,
+        id: String,
+        kind: SessionKind,
+        addr: String,
+        handle: SessionHandle,
+    ) -> Result<(), SessionHandle> {
+        if self.contains(kind, &addr) {
+            return Err(handle);
+        }
+        let info = SessionInfo {
+            id,
+            kind,
+            addr,
+            created_ms: now_ms(),
+            state: SessionState::Running,
+        };
+        self.sessions.push(Session { info, handle });
+        Ok(())
+    }
+
+    pub fn set_state(&mut self, id: &str, state: SessionState) {
+        if let Some(s) = self.sessions.iter_mut().find(|s| s.info.id == id) {
+            s.info.state = state;
+        }
+    }
+
+    pub fn remove_id(&mut self, id: &str) -> Option<Session> {
+        let i = self.sessions.iter().position(|s| s.info.id == id)?;
+        Some(self.sessions.remove(i))
+    }
+
+    pub fn remove(&mut self, kind: SessionKind, addr: &str) -> Option<Session> {
+        self.position(kind, addr).map(|i| self.sessions.remove(i))
+    }
+
+    pub fn remove_kind(&mut self, kind: SessionKind) -> Vec<Session> {
+        let (removed, kept) = std::mem::take(&mut self.sessions)
+            .into_iter()
+            .partition(|s| s.info.kind == kind);
+        self.sessions = kept;
+        removed
+    }
+
+    fn position(&self, kind: SessionKind, addr: &str) -> Option<usize> {
+        self.sessions
+            .iter()
+            .position(|s| s.info.kind == kind && s.info.addr == addr)
+    }
+}
+
+static SESSIONS: OnceCell<Mutex<Registry>> = OnceCell::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn registry() -> NetResult<MutexGuard<'static, Registry>> {
+    SESSIONS
+        .get_or_init(|| Mutex::new(Registry::default()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+/// Allocates an id before the session is inserted so worker tasks can tag their events with it.
+pub fn next_id() -> String {
+    format!("s{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn set_state(id: &str, state: SessionState) {
+    if let Ok(mut reg) = registry() {
+        reg.set_state(id, state);
+    }
+}
+
+pub fn list() -> NetResult<Vec<SessionInfo>> {
+    let reg = registry()?;
+    Ok(reg.sessions.iter().map(|s| s.info.clone()).collect())
+}
+
+pub fn get(id: &str) -> NetResult<SessionInfo> {
+    let reg = registry()?;
+    reg.get_id(id)
+        .map(|s| s.info.clone())
+        .ok_or_else(|| NetError::session_not_found(id))
+}
+
+/// Stops each session in turn and returns their ids.
+pub async fn stop_all(sessions: Vec<Session>) -> Vec<String> {
+    let mut ids = Vec::with_capacity(sessions.len());
+    for s in sessions {
+        ids.push(s.stop().await.id);
+    }
+    ids
+}
+
+pub async fn stop(id: &str) -> NetResult<Stopped> {
+    // Take the handle out first: the registry lock must not be held across the await below.
+    let session = registry()?
+        .remove_id(id)
+        .ok_or_else(|| NetError::session_not_found(id))?;
+    let info = session.stop().await;
+    Ok(Stopped {
+        message: format!("{} stopped on {}", info.kind.label(), info.addr),
+        session_ids: vec![info.id],
+    })
+}
+
+/// The socket of a running UDP session, for commands that tune it in place.
+pub fn udp_socket(id: &str) -> NetResult<Arc<UdpSocket>> {
+    let reg = registry()?;
+    let s = reg
+        .get_id(id)
+        .ok_or_else(|| NetError::session_not_found(id))?;
+    s.handle.udp_socket().ok_or_else(|| {
+        NetError::unsupported(format!(
+            "{} sessions have no UDP socket",
+            s.info.kind.label()
+        ))
+    })
+}
+
+/// The tables of a running Modbus slave, for commands that edit them in place.
+pub fn modbus_slave(id: &str) -> NetResult<Arc<Slave>> {
+    let reg = registry()?;
+    let s = reg
+        .get_id(id)
+        .ok_or_else(|| NetError::session_not_found(id))?;
+    s.handle.modbus_slave().ok_or_else(|| {
+        NetError::unsupported(format!(
+            "{} {} is not a Modbus slave",
+            s.info.kind.label(),
+            s.info.addr
+        ))
+    })
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
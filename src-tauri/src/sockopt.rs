@@ -0,0 +1,452 @@
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+use crate::error::{NetError, NetResult};
+use crate::session::{self, Updated};
+
+/// Options that must be set before `bind`, so they can only be given to `start_*`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BindOptions {
+    /// SO_REUSEADDR: share the port with other sockets that set it too.
+    #[serde(default)]
+    pub reuse_addr: bool,
+    /// SO_REUSEPORT (Unix only): load-share or co-listen on the same port.
+    #[serde(default)]
+    pub reuse_port: bool,
+}
+
+/// Binds a UDP session socket. SO_BROADCAST is always on so the session can also send to
+/// 255.255.255.255 or a subnet broadcast address.
+pub async fn bind_udp(
+    addr: &str,
+    opts: &BindOptions,
+    sockopts: &SocketOptions,
+) -> io::Result<UdpSocket> {
+    let sa = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    })?;
+    let sock = Socket::new(Domain::for_address(sa), Type::DGRAM, Some(Protocol::UDP))?;
+    if opts.reuse_addr {
+        sock.set_reuse_address(true)?;
+    }
+    if opts.reuse_port {
+        set_reuse_port(&sock)?;
+    }
+    sock.set_broadcast(true)?;
+    apply(&sock, sockopts)?;
+    set_v6only(&sock, sockopts)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&sa.into())?;
+    UdpSocket::from_std(sock.into())
+}
+
+/// Like `TcpListener::bind`, but with `sockopts` applied before the bind so IPV6_V6ONLY works.
+pub async fn bind_tcp(addr: &str, sockopts: &SocketOptions) -> io::Result<TcpListener> {
+    let sa = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    })?;
+    let sock = Socket::new(Domain::for_address(sa), Type::STREAM, Some(Protocol::TCP))?;
+    // same as tokio/std: lets a restarted server rebind while old connections sit in TIME_WAIT
+    #[cfg(unix)]
+    sock.set_reuse_address(true)?;
+    apply(&sock, sockopts)?;
+    set_v6only(&sock, sockopts)?;
+    sock.set_nonblocking(true)?;
+    sock.bind(&sa.into())?;
+    sock.listen(1024)?;
+    TcpListener::from_std(sock.into())
+}
+
+/// Connects to the first address `addr` resolves to that accepts, with `sockopts` applied
+/// before each attempt. Sessions start with TCP_NODELAY on.
+pub async fn connect_tcp(addr: &str, sockopts: &SocketOptions) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing");
+    for sa in tokio::net::lookup_host(addr).await? {
+        match connect_tcp_to(sa, sockopts).await {
+            Ok(s) => return Ok(s),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Like `TcpStream::connect` to one address, but with `sockopts` applied before the connect so
+/// IPV6_V6ONLY works.
+pub async fn connect_tcp_to(sa: SocketAddr, sockopts: &SocketOptions) -> io::Result<TcpStream> {
+    let sock = Socket::new(Domain::for_address(sa), Type::STREAM, Some(Protocol::TCP))?;
+    sock.set_tcp_nodelay(true)?;
+    apply(&sock, sockopts)?;
+    set_v6only(&sock, sockopts)?;
+    sock.set_nonblocking(true)?;
+    TcpSocket::from_std_stream(sock.into()).connect(sa).await
+}
+
+#[cfg(unix)]
+fn set_reuse_port(sock: &Socket) -> io::Result<()> {
+    sock.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_sock: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not available on this platform",
+    ))
+}
+
+/// Per-socket options accepted by every `start_*` and by `set_socket_options`.
+/// Unset fields are left alone.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SocketOptions {
+    /// IPv4 TTL or IPv6 unicast hop limit.
+    pub ttl: Option<u32>,
+    /// Whole IPv4 TOS / IPv6 traffic class byte.
+    pub tos: Option<u32>,
+    /// DSCP code point (0-63); replaces the upper six bits of the TOS byte, keeping ECN.
+    pub dscp: Option<u8>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    /// TCP only.
+    pub keepalive: Option<Keepalive>,
+    /// TCP only.
+    pub linger: Option<Linger>,
+    /// TCP only; sessions start with TCP_NODELAY on.
+    pub nodelay: Option<bool>,
+    /// IPv6 sockets only, and only before bind or connect, i.e. as a `start_*` option; ignored
+    /// for accepted connections, which inherit it from their listener.
+    pub v6only: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Keepalive {
+    pub enabled: bool,
+    /// Idle time before the first probe.
+    pub time_ms: Option<u64>,
+    pub interval_ms: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Linger {
+    pub enabled: bool,
+    #[serde(default)]
+    pub timeout_ms: u64,
+}
+
+impl SocketOptions {
+    /// Overlays the fields set in `other`; used to remember options for future connections.
+    pub fn merge(&mut self, other: &SocketOptions) {
+        self.ttl = other.ttl.or(self.ttl);
+        self.tos = other.tos.or(self.tos);
+        self.dscp = other.dscp.or(self.dscp);
+        self.recv_buffer_size = other.recv_buffer_size.or(self.recv_buffer_size);
+        self.send_buffer_size = other.send_buffer_size.or(self.send_buffer_size);
+        self.keepalive = other.keepalive.or(self.keepalive);
+        self.linger = other.linger.or(self.linger);
+        self.nodelay = other.nodelay.or(self.nodelay);
+        self.v6only = other.v6only.or(self.v6only);
+    }
+}
+
+/// Effective values read back from one socket; `null` where the option does not apply or the
+/// platform cannot report it.
+#[derive(Clone, Debug, Serialize)]
+pub struct SocketOptionValues {
+    /// `listener`, a peer address, or the session's own address.
+    pub socket: String,
+    pub ttl: Option<u32>,
+    pub tos: Option<u32>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub keepalive: Option<bool>,
+    pub keepalive_time_ms: Option<u64>,
+    pub keepalive_interval_ms: Option<u64>,
+    pub keepalive_retries: Option<u32>,
+    /// `null` when SO_LINGER is off.
+    pub linger_ms: Option<u64>,
+    pub nodelay: Option<bool>,
+    pub v6only: Option<bool>,
+}
+
+/// Applies everything but `v6only`, which the kernel refuses once a socket has a local port;
+/// see [`set_v6only`].
+pub fn apply(sock: &Socket, opts: &SocketOptions) -> io::Result<()> {
+    validate(opts)?;
+    let tcp = sock.r#type()? == Type::STREAM;
+    let v6 = sock.domain()? == Domain::IPV6;
+    let tcp_only = |name: &str| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} only applies to TCP sockets", name),
+        )
+    };
+
+    if let Some(ttl) = opts.ttl {
+        if v6 {
+            sock.set_unicast_hops_v6(ttl)?;
+        } else {
+            sock.set_ttl_v4(ttl)?;
+        }
+    }
+    if let Some(tos) = opts.tos {
+        set_tos(sock, v6, tos)?;
+    }
+    if let Some(dscp) = opts.dscp {
+        let ecn = tos(sock, v6).unwrap_or(0) & 0b11;
+        set_tos(sock, v6, (dscp as u32) << 2 | ecn)?;
+    }
+    if let Some(size) = opts.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = opts.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(ka) = opts.keepalive {
+        if !tcp {
+            return Err(tcp_only("keepalive"));
+        }
+        if ka.enabled {
+            let mut params = TcpKeepalive::new();
+            if let Some(ms) = ka.time_ms {
+                params = params.with_time(Duration::from_millis(ms));
+            }
+            if let Some(ms) = ka.interval_ms {
+                params = params.with_interval(Duration::from_millis(ms));
+            }
+            if let Some(n) = ka.retries {
+                params = params.with_retries(n);
+            }
+            sock.set_tcp_keepalive(&params)?;
+        } else {
+            sock.set_keepalive(false)?;
+        }
+    }
+    if let Some(linger) = opts.linger {
+        if !tcp {
+            return Err(tcp_only("linger"));
+        }
+        let timeout = linger
+            .enabled
+            .then(|| Duration::from_millis(linger.timeout_ms));
+        sock.set_linger(timeout)?;
+    }
+    if let Some(nodelay) = opts.nodelay {
+        if !tcp {
+            return Err(tcp_only("nodelay"));
+        }
+        sock.set_tcp_nodelay(nodelay)?;
+    }
+    Ok(())
+}
+
+/// The checks that don't need a socket.
+fn validate(opts: &SocketOptions) -> io::Result<()> {
+    match opts.dscp {
+        Some(dscp) if dscp > 63 => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("DSCP must be 0-63 (got {})", dscp),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// IPV6_V6ONLY, for sockets that are not bound or connected yet.
+fn set_v6only(sock: &Socket, opts: &SocketOptions) -> io::Result<()> {
+    if let Some(only) = opts.v6only {
+        if sock.domain()? != Domain::IPV6 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "v6only only applies to IPv6 sockets",
+            ));
+        }
+        sock.set_only_v6(only)?;
+    }
+    Ok(())
+}
+
+pub fn read(socket: String, sock: &Socket) -> SocketOptionValues {
+    let tcp = sock.r#type().map(|t| t == Type::STREAM).unwrap_or(false);
+    let v6 = sock.domain().map(|d| d == Domain::IPV6).unwrap_or(false);
+    let keepalive = if tcp { sock.keepalive().ok() } else { None };
+    let (keepalive_time_ms, keepalive_interval_ms, keepalive_retries) = if keepalive == Some(true) {
+        keepalive_params(sock)
+    } else {
+        (None, None, None)
+    };
+    SocketOptionValues {
+        socket,
+        ttl: if v6 {
+            sock.unicast_hops_v6().ok()
+        } else {
+            sock.ttl_v4().ok()
+        },
+        tos: tos(sock, v6).ok(),
+        recv_buffer_size: sock.recv_buffer_size().ok(),
+        send_buffer_size: sock.send_buffer_size().ok(),
+        keepalive,
+        keepalive_time_ms,
+        keepalive_interval_ms,
+        keepalive_retries,
+        linger_ms: if tcp {
+            sock.linger().ok().flatten().map(|d| d.as_millis() as u64)
+        } else {
+            None
+        },
+        nodelay: if tcp { sock.tcp_nodelay().ok() } else { None },
+        v6only: if v6 { sock.only_v6().ok() } else { None },
+    }
+}
+
+/// Applies `options` to every socket of a session. TCP sessions also keep them for
+/// connections made later (reconnects, newly accepted peers).
+pub fn set(session_id: String, options: SocketOptions) -> NetResult<Updated> {
+    if options.v6only.is_some() {
+        return Err(NetError::unsupported(
+            "v6only can only be set when the session starts, before its sockets are bound".into(),
+        ));
+    }
+    validate(&options).map_err(|e| NetError::io("set socket options", &session_id, e))?;
+    let sockets = {
+        let reg = session::registry()?;
+        let s = reg
+            .get_id(&session_id)
+            .ok_or_else(|| NetError::session_not_found(&session_id))?;
+        s.handle.sockets()
+    };
+    for (label, sock) in &sockets {
+        apply(sock, &options).map_err(|e| NetError::io("set socket options", label, e))?;
+    }
+    // only options the sockets took are kept, or every later connection would fail on them
+    if let Some(s) = session::registry()?.get_id(&session_id) {
+        s.handle.remember_socket_options(&options);
+    }
+    Ok(Updated {
+        message: format!("socket options applied to {} socket(s)", sockets.len()),
+        session_id,
+    })
+}
+
+pub fn get(session_id: &str) -> NetResult<Vec<SocketOptionValues>> {
+    let sockets = {
+        let reg = session::registry()?;
+        let s = reg
+            .get_id(session_id)
+            .ok_or_else(|| NetError::session_not_found(session_id))?;
+        s.handle.sockets()
+    };
+    Ok(sockets
+        .into_iter()
+        .map(|(label, sock)| read(label, &sock))
+        .collect())
+}
+
+/// An owned duplicate of a tokio socket for option calls outside the task that owns it.
+/// Close it together with the original: a live duplicate keeps the connection open.
+#[cfg(unix)]
+pub fn dup<S: std::os::fd::AsFd>(s: &S) -> io::Result<Socket> {
+    SockRef::from(s).try_clone()
+}
+
+#[cfg(windows)]
+pub fn dup<S: std::os::windows::io::AsSocket>(s: &S) -> io::Result<Socket> {
+    SockRef::from(s).try_clone()
+}
+
+fn tos(sock: &Socket, v6: bool) -> io::Result<u32> {
+    if v6 {
+        tclass(sock)
+    } else {
+        sock.tos_v4()
+    }
+}
+
+fn set_tos(sock: &Socket, v6: bool, tos: u32) -> io::Result<()> {
+    if v6 {
+        set_tclass(sock, tos)
+    } else {
+        sock.set_tos_v4(tos)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn tclass(sock: &Socket) -> io::Result<u32> {
+    sock.tclass_v6()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn set_tclass(sock: &Socket, tclass: u32) -> io::Result<()> {
+    sock.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn tclass(_sock: &Socket) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 traffic class is not available on this platform",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn set_tclass(_sock: &Socket, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 traffic class is not available on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn keepalive_params(sock: &Socket) -> (Option<u64>, Option<u64>, Option<u32>) {
+    (
+        sock.tcp_keepalive_time().ok().map(|d| d.as_millis() as u64),
+        sock.tcp_keepalive_interval()
+            .ok()
+            .map(|d| d.as_millis() as u64),
+        sock.tcp_keepalive_retries().ok(),
+    )
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn keepalive_params(_sock: &Socket) -> (Option<u64>, Option<u64>, Option<u32>) {
+    (None, None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::SockRef;
+
+    #[tokio::test]
+    async fn v6only_before_bind_and_connect() {
+        let opts = SocketOptions {
+            v6only: Some(true),
+            ttl: Some(42),
+            ..SocketOptions::default()
+        };
+        let listener = match bind_tcp("[::1]:0", &opts).await {
+            Ok(l) => l,
+            // no IPv6 on this host
+            Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => return,
+            Err(e) => panic!("bind: {}", e),
+        };
+        assert!(SockRef::from(&listener).only_v6().unwrap());
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client = connect_tcp(&addr, &opts).await.unwrap();
+        let client = SockRef::from(&client);
+        assert!(client.only_v6().unwrap());
+        assert!(client.tcp_nodelay().unwrap());
+        assert_eq!(client.unicast_hops_v6().unwrap(), 42);
+
+        // accepted and connected sockets take everything but v6only afterwards
+        let (peer, _) = listener.accept().await.unwrap();
+        apply(&SockRef::from(&peer), &opts).unwrap();
+        apply(&client, &opts).unwrap();
+
+        let e = connect_tcp("127.0.0.1:9", &opts).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+}
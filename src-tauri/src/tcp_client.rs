@@ -1,145 +1,501 @@
 use base64::Engine;
-use once_cell::sync::OnceCell;
-use serde_json::json;
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::{mpsc, Mutex};
-use std::thread::{self, JoinHandle};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io;
+use socket2::Socket;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::{self, JoinHandle};
 use tauri::AppHandle;
 use tauri::Emitter;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+use crate::checksum;
+use crate::error::{NetError, NetResult};
+use crate::framing::{self, Framer, FramerConfig};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Sent, Session, SessionHandle, SessionKind, SessionState, Started, Stopped};
+use crate::sockopt::{self, SocketOptions};
+use crate::template::Payload;
+use crate::tls::{self, BoxedStream, TlsClient, TlsClientConfig};
+
+/// Used when the caller does not pass `connect_timeout_ms`.
+pub const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backoff {
+	Fixed,
+	#[default]
+	Exponential,
+}
+
+/// What to do when an established connection drops. Without a policy the session is removed.
+/// A failed initial connect is always reported to the caller instead of retried.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReconnectPolicy {
+	#[serde(default)]
+	pub backoff: Backoff,
+	#[serde(default = "default_initial_delay_ms")]
+	pub initial_delay_ms: u64,
+	#[serde(default = "default_max_delay_ms")]
+	pub max_delay_ms: u64,
+	/// `None` retries forever.
+	pub max_attempts: Option<u32>,
+	/// Randomizes each delay by up to this fraction in either direction (0.0 - 1.0).
+	#[serde(default)]
+	pub jitter: f64,
+}
+
+fn default_initial_delay_ms() -> u64 {
+	1_000
+}
+
+fn default_max_delay_ms() -> u64 {
+	30_000
+}
+
+impl ReconnectPolicy {
+	/// Delay before reconnect attempt number `attempt` (1-based).
+	fn delay(&self, attempt: u32) -> Duration {
+		let base = match self.backoff {
+			Backoff::Fixed => self.initial_delay_ms,
+			Backoff::Exponential => self
+				.initial_delay_ms
+				.saturating_mul(1u64 << attempt.saturating_sub(1).min(32)),
+		}
+		.min(self.max_delay_ms.max(self.initial_delay_ms));
+		let jitter = self.jitter.clamp(0.0, 1.0);
+		if jitter == 0.0 || base == 0 {
+			return Duration::from_millis(base);
+		}
+		let spread = base as f64 * jitter;
+		let ms = base as f64 + rand::thread_rng().gen_range(-spread..=spread);
+		Duration::from_millis(ms.max(0.0) as u64)
+	}
+}
+
+/// State shared between the session handle and its connection task.
+struct Shared {
+	/// Empty until the connect completes.
+	writer: tokio::sync::Mutex<Option<WriteHalf<BoxedStream>>>,
+	/// Duplicate of the connected socket for the options commands; cleared together with `writer`.
+	sock: Mutex<Option<Socket>>,
+	/// Applied on every (re)connect; `set_socket_options` adds to it.
+	options: Mutex<SocketOptions>,
+	framing: Option<FramerConfig>,
+}
 
 pub struct ClientHandle {
-	stop_tx: mpsc::Sender<()>,
-	thread_handle: Option<JoinHandle<()>>,
-	stream: TcpStream,
+	cancel: CancellationToken,
+	task: JoinHandle<()>,
+	shared: Arc<Shared>,
 }
 
 impl ClientHandle {
-	pub fn stop(self) {
-		let _ = self.stop_tx.send(());
-		if let Some(h) = self.thread_handle {
-			let _ = h.join();
+	pub async fn stop(self) {
+		self.cancel.cancel();
+		let _ = self.task.await;
+	}
+
+	pub fn sockets(&self) -> Vec<(String, Socket)> {
+		let sock = match self.shared.sock.lock() {
+			Ok(s) => s.as_ref().and_then(|s| s.try_clone().ok()),
+			Err(_) => None,
+		};
+		let label = sock
+			.as_ref()
+			.and_then(|s| s.peer_addr().ok())
+			.and_then(|a| a.as_socket())
+			.map(|a| a.to_string())
+			.unwrap_or_default();
+		sock.map(|s| vec![(label, s)]).unwrap_or_default()
+	}
+
+	pub fn remember_socket_options(&self, options: &SocketOptions) {
+		if let Ok(mut o) = self.shared.options.lock() {
+			o.merge(options);
 		}
 	}
 }
 
-static TCP_CLIENT: OnceCell<Mutex<HashMap<String, ClientHandle>>> = OnceCell::new();
+/// Registers the session as `connecting` right away so `stop_tcp_client` can abort a slow
+/// connect, then waits for the outcome.
+pub async fn start(
+	app: AppHandle,
+	remote_addr: String,
+	connect_timeout_ms: Option<u64>,
+	reconnect: Option<ReconnectPolicy>,
+	tls: Option<TlsClientConfig>,
+	framing: Option<FramerConfig>,
+	socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+	if session::registry()?.contains(SessionKind::TcpClient, &remote_addr) {
+		return Err(NetError::already_running(SessionKind::TcpClient, &remote_addr));
+	}
+	let tls = tls.map(|cfg| tls::client(&cfg, &remote_addr)).transpose()?;
+	if let Some(f) = &framing {
+		f.validate()?;
+	}
+
+	let timeout_ms = connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+	let cancel = CancellationToken::new();
+	let id = session::next_id();
+	let shared = Arc::new(Shared {
+		writer: tokio::sync::Mutex::new(None),
+		sock: Mutex::new(None),
+		options: Mutex::new(socket_options.unwrap_or_default()),
+		framing,
+	});
+	let (registered_tx, registered_rx) = oneshot::channel::<()>();
+	let (connected_tx, connected_rx) = oneshot::channel::<NetResult<()>>();
+
+	let task = async_runtime::spawn(run(
+		app,
+		id.clone(),
+		remote_addr.clone(),
+		timeout_ms,
+		reconnect,
+		tls,
+		shared.clone(),
+		cancel.clone(),
+		registered_rx,
+		connected_tx,
+	));
+
+	let handle = ClientHandle {
+		cancel,
+		task,
+		shared,
+	};
+	let rejected = {
+		let mut reg = session::registry()?;
+		reg.insert(
+			id.clone(),
+			SessionKind::TcpClient,
+			remote_addr.clone(),
+			SessionHandle::TcpClient(handle),
+		)
+		.map(|()| reg.set_state(&id, SessionState::Connecting))
+	};
+	if let Err(h) = rejected {
+		h.stop().await;
+		return Err(NetError::already_running(SessionKind::TcpClient, &remote_addr));
+	}
+	let _ = registered_tx.send(());
+
+	let outcome = connected_rx
+		.await
+		.unwrap_or_else(|_| Err(NetError::cancelled("connect", &remote_addr)));
+	if let Err(e) = outcome {
+		// a stop command may already have removed it
+		let removed = session::registry()?.remove_id(&id);
+		if let Some(s) = removed {
+			s.stop().await;
+		}
+		return Err(e);
+	}
 
-fn init_cell() {
-	TCP_CLIENT.get_or_init(|| Mutex::new(HashMap::new()));
+	Ok(Started {
+		session_id: id,
+		kind: SessionKind::TcpClient,
+		message: format!("TCP client connected to {}", remote_addr),
+		addr: remote_addr,
+	})
 }
 
-pub fn start(app: AppHandle, remote_addr: String) -> Result<String, String> {
-	init_cell();
-	let cell = TCP_CLIENT.get().unwrap();
-	let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-	if guard.contains_key(&remote_addr) {
-		return Err("TCP client already connected to this address".into());
+#[allow(clippy::too_many_arguments)]
+async fn run(
+	app: AppHandle,
+	sid: String,
+	addr: String,
+	timeout_ms: u64,
+	reconnect: Option<ReconnectPolicy>,
+	tls: Option<TlsClient>,
+	shared: Arc<Shared>,
+	cancel: CancellationToken,
+	registered: oneshot::Receiver<()>,
+	connected: oneshot::Sender<NetResult<()>>,
+) {
+	// state updates below must not race the registry insert in start()
+	if registered.await.is_err() {
+		return;
 	}
 
-	let stream = TcpStream::connect(&remote_addr).map_err(|e| format!("connect error: {}", e))?;
-	let _ = stream.set_nodelay(true);
-	let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
-
-	let mut read_stream = stream
-		.try_clone()
-		.map_err(|e| format!("stream clone error: {}", e))?;
-	let _ = read_stream.set_read_timeout(Some(Duration::from_millis(100)));
-
-	let (tx, rx) = mpsc::channel::<()>();
-	let app_clone = app.clone();
-	let addr = remote_addr.clone();
-
-	let handle = thread::spawn(move || {
-		let mut buf = [0u8; 65536];
-		let mut seq: u64 = 0;
-		loop {
-			if rx.try_recv().is_ok() {
-				break;
+	// only the first connect reports back to start(); later ones come from the reconnect policy
+	let mut connected = Some(connected);
+	let mut seq: u64 = 0;
+	let mut attempt: u32 = 0;
+	loop {
+		let payload = json!({"session": sid, "remote": addr, "timeout_ms": timeout_ms, "attempt": attempt});
+		let _ = app.emit("tcp:client:connecting", payload);
+
+		let options = shared.options.lock().map(|o| o.clone()).unwrap_or_default();
+		match connect(&addr, timeout_ms, tls.as_ref(), &options, &cancel).await {
+			Ok((stream, sock, local, tls_info)) => {
+				attempt = 0;
+				let (reader, w) = tokio::io::split(stream);
+				*shared.writer.lock().await = Some(w);
+				if let Ok(mut s) = shared.sock.lock() {
+					*s = sock;
+				}
+				session::set_state(&sid, SessionState::Running);
+
+				let payload = json!({"session": sid, "remote": addr, "local": local, "tls": tls_info});
+				let _ = app.emit("tcp:client:connected", payload);
+				if let Some(tx) = connected.take() {
+					let _ = tx.send(Ok(()));
+				}
+
+				let framer = shared.framing.clone().map(Framer::new);
+				let conn = Conn {
+					app: &app,
+					sid: &sid,
+					remote: &addr,
+					local: &local,
+					framing: shared.framing.as_ref(),
+				};
+				let stopped = read_loop(&conn, reader, framer, &mut seq, &cancel).await;
+				*shared.writer.lock().await = None;
+				if let Ok(mut s) = shared.sock.lock() {
+					*s = None;
+				}
+				if stopped {
+					return;
+				}
 			}
-			match read_stream.read(&mut buf) {
-				Ok(0) => {
-					let payload = json!({"remote": addr, "error": "connection closed"});
-					let _ = app_clone.emit("tcp:client:error", payload);
-					break;
+			Err(e) => {
+				let payload = json!({"session": sid, "remote": addr, "error": e.message});
+				let _ = app.emit("tcp:client:error", payload);
+				if let Some(tx) = connected.take() {
+					let _ = tx.send(Err(e));
+					return;
 				}
-				Ok(n) => {
-					seq = seq.wrapping_add(1);
-					let b64 = base64::engine::general_purpose::STANDARD.encode(&buf[..n]);
-					let ts_ms = SystemTime::now()
-						.duration_since(UNIX_EPOCH)
-						.map(|d| d.as_millis() as u64)
-						.unwrap_or(0);
-					let payload = json!({
-						"remote": addr,
-						"data": b64,
-						"seq": seq,
-						"ts_ms": ts_ms,
-					});
-					let _ = app_clone.emit("tcp:client:message", payload);
+				if cancel.is_cancelled() {
+					return;
 				}
-				Err(e) => match e.kind() {
-					std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {}
-					_ => {
-						let payload = json!({"remote": addr, "error": format!("read error: {}", e)});
-						let _ = app_clone.emit("tcp:client:error", payload);
-						break;
-					}
-				},
 			}
 		}
-	});
 
-	guard.insert(
-		remote_addr.clone(),
-		ClientHandle {
-			stop_tx: tx,
-			thread_handle: Some(handle),
-			stream,
-		},
-	);
+		let policy = match &reconnect {
+			Some(p) if p.max_attempts.is_none_or(|max| attempt < max) => p,
+			_ => {
+				if reconnect.is_some() {
+					let payload = json!({"session": sid, "remote": addr, "error": format!("reconnect gave up after {} attempt(s)", attempt)});
+					let _ = app.emit("tcp:client:error", payload);
+				}
+				// nothing will revive this connection, so don't leave a dead session behind
+				session::drop_dead(&sid);
+				return;
+			}
+		};
+		attempt += 1;
+		let delay = policy.delay(attempt);
+		session::set_state(&sid, SessionState::Reconnecting);
+		let payload = json!({
+			"session": sid,
+			"remote": addr,
+			"attempt": attempt,
+			"max_attempts": policy.max_attempts,
+			"delay_ms": delay.as_millis() as u64,
+		});
+		let _ = app.emit("tcp:client:reconnecting", payload);
 
-	Ok(format!("TCP client connected to {}", remote_addr))
+		tokio::select! {
+			_ = cancel.cancelled() => return,
+			_ = tokio::time::sleep(delay) => {}
+		}
+	}
 }
 
-pub fn stop(remote_addr: Option<String>) -> Result<String, String> {
-	init_cell();
-	let cell = TCP_CLIENT.get().unwrap();
-	let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-	if let Some(a) = remote_addr {
-		if let Some(h) = guard.remove(&a) {
-			h.stop();
-			Ok(format!("TCP client disconnected from {}", a))
-		} else {
-			Err("TCP client not connected to that address".into())
+/// Connects (and handshakes, for TLS) within `timeout_ms`. Returns the stream, a duplicate of
+/// its socket, the local address and the negotiated TLS parameters (`null` for plain TCP).
+pub async fn connect(
+	addr: &str,
+	timeout_ms: u64,
+	tls: Option<&TlsClient>,
+	options: &SocketOptions,
+	cancel: &CancellationToken,
+) -> NetResult<(BoxedStream, Option<Socket>, String, Value)> {
+	let attempt = async {
+		let tcp = sockopt::connect_tcp(addr, options)
+			.await
+			.map_err(|e| NetError::io("connect", addr, e))?;
+		let sock = sockopt::dup(&tcp).ok();
+		let local = tcp.local_addr().map(|a| a.to_string()).unwrap_or_default();
+		match tls {
+			None => Ok((Box::new(tcp) as BoxedStream, sock, local, Value::Null)),
+			Some(t) => {
+				let stream = t
+					.connector
+					.connect(t.server_name.clone(), tcp)
+					.await
+					.map_err(|e| NetError::io("tls handshake", addr, e))?;
+				let info = tls::describe(stream.get_ref().1);
+				Ok((Box::new(stream) as BoxedStream, sock, local, info))
+			}
 		}
-	} else {
-		let previous = std::mem::take(&mut *guard);
-		for (_k, h) in previous {
-			h.stop();
+	};
+	tokio::select! {
+		_ = cancel.cancelled() => Err(NetError::cancelled("connect", addr)),
+		r = tokio::time::timeout(Duration::from_millis(timeout_ms), attempt) => r.unwrap_or_else(|_| {
+			Err(NetError::io(
+				"connect",
+				addr,
+				io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {} ms", timeout_ms)),
+			))
+		}),
+	}
+}
+
+/// Returns `true` when the session was stopped, `false` when the connection was lost.
+async fn read_loop(
+	conn: &Conn<'_>,
+	mut reader: ReadHalf<BoxedStream>,
+	mut framer: Option<Framer>,
+	seq: &mut u64,
+	cancel: &CancellationToken,
+) -> bool {
+	let mut buf = vec![0u8; 65536];
+	let mut frames = Vec::new();
+	loop {
+		let idle = framer.as_ref().filter(|f| f.has_pending()).and_then(Framer::idle_timeout);
+		let read = tokio::select! {
+			_ = cancel.cancelled() => return true,
+			_ = framing::idle_expired(idle) => {
+				if let Some(frame) = framer.as_mut().and_then(Framer::flush) {
+					emit_message(conn, &frame, seq, false);
+				}
+				continue;
+			}
+			r = reader.read(&mut buf) => r,
+		};
+		let error = match read {
+			Ok(0) => "connection closed".to_string(),
+			Ok(n) => {
+				if let Err(e) = framing::feed(framer.as_mut(), &buf[..n], &mut frames) {
+					let payload = json!({"session": conn.sid, "remote": conn.remote, "error": e.message});
+					let _ = conn.app.emit("tcp:client:error", payload);
+				}
+				for frame in frames.drain(..) {
+					emit_message(conn, &frame, seq, false);
+				}
+				continue;
+			}
+			Err(e) => format!("read error: {}", e),
+		};
+
+		// hand over a half-received frame rather than losing it
+		if let Some(f) = framer.as_mut() {
+			let partial = f.idle_timeout().is_none();
+			if let Some(frame) = f.flush() {
+				emit_message(conn, &frame, seq, partial);
+			}
 		}
-		Ok("All TCP clients disconnected".into())
+		let payload = json!({"session": conn.sid, "remote": conn.remote, "error": error});
+		let _ = conn.app.emit("tcp:client:error", payload);
+		return false;
 	}
 }
 
-pub fn send(remote_addr: String, data_b64: String) -> Result<String, String> {
-	let data = match base64::engine::general_purpose::STANDARD.decode(&data_b64) {
-		Ok(d) => d,
-		Err(e) => return Err(format!("base64 decode error: {}", e)),
-	};
+/// Identifies one established connection in the events and recordings it produces.
+struct Conn<'a> {
+	app: &'a AppHandle,
+	sid: &'a str,
+	remote: &'a str,
+	local: &'a str,
+	framing: Option<&'a FramerConfig>,
+}
 
-	init_cell();
-	let cell = TCP_CLIENT.get().unwrap();
-	let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-	let h = guard
-		.get_mut(&remote_addr)
-		.ok_or_else(|| "TCP client not connected to that address".to_string())?;
+fn emit_message(conn: &Conn<'_>, data: &[u8], seq: &mut u64, partial: bool) {
+	*seq = seq.wrapping_add(1);
+	let b64 = base64::engine::general_purpose::STANDARD.encode(data);
+	let ts_ms = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0);
+	let mut payload = json!({
+		"session": conn.sid,
+		"remote": conn.remote,
+		"data": b64,
+		"seq": *seq,
+		"ts_ms": ts_ms,
+	});
+	if partial {
+		payload["partial"] = json!(true);
+	}
+	framing::annotate(conn.framing, data, &mut payload);
+	let _ = conn.app.emit("tcp:client:message", payload);
+	recorder::record(conn.sid, Direction::Rx, conn.local, conn.remote, *seq, ts_ms, data);
+}
 
-	h.stream
-		.write_all(&data)
-		.map_err(|e| format!("send error: {}", e))?;
+pub async fn stop(remote_addr: Option<String>) -> NetResult<Stopped> {
+	if let Some(a) = remote_addr {
+		let removed = session::registry()?.remove(SessionKind::TcpClient, &a);
+		let s = removed.ok_or_else(|| NetError::not_running(SessionKind::TcpClient, &a))?;
+		let info = s.stop().await;
+		Ok(Stopped {
+			session_ids: vec![info.id],
+			message: format!("TCP client disconnected from {}", a),
+		})
+	} else {
+		let previous = session::registry()?.remove_kind(SessionKind::TcpClient);
+		Ok(Stopped {
+			session_ids: session::stop_all(previous).await,
+			message: "All TCP clients disconnected".into(),
+		})
+	}
+}
+
+/// With `framed`, the payload is wrapped by the session's framer before it is written.
+pub async fn send(remote_addr: String, payload: Payload, framed: bool) -> NetResult<Sent> {
+	send_bytes(&remote_addr, payload.bytes()?, framed).await
+}
+
+pub async fn send_bytes(remote_addr: &str, data: Vec<u8>, framed: bool) -> NetResult<Sent> {
+	let (sid, shared) = {
+		let reg = session::registry()?;
+		match reg.get(SessionKind::TcpClient, remote_addr) {
+			Some(Session {
+				info,
+				handle: SessionHandle::TcpClient(h),
+			}) => (info.id.clone(), h.shared.clone()),
+			_ => return Err(NetError::not_running(SessionKind::TcpClient, remote_addr)),
+		}
+	};
+	let data = checksum::append(&sid, &data).into_owned();
+	let data = if framed {
+		shared
+			.framing
+			.as_ref()
+			.ok_or_else(|| NetError::framing("session has no framer configured".into()))?
+			.encode(&data)?
+	} else {
+		data
+	};
+
+	let mut guard = shared.writer.lock().await;
+	let w = guard
+		.as_mut()
+		.ok_or_else(|| NetError::io("send", remote_addr, io::ErrorKind::NotConnected.into()))?;
+	w.write_all(&data)
+		.await
+		.map_err(|e| NetError::io("send", remote_addr, e))?;
+	drop(guard);
+	let local = match shared.sock.lock() {
+		Ok(s) => s.as_ref().and_then(|s| s.local_addr().ok()).and_then(|a| a.as_socket()),
+		Err(_) => None,
+	};
+	let local = local.map(|a| a.to_string()).unwrap_or_default();
+	recorder::record(&sid, Direction::Tx, &local, remote_addr, 0, session::now_ms(), &data);
 
-	Ok(format!("sent {} bytes to {}", data.len(), remote_addr))
+	Ok(Sent {
+		session_id: Some(sid),
+		bytes: data.len(),
+		peers: 1,
+		message: format!("sent {} bytes to {}", data.len(), remote_addr),
+	})
 }
@@ -1,225 +1,595 @@
 use base64::Engine;
-use once_cell::sync::OnceCell;
-use serde_json::json;
+use serde_json::{json, Value};
+use socket2::{SockRef, Socket};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::{self, JoinHandle};
 use tauri::AppHandle;
 use tauri::Emitter;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::checksum;
+use crate::error::{NetError, NetResult};
+use crate::framing::{self, Framer, FramerConfig};
+use crate::http::{self, Mock, Route};
+use crate::modbus::{self, Slave, SlaveConfig};
+use crate::recorder::{self, Direction};
+use crate::responder;
+use crate::services::{Chargen, ServerMode};
+use crate::session::{self, Sent, Session, SessionHandle, SessionKind, Started, Stopped};
+use crate::sockopt::{self, SocketOptions};
+use crate::template::Payload;
+use crate::tls::{self, BoxedStream, TlsServerConfig};
+
+/// A TLS client that has not finished its handshake by then is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of RFC 864 lines written per chargen write.
+const CHARGEN_CHUNK: usize = 8 * 1024;
+const SENT_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+type PeerWriter = Arc<tokio::sync::Mutex<WriteHalf<BoxedStream>>>;
+
+struct Peer {
+	writer: PeerWriter,
+	/// Our end of the connection, for the recorder; the bind address may be a wildcard.
+	local: String,
+	/// Duplicate of the peer's socket for the options commands; dropped together with `writer`.
+	sock: Option<Socket>,
+}
+
+/// A protocol the server answers itself instead of passing the traffic to the UI.
+enum Protocol {
+	Http(Mock),
+	Modbus(Arc<Slave>),
+}
+
+/// One peer of a server that speaks a [`Protocol`], for the protocol's events and the recorder.
+pub struct Connection<'a> {
+	pub app: &'a AppHandle,
+	pub sid: &'a str,
+	pub bind: &'a str,
+	/// Our end of this connection, as opposed to the listen address in `bind`.
+	pub local: &'a str,
+	pub peer: &'a str,
+	pub seq: &'a AtomicU64,
+}
+
+impl Connection<'_> {
+	pub fn error(&self, event: &str, error: String) {
+		let payload = json!({"session": self.sid, "bind": self.bind, "peer": self.peer, "error": error});
+		let _ = self.app.emit(event, payload);
+	}
+}
 
 pub struct ServerHandle {
-	stop_tx: mpsc::Sender<()>,
-	thread_handle: Option<JoinHandle<()>>,
-	clients: Arc<Mutex<HashMap<String, TcpStream>>>,
+	cancel: CancellationToken,
+	task: JoinHandle<()>,
+	shared: Arc<Shared>,
+	listener: Option<Socket>,
 }
 
 impl ServerHandle {
-	pub fn stop(self) {
-		let _ = self.stop_tx.send(());
-		if let Some(h) = self.thread_handle {
-			let _ = h.join();
+	pub async fn stop(self) {
+		self.cancel.cancel();
+		let _ = self.task.await;
+	}
+
+	pub fn sockets(&self) -> Vec<(String, Socket)> {
+		let mut out = Vec::new();
+		if let Some(s) = self.listener.as_ref().and_then(|l| l.try_clone().ok()) {
+			out.push(("listener".to_string(), s));
 		}
+		if let Ok(cg) = self.shared.clients.lock() {
+			for (addr, peer) in cg.iter() {
+				if let Some(s) = peer.sock.as_ref().and_then(|s| s.try_clone().ok()) {
+					out.push((addr.clone(), s));
+				}
+			}
+		}
+		out
 	}
+
+	pub fn remember_socket_options(&self, options: &SocketOptions) {
+		if let Ok(mut o) = self.shared.options.lock() {
+			o.merge(options);
+		}
+	}
+
+	pub fn modbus_slave(&self) -> Option<Arc<Slave>> {
+		match &self.shared.protocol {
+			Some(Protocol::Modbus(slave)) => Some(slave.clone()),
+			_ => None,
+		}
+	}
+}
+
+pub async fn start(
+	app: AppHandle,
+	bind_addr: String,
+	tls: Option<TlsServerConfig>,
+	framing: Option<FramerConfig>,
+	socket_options: Option<SocketOptions>,
+	mode: Option<ServerMode>,
+) -> NetResult<Started> {
+	listen(app, bind_addr, tls, framing, socket_options, mode, None).await
 }
 
-static TCP_SERVER: OnceCell<Mutex<HashMap<String, ServerHandle>>> = OnceCell::new();
+/// A TCP server whose peers are answered from `routes` as HTTP/1.1 clients; stopped like any
+/// other TCP server.
+pub async fn start_http(
+	app: AppHandle,
+	bind_addr: String,
+	tls: Option<TlsServerConfig>,
+	routes: Vec<Route>,
+	socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+	let protocol = Protocol::Http(Mock::new(routes)?);
+	listen(app, bind_addr, tls, None, socket_options, None, Some(protocol)).await
+}
 
-fn init_cell() {
-	TCP_SERVER.get_or_init(|| Mutex::new(HashMap::new()));
+/// A TCP server that simulates a Modbus slave; stopped like any other TCP server.
+pub async fn start_modbus(
+	app: AppHandle,
+	bind_addr: String,
+	tls: Option<TlsServerConfig>,
+	slave: Option<SlaveConfig>,
+	socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+	let protocol = Protocol::Modbus(Arc::new(Slave::new(slave.unwrap_or_default())?));
+	listen(app, bind_addr, tls, None, socket_options, None, Some(protocol)).await
 }
 
-pub fn start(app: AppHandle, bind_addr: String) -> Result<String, String> {
-	init_cell();
-	let cell = TCP_SERVER.get().unwrap();
-	let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-	if guard.contains_key(&bind_addr) {
-		return Err("TCP server already running for this address".into());
+async fn listen(
+	app: AppHandle,
+	bind_addr: String,
+	tls: Option<TlsServerConfig>,
+	framing: Option<FramerConfig>,
+	socket_options: Option<SocketOptions>,
+	mode: Option<ServerMode>,
+	protocol: Option<Protocol>,
+) -> NetResult<Started> {
+	if session::registry()?.contains(SessionKind::TcpServer, &bind_addr) {
+		return Err(NetError::already_running(SessionKind::TcpServer, &bind_addr));
+	}
+	let acceptor = tls.as_ref().map(tls::server).transpose()?;
+	if let Some(f) = &framing {
+		f.validate()?;
 	}
 
-	let listener = TcpListener::bind(&bind_addr).map_err(|e| format!("bind error: {}", e))?;
-	listener
-		.set_nonblocking(true)
-		.map_err(|e| format!("set_nonblocking error: {}", e))?;
+	let socket_options = socket_options.unwrap_or_default();
+	let listener = sockopt::bind_tcp(&bind_addr, &socket_options)
+		.await
+		.map_err(|e| NetError::io("bind", &bind_addr, e))?;
+	let listener_sock = sockopt::dup(&listener).ok();
 
-	let clients: Arc<Mutex<HashMap<String, TcpStream>>> = Arc::new(Mutex::new(HashMap::new()));
-	let clients_thread = clients.clone();
+	let cancel = CancellationToken::new();
+	let id = session::next_id();
 
-	let (tx, rx) = mpsc::channel::<()>();
-	let app_clone = app.clone();
-	let addr = bind_addr.clone();
+	let shared = Arc::new(Shared {
+		app,
+		sid: id.clone(),
+		addr: bind_addr.clone(),
+		clients: Mutex::new(HashMap::new()),
+		seq: AtomicU64::new(0),
+		acceptor,
+		framing,
+		options: Mutex::new(socket_options),
+		mode: mode.unwrap_or_default(),
+		protocol,
+	});
+	let message = match &shared.protocol {
+		Some(Protocol::Http(_)) => format!("HTTP mock server started on {}", bind_addr),
+		Some(Protocol::Modbus(_)) => format!("Modbus TCP slave started on {}", bind_addr),
+		None => format!("TCP {}server started on {}", shared.mode.label(), bind_addr),
+	};
+	let task = async_runtime::spawn(accept_loop(shared.clone(), listener, cancel.clone()));
 
-	let handle = thread::spawn(move || {
-		let mut buf = [0u8; 65536];
-		let mut seq: u64 = 0;
+	let handle = ServerHandle {
+		cancel,
+		task,
+		shared,
+		listener: listener_sock,
+	};
+	let rejected = session::registry()?.insert(
+		id.clone(),
+		SessionKind::TcpServer,
+		bind_addr.clone(),
+		SessionHandle::TcpServer(handle),
+	);
+	if let Err(h) = rejected {
+		h.stop().await;
+		return Err(NetError::already_running(SessionKind::TcpServer, &bind_addr));
+	}
 
-		loop {
-			if rx.try_recv().is_ok() {
-				break;
-			}
+	Ok(Started {
+		session_id: id,
+		kind: SessionKind::TcpServer,
+		message,
+		addr: bind_addr,
+	})
+}
 
-			// accept new clients
-			loop {
-				match listener.accept() {
-					Ok((stream, peer_addr)) => {
-						let peer = peer_addr.to_string();
-						let _ = stream.set_nonblocking(true);
-						let _ = stream.set_nodelay(true);
+/// State shared by the accept loop and every peer task of one server.
+struct Shared {
+	app: AppHandle,
+	sid: String,
+	addr: String,
+	clients: Mutex<HashMap<String, Peer>>,
+	seq: AtomicU64,
+	acceptor: Option<TlsAcceptor>,
+	framing: Option<FramerConfig>,
+	/// Applied to every accepted peer; `set_socket_options` adds to it.
+	options: Mutex<SocketOptions>,
+	mode: ServerMode,
+	protocol: Option<Protocol>,
+}
 
-						if let Ok(mut cg) = clients_thread.lock() {
-							cg.insert(peer.clone(), stream);
-						}
+async fn accept_loop(shared: Arc<Shared>, listener: TcpListener, cancel: CancellationToken) {
+	// one task per peer; the tracker lets stop() wait until every socket is closed
+	let tracker = TaskTracker::new();
 
-						let payload = json!({"bind": addr, "peer": peer});
-						let _ = app_clone.emit("tcp:server:client_connected", payload);
-					}
-					Err(e) => match e.kind() {
-						std::io::ErrorKind::WouldBlock => break,
-						_ => {
-							let payload = json!({"bind": addr, "error": format!("accept error: {}", e)});
-							let _ = app_clone.emit("tcp:server:error", payload);
-							break;
-						}
-					},
+	loop {
+		let accepted = tokio::select! {
+			_ = cancel.cancelled() => break,
+			r = listener.accept() => r,
+		};
+		match accepted {
+			Ok((stream, peer_addr)) => {
+				let _ = stream.set_nodelay(true);
+				let options = shared.options.lock().map(|o| o.clone()).unwrap_or_default();
+				if let Err(e) = sockopt::apply(&SockRef::from(&stream), &options) {
+					let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer_addr.to_string(), "error": format!("socket options error: {}", e)});
+					let _ = shared.app.emit("tcp:server:error", payload);
 				}
+				async_runtime::spawn(tracker.track_future(serve_peer(
+					shared.clone(),
+					stream,
+					peer_addr.to_string(),
+					cancel.child_token(),
+				)));
 			}
+			Err(e) => {
+				let payload = json!({"session": shared.sid, "bind": shared.addr, "error": format!("accept error: {}", e)});
+				let _ = shared.app.emit("tcp:server:error", payload);
+			}
+		}
+	}
 
-			// read from clients
-			let peers: Vec<String> = match clients_thread.lock() {
-				Ok(cg) => cg.keys().cloned().collect(),
-				Err(_) => Vec::new(),
-			};
+	drop(listener);
+	tracker.close();
+	tracker.wait().await;
+	if let Ok(mut cg) = shared.clients.lock() {
+		cg.clear();
+	}
+}
 
-			for peer in peers {
-				let mut remove_peer = false;
-				let mut data_opt: Option<Vec<u8>> = None;
-
-				if let Ok(mut cg) = clients_thread.lock() {
-					if let Some(stream) = cg.get_mut(&peer) {
-						match stream.read(&mut buf) {
-							Ok(0) => {
-								remove_peer = true;
-							}
-							Ok(n) => {
-								data_opt = Some(buf[..n].to_vec());
-							}
-							Err(e) => match e.kind() {
-								std::io::ErrorKind::WouldBlock => {}
-								_ => {
-									remove_peer = true;
-								}
-							},
-						}
-					}
-					if remove_peer {
-						cg.remove(&peer);
-					}
+async fn serve_peer(shared: Arc<Shared>, tcp: TcpStream, peer: String, cancel: CancellationToken) {
+	let sock = sockopt::dup(&tcp).ok();
+	let local = tcp.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| shared.addr.clone());
+	let (stream, tls_info): (BoxedStream, Value) = match &shared.acceptor {
+		None => (Box::new(tcp), Value::Null),
+		Some(acceptor) => {
+			let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp));
+			let result = tokio::select! {
+				_ = cancel.cancelled() => return,
+				r = handshake => r,
+			};
+			match result {
+				Ok(Ok(s)) => {
+					let info = tls::describe(s.get_ref().1);
+					(Box::new(s), info)
 				}
-
-				if let Some(data) = data_opt {
-					seq = seq.wrapping_add(1);
-					let b64 = base64::engine::general_purpose::STANDARD.encode(&data);
-					let ts_ms = SystemTime::now()
-						.duration_since(UNIX_EPOCH)
-						.map(|d| d.as_millis() as u64)
-						.unwrap_or(0);
-					let payload = json!({
-						"bind": addr,
-						"from": peer,
-						"data": b64,
-						"seq": seq,
-						"ts_ms": ts_ms,
-					});
-					let _ = app_clone.emit("tcp:server:message", payload);
-				} else if remove_peer {
-					let payload = json!({"bind": addr, "peer": peer});
-					let _ = app_clone.emit("tcp:server:client_disconnected", payload);
+				Ok(Err(e)) => {
+					let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "error": format!("tls handshake error: {}", e)});
+					let _ = shared.app.emit("tcp:server:error", payload);
+					return;
+				}
+				Err(_) => {
+					let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "error": "tls handshake timed out"});
+					let _ = shared.app.emit("tcp:server:error", payload);
+					return;
 				}
 			}
+		}
+	};
+
+	let (reader, writer) = tokio::io::split(stream);
+	let writer: PeerWriter = Arc::new(tokio::sync::Mutex::new(writer));
+	if let Ok(mut cg) = shared.clients.lock() {
+		cg.insert(peer.clone(), Peer { writer: writer.clone(), local: local.clone(), sock });
+	}
+	let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "tls": tls_info});
+	let _ = shared.app.emit("tcp:server:client_connected", payload);
 
-			thread::sleep(Duration::from_millis(10));
+	if let Some(protocol) = &shared.protocol {
+		let conn = Connection {
+			app: &shared.app,
+			sid: &shared.sid,
+			bind: &shared.addr,
+			local: &local,
+			peer: &peer,
+			seq: &shared.seq,
+		};
+		match protocol {
+			Protocol::Http(mock) => http::serve(mock, conn, reader, &writer, &cancel).await,
+			Protocol::Modbus(slave) => modbus::serve(slave, conn, reader, &writer, &cancel).await,
 		}
-	});
+		let _ = writer.lock().await.shutdown().await;
+		disconnected(&shared, &peer);
+		return;
+	}
+	if let Some(greeting) = shared.mode.greeting() {
+		if write_peer(&shared, &local, &peer, &writer, &greeting).await.is_ok() {
+			emit_sent(&shared, &peer, greeting.len());
+			let _ = writer.lock().await.shutdown().await;
+		}
+		drop(reader);
+		disconnected(&shared, &peer);
+		return;
+	}
+	if shared.mode == ServerMode::Chargen {
+		let stop = cancel.child_token();
+		let gen = async_runtime::spawn(chargen(shared.clone(), local.clone(), peer.clone(), writer.clone(), stop.clone()));
+		read_peer(&shared, &local, &peer, reader, &writer, &cancel).await;
+		stop.cancel();
+		let _ = gen.await;
+		return;
+	}
+	read_peer(&shared, &local, &peer, reader, &writer, &cancel).await;
+}
 
-	guard.insert(
-		bind_addr.clone(),
-		ServerHandle {
-			stop_tx: tx,
-			thread_handle: Some(handle),
-			clients,
-		},
-	);
+/// Streams RFC 864 lines to a peer until it goes away; `tcp:server:sent` is emitted at most every
+/// `SENT_EVENT_INTERVAL` so a fast reader doesn't flood the UI.
+async fn chargen(shared: Arc<Shared>, local: String, peer: String, writer: PeerWriter, cancel: CancellationToken) {
+	let mut gen = Chargen::default();
+	let mut buf = Vec::with_capacity(CHARGEN_CHUNK + 80);
+	let mut unreported = 0usize;
+	let mut last_event = tokio::time::Instant::now();
+	loop {
+		buf.clear();
+		while buf.len() < CHARGEN_CHUNK {
+			gen.line(&mut buf);
+		}
+		let written = tokio::select! {
+			_ = cancel.cancelled() => break,
+			r = write_peer(&shared, &local, &peer, &writer, &buf) => r,
+		};
+		if written.is_err() {
+			break;
+		}
+		unreported += buf.len();
+		if last_event.elapsed() >= SENT_EVENT_INTERVAL {
+			emit_sent(&shared, &peer, unreported);
+			unreported = 0;
+			last_event = tokio::time::Instant::now();
+		}
+	}
+	if unreported > 0 {
+		emit_sent(&shared, &peer, unreported);
+	}
+}
 
-	Ok(format!("TCP server started on {}", bind_addr))
+/// Writes on behalf of the server mode, bypassing the framer and send-side checksum.
+async fn write_peer(shared: &Shared, local: &str, peer: &str, writer: &PeerWriter, data: &[u8]) -> io::Result<()> {
+	writer.lock().await.write_all(data).await?;
+	recorder::record(&shared.sid, Direction::Tx, local, peer, 0, session::now_ms(), data);
+	Ok(())
 }
 
-pub fn stop(bind_addr: Option<String>) -> Result<String, String> {
-	init_cell();
-	let cell = TCP_SERVER.get().unwrap();
-	let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-	if let Some(b) = bind_addr {
-		if let Some(h) = guard.remove(&b) {
-			h.stop();
-			Ok(format!("TCP server stopped on {}", b))
-		} else {
-			Err("TCP server not running for that address".into())
+fn emit_sent(shared: &Shared, peer: &str, bytes: usize) {
+	let payload = json!({"session": shared.sid, "bind": shared.addr, "to": peer, "bytes": bytes, "mode": shared.mode});
+	let _ = shared.app.emit("tcp:server:sent", payload);
+}
+
+fn disconnected(shared: &Shared, peer: &str) {
+	if let Ok(mut cg) = shared.clients.lock() {
+		cg.remove(peer);
+	}
+	let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer});
+	let _ = shared.app.emit("tcp:server:client_disconnected", payload);
+}
+
+async fn read_peer(
+	shared: &Shared,
+	local: &str,
+	peer: &str,
+	mut reader: ReadHalf<BoxedStream>,
+	writer: &PeerWriter,
+	cancel: &CancellationToken,
+) {
+	let mut buf = vec![0u8; 65536];
+	let mut framer = shared.framing.clone().map(Framer::new);
+	let mut frames = Vec::new();
+	loop {
+		let idle = framer.as_ref().filter(|f| f.has_pending()).and_then(Framer::idle_timeout);
+		let read = tokio::select! {
+			_ = cancel.cancelled() => return,
+			_ = framing::idle_expired(idle) => {
+				if let Some(frame) = framer.as_mut().and_then(Framer::flush) {
+					emit_message(shared, local, peer, &frame, false);
+				}
+				continue;
+			}
+			r = reader.read(&mut buf) => r,
+		};
+		match read {
+			Ok(0) => break,
+			Err(e) => {
+				let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "error": format!("read error: {}", e)});
+				let _ = shared.app.emit("tcp:server:error", payload);
+				break;
+			}
+			Ok(n) => {
+				if let Err(e) = framing::feed(framer.as_mut(), &buf[..n], &mut frames) {
+					let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "error": e.message});
+					let _ = shared.app.emit("tcp:server:error", payload);
+				}
+				for frame in frames.drain(..) {
+					emit_message(shared, local, peer, &frame, false);
+				}
+				if shared.mode == ServerMode::Echo {
+					if write_peer(shared, local, peer, writer, &buf[..n]).await.is_err() {
+						break;
+					}
+					emit_sent(shared, peer, n);
+				}
+			}
 		}
-	} else {
-		let previous = std::mem::take(&mut *guard);
-		for (_k, h) in previous {
-			h.stop();
+	}
+
+	// hand over a half-received frame rather than losing it
+	if let Some(f) = framer.as_mut() {
+		let partial = f.idle_timeout().is_none();
+		if let Some(frame) = f.flush() {
+			emit_message(shared, local, peer, &frame, partial);
 		}
-		Ok("All TCP servers stopped".into())
 	}
+	disconnected(shared, peer);
 }
 
-pub fn send(bind_addr: String, to_peer: Option<String>, data_b64: String) -> Result<String, String> {
-	let data = match base64::engine::general_purpose::STANDARD.decode(&data_b64) {
-		Ok(d) => d,
-		Err(e) => return Err(format!("base64 decode error: {}", e)),
-	};
+fn emit_message(shared: &Shared, local: &str, peer: &str, data: &[u8], partial: bool) {
+	let seq = shared.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+	let b64 = base64::engine::general_purpose::STANDARD.encode(data);
+	let ts_ms = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0);
+	let mut payload = json!({
+		"session": shared.sid,
+		"bind": shared.addr,
+		"from": peer,
+		"data": b64,
+		"seq": seq,
+		"ts_ms": ts_ms,
+	});
+	if partial {
+		payload["partial"] = json!(true);
+	}
+	framing::annotate(shared.framing.as_ref(), data, &mut payload);
+	let _ = shared.app.emit("tcp:server:message", payload);
+	recorder::record(&shared.sid, Direction::Rx, local, peer, seq, ts_ms, data);
+	responder::handle(&shared.app, &shared.sid, SessionKind::TcpServer, &shared.addr, peer, data);
+}
+
+pub async fn stop(bind_addr: Option<String>) -> NetResult<Stopped> {
+	if let Some(b) = bind_addr {
+		let removed = session::registry()?.remove(SessionKind::TcpServer, &b);
+		let s = removed.ok_or_else(|| NetError::not_running(SessionKind::TcpServer, &b))?;
+		let info = s.stop().await;
+		Ok(Stopped {
+			session_ids: vec![info.id],
+			message: format!("TCP server stopped on {}", b),
+		})
+	} else {
+		let previous = session::registry()?.remove_kind(SessionKind::TcpServer);
+		Ok(Stopped {
+			session_ids: session::stop_all(previous).await,
+			message: "All TCP servers stopped".into(),
+		})
+	}
+}
 
-	init_cell();
-	let cell = TCP_SERVER.get().unwrap();
-	let guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-	let h = guard
-		.get(&bind_addr)
-		.ok_or_else(|| "TCP server not running for that address".to_string())?;
+/// With `framed`, the payload is wrapped by the session's framer before it is written.
+pub async fn send(
+	bind_addr: String,
+	to_peer: Option<String>,
+	payload: Payload,
+	framed: bool,
+) -> NetResult<Sent> {
+	send_bytes(&bind_addr, to_peer.as_deref(), payload.bytes()?, framed).await
+}
 
-	let mut cg = h
-		.clients
-		.lock()
-		.map_err(|e| format!("lock clients error: {}", e))?;
+pub async fn send_bytes(
+	bind_addr: &str,
+	to_peer: Option<&str>,
+	data: Vec<u8>,
+	framed: bool,
+) -> NetResult<Sent> {
+	// snapshot the writers so no std lock is held across the awaits below
+	let (sid, shared) = {
+		let reg = session::registry()?;
+		match reg.get(SessionKind::TcpServer, bind_addr) {
+			Some(Session {
+				info,
+				handle: SessionHandle::TcpServer(h),
+			}) => (info.id.clone(), h.shared.clone()),
+			_ => return Err(NetError::not_running(SessionKind::TcpServer, bind_addr)),
+		}
+	};
+	let data = checksum::append(&sid, &data).into_owned();
+	let data = if framed {
+		shared
+			.framing
+			.as_ref()
+			.ok_or_else(|| NetError::framing("session has no framer configured".into()))?
+			.encode(&data)?
+	} else {
+		data
+	};
+	let targets: Vec<(String, String, PeerWriter)> = {
+		let cg = shared
+			.clients
+			.lock()
+			.map_err(|e| NetError::internal(format!("lock clients error: {}", e)))?;
+		match to_peer {
+			Some(peer) => {
+				let w = cg
+					.get(peer)
+					.ok_or_else(|| NetError::peer_not_found(bind_addr, peer))?;
+				vec![(peer.to_string(), w.local.clone(), w.writer.clone())]
+			}
+			None => cg.iter().map(|(p, w)| (p.clone(), w.local.clone(), w.writer.clone())).collect(),
+		}
+	};
 
 	let mut sent = 0usize;
 
 	match to_peer {
 		Some(peer) => {
-			let stream = cg
-				.get_mut(&peer)
-				.ok_or_else(|| "peer not connected".to_string())?;
-			stream
+			let (_, local, writer) = &targets[0];
+			writer
+				.lock()
+				.await
 				.write_all(&data)
-				.map_err(|e| format!("send error: {}", e))?;
+				.await
+				.map_err(|e| NetError::io("send", peer, e))?;
+			recorder::record(&sid, Direction::Tx, local, peer, 0, session::now_ms(), &data);
 			sent = 1;
-			Ok(format!("sent {} bytes to {} ({} client)", data.len(), peer, sent))
+			Ok(Sent {
+				session_id: Some(sid),
+				bytes: data.len(),
+				peers: sent,
+				message: format!("sent {} bytes to {} ({} client)", data.len(), peer, sent),
+			})
 		}
 		None => {
 			// broadcast
-			let peers: Vec<String> = cg.keys().cloned().collect();
-			for peer in peers {
-				if let Some(stream) = cg.get_mut(&peer) {
-					match stream.write_all(&data) {
-						Ok(_) => sent += 1,
-						Err(_) => {
+			for (peer, local, writer) in targets {
+				match writer.lock().await.write_all(&data).await {
+					Ok(_) => {
+						recorder::record(&sid, Direction::Tx, &local, &peer, 0, session::now_ms(), &data);
+						sent += 1;
+					}
+					Err(_) => {
+						if let Ok(mut cg) = shared.clients.lock() {
 							cg.remove(&peer);
 						}
 					}
 				}
 			}
-			Ok(format!("broadcast {} bytes to {} client(s)", data.len(), sent))
+			Ok(Sent {
+				session_id: Some(sid),
+				bytes: data.len(),
+				peers: sent,
+				message: format!("broadcast {} bytes to {} client(s)", data.len(), sent),
+			})
 		}
 	}
 }
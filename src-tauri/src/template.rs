@@ -0,0 +1,712 @@
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::checksum::{Algorithm, Checksum};
+use crate::error::{NetError, NetResult};
+use crate::framing::Endian;
+
+/// A payload built from fields, rendered again for every send.
+///
+/// e.g. `{"fields":[{"type":"bytes","data_b64":"qg=="},{"type":"counter","width":2},
+/// {"type":"checksum","algorithm":"crc16_modbus"}]}`
+#[derive(Clone, Debug, Deserialize)]
+pub struct TemplateSpec {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Field {
+    Bytes {
+        data_b64: String,
+    },
+    Text {
+        text: String,
+    },
+    /// Increments by `step` after every render, wrapping at the field width.
+    Counter {
+        #[serde(default)]
+        start: u64,
+        #[serde(default = "one")]
+        step: u64,
+        /// Bytes for `binary` (1, 2, 4 or 8, default 4); minimum digits for text encodings.
+        width: Option<usize>,
+        #[serde(default)]
+        endian: Endian,
+        #[serde(default)]
+        encoding: NumberEncoding,
+    },
+    Timestamp {
+        #[serde(default)]
+        format: TimestampFormat,
+        /// Same meaning as for `counter`; binary timestamps default to 8 bytes.
+        width: Option<usize>,
+        #[serde(default)]
+        endian: Endian,
+        #[serde(default)]
+        encoding: NumberEncoding,
+    },
+    Random {
+        len: usize,
+    },
+    /// Each render takes the next item of a file, starting over after the last one. Items are
+    /// lines (without the line ending) unless `chunk` splits the file into fixed-size pieces.
+    FileSequence {
+        path: String,
+        chunk: Option<usize>,
+    },
+    /// Checksum over rendered bytes `[from, to)`; `to` defaults to where this field starts.
+    Checksum {
+        /// Any name or parameter set accepted by the checksum commands.
+        algorithm: Algorithm,
+        #[serde(default)]
+        from: usize,
+        to: Option<usize>,
+        /// Defaults to low byte first for reflected CRCs such as CRC-16/MODBUS, high byte first
+        /// otherwise.
+        endian: Option<Endian>,
+    },
+}
+
+fn one() -> u64 {
+    1
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberEncoding {
+    #[default]
+    Binary,
+    /// ASCII decimal digits.
+    Decimal,
+    /// ASCII uppercase hex digits.
+    Hex,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    UnixS,
+    #[default]
+    UnixMs,
+    UnixUs,
+    UnixNs,
+    /// `2024-05-01T12:34:56.789Z`, always text.
+    Iso8601,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub fields: usize,
+    pub message: String,
+}
+
+/// What a send command transmits: literal bytes, or the next rendering of a stored template.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Payload {
+    pub data_b64: Option<String>,
+    pub template: Option<String>,
+}
+
+impl Payload {
+    pub fn new(data_b64: Option<String>, template: Option<String>) -> Self {
+        Payload { data_b64, template }
+    }
+
+    /// Decodes `data_b64`, or renders `template` (advancing its counters and sequences).
+    pub fn bytes(&self) -> NetResult<Vec<u8>> {
+        match (&self.template, &self.data_b64) {
+            (Some(name), _) => render(name),
+            (None, Some(b64)) => base64::engine::general_purpose::STANDARD
+                .decode(b64)
+                .map_err(NetError::decode),
+            (None, None) => Err(NetError::template(
+                "either data_b64 or template is required".into(),
+            )),
+        }
+    }
+
+    /// Fails like [`Payload::bytes`] would, without rendering the template.
+    pub fn check(&self) -> NetResult<()> {
+        match &self.template {
+            Some(name) => templates()?
+                .get(name)
+                .map(|_| ())
+                .ok_or_else(|| NetError::template_not_found(name)),
+            None => self.bytes().map(|_| ()),
+        }
+    }
+}
+
+/// A spec with the per-field state it carries between renders.
+#[derive(Clone)]
+struct Template {
+    fields: Vec<Part>,
+}
+
+#[derive(Clone)]
+enum Part {
+    Bytes(Vec<u8>),
+    Counter {
+        next: u64,
+        step: u64,
+        width: Option<usize>,
+        endian: Endian,
+        encoding: NumberEncoding,
+    },
+    Timestamp {
+        format: TimestampFormat,
+        width: Option<usize>,
+        endian: Endian,
+        encoding: NumberEncoding,
+    },
+    Random(usize),
+    Sequence {
+        items: Vec<Vec<u8>>,
+        next: usize,
+    },
+    Checksum {
+        checksum: Checksum,
+        from: usize,
+        to: Option<usize>,
+        endian: Endian,
+    },
+}
+
+static TEMPLATES: OnceCell<Mutex<HashMap<String, Template>>> = OnceCell::new();
+
+fn templates() -> NetResult<MutexGuard<'static, HashMap<String, Template>>> {
+    TEMPLATES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock error: {}", e)))
+}
+
+/// Stores (or replaces, resetting its state) a named template.
+pub fn set(name: String, spec: TemplateSpec) -> NetResult<TemplateInfo> {
+    let fields = spec
+        .fields
+        .into_iter()
+        .map(compile)
+        .collect::<NetResult<Vec<_>>>()?;
+    let n = fields.len();
+    templates()?.insert(name.clone(), Template { fields });
+    Ok(TemplateInfo {
+        message: format!("template {} set ({} field(s))", name, n),
+        name,
+        fields: n,
+    })
+}
+
+pub fn remove(name: &str) -> NetResult<TemplateInfo> {
+    let t = templates()?
+        .remove(name)
+        .ok_or_else(|| NetError::template_not_found(name))?;
+    Ok(TemplateInfo {
+        name: name.to_string(),
+        fields: t.fields.len(),
+        message: format!("template {} removed", name),
+    })
+}
+
+pub fn list() -> NetResult<Vec<String>> {
+    let mut names: Vec<String> = templates()?.keys().cloned().collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Renders the next payload of a template.
+pub fn render(name: &str) -> NetResult<Vec<u8>> {
+    let mut ts = templates()?;
+    let t = ts
+        .get_mut(name)
+        .ok_or_else(|| NetError::template_not_found(name))?;
+    t.render()
+}
+
+/// The next `count` payloads, base64-encoded, without advancing the stored template.
+pub fn preview(name: &str, count: usize) -> NetResult<Vec<String>> {
+    let mut t = templates()?
+        .get(name)
+        .cloned()
+        .ok_or_else(|| NetError::template_not_found(name))?;
+    (0..count)
+        .map(|_| {
+            t.render()
+                .map(|b| base64::engine::general_purpose::STANDARD.encode(b))
+        })
+        .collect()
+}
+
+fn compile(field: Field) -> NetResult<Part> {
+    Ok(match field {
+        Field::Bytes { data_b64 } => Part::Bytes(
+            base64::engine::general_purpose::STANDARD
+                .decode(&data_b64)
+                .map_err(NetError::decode)?,
+        ),
+        Field::Text { text } => Part::Bytes(text.into_bytes()),
+        Field::Counter {
+            start,
+            step,
+            width,
+            endian,
+            encoding,
+        } => {
+            check_width(width, encoding)?;
+            Part::Counter {
+                next: start,
+                step,
+                width,
+                endian,
+                encoding,
+            }
+        }
+        Field::Timestamp {
+            format,
+            width,
+            endian,
+            encoding,
+        } => {
+            check_width(width, encoding)?;
+            Part::Timestamp {
+                format,
+                width,
+                endian,
+                encoding,
+            }
+        }
+        Field::Random { len } => Part::Random(len),
+        Field::FileSequence { path, chunk } => {
+            let data = fs::read(&path).map_err(|e| NetError::io("read file", &path, e))?;
+            let items: Vec<Vec<u8>> = match chunk {
+                Some(0) => return Err(NetError::template("chunk must be at least 1".into())),
+                Some(n) => data.chunks(n).map(<[u8]>::to_vec).collect(),
+                None => data
+                    .split(|b| *b == b'\n')
+                    .map(|l| l.strip_suffix(b"\r").unwrap_or(l).to_vec())
+                    .filter(|l| !l.is_empty())
+                    .collect(),
+            };
+            if items.is_empty() {
+                return Err(NetError::template(format!("{} has no items", path)));
+            }
+            Part::Sequence { items, next: 0 }
+        }
+        Field::Checksum {
+            algorithm,
+            from,
+            to,
+            endian,
+        } => {
+            let checksum = algorithm.resolve()?;
+            Part::Checksum {
+                checksum,
+                from,
+                to,
+                endian: endian.unwrap_or(checksum.default_endian()),
+            }
+        }
+    })
+}
+
+fn check_width(width: Option<usize>, encoding: NumberEncoding) -> NetResult<()> {
+    match (encoding, width) {
+        (NumberEncoding::Binary, Some(w)) if ![1, 2, 4, 8].contains(&w) => Err(NetError::template(
+            format!("binary width must be 1, 2, 4 or 8, got {}", w),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl Template {
+    fn render(&mut self) -> NetResult<Vec<u8>> {
+        let mut out = Vec::new();
+        // checksums are filled in once every other field is in place
+        let mut checksums = Vec::new();
+        for part in &mut self.fields {
+            match part {
+                Part::Bytes(b) => out.extend_from_slice(b),
+                Part::Counter {
+                    next,
+                    step,
+                    width,
+                    endian,
+                    encoding,
+                } => {
+                    out.extend(number(*next, *width, 4, *endian, *encoding));
+                    *next = next.wrapping_add(*step);
+                }
+                Part::Timestamp {
+                    format,
+                    width,
+                    endian,
+                    encoding,
+                } => out.extend(timestamp(*format, *width, *endian, *encoding)),
+                Part::Random(len) => {
+                    let at = out.len();
+                    out.resize(at + *len, 0);
+                    rand::thread_rng().fill_bytes(&mut out[at..]);
+                }
+                Part::Sequence { items, next } => {
+                    out.extend_from_slice(&items[*next]);
+                    *next = (*next + 1) % items.len();
+                }
+                Part::Checksum {
+                    checksum,
+                    from,
+                    to,
+                    endian,
+                } => {
+                    let at = out.len();
+                    out.resize(at + checksum.width(), 0);
+                    checksums.push((at, *checksum, *from, to.unwrap_or(at), *endian));
+                }
+            }
+        }
+        for (at, checksum, from, to, endian) in checksums {
+            let range = out.get(from..to).ok_or_else(|| {
+                NetError::template(format!(
+                    "checksum range {}..{} is outside the {}-byte payload",
+                    from,
+                    to,
+                    out.len()
+                ))
+            })?;
+            let bytes = checksum.bytes(range, endian);
+            out[at..at + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(out)
+    }
+}
+
+/// `width` is a byte count for binary (`default_bytes` when unset) and a minimum digit count for
+/// text encodings (no padding when unset).
+fn number(
+    v: u64,
+    width: Option<usize>,
+    default_bytes: usize,
+    endian: Endian,
+    encoding: NumberEncoding,
+) -> Vec<u8> {
+    let digits = width.unwrap_or(0);
+    match encoding {
+        NumberEncoding::Binary => {
+            let be = v.to_be_bytes();
+            let mut b = be[8 - width.unwrap_or(default_bytes)..].to_vec();
+            if let Endian::Little = endian {
+                b.reverse();
+            }
+            b
+        }
+        NumberEncoding::Decimal => format!("{:0w$}", v, w = digits).into_bytes(),
+        NumberEncoding::Hex => format!("{:0w$X}", v, w = digits).into_bytes(),
+    }
+}
+
+fn timestamp(
+    format: TimestampFormat,
+    width: Option<usize>,
+    endian: Endian,
+    encoding: NumberEncoding,
+) -> Vec<u8> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let v = match format {
+        TimestampFormat::UnixS => now.as_secs(),
+        TimestampFormat::UnixMs => now.as_millis() as u64,
+        TimestampFormat::UnixUs => now.as_micros() as u64,
+        TimestampFormat::UnixNs => now.as_nanos() as u64,
+        TimestampFormat::Iso8601 => {
+            return iso8601(now.as_secs(), now.subsec_millis()).into_bytes()
+        }
+    };
+    number(v, width, 8, endian, encoding)
+}
+
+fn iso8601(secs: u64, millis: u32) -> String {
+    let (year, month, day) = civil_date((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        millis
+    )
+}
+
+/// Year, month (1-12) and day of a day count since 1970-01-01, proleptic Gregorian.
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn compiled(fields: Value) -> NetResult<Template> {
+        let spec: TemplateSpec = serde_json::from_value(json!({ "fields": fields })).unwrap();
+        Ok(Template {
+            fields: spec
+                .fields
+                .into_iter()
+                .map(compile)
+                .collect::<NetResult<_>>()?,
+        })
+    }
+
+    fn renders(fields: Value, n: usize) -> Vec<Vec<u8>> {
+        let mut t = compiled(fields).unwrap();
+        (0..n).map(|_| t.render().unwrap()).collect()
+    }
+
+    #[test]
+    fn binary_counters_truncate_to_their_width() {
+        let start = 0x0102_0304_0506_0708u64;
+        let cases: [(usize, &str, &[u8]); 8] = [
+            (1, "big", &[0x08]),
+            (1, "little", &[0x08]),
+            (2, "big", &[0x07, 0x08]),
+            (2, "little", &[0x08, 0x07]),
+            (4, "big", &[0x05, 0x06, 0x07, 0x08]),
+            (4, "little", &[0x08, 0x07, 0x06, 0x05]),
+            (8, "big", &[1, 2, 3, 4, 5, 6, 7, 8]),
+            (8, "little", &[8, 7, 6, 5, 4, 3, 2, 1]),
+        ];
+        for (width, endian, expected) in cases {
+            let field =
+                json!([{"type": "counter", "start": start, "width": width, "endian": endian}]);
+            assert_eq!(renders(field, 1)[0], expected, "{} {}", width, endian);
+        }
+        // 4 bytes, big endian, step 1 by default
+        assert_eq!(
+            renders(json!([{"type": "counter", "start": 9}]), 2),
+            [[0, 0, 0, 9], [0, 0, 0, 10]]
+        );
+    }
+
+    #[test]
+    fn counters_wrap_at_their_width() {
+        assert_eq!(
+            renders(json!([{"type": "counter", "start": 254, "width": 1}]), 3),
+            [[0xFE], [0xFF], [0x00]]
+        );
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": 0xFFFF, "step": 2, "width": 2,
+                        "endian": "little"}]),
+                2
+            ),
+            [[0xFF, 0xFF], [0x01, 0x00]]
+        );
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": u64::MAX, "width": 8}]),
+                2
+            ),
+            [[0xFF; 8], [0; 8]]
+        );
+    }
+
+    #[test]
+    fn text_counters_pad_to_minimum_digits() {
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": 7, "width": 3, "encoding": "decimal"}]),
+                2
+            ),
+            [b"007", b"008"]
+        );
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": 98, "step": 1, "encoding": "decimal"}]),
+                3
+            ),
+            [&b"98"[..], b"99", b"100"]
+        );
+        // a minimum, not a maximum
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": 12345, "width": 2, "encoding": "decimal"}]),
+                1
+            )[0],
+            b"12345"
+        );
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": 255, "width": 4, "encoding": "hex"}]),
+                2
+            ),
+            [b"00FF", b"0100"]
+        );
+        assert_eq!(
+            renders(
+                json!([{"type": "counter", "start": 0xABC, "encoding": "hex"}]),
+                1
+            )[0],
+            b"ABC"
+        );
+    }
+
+    #[test]
+    fn binary_widths_are_checked() {
+        for width in [0, 3, 5, 6, 7, 9, 16] {
+            for kind in ["counter", "timestamp"] {
+                let e = compiled(json!([{"type": kind, "width": width}]))
+                    .err()
+                    .unwrap();
+                assert!(e.message.contains("1, 2, 4 or 8"), "{} {}", kind, width);
+            }
+        }
+        for width in [1, 2, 4, 8] {
+            assert!(compiled(json!([{"type": "counter", "width": width}])).is_ok());
+        }
+        // text encodings take any minimum
+        assert!(compiled(json!([{"type": "counter", "width": 3, "encoding": "hex"}])).is_ok());
+        assert!(check_width(Some(12), NumberEncoding::Decimal).is_ok());
+        assert!(check_width(None, NumberEncoding::Binary).is_ok());
+        assert!(check_width(Some(3), NumberEncoding::Binary).is_err());
+    }
+
+    #[test]
+    fn checksums_cover_their_range() {
+        let pdu = base64::engine::general_purpose::STANDARD.encode([1, 3, 0, 0, 0, 1]);
+        // CRC-16/MODBUS is reflected, so low byte first unless asked otherwise
+        assert_eq!(
+            renders(
+                json!([{"type": "bytes", "data_b64": pdu},
+                       {"type": "checksum", "algorithm": "crc16_modbus"}]),
+                1
+            )[0],
+            [1, 3, 0, 0, 0, 1, 0x84, 0x0A]
+        );
+        assert_eq!(
+            renders(
+                json!([{"type": "bytes", "data_b64": pdu},
+                       {"type": "checksum", "algorithm": "modbus", "endian": "big"}]),
+                1
+            )[0],
+            [1, 3, 0, 0, 0, 1, 0x0A, 0x84]
+        );
+        // a header left out with `from`, a range that ends before the field, and one that runs
+        // past it over later fields
+        let out = renders(
+            json!([
+                {"type": "text", "text": "\u{2}AB"},
+                {"type": "checksum", "algorithm": "xor", "from": 1},
+                {"type": "checksum", "algorithm": "sum8", "from": 1, "to": 2},
+                {"type": "text", "text": "CD"},
+                {"type": "checksum", "algorithm": "lrc", "from": 5, "to": 7},
+                {"type": "counter", "start": 1, "width": 1}
+            ]),
+            2,
+        );
+        assert_eq!(out[0], [0x02, b'A', b'B', 0x03, 0x41, b'C', b'D', 0x79, 1]);
+        assert_eq!(out[1][8], 2);
+        assert_eq!(out[1][..8], out[0][..8]);
+
+        let crc32 = renders(
+            json!([{"type": "text", "text": "123456789"},
+                   {"type": "checksum", "algorithm": "crc32"}]),
+            1,
+        );
+        assert_eq!(crc32[0][9..], 0xCBF4_3926u32.to_le_bytes());
+
+        let mut t = compiled(json!([
+            {"type": "text", "text": "ab"},
+            {"type": "checksum", "algorithm": "xor", "from": 1, "to": 9}
+        ]))
+        .unwrap();
+        assert!(t.render().unwrap_err().message.contains("outside"));
+        let mut t = compiled(json!([
+            {"type": "text", "text": "ab"},
+            {"type": "checksum", "algorithm": "xor", "from": 2, "to": 1}
+        ]))
+        .unwrap();
+        assert!(t.render().is_err());
+        assert!(compiled(json!([{"type": "checksum", "algorithm": "nope"}])).is_err());
+    }
+
+    #[test]
+    fn file_sequences_wrap_around() {
+        let path =
+            std::env::temp_dir().join(format!("netdebugger-template-{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        fs::write(&path, "one\r\ntwo\n\nthree").unwrap();
+        assert_eq!(
+            renders(json!([{"type": "file_sequence", "path": path_str}]), 4),
+            [&b"one"[..], b"two", b"three", b"one"]
+        );
+        fs::write(&path, "abcdefghij").unwrap();
+        assert_eq!(
+            renders(
+                json!([{"type": "file_sequence", "path": path_str, "chunk": 4}]),
+                5
+            ),
+            [&b"abcd"[..], b"efgh", b"ij", b"abcd", b"efgh"]
+        );
+        assert!(
+            compiled(json!([{"type": "file_sequence", "path": path_str, "chunk": 0}])).is_err()
+        );
+        fs::write(&path, "\n\r\n").unwrap();
+        assert!(compiled(json!([{"type": "file_sequence", "path": path_str}])).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(compiled(json!([{"type": "file_sequence", "path": path_str}])).is_err());
+    }
+
+    #[test]
+    fn preview_leaves_the_stored_template_alone() {
+        let name = format!("preview-{}", std::process::id());
+        let spec = serde_json::from_value(json!({"fields": [
+            {"type": "counter", "start": 1, "width": 1},
+            {"type": "random", "len": 3}
+        ]}))
+        .unwrap();
+        set(name.clone(), spec).unwrap();
+        let previews = preview(&name, 2).unwrap();
+        let b64 = base64::engine::general_purpose::STANDARD;
+        assert_eq!(b64.decode(&previews[0]).unwrap()[0], 1);
+        assert_eq!(b64.decode(&previews[1]).unwrap()[0], 2);
+        let first = render(&name).unwrap();
+        assert_eq!((first[0], first.len()), (1, 4));
+        assert_eq!(
+            Payload::new(None, Some(name.clone())).bytes().unwrap()[0],
+            2
+        );
+        assert!(list().unwrap().contains(&name));
+        remove(&name).unwrap();
+        assert!(render(&name).is_err());
+        assert!(Payload::new(None, Some(name)).check().is_err());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(iso8601(1_714_566_896, 789), "2024-05-01T12:34:56.789Z");
+    }
+}
@@ -0,0 +1,418 @@
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{
+    ClientConfig, CommonState, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::error::{NetError, NetResult};
+
+/// Plain TCP and TLS streams behind one type so sessions don't care which they got.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TlsClientConfig {
+    /// SNI / verification name; defaults to the host part of the remote address.
+    pub server_name: Option<String>,
+    /// PEM bundle to trust instead of the built-in web PKI roots.
+    pub ca_file: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsServerConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    /// PEM bundle used to verify client certificates; enables client auth when set.
+    pub client_ca_file: Option<String>,
+    /// Reject clients that present no certificate (needs `client_ca_file`).
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+/// A ready-to-use client side: connector plus the name to present.
+pub struct TlsClient {
+    pub connector: TlsConnector,
+    pub server_name: ServerName<'static>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn read_certs(path: &str) -> NetResult<Vec<CertificateDer<'static>>> {
+    let f = File::open(path).map_err(|e| NetError::io("open certificate", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(f))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| NetError::io("read certificate", path, e))?;
+    if certs.is_empty() {
+        return Err(NetError::tls(format!("no certificates found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &str) -> NetResult<PrivateKeyDer<'static>> {
+    let f = File::open(path).map_err(|e| NetError::io("open key", path, e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(f))
+        .map_err(|e| NetError::io("read key", path, e))?
+        .ok_or_else(|| NetError::tls(format!("no private key found in {}", path)))
+}
+
+fn root_store(ca_file: Option<&str>) -> NetResult<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| NetError::tls(format!("bad CA certificate in {}: {}", path, e)))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(roots)
+}
+
+pub fn client(cfg: &TlsClientConfig, remote_addr: &str) -> NetResult<TlsClient> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| NetError::tls(e.to_string()))?;
+    let builder = if cfg.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerify(provider())))
+    } else {
+        builder.with_root_certificates(root_store(cfg.ca_file.as_deref())?)
+    };
+    let config = match (&cfg.client_cert_file, &cfg.client_key_file) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| NetError::tls(e.to_string()))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(NetError::tls(
+                "client_cert_file and client_key_file must be given together".into(),
+            ))
+        }
+    };
+
+    let name = match &cfg.server_name {
+        Some(n) => n.clone(),
+        None => host_of(remote_addr).to_string(),
+    };
+    let server_name = ServerName::try_from(name.clone())
+        .map_err(|_| NetError::tls(format!("invalid server name: {}", name)))?;
+
+    Ok(TlsClient {
+        connector: TlsConnector::from(Arc::new(config)),
+        server_name,
+    })
+}
+
+pub fn server(cfg: &TlsServerConfig) -> NetResult<TlsAcceptor> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| NetError::tls(e.to_string()))?;
+    let builder = match &cfg.client_ca_file {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(root_store(Some(path))?),
+                provider(),
+            );
+            let verifier = if cfg.require_client_cert {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(
+                verifier.build().map_err(|e| NetError::tls(e.to_string()))?,
+            )
+        }
+        None if cfg.require_client_cert => {
+            return Err(NetError::tls(
+                "require_client_cert needs client_ca_file".into(),
+            ))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(read_certs(&cfg.cert_file)?, read_key(&cfg.key_file)?)
+        .map_err(|e| NetError::tls(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Negotiated parameters for the connected events; certificates are base64 DER, leaf first.
+pub fn describe(conn: &CommonState) -> Value {
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let chain: Vec<String> = conn
+        .peer_certificates()
+        .unwrap_or_default()
+        .iter()
+        .map(|c| b64.encode(c.as_ref()))
+        .collect();
+    json!({
+        "protocol": conn.protocol_version().map(|v| format!("{:?}", v)),
+        "cipher": conn.negotiated_cipher_suite().map(|c| format!("{:?}", c.suite())),
+        "alpn": conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        "peer_certificates": chain,
+    })
+}
+
+fn host_of(addr: &str) -> &str {
+    let host = match addr.rfind(':') {
+        Some(i) => &addr[..i],
+        None => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Accepts any server certificate; only for `insecure_skip_verify`.
+#[derive(Debug)]
+struct NoVerify(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A CA and a server and client leaf signed by it, written as PEM files.
+    struct Pki {
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "netdebugger-tls-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            ca_params
+                .distinguished_name
+                .push(DnType::CommonName, "netdebugger test CA");
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            for (name, purpose) in [
+                ("server", ExtendedKeyUsagePurpose::ServerAuth),
+                ("client", ExtendedKeyUsagePurpose::ClientAuth),
+            ] {
+                let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+                params.extended_key_usages = vec![purpose];
+                let key = KeyPair::generate().unwrap();
+                let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+                std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+                std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+            }
+            Pki { dir }
+        }
+
+        fn path(&self, file: &str) -> Option<String> {
+            Some(self.dir.join(file).to_string_lossy().into_owned())
+        }
+
+        fn server(&self) -> TlsServerConfig {
+            TlsServerConfig {
+                cert_file: self.path("server.pem").unwrap(),
+                key_file: self.path("server.key").unwrap(),
+                client_ca_file: None,
+                require_client_cert: false,
+            }
+        }
+
+        fn client(&self) -> TlsClientConfig {
+            TlsClientConfig {
+                server_name: Some("localhost".into()),
+                ca_file: self.path("ca.pem"),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Runs one handshake over loopback and echoes a byte through it; returns the server's and
+    /// the client's outcome.
+    async fn handshake(
+        server: &TlsServerConfig,
+        client: &TlsClientConfig,
+    ) -> (NetResult<()>, NetResult<()>) {
+        let acceptor = super::server(server).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut tls = acceptor
+                .accept(tcp)
+                .await
+                .map_err(|e| NetError::tls(e.to_string()))?;
+            let mut b = [0u8; 1];
+            tls.read_exact(&mut b)
+                .await
+                .map_err(|e| NetError::tls(e.to_string()))?;
+            tls.write_all(&b)
+                .await
+                .map_err(|e| NetError::tls(e.to_string()))?;
+            tls.flush().await.map_err(|e| NetError::tls(e.to_string()))
+        });
+
+        let tls = super::client(client, &addr).unwrap();
+        let connected = async {
+            let tcp = TcpStream::connect(&addr).await.unwrap();
+            let mut s = tls
+                .connector
+                .connect(tls.server_name.clone(), tcp)
+                .await
+                .map_err(|e| NetError::tls(e.to_string()))?;
+            s.write_all(b"x")
+                .await
+                .map_err(|e| NetError::tls(e.to_string()))?;
+            let mut b = [0u8; 1];
+            // TLS 1.3 reports a rejected client certificate only on the first read
+            s.read_exact(&mut b)
+                .await
+                .map_err(|e| NetError::tls(e.to_string()))?;
+            assert_eq!(&b, b"x");
+            Ok(())
+        }
+        .await;
+        (accepted.await.unwrap(), connected)
+    }
+
+    #[tokio::test]
+    async fn trusted_ca() {
+        let pki = Pki::new("trusted");
+        let (server, client) = handshake(&pki.server(), &pki.client()).await;
+        server.unwrap();
+        client.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_ca_needs_insecure_skip_verify() {
+        let pki = Pki::new("unknown");
+        let mut cfg = pki.client();
+        cfg.ca_file = None;
+        let (_, client) = handshake(&pki.server(), &cfg).await;
+        assert!(client.unwrap_err().message.contains("UnknownIssuer"));
+
+        cfg.insecure_skip_verify = true;
+        cfg.server_name = Some("not-the-cert-name".into());
+        let (server, client) = handshake(&pki.server(), &cfg).await;
+        server.unwrap();
+        client.unwrap();
+    }
+
+    #[tokio::test]
+    async fn require_client_cert() {
+        let pki = Pki::new("mtls");
+        let mut server_cfg = pki.server();
+        server_cfg.client_ca_file = pki.path("ca.pem");
+        server_cfg.require_client_cert = true;
+
+        let (server, client) = handshake(&server_cfg, &pki.client()).await;
+        assert!(server.is_err());
+        assert!(client.is_err());
+
+        let mut client_cfg = pki.client();
+        client_cfg.client_cert_file = pki.path("client.pem");
+        client_cfg.client_key_file = pki.path("client.key");
+        let (server, client) = handshake(&server_cfg, &client_cfg).await;
+        server.unwrap();
+        client.unwrap();
+    }
+
+    #[test]
+    fn config_errors() {
+        let pki = Pki::new("config");
+        let mut server_cfg = pki.server();
+        server_cfg.require_client_cert = true;
+        let e = super::server(&server_cfg).err().unwrap();
+        assert_eq!(e.message, "require_client_cert needs client_ca_file");
+
+        let mut client_cfg = pki.client();
+        client_cfg.client_cert_file = pki.path("client.pem");
+        let e = super::client(&client_cfg, "127.0.0.1:1").err().unwrap();
+        assert_eq!(
+            e.message,
+            "client_cert_file and client_key_file must be given together"
+        );
+        assert_eq!(host_of("[::1]:443"), "::1");
+    }
+}
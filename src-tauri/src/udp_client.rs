@@ -1,170 +1,333 @@
 use base64::Engine;
-use once_cell::sync::OnceCell;
 use serde_json::json;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use std::net::UdpSocket;
-use std::sync::{mpsc, Mutex};
-use std::thread::{self, JoinHandle};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::{self, JoinHandle};
 use tauri::AppHandle;
 use tauri::Emitter;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::checksum;
+use crate::error::{NetError, NetResult};
+use crate::framing::{self, FramerConfig};
+use crate::impair::{self, Way};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Sent, Session, SessionHandle, SessionKind, Started, Stopped};
+use crate::sockopt::{self, BindOptions, SocketOptions};
+use crate::template::Payload;
 
 pub struct ClientHandle {
-    stop_tx: mpsc::Sender<()>,
-    thread_handle: Option<JoinHandle<()>>,
-    send_sock: UdpSocket,
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    send_sock: Arc<UdpSocket>,
+    framing: Option<FramerConfig>,
 }
 
 impl ClientHandle {
-    pub fn stop(self) {
-        let _ = self.stop_tx.send(());
-        if let Some(h) = self.thread_handle {
-            let _ = h.join();
-        }
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
     }
-}
 
-static UDP_CLIENT: OnceCell<Mutex<HashMap<String, ClientHandle>>> = OnceCell::new();
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.send_sock.clone()
+    }
 
-fn init_cell() {
-    UDP_CLIENT.get_or_init(|| Mutex::new(HashMap::new()));
+    pub fn sockets(&self) -> Vec<(String, socket2::Socket)> {
+        let label = self
+            .send_sock
+            .local_addr()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        sockopt::dup(&*self.send_sock)
+            .map(|s| vec![(label, s)])
+            .unwrap_or_default()
+    }
 }
 
-pub fn start(app: AppHandle, bind_addr: String) -> Result<String, String> {
-    init_cell();
-    let cell = UDP_CLIENT.get().unwrap();
-    let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-    if guard.contains_key(&bind_addr) {
-        return Err("UDP client already running for this address".into());
+pub async fn start(
+    app: AppHandle,
+    bind_addr: String,
+    options: Option<BindOptions>,
+    socket_options: Option<SocketOptions>,
+    framing: Option<FramerConfig>,
+) -> NetResult<Started> {
+    if session::registry()?.contains(SessionKind::UdpClient, &bind_addr) {
+        return Err(NetError::already_running(
+            SessionKind::UdpClient,
+            &bind_addr,
+        ));
     }
 
-    let sock = UdpSocket::bind(&bind_addr).map_err(|e| format!("bind error: {}", e))?;
-    let _ = sock.set_read_timeout(Some(Duration::from_millis(100)));
-    let send_sock = sock
-        .try_clone()
-        .map_err(|e| format!("socket clone error: {}", e))?;
-
-    let (tx, rx) = mpsc::channel::<()>();
-    let app_clone = app.clone();
-    let addr = bind_addr.clone();
-
-    let handle = thread::spawn(move || {
-        let sock = sock;
-        let mut buf = [0u8; 65536];
-        let mut seq: u64 = 0;
-        let mut last: Option<(u64, Instant)> = None;
-
-        loop {
-            if rx.try_recv().is_ok() {
-                break;
-            }
-            match sock.recv_from(&mut buf) {
-                Ok((n, src)) => {
-                    seq = seq.wrapping_add(1);
-                    let data = &buf[..n];
-
-                    let mut hasher = DefaultHasher::new();
-                    src.to_string().hash(&mut hasher);
-                    n.hash(&mut hasher);
-                    data.hash(&mut hasher);
-                    let hash = hasher.finish();
-
-                    let now = Instant::now();
-                    let dup = last
-                        .map(|(h, t)| {
-                            h == hash && now.duration_since(t) < Duration::from_millis(50)
-                        })
-                        .unwrap_or(false);
-                    last = Some((hash, now));
-
-                    let b64 = base64::engine::general_purpose::STANDARD.encode(data);
-                    let ts_ms = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .map(|d| d.as_millis() as u64)
-                        .unwrap_or(0);
-                    let payload = json!({
-                        "bind": addr,
-                        "from": src.to_string(),
-                        "data": b64,
-                        "seq": seq,
-                        "ts_ms": ts_ms,
-                        "dup": dup,
-                    });
-                    println!("[udp-client:{}] recv {} bytes from {}", addr, n, src);
-                    let _ = app_clone.emit("udp:client:message", payload);
-                }
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {}
-                    _ => {
-                        let payload = json!({"error": format!("recv error: {}", e), "bind": addr});
-                        let _ = app_clone.emit("udp:client:error", payload);
-                    }
-                },
-            }
-        }
-    });
+    if let Some(f) = &framing {
+        f.validate_datagram()?;
+    }
 
-    guard.insert(
+    let sock = sockopt::bind_udp(
+        &bind_addr,
+        &options.unwrap_or_default(),
+        &socket_options.unwrap_or_default(),
+    )
+    .await
+    .map_err(|e| NetError::io("bind", &bind_addr, e))?;
+    let sock = Arc::new(sock);
+
+    let cancel = CancellationToken::new();
+    let id = session::next_id();
+
+    let task = async_runtime::spawn(recv_loop(
+        app,
+        id.clone(),
+        bind_addr.clone(),
+        sock.clone(),
+        framing.clone(),
+        cancel.clone(),
+    ));
+
+    let handle = ClientHandle {
+        cancel,
+        task,
+        send_sock: sock,
+        framing,
+    };
+    let rejected = session::registry()?.insert(
+        id.clone(),
+        SessionKind::UdpClient,
         bind_addr.clone(),
-        ClientHandle {
-            stop_tx: tx,
-            thread_handle: Some(handle),
-            send_sock,
-        },
+        SessionHandle::UdpClient(handle),
     );
+    if let Err(h) = rejected {
+        h.stop().await;
+        return Err(NetError::already_running(
+            SessionKind::UdpClient,
+            &bind_addr,
+        ));
+    }
 
-    Ok(format!("UDP client started on {}", bind_addr))
+    Ok(Started {
+        session_id: id,
+        kind: SessionKind::UdpClient,
+        message: format!("UDP client started on {}", bind_addr),
+        addr: bind_addr,
+    })
 }
 
-pub fn stop(bind_addr: Option<String>) -> Result<String, String> {
-    init_cell();
-    let cell = UDP_CLIENT.get().unwrap();
-    let mut guard = cell.lock().map_err(|e| format!("lock error: {}", e))?;
-    if let Some(b) = bind_addr {
-        if let Some(h) = guard.remove(&b) {
-            h.stop();
-            Ok(format!("UDP client stopped on {}", b))
-        } else {
-            Err("UDP client not running for that address".into())
+async fn recv_loop(
+    app: AppHandle,
+    sid: String,
+    addr: String,
+    sock: Arc<UdpSocket>,
+    framing: Option<FramerConfig>,
+    cancel: CancellationToken,
+) {
+    let mut buf = vec![0u8; 65536];
+    let mut seq: u64 = 0;
+    let mut last: Option<(u64, Instant)> = None;
+    // datagrams held back by an inbound impairment come back through here
+    let (impaired_tx, mut impaired_rx) = mpsc::unbounded_channel::<(SocketAddr, Vec<u8>)>();
+
+    loop {
+        let received = tokio::select! {
+            _ = cancel.cancelled() => break,
+            Some((src, data)) = impaired_rx.recv() => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok((data.len(), src))
+            }
+            r = sock.recv_from(&mut buf) => match r {
+                Ok((n, src)) if impair::divert(&sid, Way::Inbound, &buf[..n], |d| {
+                    let tx = impaired_tx.clone();
+                    async move {
+                        let _ = tx.send((src, d));
+                        Ok(())
+                    }
+                }) => continue,
+                r => r,
+            },
+        };
+        match received {
+            Ok((n, src)) => {
+                seq = seq.wrapping_add(1);
+                let data = &buf[..n];
+
+                let mut hasher = DefaultHasher::new();
+                src.to_string().hash(&mut hasher);
+                n.hash(&mut hasher);
+                data.hash(&mut hasher);
+                let hash = hasher.finish();
+
+                let now = Instant::now();
+                let dup = last
+                    .map(|(h, t)| h == hash && now.duration_since(t) < Duration::from_millis(50))
+                    .unwrap_or(false);
+                last = Some((hash, now));
+
+                let b64 = base64::engine::general_purpose::STANDARD.encode(data);
+                let ts_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                let mut payload = json!({
+                    "session": sid,
+                    "bind": addr,
+                    "from": src.to_string(),
+                    "data": b64,
+                    "seq": seq,
+                    "ts_ms": ts_ms,
+                    "dup": dup,
+                });
+                framing::annotate(framing.as_ref(), data, &mut payload);
+                let _ = app.emit("udp:client:message", payload);
+                recorder::record(
+                    &sid,
+                    Direction::Rx,
+                    &addr,
+                    &src.to_string(),
+                    seq,
+                    ts_ms,
+                    data,
+                );
+            }
+            Err(e) => {
+                let payload =
+                    json!({"session": sid, "error": format!("recv error: {}", e), "bind": addr});
+                let _ = app.emit("udp:client:error", payload);
+            }
         }
+    }
+}
+
+pub async fn stop(bind_addr: Option<String>) -> NetResult<Stopped> {
+    if let Some(b) = bind_addr {
+        let removed = session::registry()?.remove(SessionKind::UdpClient, &b);
+        let s = removed.ok_or_else(|| NetError::not_running(SessionKind::UdpClient, &b))?;
+        let info = s.stop().await;
+        Ok(Stopped {
+            session_ids: vec![info.id],
+            message: format!("UDP client stopped on {}", b),
+        })
     } else {
-        let previous = std::mem::take(&mut *guard);
-        for (_k, h) in previous {
-            h.stop();
-        }
-        Ok("All UDP clients stopped".into())
+        let previous = session::registry()?.remove_kind(SessionKind::UdpClient);
+        Ok(Stopped {
+            session_ids: session::stop_all(previous).await,
+            message: "All UDP clients stopped".into(),
+        })
     }
 }
 
-pub fn send_from(bind_addr: String, to_addr: String, data_b64: String) -> Result<String, String> {
-    let data = match base64::engine::general_purpose::STANDARD.decode(&data_b64) {
-        Ok(d) => d,
-        Err(e) => return Err(format!("base64 decode error: {}", e)),
-    };
+/// With `framed`, the payload is wrapped by the session's framer before it is sent.
+pub async fn send_from(
+    bind_addr: String,
+    to_addr: String,
+    payload: Payload,
+    framed: bool,
+) -> NetResult<Sent> {
+    let data = payload.bytes()?;
+    send_bytes_from(&bind_addr, &to_addr, &data, framed).await
+}
 
-    init_cell();
-    let cell = UDP_CLIENT.get().unwrap();
-
-    if let Ok(guard) = cell.lock() {
-        if let Some(h) = guard.get(&bind_addr) {
-            return match h.send_sock.send_to(&data, &to_addr) {
-                Ok(n) => Ok(format!(
-                    "sent {} bytes to {} from {}",
-                    n, to_addr, bind_addr
-                )),
-                Err(e) => Err(format!("send error: {}", e)),
-            };
+/// Sends through the UDP client bound to `bind_addr`, or a temporary socket when none is running
+/// and the payload isn't `framed`.
+pub async fn send_bytes_from(
+    bind_addr: &str,
+    to_addr: &str,
+    data: &[u8],
+    framed: bool,
+) -> NetResult<Sent> {
+    let running = match session::registry()?.get(SessionKind::UdpClient, bind_addr) {
+        Some(Session {
+            info,
+            handle: SessionHandle::UdpClient(h),
+        }) => Some((info.id.clone(), h.send_sock.clone(), h.framing.clone())),
+        _ => None,
+    };
+    if let Some((sid, sock, framing)) = running {
+        let data = checksum::append(&sid, data);
+        let data = if framed {
+            framing
+                .as_ref()
+                .ok_or_else(|| NetError::framing("session has no framer configured".into()))?
+                .encode(&data)?
+        } else {
+            data.into_owned()
+        };
+        let diverted = impair::divert(&sid, Way::Outbound, &data, |d| {
+            let (sid, bind, to, sock) = (
+                sid.clone(),
+                bind_addr.to_string(),
+                to_addr.to_string(),
+                sock.clone(),
+            );
+            async move {
+                let n = sock.send_to(&d, &to).await?;
+                recorder::record(
+                    &sid,
+                    Direction::Tx,
+                    &bind,
+                    &to,
+                    0,
+                    session::now_ms(),
+                    &d[..n],
+                );
+                Ok(())
+            }
+        });
+        if diverted {
+            return Ok(Sent {
+                session_id: Some(sid),
+                bytes: data.len(),
+                peers: 1,
+                message: format!(
+                    "queued {} bytes to {} from {} behind the impairment",
+                    data.len(),
+                    to_addr,
+                    bind_addr
+                ),
+            });
         }
+        let n = sock
+            .send_to(&data, to_addr)
+            .await
+            .map_err(|e| NetError::io("send", to_addr, e))?;
+        recorder::record(
+            &sid,
+            Direction::Tx,
+            bind_addr,
+            to_addr,
+            0,
+            session::now_ms(),
+            &data[..n],
+        );
+        return Ok(Sent {
+            session_id: Some(sid),
+            bytes: n,
+            peers: 1,
+            message: format!("sent {} bytes to {} from {}", n, to_addr, bind_addr),
+        });
     }
-
-    let sock = UdpSocket::bind(&bind_addr).map_err(|e| format!("bind error: {}", e))?;
-    match sock.send_to(&data, &to_addr) {
-        Ok(n) => Ok(format!(
-            "sent {} bytes to {} from {}",
-            n, to_addr, bind_addr
-        )),
-        Err(e) => Err(format!("send error: {}", e)),
+    if framed {
+        return Err(NetError::not_running(SessionKind::UdpClient, bind_addr));
     }
+
+    let sock = sockopt::bind_udp(
+        bind_addr,
+        &BindOptions::default(),
+        &SocketOptions::default(),
+    )
+    .await
+    .map_err(|e| NetError::io("bind", bind_addr, e))?;
+    let n = sock
+        .send_to(data, to_addr)
+        .await
+        .map_err(|e| NetError::io("send", to_addr, e))?;
+    Ok(Sent {
+        session_id: None,
+        bytes: n,
+        peers: 1,
+        message: format!("sent {} bytes to {} from {}", n, to_addr, bind_addr),
+    })
 }
@@ -0,0 +1,1407 @@
+use base64::Engine;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::checksum;
+use crate::error::{NetError, NetResult};
+
+/// RFC 6455 section 1.3.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// An HTTP head that has not ended by then is rejected.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+/// Messages larger than this close the connection with 1009 unless `max_message_size` is set.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 << 20;
+/// Appended by a sync flush; RFC 7692 strips it from every compressed message.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(b: u8) -> Option<Self> {
+        match b {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// Handshake and protocol settings shared by WebSocket servers and clients.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WsOptions {
+    /// A client offers these in order of preference; a server picks the first one the client
+    /// offers that is also in its list.
+    #[serde(default)]
+    pub subprotocols: Vec<String>,
+    /// Extra headers for the handshake request (client) or its response (server).
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Negotiate permessage-deflate (RFC 7692).
+    #[serde(default)]
+    pub deflate: bool,
+    pub max_message_size: Option<usize>,
+}
+
+impl WsOptions {
+    fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+/// The permessage-deflate parameters both sides agreed on.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+/// What a finished handshake settled, as reported in the `connected` events.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Negotiated {
+    pub subprotocol: Option<String>,
+    pub deflate: Option<DeflateParams>,
+    /// The peer's handshake headers, lower-cased.
+    pub headers: HashMap<String, String>,
+}
+
+/// One complete message; fragments are joined and compressed ones inflated.
+pub struct Message {
+    pub opcode: Opcode,
+    pub data: Vec<u8>,
+}
+
+pub enum ReadError {
+    /// The peer closed the TCP connection without a close frame.
+    Eof,
+    Io(io::Error),
+    /// The peer broke the protocol; the connection should be closed with `code`.
+    Protocol {
+        code: u16,
+        reason: String,
+    },
+}
+
+impl ReadError {
+    fn protocol(code: u16, reason: impl Into<String>) -> Self {
+        ReadError::Protocol {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Parses frames out of a byte stream. `next` is cancel safe: bytes are only consumed once a whole
+/// frame has arrived.
+pub struct Reader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Servers require masked frames, clients unmasked ones.
+    masked: bool,
+    max_message_size: usize,
+    inflate: Option<Inflate>,
+    /// The data message being reassembled from fragments, and whether it is compressed.
+    partial: Option<(Opcode, bool, Vec<u8>)>,
+}
+
+struct Frame {
+    fin: bool,
+    compressed: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    /// `leftover` is whatever arrived behind the handshake head.
+    pub fn new(
+        inner: R,
+        leftover: Vec<u8>,
+        server: bool,
+        options: &WsOptions,
+        deflate: Option<DeflateParams>,
+    ) -> Self {
+        Reader {
+            inner,
+            buf: leftover,
+            masked: server,
+            max_message_size: options.max_message_size(),
+            inflate: deflate.map(|p| Inflate::new(p, server)),
+            partial: None,
+        }
+    }
+
+    pub async fn next(&mut self) -> Result<Message, ReadError> {
+        loop {
+            while let Some(frame) = self.parse()? {
+                if let Some(message) = self.assemble(frame)? {
+                    return Ok(message);
+                }
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match self.inner.read(&mut chunk).await {
+                Ok(0) => return Err(ReadError::Eof),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(ReadError::Io(e)),
+            }
+        }
+    }
+
+    fn parse(&mut self) -> Result<Option<Frame>, ReadError> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let (b0, b1) = (self.buf[0], self.buf[1]);
+        let opcode = Opcode::from_bits(b0 & 0x0F).ok_or_else(|| {
+            ReadError::protocol(
+                CLOSE_PROTOCOL_ERROR,
+                format!("unknown opcode {:#x}", b0 & 0x0F),
+            )
+        })?;
+        if b0 & 0x30 != 0 {
+            return Err(ReadError::protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "reserved bits set",
+            ));
+        }
+        let masked = b1 & 0x80 != 0;
+        if masked != self.masked {
+            let reason = if self.masked {
+                "client frames must be masked"
+            } else {
+                "server frames must not be masked"
+            };
+            return Err(ReadError::protocol(CLOSE_PROTOCOL_ERROR, reason));
+        }
+        let (len, mut at) = match b1 & 0x7F {
+            126 if self.buf.len() >= 4 => {
+                (u16::from_be_bytes([self.buf[2], self.buf[3]]) as u64, 4)
+            }
+            127 if self.buf.len() >= 10 => {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&self.buf[2..10]);
+                (u64::from_be_bytes(b), 10)
+            }
+            126 | 127 => return Ok(None),
+            n => (n as u64, 2),
+        };
+        if opcode.is_control() && (len > 125 || b0 & 0x80 == 0) {
+            return Err(ReadError::protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "control frames must be whole and at most 125 bytes",
+            ));
+        }
+        let held = match opcode {
+            Opcode::Continuation => self.partial.as_ref().map_or(0, |p| p.2.len()),
+            _ => 0,
+        };
+        if len.saturating_add(held as u64) > self.max_message_size as u64 {
+            return Err(ReadError::protocol(
+                CLOSE_TOO_BIG,
+                format!("message exceeds {} bytes", self.max_message_size),
+            ));
+        }
+        let len = len as usize;
+        let key_at = at;
+        if masked {
+            at += 4;
+        }
+        if self.buf.len() < at + len {
+            return Ok(None);
+        }
+        let mut payload: Vec<u8> = self.buf[at..at + len].to_vec();
+        if masked {
+            let key = [
+                self.buf[key_at],
+                self.buf[key_at + 1],
+                self.buf[key_at + 2],
+                self.buf[key_at + 3],
+            ];
+            payload
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b ^= key[i % 4]);
+        }
+        self.buf.drain(..at + len);
+        Ok(Some(Frame {
+            fin: b0 & 0x80 != 0,
+            compressed: b0 & 0x40 != 0,
+            opcode,
+            payload,
+        }))
+    }
+
+    fn assemble(&mut self, frame: Frame) -> Result<Option<Message>, ReadError> {
+        let starts_message = matches!(frame.opcode, Opcode::Text | Opcode::Binary);
+        if frame.compressed && (!starts_message || self.inflate.is_none()) {
+            return Err(ReadError::protocol(
+                CLOSE_PROTOCOL_ERROR,
+                "RSV1 set without permessage-deflate",
+            ));
+        }
+        if frame.opcode == Opcode::Close {
+            check_close(&frame.payload)?;
+        }
+        if frame.opcode.is_control() {
+            return Ok(Some(Message {
+                opcode: frame.opcode,
+                data: frame.payload,
+            }));
+        }
+        let (opcode, compressed, data) = match (frame.opcode, self.partial.take()) {
+            (Opcode::Continuation, None) => {
+                return Err(ReadError::protocol(
+                    CLOSE_PROTOCOL_ERROR,
+                    "continuation without a message to continue",
+                ));
+            }
+            (Opcode::Continuation, Some((op, compressed, mut data))) => {
+                data.extend_from_slice(&frame.payload);
+                (op, compressed, data)
+            }
+            (_, Some(_)) => {
+                return Err(ReadError::protocol(
+                    CLOSE_PROTOCOL_ERROR,
+                    "new message before the previous one finished",
+                ));
+            }
+            (op, None) => (op, frame.compressed, frame.payload),
+        };
+        if !frame.fin {
+            self.partial = Some((opcode, compressed, data));
+            return Ok(None);
+        }
+        let data = match (&mut self.inflate, compressed) {
+            (Some(inflate), true) => inflate.message(&data, self.max_message_size)?,
+            _ => data,
+        };
+        if opcode == Opcode::Text && std::str::from_utf8(&data).is_err() {
+            return Err(ReadError::protocol(
+                CLOSE_INVALID_DATA,
+                "text message is not valid UTF-8",
+            ));
+        }
+        Ok(Some(Message { opcode, data }))
+    }
+}
+
+/// Writes whole messages as single frames.
+pub struct Writer<W> {
+    inner: W,
+    /// Clients mask every frame.
+    mask: bool,
+    deflate: Option<Deflate>,
+    /// Nothing may follow a close frame.
+    closed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    pub fn new(inner: W, server: bool, deflate: Option<DeflateParams>) -> Self {
+        Writer {
+            inner,
+            mask: !server,
+            deflate: deflate.map(|p| Deflate::new(p, server)),
+            closed: false,
+        }
+    }
+
+    pub fn close_sent(&self) -> bool {
+        self.closed
+    }
+
+    /// Text and binary messages are compressed when deflate was negotiated. Returns the
+    /// number of bytes put on the wire.
+    pub async fn send(&mut self, opcode: Opcode, data: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "close frame already sent",
+            ));
+        }
+        if opcode.is_control() && data.len() > 125 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "control frames carry at most 125 bytes",
+            ));
+        }
+        let compressed = match (&mut self.deflate, opcode) {
+            (Some(d), Opcode::Text | Opcode::Binary) => Some(d.message(data)?),
+            _ => None,
+        };
+        let frame = encode(
+            opcode,
+            compressed.as_deref().unwrap_or(data),
+            compressed.is_some(),
+            self.mask,
+        );
+        self.inner.write_all(&frame).await?;
+        self.closed = opcode == Opcode::Close;
+        Ok(frame.len())
+    }
+
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<usize> {
+        let mut data = code.to_be_bytes().to_vec();
+        // keep within the 125 bytes of a control frame without splitting a character
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        data.extend_from_slice(&reason.as_bytes()[..end]);
+        self.send(Opcode::Close, &data).await
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+fn encode(opcode: Opcode, payload: &[u8], compressed: bool, mask: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 14);
+    out.push(0x80 | if compressed { 0x40 } else { 0 } | opcode.bits());
+    let mask_bit = if mask { 0x80 } else { 0 };
+    match payload.len() {
+        n if n < 126 => out.push(mask_bit | n as u8),
+        n if n <= u16::MAX as usize => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    if mask {
+        let key: [u8; 4] = rand::thread_rng().gen();
+        out.extend_from_slice(&key);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        out.extend_from_slice(payload);
+    }
+    out
+}
+
+/// Whether `code` may be sent in a close frame (RFC 6455 section 7.4): the registered codes
+/// except those reserved for reporting a missing or abnormal close, plus the ranges for
+/// libraries and applications.
+pub fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// A close payload is empty or a valid code followed by a UTF-8 reason.
+fn check_close(data: &[u8]) -> Result<(), ReadError> {
+    match data {
+        [] => Ok(()),
+        [_] => Err(ReadError::protocol(
+            CLOSE_PROTOCOL_ERROR,
+            "close frame with a 1-byte payload",
+        )),
+        [a, b, reason @ ..] => {
+            let code = u16::from_be_bytes([*a, *b]);
+            if !valid_close_code(code) {
+                return Err(ReadError::protocol(
+                    CLOSE_PROTOCOL_ERROR,
+                    format!("invalid close code {}", code),
+                ));
+            }
+            if std::str::from_utf8(reason).is_err() {
+                return Err(ReadError::protocol(
+                    CLOSE_INVALID_DATA,
+                    "close reason is not valid UTF-8",
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Splits a close frame's payload into its status code and reason.
+pub fn close_reason(data: &[u8]) -> (Option<u16>, String) {
+    match data {
+        [a, b, reason @ ..] => (
+            Some(u16::from_be_bytes([*a, *b])),
+            String::from_utf8_lossy(reason).into_owned(),
+        ),
+        _ => (None, String::new()),
+    }
+}
+
+struct Deflate {
+    compress: Compress,
+    /// Start every message with an empty window.
+    reset: bool,
+}
+
+impl Deflate {
+    fn new(params: DeflateParams, server: bool) -> Self {
+        Deflate {
+            compress: Compress::new(Compression::default(), false),
+            reset: if server {
+                params.server_no_context_takeover
+            } else {
+                params.client_no_context_takeover
+            },
+        }
+    }
+
+    fn message(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let done = (self.compress.total_in() - start) as usize == data.len();
+            // a flush that left spare room has written everything it had
+            if done && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.reset {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+}
+
+struct Inflate {
+    decompress: Decompress,
+    reset: bool,
+}
+
+impl Inflate {
+    fn new(params: DeflateParams, server: bool) -> Self {
+        Inflate {
+            decompress: Decompress::new(false),
+            reset: if server {
+                params.client_no_context_takeover
+            } else {
+                params.server_no_context_takeover
+            },
+        }
+    }
+
+    fn message(&mut self, data: &[u8], max: usize) -> Result<Vec<u8>, ReadError> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let start = self.decompress.total_in();
+        let mut out = Vec::with_capacity(data.len() * 2 + 64);
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| {
+                    ReadError::protocol(CLOSE_INVALID_DATA, format!("inflate error: {}", e))
+                })?;
+            if out.len() > max {
+                return Err(ReadError::protocol(
+                    CLOSE_TOO_BIG,
+                    format!("message exceeds {} bytes", max),
+                ));
+            }
+            let done = (self.decompress.total_in() - start) as usize == input.len();
+            if (done && out.len() < out.capacity()) || status == Status::StreamEnd {
+                break;
+            }
+            if status == Status::BufError && out.len() < out.capacity() {
+                return Err(ReadError::protocol(
+                    CLOSE_INVALID_DATA,
+                    "truncated deflate data",
+                ));
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if self.reset {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+/// Reads up to the blank line that ends an HTTP head. Returns the head and any bytes after it.
+pub async fn read_head<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handshake head too long",
+            ));
+        }
+        let n = r.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha = sha1_smol::Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(ACCEPT_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha.digest().bytes())
+}
+
+fn header_map(headers: &[httparse::Header<'_>]) -> HashMap<String, String> {
+    let mut map: HashMap<String, String> = HashMap::new();
+    for h in headers {
+        let value = String::from_utf8_lossy(h.value).trim().to_string();
+        map.entry(h.name.to_ascii_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    map
+}
+
+/// Comma-separated tokens of a header, e.g. `Connection: keep-alive, Upgrade`.
+fn tokens(value: Option<&String>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn has_token(value: Option<&String>, token: &str) -> bool {
+    tokens(value).iter().any(|t| t.eq_ignore_ascii_case(token))
+}
+
+/// Parameters of every permessage-deflate offer in a `Sec-WebSocket-Extensions` header.
+fn deflate_offers(value: Option<&String>) -> Vec<Vec<(String, Option<String>)>> {
+    tokens(value)
+        .iter()
+        .filter_map(|ext| {
+            let mut parts = ext.split(';').map(str::trim);
+            if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") {
+                return None;
+            }
+            Some(
+                parts
+                    .filter(|p| !p.is_empty())
+                    .map(|p| match p.split_once('=') {
+                        Some((k, v)) => (
+                            k.trim().to_ascii_lowercase(),
+                            Some(v.trim().trim_matches('"').to_string()),
+                        ),
+                        None => (p.to_ascii_lowercase(), None),
+                    })
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// A server's answer to an upgrade request.
+pub struct Accepted {
+    pub path: String,
+    pub negotiated: Negotiated,
+    pub response: Vec<u8>,
+}
+
+/// Checks an upgrade request and builds the 101 response. On failure returns the message and the
+/// error response to send before closing.
+pub fn accept(head: &[u8], options: &WsOptions) -> Result<Accepted, (String, Vec<u8>)> {
+    let reject = |status: &str, message: String| {
+        let response = format!(
+            "HTTP/1.1 {}\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        );
+        (message, response.into_bytes())
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => {
+            return Err(reject("400 Bad Request", "incomplete request".into()))
+        }
+        Err(e) => return Err(reject("400 Bad Request", format!("bad request: {}", e))),
+    }
+    if req.method != Some("GET") {
+        return Err(reject(
+            "405 Method Not Allowed",
+            "upgrade requests must use GET".into(),
+        ));
+    }
+    let path = req.path.unwrap_or("/").to_string();
+    let map = header_map(req.headers);
+    if !has_token(map.get("upgrade"), "websocket") || !has_token(map.get("connection"), "upgrade") {
+        return Err(reject(
+            "426 Upgrade Required",
+            "not a WebSocket upgrade request".into(),
+        ));
+    }
+    if map.get("sec-websocket-version").map(String::as_str) != Some("13") {
+        return Err(reject(
+            "426 Upgrade Required",
+            "unsupported WebSocket version".into(),
+        ));
+    }
+    let key = match map.get("sec-websocket-key") {
+        Some(k)
+            if base64::engine::general_purpose::STANDARD
+                .decode(k)
+                .map(|b| b.len())
+                == Ok(16) =>
+        {
+            k.clone()
+        }
+        _ => {
+            return Err(reject(
+                "400 Bad Request",
+                "missing or invalid Sec-WebSocket-Key".into(),
+            ))
+        }
+    };
+
+    let subprotocol = tokens(map.get("sec-websocket-protocol"))
+        .into_iter()
+        .find(|p| options.subprotocols.iter().any(|s| s == p));
+    // window bits below 15 would need a zlib build that can shrink the window, so such offers
+    // are declined; a client's own limit needs nothing from this side
+    let offers = match options.deflate {
+        true => deflate_offers(map.get("sec-websocket-extensions")),
+        false => Vec::new(),
+    };
+    let deflate = offers
+        .into_iter()
+        .find(|params| {
+            params.iter().all(|(k, v)| match k.as_str() {
+                "server_max_window_bits" => v.as_deref() == Some("15"),
+                "client_max_window_bits"
+                | "server_no_context_takeover"
+                | "client_no_context_takeover" => true,
+                _ => false,
+            })
+        })
+        .map(|params| DeflateParams {
+            server_no_context_takeover: params
+                .iter()
+                .any(|(k, _)| k == "server_no_context_takeover"),
+            client_no_context_takeover: params
+                .iter()
+                .any(|(k, _)| k == "client_no_context_takeover"),
+        });
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+        accept_key(&key)
+    );
+    if let Some(p) = &subprotocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", p));
+    }
+    if let Some(d) = &deflate {
+        response.push_str("Sec-WebSocket-Extensions: permessage-deflate");
+        if d.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if d.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        response.push_str("\r\n");
+    }
+    for (name, value) in &options.headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+
+    Ok(Accepted {
+        path,
+        negotiated: Negotiated {
+            subprotocol,
+            deflate,
+            headers: map,
+        },
+        response: response.into_bytes(),
+    })
+}
+
+/// Where a `ws://` or `wss://` URL points.
+pub struct Url {
+    pub tls: bool,
+    /// `host:port`, with IPv6 hosts in brackets, for connecting.
+    pub addr: String,
+    /// The `Host` header: the authority as written in the URL.
+    pub host: String,
+    pub path: String,
+}
+
+pub fn parse_url(url: &str) -> NetResult<Url> {
+    let (tls, rest) = if let Some(r) = url.strip_prefix("ws://") {
+        (false, r)
+    } else if let Some(r) = url.strip_prefix("wss://") {
+        (true, r)
+    } else {
+        return Err(NetError::websocket(format!(
+            "{} is not a ws:// or wss:// URL",
+            url
+        )));
+    };
+    let Some(Authority { addr, host, path }) = split_authority(rest, if tls { 443 } else { 80 })
+    else {
+        return Err(NetError::websocket(format!("{} has no usable host", url)));
+    };
+    Ok(Url {
+        tls,
+        addr,
+        host,
+        path,
+    })
+}
+
+/// The parts of a URL after its `scheme://`.
+pub struct Authority {
+    /// `host:port`, with `default_port` filled in when the URL has none.
+    pub addr: String,
+    /// The authority as written in the URL.
+    pub host: String,
+    /// The path and query, `/` when empty, without any fragment.
+    pub path: String,
+}
+
+/// Splits what follows a URL's `scheme://`; `None` when there is no host or it carries
+/// credentials.
+pub fn split_authority(rest: &str, default_port: u16) -> Option<Authority> {
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], rest[i..].to_string()),
+        Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+        None => (rest, "/".to_string()),
+    };
+    let path = path.split('#').next().unwrap_or("/").to_string();
+    if authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    // a colon after the last `]` (or anywhere, without brackets) starts the port
+    let port_at = match authority.rfind(']') {
+        Some(b) => authority[b..].find(':').map(|i| b + i),
+        None => authority.rfind(':'),
+    };
+    let addr = match port_at {
+        Some(_) => authority.to_string(),
+        None => format!("{}:{}", authority, default_port),
+    };
+    Some(Authority {
+        addr,
+        host: authority.to_string(),
+        path,
+    })
+}
+
+/// Builds an upgrade request; returns it with the key the response must answer.
+pub fn request(url: &Url, options: &WsOptions) -> (Vec<u8>, String) {
+    let key_bytes: [u8; 16] = rand::thread_rng().gen();
+    let key = base64::engine::general_purpose::STANDARD.encode(key_bytes);
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        url.path, url.host, key
+    );
+    if !options.subprotocols.is_empty() {
+        req.push_str(&format!(
+            "Sec-WebSocket-Protocol: {}\r\n",
+            options.subprotocols.join(", ")
+        ));
+    }
+    if options.deflate {
+        req.push_str("Sec-WebSocket-Extensions: permessage-deflate\r\n");
+    }
+    for (name, value) in &options.headers {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    req.push_str("\r\n");
+    (req.into_bytes(), key)
+}
+
+/// Checks a server's answer to `request`.
+pub fn check_response(head: &[u8], key: &str, options: &WsOptions) -> NetResult<Negotiated> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    match res.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => {
+            return Err(NetError::websocket("incomplete handshake response".into()))
+        }
+        Err(e) => {
+            return Err(NetError::websocket(format!(
+                "bad handshake response: {}",
+                e
+            )))
+        }
+    }
+    let code = res.code.unwrap_or(0);
+    if code != 101 {
+        return Err(NetError::websocket(format!(
+            "server refused the upgrade: {} {}",
+            code,
+            res.reason.unwrap_or("")
+        )));
+    }
+    let map = header_map(res.headers);
+    if map.get("sec-websocket-accept") != Some(&accept_key(key)) {
+        return Err(NetError::websocket(
+            "server sent a wrong Sec-WebSocket-Accept".into(),
+        ));
+    }
+    let subprotocol = map.get("sec-websocket-protocol").cloned();
+    if let Some(p) = &subprotocol {
+        if !options.subprotocols.contains(p) {
+            return Err(NetError::websocket(format!(
+                "server chose subprotocol {} that was not offered",
+                p
+            )));
+        }
+    }
+    let extensions = map.get("sec-websocket-extensions");
+    let mut offers = deflate_offers(extensions);
+    if tokens(extensions).len() != offers.len() {
+        return Err(NetError::websocket(
+            "server enabled an unknown extension".into(),
+        ));
+    }
+    let deflate = match offers.pop() {
+        None => None,
+        Some(_) if !options.deflate || !offers.is_empty() => {
+            return Err(NetError::websocket(
+                "server enabled an extension that was not offered".into(),
+            ));
+        }
+        Some(params) => {
+            let mut d = DeflateParams::default();
+            for (k, _) in params {
+                match k.as_str() {
+                    "server_no_context_takeover" => d.server_no_context_takeover = true,
+                    "client_no_context_takeover" => d.client_no_context_takeover = true,
+                    // the server may shrink its own window; inflating with the full one copes
+                    "server_max_window_bits" => {}
+                    other => {
+                        return Err(NetError::websocket(format!(
+                            "unsupported permessage-deflate parameter {}",
+                            other
+                        )));
+                    }
+                }
+            }
+            Some(d)
+        }
+    };
+    Ok(Negotiated {
+        subprotocol,
+        deflate,
+        headers: map,
+    })
+}
+
+/// The opcode for a user message: text when none is given and `data` is valid UTF-8, binary
+/// otherwise. Close frames go through the close commands instead.
+pub fn message_opcode(opcode: Option<Opcode>, data: &[u8]) -> NetResult<Opcode> {
+    let utf8 = std::str::from_utf8(data).is_ok();
+    match opcode {
+        None if utf8 => Ok(Opcode::Text),
+        None => Ok(Opcode::Binary),
+        Some(Opcode::Text) if !utf8 => Err(NetError::websocket(
+            "text messages must be valid UTF-8".into(),
+        )),
+        Some(Opcode::Continuation | Opcode::Close) => Err(NetError::websocket(
+            "send text, binary, ping or pong; use the close command to close".into(),
+        )),
+        Some(op) => Ok(op),
+    }
+}
+
+/// A user message as it goes on the wire: data messages get the session's checksum appended
+/// first, so the default opcode reflects the bytes actually sent. An explicit text opcode is
+/// refused when the checksum would make the message invalid UTF-8.
+pub fn outgoing(
+    session_id: &str,
+    opcode: Option<Opcode>,
+    data: Vec<u8>,
+) -> NetResult<(Opcode, Vec<u8>)> {
+    if let Some(op @ (Opcode::Ping | Opcode::Pong)) = opcode {
+        return Ok((op, data));
+    }
+    let plain = std::str::from_utf8(&data).is_ok();
+    let data = checksum::append(session_id, &data).into_owned();
+    match message_opcode(opcode, &data) {
+        Err(_) if plain && opcode == Some(Opcode::Text) => Err(NetError::websocket(
+            "the appended checksum is not valid UTF-8; send the message as binary".into(),
+        )),
+        op => Ok((op?, data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(server: bool, deflate: Option<DeflateParams>) -> Reader<&'static [u8]> {
+        Reader::new(&[][..], Vec::new(), server, &WsOptions::default(), deflate)
+    }
+
+    /// A frame with the given first byte, masked with a fixed key when `mask` is set.
+    fn frame(b0: u8, payload: &[u8], mask: bool) -> Vec<u8> {
+        let mut out = encode(Opcode::Binary, payload, false, false);
+        out[0] = b0;
+        if mask {
+            let key = [0x37, 0xFA, 0x21, 0x3D];
+            let at = out.len() - payload.len();
+            out[1] |= 0x80;
+            let masked: Vec<u8> = payload
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ key[i % 4])
+                .collect();
+            out.truncate(at);
+            out.extend_from_slice(&key);
+            out.extend_from_slice(&masked);
+        }
+        out
+    }
+
+    fn next(r: &mut Reader<&'static [u8]>) -> Result<Option<Message>, ReadError> {
+        while let Some(frame) = r.parse()? {
+            if let Some(message) = r.assemble(frame)? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    fn protocol_code(result: Result<Option<Message>, ReadError>) -> u16 {
+        match result {
+            Err(ReadError::Protocol { code, .. }) => code,
+            Ok(_) => panic!("expected a protocol error, got a message"),
+            Err(_) => panic!("expected a protocol error"),
+        }
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_sample() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn parse_masked_lengths_across_partial_reads() {
+        for len in [0usize, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let wire = frame(0x82, &payload, true);
+            let header = match len {
+                n if n < 126 => 2,
+                n if n <= 65535 => 4,
+                _ => 10,
+            };
+            assert_eq!(wire.len(), header + 4 + len);
+            let mut r = reader(true, None);
+            // a byte short of the whole frame leaves everything in the buffer
+            r.buf.extend_from_slice(&wire[..wire.len() - 1]);
+            assert!(r.parse().ok().unwrap().is_none());
+            assert_eq!(r.buf.len(), wire.len() - 1);
+            r.buf.push(wire[wire.len() - 1]);
+            let f = r.parse().ok().unwrap().unwrap();
+            assert!(f.fin && !f.compressed);
+            assert_eq!(f.opcode, Opcode::Binary);
+            assert_eq!(f.payload, payload);
+            assert!(r.buf.is_empty());
+        }
+
+        // the extended length itself can arrive in pieces
+        let wire = frame(0x81, &[b'a'; 300], false);
+        let mut r = reader(false, None);
+        r.buf.extend_from_slice(&wire[..3]);
+        assert!(r.parse().ok().unwrap().is_none());
+        r.buf.extend_from_slice(&wire[3..]);
+        assert_eq!(r.parse().ok().unwrap().unwrap().payload, [b'a'; 300]);
+    }
+
+    #[test]
+    fn masking_must_match_the_side() {
+        let mut r = reader(true, None);
+        r.buf = frame(0x82, b"x", false);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+        let mut r = reader(false, None);
+        r.buf = frame(0x82, b"x", true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn fragments_join_around_control_frames() {
+        let mut r = reader(true, None);
+        r.buf.extend(frame(0x01, b"hel", true));
+        r.buf.extend(frame(0x89, b"ping", true));
+        r.buf.extend(frame(0x00, b"lo ", true));
+        r.buf.extend(frame(0x80, b"world", true));
+        let ping = next(&mut r).ok().unwrap().unwrap();
+        assert_eq!(ping.opcode, Opcode::Ping);
+        assert_eq!(ping.data, b"ping");
+        let text = next(&mut r).ok().unwrap().unwrap();
+        assert_eq!(text.opcode, Opcode::Text);
+        assert_eq!(text.data, b"hello world");
+        assert!(next(&mut r).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn fragmentation_errors() {
+        let cases: [&[(u8, &[u8])]; 4] = [
+            // continuation with nothing to continue
+            &[(0x80, b"x")],
+            // a new message while one is still open
+            &[(0x01, b"a"), (0x81, b"b")],
+            // a fragmented control frame
+            &[(0x09, b"p")],
+            // an unknown opcode
+            &[(0x83, b"")],
+        ];
+        for frames in cases {
+            let mut r = reader(true, None);
+            for (b0, payload) in frames {
+                r.buf.extend(frame(*b0, payload, true));
+            }
+            assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+        }
+
+        let mut r = reader(true, None);
+        r.buf = frame(0x89, &[0; 126], true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+        let mut r = reader(true, None);
+        r.buf = frame(0x81, &[0xFF, 0xFE], true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_INVALID_DATA);
+        let mut r = Reader::new(
+            &[][..],
+            frame(0x82, &[0; 11], true),
+            true,
+            &WsOptions {
+                max_message_size: Some(10),
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn rsv1_only_on_the_first_frame_of_a_deflated_message() {
+        let deflate = Some(DeflateParams::default());
+        let mut r = reader(true, deflate);
+        r.buf.extend(frame(0x41, b"", true));
+        r.buf.extend(frame(0xC0, b"", true));
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+
+        let mut r = reader(true, deflate);
+        r.buf = frame(0xC9, b"", true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+
+        // without the extension RSV1 is never allowed
+        let mut r = reader(true, None);
+        r.buf = frame(0xC1, b"", true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+    }
+
+    /// Sends `messages` from a client writer to a server reader and returns the frame sizes.
+    async fn deflate_round_trip(params: DeflateParams, messages: &[&[u8]]) -> Vec<usize> {
+        let mut w = Writer::new(Vec::new(), false, Some(params));
+        let mut sizes = Vec::new();
+        for m in messages {
+            sizes.push(w.send(Opcode::Text, m).await.unwrap());
+        }
+        let wire: &'static [u8] = Box::leak(w.inner.into_boxed_slice());
+        let mut r = Reader::new(wire, Vec::new(), true, &WsOptions::default(), Some(params));
+        for m in messages {
+            match r.next().await {
+                Ok(message) => {
+                    assert_eq!(message.opcode, Opcode::Text);
+                    assert_eq!(&message.data, m);
+                }
+                Err(_) => panic!("reading a deflated message failed"),
+            }
+        }
+        assert!(matches!(r.next().await, Err(ReadError::Eof)));
+        sizes
+    }
+
+    #[tokio::test]
+    async fn deflate_round_trip_with_and_without_context_takeover() {
+        let text = b"the quick brown fox jumps over the lazy dog";
+        let big = text.repeat(2000);
+        let messages: [&[u8]; 4] = [text, text, &big, b""];
+
+        let sizes = deflate_round_trip(DeflateParams::default(), &messages).await;
+        // the second copy refers back into the window the first one left
+        assert!(sizes[1] < sizes[0]);
+        assert!(sizes[2] < big.len() / 10);
+
+        let sizes = deflate_round_trip(
+            DeflateParams {
+                server_no_context_takeover: true,
+                client_no_context_takeover: true,
+            },
+            &messages,
+        )
+        .await;
+        assert_eq!(sizes[1], sizes[0]);
+
+        // a reader that keeps its window still follows a writer that resets its own
+        let sizes = deflate_round_trip(
+            DeflateParams {
+                server_no_context_takeover: false,
+                client_no_context_takeover: true,
+            },
+            &messages,
+        )
+        .await;
+        assert_eq!(sizes[1], sizes[0]);
+    }
+
+    #[tokio::test]
+    async fn close_frame_carries_code_and_reason() {
+        let mut w = Writer::new(Vec::new(), true, None);
+        w.close(CLOSE_NORMAL, &"é".repeat(70)).await.unwrap();
+        assert!(w.close_sent());
+        assert!(w.send(Opcode::Text, b"late").await.is_err());
+        let wire = std::mem::take(&mut w.inner);
+        // 2 bytes of code and 61 two-byte characters: the reason stops before splitting one
+        assert_eq!(wire[1] as usize, 124);
+        let (code, reason) = close_reason(&wire[2..]);
+        assert_eq!(code, Some(CLOSE_NORMAL));
+        assert_eq!(reason, "é".repeat(61));
+        assert_eq!(close_reason(&[]), (None, String::new()));
+    }
+
+    #[test]
+    fn close_codes_and_reasons_are_checked() {
+        for code in [1000u16, 1003, 1007, 1011, 1014, 3000, 4999] {
+            let mut r = reader(true, None);
+            r.buf = frame(0x88, &code.to_be_bytes(), true);
+            assert_eq!(next(&mut r).ok().unwrap().unwrap().opcode, Opcode::Close);
+        }
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, 65535] {
+            let mut r = reader(true, None);
+            r.buf = frame(0x88, &code.to_be_bytes(), true);
+            assert_eq!(
+                protocol_code(next(&mut r)),
+                CLOSE_PROTOCOL_ERROR,
+                "{}",
+                code
+            );
+        }
+        let mut r = reader(true, None);
+        r.buf = frame(0x88, &[0x03], true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_PROTOCOL_ERROR);
+        let mut r = reader(true, None);
+        r.buf = frame(0x88, &[0x03, 0xE8, 0xC3, 0x28], true);
+        assert_eq!(protocol_code(next(&mut r)), CLOSE_INVALID_DATA);
+        let mut r = reader(true, None);
+        r.buf = frame(0x88, b"", true);
+        assert!(next(&mut r).ok().unwrap().unwrap().data.is_empty());
+    }
+
+    fn url(path: &str) -> Url {
+        Url {
+            tls: false,
+            addr: "127.0.0.1:80".into(),
+            host: "example.com".into(),
+            path: path.into(),
+        }
+    }
+
+    fn head_of(bytes: &[u8]) -> &[u8] {
+        let end = bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        &bytes[..end + 4]
+    }
+
+    #[test]
+    fn handshake_negotiates_subprotocol_and_deflate() {
+        let client = WsOptions {
+            subprotocols: vec!["mqtt".into(), "chat".into()],
+            deflate: true,
+            ..Default::default()
+        };
+        let server = WsOptions {
+            subprotocols: vec!["chat".into()],
+            deflate: true,
+            headers: HashMap::from([("X-Test".to_string(), "1".to_string())]),
+            ..Default::default()
+        };
+        let (req, key) = request(&url("/feed?x=1"), &client);
+        let accepted = accept(&req, &server).ok().unwrap();
+        assert_eq!(accepted.path, "/feed?x=1");
+        assert_eq!(accepted.negotiated.subprotocol.as_deref(), Some("chat"));
+        assert!(accepted.negotiated.deflate.is_some());
+        let negotiated = check_response(head_of(&accepted.response), &key, &client).unwrap();
+        assert_eq!(negotiated.subprotocol.as_deref(), Some("chat"));
+        let d = negotiated.deflate.unwrap();
+        assert!(!d.server_no_context_takeover && !d.client_no_context_takeover);
+        assert_eq!(
+            negotiated.headers.get("x-test").map(String::as_str),
+            Some("1")
+        );
+
+        // a server without deflate or the subprotocols answers plainly
+        let accepted = accept(&req, &WsOptions::default()).ok().unwrap();
+        let negotiated = check_response(&accepted.response, &key, &client).unwrap();
+        assert!(negotiated.subprotocol.is_none() && negotiated.deflate.is_none());
+
+        // the answer must be for this key
+        assert!(check_response(&accepted.response, "AAAAAAAAAAAAAAAAAAAAAA==", &client).is_err());
+    }
+
+    #[test]
+    fn server_picks_an_acceptable_deflate_offer() {
+        let offer = |ext: &str| {
+            format!(
+                "GET / HTTP/1.1\r\nHost: h\r\nUpgrade: websocket\r\n\
+                 Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Extensions: {}\r\n\r\n",
+                ext
+            )
+        };
+        let server = WsOptions {
+            deflate: true,
+            ..Default::default()
+        };
+        let accepted = accept(
+            offer(
+                "permessage-deflate; server_max_window_bits=10, \
+                 permessage-deflate; client_no_context_takeover; client_max_window_bits",
+            )
+            .as_bytes(),
+            &server,
+        )
+        .ok()
+        .unwrap();
+        let d = accepted.negotiated.deflate.unwrap();
+        assert!(d.client_no_context_takeover && !d.server_no_context_takeover);
+        let response = String::from_utf8(accepted.response).unwrap();
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains(
+            "Sec-WebSocket-Extensions: permessage-deflate; client_no_context_takeover\r\n"
+        ));
+
+        let accepted = accept(offer("permessage-deflate; mystery").as_bytes(), &server)
+            .ok()
+            .unwrap();
+        assert!(accepted.negotiated.deflate.is_none());
+    }
+
+    #[test]
+    fn bad_upgrade_requests_are_refused() {
+        let good = "GET / HTTP/1.1\r\nHost: h\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                    Sec-WebSocket-Version: 13\r\n\r\n";
+        assert!(accept(good.as_bytes(), &WsOptions::default()).is_ok());
+        for (from, to, status) in [
+            ("GET", "POST", "405"),
+            ("Upgrade: websocket", "Upgrade: h2c", "426"),
+            ("Version: 13", "Version: 8", "426"),
+            ("dGhlIHNhbXBsZSBub25jZQ==", "c2hvcnQ=", "400"),
+        ] {
+            let (_, response) =
+                accept(good.replacen(from, to, 1).as_bytes(), &WsOptions::default())
+                    .err()
+                    .unwrap();
+            assert!(response.starts_with(format!("HTTP/1.1 {}", status).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn client_rejects_what_it_did_not_offer() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let response = |extra: &str| {
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n{}\r\n",
+                extra
+            )
+        };
+        let plain = WsOptions::default();
+        let offering = WsOptions {
+            subprotocols: vec!["chat".into()],
+            deflate: true,
+            ..Default::default()
+        };
+        assert!(check_response(response("").as_bytes(), key, &plain).is_ok());
+        for extra in [
+            "Sec-WebSocket-Protocol: chat\r\n",
+            "Sec-WebSocket-Extensions: permessage-deflate\r\n",
+        ] {
+            assert!(check_response(response(extra).as_bytes(), key, &plain).is_err());
+            assert!(check_response(response(extra).as_bytes(), key, &offering).is_ok());
+        }
+        for extra in [
+            "Sec-WebSocket-Protocol: other\r\n",
+            "Sec-WebSocket-Extensions: x-webkit-deflate-frame\r\n",
+            "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=9\r\n",
+        ] {
+            assert!(check_response(response(extra).as_bytes(), key, &offering).is_err());
+        }
+        let d = check_response(
+            response(
+                "Sec-WebSocket-Extensions: permessage-deflate; \
+                 server_no_context_takeover; server_max_window_bits=12\r\n",
+            )
+            .as_bytes(),
+            key,
+            &offering,
+        )
+        .unwrap()
+        .deflate
+        .unwrap();
+        assert!(d.server_no_context_takeover && !d.client_no_context_takeover);
+        let refused = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+        assert!(check_response(refused.as_bytes(), key, &plain).is_err());
+    }
+}
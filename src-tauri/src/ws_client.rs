@@ -0,0 +1,356 @@
+use base64::Engine;
+use serde_json::json;
+use socket2::Socket;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::async_runtime::{self, JoinHandle};
+use tauri::{AppHandle, Emitter};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{NetError, NetResult};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Sent, Session, SessionHandle, SessionKind, Started, Stopped};
+use crate::sockopt::SocketOptions;
+use crate::tcp_client::{self, DEFAULT_CONNECT_TIMEOUT_MS};
+use crate::template::Payload;
+use crate::tls::{self, BoxedStream, TlsClientConfig};
+use crate::ws::{self, Message, Opcode, ReadError, WsOptions};
+
+type Writer = ws::Writer<WriteHalf<BoxedStream>>;
+
+/// State shared between the session handle and its read task.
+struct Shared {
+    writer: tokio::sync::Mutex<Writer>,
+    /// Duplicate of the connected socket for the options commands.
+    sock: Mutex<Option<Socket>>,
+    local: String,
+    seq: AtomicU64,
+}
+
+pub struct ClientHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+
+    pub fn sockets(&self) -> Vec<(String, Socket)> {
+        let sock = match self.shared.sock.lock() {
+            Ok(s) => s.as_ref().and_then(|s| s.try_clone().ok()),
+            Err(_) => None,
+        };
+        let label = sock
+            .as_ref()
+            .and_then(|s| s.peer_addr().ok())
+            .and_then(|a| a.as_socket())
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        sock.map(|s| vec![(label, s)]).unwrap_or_default()
+    }
+}
+
+/// Connects and completes the upgrade before the session is registered; `url` is the session's
+/// address. A dropped or closed connection ends the session.
+pub async fn start(
+    app: AppHandle,
+    url: String,
+    connect_timeout_ms: Option<u64>,
+    tls: Option<TlsClientConfig>,
+    ws: Option<WsOptions>,
+    socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+    if session::registry()?.contains(SessionKind::WsClient, &url) {
+        return Err(NetError::already_running(SessionKind::WsClient, &url));
+    }
+    let target = ws::parse_url(&url)?;
+    let tls = match (target.tls, tls) {
+        (true, cfg) => Some(tls::client(&cfg.unwrap_or_default(), &target.addr)?),
+        (false, None) => None,
+        (false, Some(_)) => {
+            return Err(NetError::websocket("TLS options need a wss:// URL".into()))
+        }
+    };
+    let ws = ws.unwrap_or_default();
+    let timeout_ms = connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+
+    let (stream, sock, local, tls_info) = tcp_client::connect(
+        &target.addr,
+        timeout_ms,
+        tls.as_ref(),
+        &socket_options.unwrap_or_default(),
+        &CancellationToken::new(),
+    )
+    .await?;
+    let upgrade = tokio::time::timeout(
+        Duration::from_millis(timeout_ms),
+        handshake(stream, &target, &ws),
+    );
+    let (stream, leftover, negotiated) = upgrade.await.unwrap_or_else(|_| {
+        Err(NetError::io(
+            "websocket handshake",
+            &url,
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {} ms", timeout_ms),
+            ),
+        ))
+    })?;
+
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = ws::Reader::new(reader, leftover, false, &ws, negotiated.deflate);
+    let shared = Arc::new(Shared {
+        writer: tokio::sync::Mutex::new(ws::Writer::new(writer, false, negotiated.deflate)),
+        sock: Mutex::new(sock),
+        local: local.clone(),
+        seq: AtomicU64::new(0),
+    });
+
+    let cancel = CancellationToken::new();
+    let id = session::next_id();
+    let (registered_tx, registered_rx) = oneshot::channel::<()>();
+    let task = async_runtime::spawn(run(
+        app.clone(),
+        id.clone(),
+        url.clone(),
+        reader,
+        shared.clone(),
+        cancel.clone(),
+        registered_rx,
+    ));
+    let handle = ClientHandle {
+        cancel,
+        task,
+        shared,
+    };
+    let rejected = session::registry()?.insert(
+        id.clone(),
+        SessionKind::WsClient,
+        url.clone(),
+        SessionHandle::WsClient(handle),
+    );
+    if let Err(h) = rejected {
+        h.stop().await;
+        return Err(NetError::already_running(SessionKind::WsClient, &url));
+    }
+    let payload = json!({
+        "session": id,
+        "remote": url,
+        "local": local,
+        "subprotocol": negotiated.subprotocol,
+        "deflate": negotiated.deflate,
+        "headers": negotiated.headers,
+        "tls": tls_info,
+    });
+    let _ = app.emit("ws:client:connected", payload);
+    let _ = registered_tx.send(());
+
+    Ok(Started {
+        session_id: id,
+        kind: SessionKind::WsClient,
+        message: format!("WebSocket client connected to {}", url),
+        addr: url,
+    })
+}
+
+/// Sends the upgrade request over a connected stream and checks the answer; returns the stream
+/// with any bytes read past the response head.
+pub async fn handshake(
+    mut stream: BoxedStream,
+    target: &ws::Url,
+    options: &WsOptions,
+) -> NetResult<(BoxedStream, Vec<u8>, ws::Negotiated)> {
+    let (request, key) = ws::request(target, options);
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|e| NetError::io("websocket handshake", &target.addr, e))?;
+    let (head, leftover) = ws::read_head(&mut stream)
+        .await
+        .map_err(|e| NetError::io("websocket handshake", &target.addr, e))?;
+    let negotiated = ws::check_response(&head, &key, options)?;
+    Ok((stream, leftover, negotiated))
+}
+
+async fn run(
+    app: AppHandle,
+    sid: String,
+    url: String,
+    mut reader: ws::Reader<ReadHalf<BoxedStream>>,
+    shared: Arc<Shared>,
+    cancel: CancellationToken,
+    registered: oneshot::Receiver<()>,
+) {
+    // the session must be in the registry before this task can remove it
+    if registered.await.is_err() {
+        return;
+    }
+    let (code, reason) = loop {
+        let read = tokio::select! {
+            _ = cancel.cancelled() => {
+                let mut w = shared.writer.lock().await;
+                let _ = w.close(ws::CLOSE_NORMAL, "").await;
+                let _ = w.shutdown().await;
+                return;
+            }
+            r = reader.next() => r,
+        };
+        match read {
+            Ok(Message {
+                opcode: Opcode::Close,
+                data,
+            }) => {
+                let (code, reason) = ws::close_reason(&data);
+                let mut w = shared.writer.lock().await;
+                if !w.close_sent() {
+                    let _ = w.close(code.unwrap_or(ws::CLOSE_NORMAL), "").await;
+                }
+                break (code, reason);
+            }
+            Ok(message) => {
+                emit_message(&app, &sid, &url, &shared, &message);
+                if message.opcode == Opcode::Ping {
+                    let _ = shared
+                        .writer
+                        .lock()
+                        .await
+                        .send(Opcode::Pong, &message.data)
+                        .await;
+                }
+            }
+            Err(ReadError::Eof) => break (None, "connection closed".to_string()),
+            Err(ReadError::Io(e)) => {
+                let payload =
+                    json!({"session": sid, "remote": url, "error": format!("read error: {}", e)});
+                let _ = app.emit("ws:client:error", payload);
+                break (None, e.to_string());
+            }
+            Err(ReadError::Protocol { code, reason }) => {
+                let payload = json!({"session": sid, "remote": url, "error": format!("protocol error: {}", reason)});
+                let _ = app.emit("ws:client:error", payload);
+                let _ = shared.writer.lock().await.close(code, &reason).await;
+                break (Some(code), reason);
+            }
+        }
+    };
+
+    let _ = shared.writer.lock().await.shutdown().await;
+    let payload = json!({"session": sid, "remote": url, "code": code, "reason": reason});
+    let _ = app.emit("ws:client:closed", payload);
+    // nothing will revive this connection, so don't leave a dead session behind
+    session::drop_dead(&sid);
+}
+
+fn emit_message(app: &AppHandle, sid: &str, url: &str, shared: &Shared, message: &Message) {
+    let seq = shared.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    let ts_ms = session::now_ms();
+    let payload = json!({
+        "session": sid,
+        "remote": url,
+        "data": base64::engine::general_purpose::STANDARD.encode(&message.data),
+        "seq": seq,
+        "ts_ms": ts_ms,
+        "opcode": message.opcode,
+    });
+    let _ = app.emit("ws:client:message", payload);
+    recorder::record(
+        sid,
+        Direction::Rx,
+        &shared.local,
+        url,
+        seq,
+        ts_ms,
+        &message.data,
+    );
+}
+
+pub async fn stop(url: Option<String>) -> NetResult<Stopped> {
+    if let Some(u) = url {
+        let removed = session::registry()?.remove(SessionKind::WsClient, &u);
+        let s = removed.ok_or_else(|| NetError::not_running(SessionKind::WsClient, &u))?;
+        let info = s.stop().await;
+        Ok(Stopped {
+            session_ids: vec![info.id],
+            message: format!("WebSocket client disconnected from {}", u),
+        })
+    } else {
+        let previous = session::registry()?.remove_kind(SessionKind::WsClient);
+        Ok(Stopped {
+            session_ids: session::stop_all(previous).await,
+            message: "All WebSocket clients disconnected".into(),
+        })
+    }
+}
+
+/// See [`ws::outgoing`] for the default opcode and the appended checksum.
+pub async fn send(url: String, payload: Payload, opcode: Option<Opcode>) -> NetResult<Sent> {
+    let data = payload.bytes()?;
+    let (sid, shared) = running(&url)?;
+    let (opcode, data) = ws::outgoing(&sid, opcode, data)?;
+    shared
+        .writer
+        .lock()
+        .await
+        .send(opcode, &data)
+        .await
+        .map_err(|e| NetError::io("send", &url, e))?;
+    recorder::record(
+        &sid,
+        Direction::Tx,
+        &shared.local,
+        &url,
+        0,
+        session::now_ms(),
+        &data,
+    );
+    Ok(Sent {
+        session_id: Some(sid),
+        bytes: data.len(),
+        peers: 1,
+        message: format!("sent {} bytes to {} as {:?}", data.len(), url, opcode),
+    })
+}
+
+/// Starts the close handshake; the session ends when the server answers.
+pub async fn close(url: String, code: Option<u16>, reason: Option<String>) -> NetResult<Sent> {
+    let code = code.unwrap_or(ws::CLOSE_NORMAL);
+    if !ws::valid_close_code(code) {
+        return Err(NetError::websocket(format!(
+            "{} is not a close code that may be sent",
+            code
+        )));
+    }
+    let (sid, shared) = running(&url)?;
+    shared
+        .writer
+        .lock()
+        .await
+        .close(code, reason.as_deref().unwrap_or_default())
+        .await
+        .map_err(|e| NetError::io("close", &url, e))?;
+    Ok(Sent {
+        session_id: Some(sid),
+        bytes: 0,
+        peers: 1,
+        message: format!("sent close {} to {}", code, url),
+    })
+}
+
+fn running(url: &str) -> NetResult<(String, Arc<Shared>)> {
+    let reg = session::registry()?;
+    match reg.get(SessionKind::WsClient, url) {
+        Some(Session {
+            info,
+            handle: SessionHandle::WsClient(h),
+        }) => Ok((info.id.clone(), h.shared.clone())),
+        _ => Err(NetError::not_running(SessionKind::WsClient, url)),
+    }
+}
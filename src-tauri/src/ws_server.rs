@@ -0,0 +1,488 @@
+use base64::Engine;
+use serde_json::{json, Value};
+use socket2::{SockRef, Socket};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::async_runtime::{self, JoinHandle};
+use tauri::{AppHandle, Emitter};
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::error::{NetError, NetResult};
+use crate::recorder::{self, Direction};
+use crate::session::{self, Sent, Session, SessionHandle, SessionKind, Started, Stopped};
+use crate::sockopt::{self, SocketOptions};
+use crate::template::Payload;
+use crate::tls::{self, BoxedStream, TlsServerConfig};
+use crate::ws::{self, Message, Opcode, ReadError, WsOptions};
+
+/// Covers the TLS and the WebSocket handshake; a client that is slower is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Sent to every client when the server stops.
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+type PeerWriter = Arc<tokio::sync::Mutex<ws::Writer<WriteHalf<BoxedStream>>>>;
+/// A client to send to: its address, our end of the connection and its writer.
+type Target = (String, String, PeerWriter);
+
+struct Peer {
+    writer: PeerWriter,
+    /// Our end of the connection, for the recorder; the bind address may be a wildcard.
+    local: String,
+    /// Duplicate of the peer's socket for the options commands; dropped together with `writer`.
+    sock: Option<Socket>,
+}
+
+pub struct ServerHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+    shared: Arc<Shared>,
+    listener: Option<Socket>,
+}
+
+impl ServerHandle {
+    pub async fn stop(self) {
+        self.cancel.cancel();
+        let _ = self.task.await;
+    }
+
+    pub fn sockets(&self) -> Vec<(String, Socket)> {
+        let mut out = Vec::new();
+        if let Some(s) = self.listener.as_ref().and_then(|l| l.try_clone().ok()) {
+            out.push(("listener".to_string(), s));
+        }
+        if let Ok(cg) = self.shared.clients.lock() {
+            for (addr, peer) in cg.iter() {
+                if let Some(s) = peer.sock.as_ref().and_then(|s| s.try_clone().ok()) {
+                    out.push((addr.clone(), s));
+                }
+            }
+        }
+        out
+    }
+
+    pub fn remember_socket_options(&self, options: &SocketOptions) {
+        if let Ok(mut o) = self.shared.options.lock() {
+            o.merge(options);
+        }
+    }
+}
+
+/// State shared by the accept loop and every peer task of one server.
+struct Shared {
+    app: AppHandle,
+    sid: String,
+    addr: String,
+    clients: Mutex<HashMap<String, Peer>>,
+    seq: AtomicU64,
+    acceptor: Option<TlsAcceptor>,
+    ws: WsOptions,
+    /// Applied to every accepted peer; `set_socket_options` adds to it.
+    options: Mutex<SocketOptions>,
+}
+
+impl Shared {
+    fn error(&self, peer: Option<&str>, error: String) {
+        let payload = json!({"session": self.sid, "bind": self.addr, "peer": peer, "error": error});
+        let _ = self.app.emit("ws:server:error", payload);
+    }
+}
+
+pub async fn start(
+    app: AppHandle,
+    bind_addr: String,
+    tls: Option<TlsServerConfig>,
+    ws: Option<WsOptions>,
+    socket_options: Option<SocketOptions>,
+) -> NetResult<Started> {
+    if session::registry()?.contains(SessionKind::WsServer, &bind_addr) {
+        return Err(NetError::already_running(SessionKind::WsServer, &bind_addr));
+    }
+    let acceptor = tls.as_ref().map(tls::server).transpose()?;
+
+    let socket_options = socket_options.unwrap_or_default();
+    let listener = sockopt::bind_tcp(&bind_addr, &socket_options)
+        .await
+        .map_err(|e| NetError::io("bind", &bind_addr, e))?;
+    let listener_sock = sockopt::dup(&listener).ok();
+
+    let cancel = CancellationToken::new();
+    let id = session::next_id();
+    let shared = Arc::new(Shared {
+        app,
+        sid: id.clone(),
+        addr: bind_addr.clone(),
+        clients: Mutex::new(HashMap::new()),
+        seq: AtomicU64::new(0),
+        acceptor,
+        ws: ws.unwrap_or_default(),
+        options: Mutex::new(socket_options),
+    });
+    let task = async_runtime::spawn(accept_loop(shared.clone(), listener, cancel.clone()));
+
+    let handle = ServerHandle {
+        cancel,
+        task,
+        shared,
+        listener: listener_sock,
+    };
+    let rejected = session::registry()?.insert(
+        id.clone(),
+        SessionKind::WsServer,
+        bind_addr.clone(),
+        SessionHandle::WsServer(handle),
+    );
+    if let Err(h) = rejected {
+        h.stop().await;
+        return Err(NetError::already_running(SessionKind::WsServer, &bind_addr));
+    }
+
+    Ok(Started {
+        session_id: id,
+        kind: SessionKind::WsServer,
+        message: format!("WebSocket server started on {}", bind_addr),
+        addr: bind_addr,
+    })
+}
+
+async fn accept_loop(shared: Arc<Shared>, listener: TcpListener, cancel: CancellationToken) {
+    let tracker = TaskTracker::new();
+    loop {
+        let accepted = tokio::select! {
+            _ = cancel.cancelled() => break,
+            r = listener.accept() => r,
+        };
+        match accepted {
+            Ok((stream, peer_addr)) => {
+                let _ = stream.set_nodelay(true);
+                let options = shared.options.lock().map(|o| o.clone()).unwrap_or_default();
+                if let Err(e) = sockopt::apply(&SockRef::from(&stream), &options) {
+                    shared.error(
+                        Some(&peer_addr.to_string()),
+                        format!("socket options error: {}", e),
+                    );
+                }
+                async_runtime::spawn(tracker.track_future(serve_peer(
+                    shared.clone(),
+                    stream,
+                    peer_addr.to_string(),
+                    cancel.child_token(),
+                )));
+            }
+            Err(e) => shared.error(None, format!("accept error: {}", e)),
+        }
+    }
+
+    drop(listener);
+    tracker.close();
+    tracker.wait().await;
+    if let Ok(mut cg) = shared.clients.lock() {
+        cg.clear();
+    }
+}
+
+async fn serve_peer(shared: Arc<Shared>, tcp: TcpStream, peer: String, cancel: CancellationToken) {
+    let sock = sockopt::dup(&tcp).ok();
+    let local = tcp
+        .local_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| shared.addr.clone());
+    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&shared, tcp));
+    let result = tokio::select! {
+        _ = cancel.cancelled() => return,
+        r = handshake => r,
+    };
+    let (stream, leftover, accepted, tls_info) = match result {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => {
+            shared.error(Some(&peer), e);
+            return;
+        }
+        Err(_) => {
+            shared.error(Some(&peer), "handshake timed out".into());
+            return;
+        }
+    };
+
+    let deflate = accepted.negotiated.deflate;
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = ws::Reader::new(reader, leftover, true, &shared.ws, deflate);
+    let writer: PeerWriter = Arc::new(tokio::sync::Mutex::new(ws::Writer::new(
+        writer, true, deflate,
+    )));
+    if let Ok(mut cg) = shared.clients.lock() {
+        cg.insert(
+            peer.clone(),
+            Peer {
+                writer: writer.clone(),
+                local: local.clone(),
+                sock,
+            },
+        );
+    }
+    let payload = json!({
+        "session": shared.sid,
+        "bind": shared.addr,
+        "peer": peer,
+        "path": accepted.path,
+        "subprotocol": accepted.negotiated.subprotocol,
+        "deflate": accepted.negotiated.deflate,
+        "headers": accepted.negotiated.headers,
+        "tls": tls_info,
+    });
+    let _ = shared.app.emit("ws:server:client_connected", payload);
+
+    let (code, reason) = read_peer(&shared, &local, &peer, reader, &writer, &cancel).await;
+    let _ = writer.lock().await.shutdown().await;
+    if let Ok(mut cg) = shared.clients.lock() {
+        cg.remove(&peer);
+    }
+    let payload = json!({"session": shared.sid, "bind": shared.addr, "peer": peer, "code": code, "reason": reason});
+    let _ = shared.app.emit("ws:server:client_disconnected", payload);
+}
+
+/// TLS (when configured) and the HTTP upgrade. Returns the stream, bytes that arrived after the
+/// request head, the negotiated settings and the TLS parameters.
+async fn handshake(
+    shared: &Shared,
+    tcp: TcpStream,
+) -> Result<(BoxedStream, Vec<u8>, ws::Accepted, Value), String> {
+    let (mut stream, tls_info): (BoxedStream, Value) = match &shared.acceptor {
+        None => (Box::new(tcp), Value::Null),
+        Some(acceptor) => {
+            let s = acceptor
+                .accept(tcp)
+                .await
+                .map_err(|e| format!("tls handshake error: {}", e))?;
+            let info = tls::describe(s.get_ref().1);
+            (Box::new(s), info)
+        }
+    };
+    let (head, leftover) = ws::read_head(&mut stream)
+        .await
+        .map_err(|e| format!("handshake read error: {}", e))?;
+    match ws::accept(&head, &shared.ws) {
+        Ok(accepted) => {
+            stream
+                .write_all(&accepted.response)
+                .await
+                .map_err(|e| format!("handshake write error: {}", e))?;
+            Ok((stream, leftover, accepted, tls_info))
+        }
+        Err((message, response)) => {
+            let _ = stream.write_all(&response).await;
+            let _ = stream.shutdown().await;
+            Err(format!("handshake rejected: {}", message))
+        }
+    }
+}
+
+/// Runs until the close handshake, a protocol error or a dropped connection. Returns the close
+/// code and reason, if any.
+async fn read_peer(
+    shared: &Shared,
+    local: &str,
+    peer: &str,
+    mut reader: ws::Reader<ReadHalf<BoxedStream>>,
+    writer: &PeerWriter,
+    cancel: &CancellationToken,
+) -> (Option<u16>, String) {
+    loop {
+        let read = tokio::select! {
+            _ = cancel.cancelled() => {
+                let _ = writer.lock().await.close(CLOSE_GOING_AWAY, "server stopping").await;
+                return (Some(CLOSE_GOING_AWAY), "server stopping".into());
+            }
+            r = reader.next() => r,
+        };
+        match read {
+            Ok(Message {
+                opcode: Opcode::Close,
+                data,
+            }) => {
+                let (code, reason) = ws::close_reason(&data);
+                let mut w = writer.lock().await;
+                if !w.close_sent() {
+                    // echo the peer's code to complete the close handshake; the reader has
+                    // already refused invalid ones
+                    let _ = w.close(code.unwrap_or(ws::CLOSE_NORMAL), "").await;
+                }
+                return (code, reason);
+            }
+            Ok(message) => {
+                emit_message(shared, local, peer, &message);
+                if message.opcode == Opcode::Ping {
+                    let _ = writer.lock().await.send(Opcode::Pong, &message.data).await;
+                }
+            }
+            Err(ReadError::Eof) => return (None, "connection closed".into()),
+            Err(ReadError::Io(e)) => {
+                shared.error(Some(peer), format!("read error: {}", e));
+                return (None, e.to_string());
+            }
+            Err(ReadError::Protocol { code, reason }) => {
+                shared.error(Some(peer), format!("protocol error: {}", reason));
+                let _ = writer.lock().await.close(code, &reason).await;
+                return (Some(code), reason);
+            }
+        }
+    }
+}
+
+fn emit_message(shared: &Shared, local: &str, peer: &str, message: &Message) {
+    let seq = shared.seq.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    let ts_ms = session::now_ms();
+    let payload = json!({
+        "session": shared.sid,
+        "bind": shared.addr,
+        "from": peer,
+        "data": base64::engine::general_purpose::STANDARD.encode(&message.data),
+        "seq": seq,
+        "ts_ms": ts_ms,
+        "opcode": message.opcode,
+    });
+    let _ = shared.app.emit("ws:server:message", payload);
+    recorder::record(
+        &shared.sid,
+        Direction::Rx,
+        local,
+        peer,
+        seq,
+        ts_ms,
+        &message.data,
+    );
+}
+
+pub async fn stop(bind_addr: Option<String>) -> NetResult<Stopped> {
+    if let Some(b) = bind_addr {
+        let removed = session::registry()?.remove(SessionKind::WsServer, &b);
+        let s = removed.ok_or_else(|| NetError::not_running(SessionKind::WsServer, &b))?;
+        let info = s.stop().await;
+        Ok(Stopped {
+            session_ids: vec![info.id],
+            message: format!("WebSocket server stopped on {}", b),
+        })
+    } else {
+        let previous = session::registry()?.remove_kind(SessionKind::WsServer);
+        Ok(Stopped {
+            session_ids: session::stop_all(previous).await,
+            message: "All WebSocket servers stopped".into(),
+        })
+    }
+}
+
+/// Sends one message to `to_peer`, or to every client when it is `None`. See
+/// [`ws::outgoing`] for the default opcode and the appended checksum.
+pub async fn send(
+    bind_addr: String,
+    to_peer: Option<String>,
+    payload: Payload,
+    opcode: Option<Opcode>,
+) -> NetResult<Sent> {
+    let data = payload.bytes()?;
+    let (sid, targets) = targets(&bind_addr, to_peer.as_deref())?;
+    let (opcode, data) = ws::outgoing(&sid, opcode, data)?;
+
+    let mut sent = 0usize;
+    let mut last_error = None;
+    for (peer, local, writer) in &targets {
+        match writer.lock().await.send(opcode, &data).await {
+            Ok(_) => {
+                recorder::record(
+                    &sid,
+                    Direction::Tx,
+                    local,
+                    peer,
+                    0,
+                    session::now_ms(),
+                    &data,
+                );
+                sent += 1;
+            }
+            Err(e) => last_error = Some(NetError::io("send", peer, e)),
+        }
+    }
+    match (to_peer, last_error) {
+        (Some(_), Some(e)) => Err(e),
+        (Some(peer), None) => Ok(Sent {
+            session_id: Some(sid),
+            bytes: data.len(),
+            peers: sent,
+            message: format!("sent {} bytes to {} as {:?}", data.len(), peer, opcode),
+        }),
+        (None, _) => Ok(Sent {
+            session_id: Some(sid),
+            bytes: data.len(),
+            peers: sent,
+            message: format!("broadcast {} bytes to {} client(s)", data.len(), sent),
+        }),
+    }
+}
+
+/// Starts the close handshake with `to_peer`, or with every client; the connection ends when the
+/// client answers.
+pub async fn close(
+    bind_addr: String,
+    to_peer: Option<String>,
+    code: Option<u16>,
+    reason: Option<String>,
+) -> NetResult<Sent> {
+    let code = code.unwrap_or(ws::CLOSE_NORMAL);
+    if !ws::valid_close_code(code) {
+        return Err(NetError::websocket(format!(
+            "{} is not a close code that may be sent",
+            code
+        )));
+    }
+    let reason = reason.unwrap_or_default();
+    let (sid, targets) = targets(&bind_addr, to_peer.as_deref())?;
+    let mut closed = 0usize;
+    for (peer, _, writer) in &targets {
+        match writer.lock().await.close(code, &reason).await {
+            Ok(_) => closed += 1,
+            Err(e) if to_peer.is_some() => return Err(NetError::io("close", peer, e)),
+            Err(_) => {}
+        }
+    }
+    Ok(Sent {
+        session_id: Some(sid),
+        bytes: 0,
+        peers: closed,
+        message: format!("sent close {} to {} client(s)", code, closed),
+    })
+}
+
+/// Snapshots the writers so no std lock is held across the awaits of a send.
+fn targets(bind_addr: &str, to_peer: Option<&str>) -> NetResult<(String, Vec<Target>)> {
+    let reg = session::registry()?;
+    let (sid, shared) = match reg.get(SessionKind::WsServer, bind_addr) {
+        Some(Session {
+            info,
+            handle: SessionHandle::WsServer(h),
+        }) => (info.id.clone(), h.shared.clone()),
+        _ => return Err(NetError::not_running(SessionKind::WsServer, bind_addr)),
+    };
+    drop(reg);
+    let cg = shared
+        .clients
+        .lock()
+        .map_err(|e| NetError::internal(format!("lock clients error: {}", e)))?;
+    let targets = match to_peer {
+        Some(peer) => {
+            let p = cg
+                .get(peer)
+                .ok_or_else(|| NetError::peer_not_found(bind_addr, peer))?;
+            vec![(peer.to_string(), p.local.clone(), p.writer.clone())]
+        }
+        None => cg
+            .iter()
+            .map(|(p, w)| (p.clone(), w.local.clone(), w.writer.clone()))
+            .collect(),
+    };
+    Ok((sid, targets))
+}
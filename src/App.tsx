@@ -10,6 +10,15 @@ type Command = {
 	data: string;
 };
 
+// Every backend command resolves with an object carrying a human-readable `message`
+// and rejects with { code, errno, addr, message }.
+type CommandResult = { message: string; session_id?: string };
+
+function errorText(e: unknown): string {
+	if (e && typeof e === "object" && "message" in e) return String((e as { message: unknown }).message);
+	return String(e);
+}
+
 function CommandsSidebar({
 	open,
 	setOpen,
@@ -604,13 +613,13 @@ function UDPServerView({ active }: { active: boolean }) {
 			const b64 = btoa(String.fromCharCode(...bytes));
 			// Send using the currently configured IP:Port as source when possible.
 			// If there is a running server on that bind, backend uses the same socket (source port matches the listener).
-			await invoke<string>("udp_send_from", { bindAddr: currentBind, toAddr: sendTarget, dataB64: b64 });
+			await invoke<CommandResult>("udp_send_from", { bindAddr: currentBind, toAddr: sendTarget, dataB64: b64 });
 			// save to histories
 			addHistory("send_msg", sendMsg);
 			addHistory("send_target", sendTarget);
 			appendStatus(`sent ${bytes.length} bytes to ${sendTarget}`);
 		} catch (e) {
-			appendStatus(String(e));
+			appendStatus(errorText(e));
 		}
 	};
 
@@ -740,36 +749,36 @@ function UDPServerView({ active }: { active: boolean }) {
 		const bindAddr = currentBind;
 		if (!isConnected(bindAddr)) {
 			try {
-				const res = await invoke<string>("start_udp_server", { bindAddr });
-				appendStatus(res ?? `started ${bindAddr}`);
-				setStatus(res ?? "started");
+				const res = await invoke<CommandResult>("start_udp_server", { bindAddr });
+				appendStatus(res?.message ?? `started ${bindAddr}`);
+				setStatus(res?.message ?? "started");
 				setConnections((prev) => [bindAddr, ...prev.filter((v) => v !== bindAddr)]);
 			} catch (e) {
-				appendStatus(String(e));
-				setStatus(String(e));
+				appendStatus(errorText(e));
+				setStatus(errorText(e));
 			}
 		} else {
 			try {
-				const res = await invoke<string>("stop_udp_server", { bindAddr });
-				appendStatus(res ?? `stopped ${bindAddr}`);
-				setStatus(res ?? "stopped");
+				const res = await invoke<CommandResult>("stop_udp_server", { bindAddr });
+				appendStatus(res?.message ?? `stopped ${bindAddr}`);
+				setStatus(res?.message ?? "stopped");
 				setConnections((prev) => prev.filter((v) => v !== bindAddr));
 			} catch (e) {
-				appendStatus(String(e));
-				setStatus(String(e));
+				appendStatus(errorText(e));
+				setStatus(errorText(e));
 			}
 		}
 	};
 
 	const disconnectBind = async (bindAddr: string) => {
 		try {
-			const res = await invoke<string>("stop_udp_server", { bindAddr });
-			appendStatus(res ?? `stopped ${bindAddr}`);
-			setStatus(res ?? "stopped");
+			const res = await invoke<CommandResult>("stop_udp_server", { bindAddr });
+			appendStatus(res?.message ?? `stopped ${bindAddr}`);
+			setStatus(res?.message ?? "stopped");
 			setConnections((prev) => prev.filter((v) => v !== bindAddr));
 		} catch (e) {
-			appendStatus(String(e));
-			setStatus(String(e));
+			appendStatus(errorText(e));
+			setStatus(errorText(e));
 		}
 	};
 
@@ -1213,21 +1222,21 @@ function UDPClientView({ active }: { active: boolean }) {
 		const bindAddr = currentBind;
 		if (!isConnected(bindAddr)) {
 			try {
-				const res = await invoke<string>("start_udp_client", { bindAddr });
-				appendStatus(res ?? `started ${bindAddr}`);
+				const res = await invoke<CommandResult>("start_udp_client", { bindAddr });
+				appendStatus(res?.message ?? `started ${bindAddr}`);
 				setConnections((prev) => [bindAddr, ...prev.filter((v) => v !== bindAddr)]);
 				addHistory("bind_ip", ip);
 				addHistory("bind_port", port);
 			} catch (e) {
-				appendStatus(String(e));
+				appendStatus(errorText(e));
 			}
 		} else {
 			try {
-				const res = await invoke<string>("stop_udp_client", { bindAddr });
-				appendStatus(res ?? `stopped ${bindAddr}`);
+				const res = await invoke<CommandResult>("stop_udp_client", { bindAddr });
+				appendStatus(res?.message ?? `stopped ${bindAddr}`);
 				setConnections((prev) => prev.filter((v) => v !== bindAddr));
 			} catch (e) {
-				appendStatus(String(e));
+				appendStatus(errorText(e));
 			}
 		}
 	};
@@ -1238,12 +1247,12 @@ function UDPClientView({ active }: { active: boolean }) {
 			if (sendMode === "hex") bytes = parseHex(sendMsg);
 			else bytes = new TextEncoder().encode(sendMsg);
 			const b64 = btoa(String.fromCharCode(...bytes));
-			await invoke<string>("udp_client_send_from", { bindAddr: currentBind, toAddr: sendTarget, dataB64: b64 });
+			await invoke<CommandResult>("udp_client_send_from", { bindAddr: currentBind, toAddr: sendTarget, dataB64: b64 });
 			addHistory("send_msg", sendMsg);
 			addHistory("send_target", sendTarget);
 			appendStatus(`sent ${bytes.length} bytes to ${sendTarget}`);
 		} catch (e) {
-			appendStatus(String(e));
+			appendStatus(errorText(e));
 		}
 	};
 
@@ -1304,7 +1313,7 @@ function UDPClientView({ active }: { active: boolean }) {
 						{connections.map((c) => (
 							<li key={c} className={c === currentBind ? "connection-item current" : "connection-item"} style={{ display: "flex", alignItems: "center", minHeight: 30, gap: 8 }}>
 								<span className="conn-text" style={{ display: "inline-flex", alignItems: "center", height: 26, lineHeight: "26px", padding: "0 6px" }}>{c}</span>
-								<button className="conn-disconnect" onClick={async () => { try { const res = await invoke<string>("stop_udp_client", { bindAddr: c }); appendStatus(res ?? `stopped ${c}`); setConnections((prev) => prev.filter((v) => v !== c)); } catch (e) { appendStatus(String(e)); } }} style={{ height: 26, lineHeight: "26px", padding: "0 8px", display: "inline-flex", alignItems: "center", justifyContent: "center" }}>断开</button>
+								<button className="conn-disconnect" onClick={async () => { try { const res = await invoke<CommandResult>("stop_udp_client", { bindAddr: c }); appendStatus(res?.message ?? `stopped ${c}`); setConnections((prev) => prev.filter((v) => v !== c)); } catch (e) { appendStatus(errorText(e)); } }} style={{ height: 26, lineHeight: "26px", padding: "0 8px", display: "inline-flex", alignItems: "center", justifyContent: "center" }}>断开</button>
 							</li>
 						))}
 					</ul>
@@ -1501,16 +1510,16 @@ function TCPServerView({ active }: { active: boolean }) {
 			// check if already running by clients/other state
 			const running = false; // simple toggle based on client list not reliable; UI shows bind/unbind
 			if (!running) {
-				const res = await invoke<string>("start_tcp_server", { bindAddr: b });
-				appendStatus(res ?? `started ${b}`);
+				const res = await invoke<CommandResult>("start_tcp_server", { bindAddr: b });
+				appendStatus(res?.message ?? `started ${b}`);
 				addHistory("bind_ip", ip);
 				addHistory("bind_port", port);
 			} else {
-				const res = await invoke<string>("stop_tcp_server", { bindAddr: b });
-				appendStatus(res ?? `stopped ${b}`);
+				const res = await invoke<CommandResult>("stop_tcp_server", { bindAddr: b });
+				appendStatus(res?.message ?? `stopped ${b}`);
 			}
 		} catch (e) {
-			appendStatus(String(e));
+			appendStatus(errorText(e));
 		}
 	};
 
@@ -1520,11 +1529,11 @@ function TCPServerView({ active }: { active: boolean }) {
 			if (sendMode === "hex") bytes = parseHex(sendMsg);
 			else bytes = new TextEncoder().encode(sendMsg);
 			const b64 = btoa(String.fromCharCode(...bytes));
-			await invoke<string>("tcp_server_send", { bindAddr: bindAddr, toPeer: toPeer ?? undefined, dataB64: b64 });
+			await invoke<CommandResult>("tcp_server_send", { bindAddr: bindAddr, toPeer: toPeer ?? undefined, dataB64: b64 });
 			addHistory("send_msg", sendMsg);
 			appendStatus(`sent ${bytes.length} bytes`);
 		} catch (e) {
-			appendStatus(String(e));
+			appendStatus(errorText(e));
 		}
 	};
 
@@ -1761,20 +1770,20 @@ function TCPClientView({ active }: { active: boolean }) {
 		if (!addr) return;
 		if (!isConnected(addr)) {
 			try {
-				const res = await invoke<string>("start_tcp_client", { remoteAddr: addr });
-				appendStatus(res ?? `connected ${addr}`);
+				const res = await invoke<CommandResult>("start_tcp_client", { remoteAddr: addr });
+				appendStatus(res?.message ?? `connected ${addr}`);
 				setConnections((prev) => [addr, ...prev.filter((v) => v !== addr)]);
 				addHistory("tcp_remote", addr);
 			} catch (e) {
-				appendStatus(String(e));
+				appendStatus(errorText(e));
 			}
 		} else {
 			try {
-				const res = await invoke<string>("stop_tcp_client", { remoteAddr: addr });
-				appendStatus(res ?? `disconnected ${addr}`);
+				const res = await invoke<CommandResult>("stop_tcp_client", { remoteAddr: addr });
+				appendStatus(res?.message ?? `disconnected ${addr}`);
 				setConnections((prev) => prev.filter((v) => v !== addr));
 			} catch (e) {
-				appendStatus(String(e));
+				appendStatus(errorText(e));
 			}
 		}
 	};
@@ -1788,11 +1797,11 @@ function TCPClientView({ active }: { active: boolean }) {
 				bytes = new TextEncoder().encode(sendMsg);
 			}
 			const b64 = btoa(String.fromCharCode(...bytes));
-			await invoke<string>("tcp_client_send", { remoteAddr: remoteAddr.trim(), dataB64: b64 });
+			await invoke<CommandResult>("tcp_client_send", { remoteAddr: remoteAddr.trim(), dataB64: b64 });
 			addHistory("send_msg", sendMsg);
 			appendStatus(`sent ${bytes.length} bytes to ${remoteAddr}`);
 		} catch (e) {
-			appendStatus(String(e));
+			appendStatus(errorText(e));
 		}
 	};
 
@@ -1843,7 +1852,7 @@ function TCPClientView({ active }: { active: boolean }) {
 						{connections.map((c) => (
 							<li key={c} className={c === remoteAddr ? "connection-item current" : "connection-item"} style={{ display: "flex", alignItems: "center", minHeight: 30, gap: 8 }}>
 								<span className="conn-text" style={{ display: "inline-flex", alignItems: "center", height: 26, lineHeight: "26px", padding: "0 6px" }}>{c}</span>
-								<button className="conn-disconnect" onClick={async () => { try { const res = await invoke<string>("stop_tcp_client", { remoteAddr: c }); appendStatus(res ?? `disconnected ${c}`); setConnections((prev) => prev.filter((v) => v !== c)); } catch (e) { appendStatus(String(e)); } }} style={{ height: 26, lineHeight: "26px", padding: "0 8px", display: "inline-flex", alignItems: "center", justifyContent: "center" }}>断开</button>
+								<button className="conn-disconnect" onClick={async () => { try { const res = await invoke<CommandResult>("stop_tcp_client", { remoteAddr: c }); appendStatus(res?.message ?? `disconnected ${c}`); setConnections((prev) => prev.filter((v) => v !== c)); } catch (e) { appendStatus(errorText(e)); } }} style={{ height: 26, lineHeight: "26px", padding: "0 8px", display: "inline-flex", alignItems: "center", justifyContent: "center" }}>断开</button>
 							</li>
 						))}
 					</ul>